@@ -0,0 +1,54 @@
+use crate::http::{HttpRequest, StatusCode};
+use crate::logging::info;
+use crate::metrics::Metrics;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Started when a request has been parsed; finished once its response is on the wire
+pub struct RequestLog {
+    client: IpAddr,
+    country: Option<String>,
+    method: &'static str,
+    path: String,
+    started: Instant,
+    latency: Option<Duration>,
+}
+
+impl RequestLog {
+    pub fn start(request: &HttpRequest) -> Self {
+        Self {
+            client: request.connection_info.peer_addr.ip(),
+            country: request.connection_info.country.clone(),
+            method: request.method.as_str(),
+            path: request.path().to_string(),
+            started: Instant::now(),
+            latency: None,
+        }
+    }
+
+    // Marks the end of the handler, so write time isn't counted as handler latency
+    pub fn handled(&mut self) {
+        self.latency = Some(self.started.elapsed());
+    }
+
+    pub fn finish(self, status: Option<StatusCode>, bytes: u64, metrics: &Metrics) {
+        let latency = self.latency.unwrap_or_else(|| self.started.elapsed());
+        let status_code = status.map_or(0, |s| s.as_u16());
+        // The client's country, when GeoIP resolved one, follows its address
+        let country = self
+            .country
+            .as_deref()
+            .map_or(String::new(), |c| format!(" [{c}]"));
+        info!(
+            "{}{} {} {} {} {}B {:.2}ms",
+            self.client,
+            country,
+            self.method,
+            self.path,
+            status_code,
+            bytes,
+            latency.as_secs_f64() * 1000.0
+        );
+        metrics.record_request(self.client, &self.path, status, bytes, latency);
+    }
+}
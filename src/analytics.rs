@@ -0,0 +1,304 @@
+use crate::http::StatusCode;
+use crate::utils::json_string;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Traffic is aggregated into one bucket per minute, kept for an hour
+const BUCKET_LENGTH: Duration = Duration::from_secs(60);
+const BUCKETS_KEPT: u64 = 60;
+
+// Reported windows, in buckets; the first is just the current (partial) minute
+const WINDOWS: &[(&str, u64)] = &[("1m", 1), ("5m", 5), ("1h", 60)];
+
+const TOP_PATHS: usize = 10;
+
+// Distinct paths tracked per bucket; beyond this they are only counted in aggregate,
+// so a scan of random URLs can't grow memory without bound
+const MAX_PATHS_PER_BUCKET: usize = 1000;
+
+// Rolling traffic aggregates for the analytics endpoint: requests, bytes, status codes,
+// the busiest paths and an estimate of distinct client IPs
+pub struct Analytics {
+    started: Instant,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+struct Bucket {
+    // Minutes since `started`
+    index: u64,
+    requests: u64,
+    bytes: u64,
+    statuses: BTreeMap<u16, u64>,
+    paths: HashMap<String, u64>,
+    other_paths: u64,
+    clients: HyperLogLog,
+}
+
+impl Bucket {
+    fn new(index: u64) -> Self {
+        Bucket {
+            index,
+            requests: 0,
+            bytes: 0,
+            statuses: BTreeMap::new(),
+            paths: HashMap::new(),
+            other_paths: 0,
+            clients: HyperLogLog::new(),
+        }
+    }
+}
+
+impl Analytics {
+    pub fn new() -> Self {
+        Analytics {
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, client: IpAddr, path: &str, status: Option<StatusCode>, bytes: u64) {
+        self.record_at(Instant::now(), client, path, status, bytes);
+    }
+
+    fn record_at(
+        &self,
+        now: Instant,
+        client: IpAddr,
+        path: &str,
+        status: Option<StatusCode>,
+        bytes: u64,
+    ) {
+        let index = self.bucket_index(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.back().is_none_or(|b| b.index != index) {
+            buckets.push_back(Bucket::new(index));
+            while buckets
+                .front()
+                .is_some_and(|b| b.index + BUCKETS_KEPT <= index)
+            {
+                buckets.pop_front();
+            }
+        }
+        let bucket = buckets.back_mut().unwrap();
+
+        bucket.requests += 1;
+        bucket.bytes += bytes;
+        *bucket
+            .statuses
+            .entry(status.map_or(0, |s| s.as_u16()))
+            .or_default() += 1;
+        if let Some(count) = bucket.paths.get_mut(path) {
+            *count += 1;
+        } else if bucket.paths.len() < MAX_PATHS_PER_BUCKET {
+            bucket.paths.insert(path.to_string(), 1);
+        } else {
+            bucket.other_paths += 1;
+        }
+        bucket.clients.insert(&client);
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / BUCKET_LENGTH.as_secs()
+    }
+
+    // The summary served by the admin endpoint
+    pub fn to_json(&self) -> String {
+        self.to_json_at(Instant::now())
+    }
+
+    fn to_json_at(&self, now: Instant) -> String {
+        let current = self.bucket_index(now);
+        let buckets = self.buckets.lock().unwrap();
+
+        let mut out = String::from("{\"windows\":{");
+        for (i, (name, span)) in WINDOWS.iter().enumerate() {
+            let in_window: Vec<&Bucket> = buckets
+                .iter()
+                .filter(|b| b.index + span > current)
+                .collect();
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}:", json_string(name));
+            write_window(&mut out, &in_window);
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+impl fmt::Debug for Analytics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self.buckets.lock().unwrap().len();
+        write!(f, "Analytics({buckets} buckets)")
+    }
+}
+
+fn write_window(out: &mut String, buckets: &[&Bucket]) {
+    let mut statuses: BTreeMap<u16, u64> = BTreeMap::new();
+    let mut paths: HashMap<&str, u64> = HashMap::new();
+    let mut clients = HyperLogLog::new();
+    let (mut requests, mut bytes, mut other_paths) = (0, 0, 0);
+    for bucket in buckets {
+        requests += bucket.requests;
+        bytes += bucket.bytes;
+        other_paths += bucket.other_paths;
+        for (status, count) in &bucket.statuses {
+            *statuses.entry(*status).or_default() += count;
+        }
+        for (path, count) in &bucket.paths {
+            *paths.entry(path).or_default() += count;
+        }
+        clients.merge(&bucket.clients);
+    }
+
+    let mut top: Vec<(&str, u64)> = paths.into_iter().collect();
+    // Ties broken by path so the output is stable
+    top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    top.truncate(TOP_PATHS);
+
+    let _ = write!(
+        out,
+        "{{\"requests\":{requests},\"bytes_sent\":{bytes},\"unique_clients\":{},\"statuses\":{{",
+        clients.estimate()
+    );
+    for (i, (status, count)) in statuses.iter().enumerate() {
+        let sep = if i > 0 { "," } else { "" };
+        let _ = write!(out, "{sep}\"{status}\":{count}");
+    }
+    out.push_str("},\"top_paths\":[");
+    for (i, (path, count)) in top.iter().enumerate() {
+        let sep = if i > 0 { "," } else { "" };
+        let _ = write!(
+            out,
+            "{sep}{{\"path\":{},\"requests\":{count}}}",
+            json_string(path)
+        );
+    }
+    let _ = write!(out, "],\"untracked_path_requests\":{other_paths}}}");
+}
+
+// Cardinality sketch: 2^10 one-byte registers estimate distinct counts to within a
+// few percent in 1 KiB, and sketches merge by taking the larger register
+const HLL_BITS: u32 = 10;
+const HLL_REGISTERS: usize = 1 << HLL_BITS;
+
+struct HyperLogLog {
+    registers: Box<[u8; HLL_REGISTERS]>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        HyperLogLog {
+            registers: Box::new([0; HLL_REGISTERS]),
+        }
+    }
+
+    fn insert(&mut self, item: &impl Hash) {
+        // DefaultHasher::new() uses fixed keys, so the same IP always lands in the same
+        // register and merged sketches agree
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let register = (hash >> (64 - HLL_BITS)) as usize;
+        let rest = hash << HLL_BITS;
+        let rank = (rest.leading_zeros() + 1).min(64 - HLL_BITS + 1) as u8;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&r| 2_f64.powi(-(r as i32)))
+            .sum();
+        let raw = alpha * m * m / sum;
+
+        // Small cardinalities are better counted from the empty registers
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn hyperloglog_estimates_distinct_items() {
+        let mut sketch = HyperLogLog::new();
+        assert_eq!(sketch.estimate(), 0);
+
+        for n in 0..20_000 {
+            // Duplicates don't count
+            sketch.insert(&ip(n % 10_000));
+        }
+        let estimate = sketch.estimate() as f64;
+        assert!((estimate - 10_000.0).abs() < 1_000.0, "{estimate}");
+
+        let mut small = HyperLogLog::new();
+        for n in 0..5 {
+            small.insert(&ip(n));
+        }
+        assert_eq!(small.estimate(), 5);
+    }
+
+    #[test]
+    fn summarises_each_window() {
+        let analytics = Analytics::new();
+        let start = analytics.started;
+        let ok = Some(StatusCode::OK);
+
+        // Ten minutes ago: only in the hour window
+        let old = start;
+        analytics.record_at(old, ip(1), "/old", Some(StatusCode::NOT_FOUND), 0);
+        let now = start + Duration::from_secs(600);
+        analytics.record_at(now, ip(1), "/", ok, 10);
+        analytics.record_at(now, ip(2), "/", ok, 10);
+        analytics.record_at(now, ip(2), "/echo/\"hi\"", ok, 5);
+
+        let json = analytics.to_json_at(now);
+        assert!(json.starts_with(
+            "{\"windows\":{\"1m\":{\"requests\":3,\"bytes_sent\":25,\"unique_clients\":2,\
+             \"statuses\":{\"200\":3},\"top_paths\":[{\"path\":\"/\",\"requests\":2},\
+             {\"path\":\"/echo/\\\"hi\\\"\",\"requests\":1}],\"untracked_path_requests\":0},"
+        ));
+        assert!(json.contains(
+            "\"1h\":{\"requests\":4,\"bytes_sent\":25,\"unique_clients\":2,\
+             \"statuses\":{\"200\":3,\"404\":1}"
+        ));
+    }
+
+    #[test]
+    fn old_buckets_expire() {
+        let analytics = Analytics::new();
+        let start = analytics.started;
+        analytics.record_at(start, ip(1), "/", None, 0);
+        analytics.record_at(start + Duration::from_secs(3600), ip(1), "/", None, 0);
+
+        assert_eq!(analytics.buckets.lock().unwrap().len(), 1);
+    }
+}
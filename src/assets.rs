@@ -0,0 +1,295 @@
+use crate::http::request::HttpMethod;
+use crate::http::{Body, HttpRequest, HttpResponse, StatusCode, header};
+use crate::mime;
+use crate::utils::json_string;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+// Hex digits of the content hash put into file names
+const HASH_LENGTH: usize = 8;
+
+// Hashed names change whenever the content does, so they can be cached forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetConfig {
+    // URL prefix the directory is mounted at, e.g. "/assets"
+    pub prefix: String,
+    pub directory: PathBuf,
+    // Where the name -> hashed URL manifest is served; defaults to <prefix>/manifest.json
+    pub manifest_path: Option<String>,
+}
+
+impl AssetConfig {
+    pub fn manifest_path(&self) -> String {
+        match &self.manifest_path {
+            Some(path) => path.clone(),
+            None => format!("{}/manifest.json", self.prefix.trim_end_matches('/')),
+        }
+    }
+}
+
+// Serves a directory under a URL prefix with content-hashed file names for cache
+// busting: `app.js` is also served as `app.3f9a2c1d.js`, with immutable caching, and
+// the manifest tells templates which hashed URL to link to. The original names still
+// work but must be revalidated.
+pub struct Assets {
+    config: AssetConfig,
+    index: RwLock<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    // Hashed relative name -> original relative name
+    hashed: HashMap<String, String>,
+    // Original relative name -> hashed URL
+    manifest: BTreeMap<String, String>,
+}
+
+impl Assets {
+    pub fn new(config: AssetConfig) -> io::Result<Self> {
+        let index = Index::scan(&config)?;
+        Ok(Assets {
+            config,
+            index: RwLock::new(index),
+        })
+    }
+
+    // Hashes are computed up front, so content changed in place needs a rescan
+    pub fn rescan(&self) -> io::Result<()> {
+        let index = Index::scan(&self.config)?;
+        *self.index.write().unwrap() = index;
+        Ok(())
+    }
+
+    pub fn files(&self) -> usize {
+        self.index.read().unwrap().manifest.len()
+    }
+
+    // The response for a request under the mount, or None when the path is elsewhere
+    pub async fn respond(&self, request: &HttpRequest, path: &str) -> Option<HttpResponse> {
+        let manifest = path == self.config.manifest_path();
+        let relative = path
+            .strip_prefix(self.config.prefix.trim_end_matches('/'))
+            .and_then(|rest| rest.strip_prefix('/'));
+        if !manifest && relative.is_none() {
+            return None;
+        }
+        // Mounted, so the router leaves OPTIONS and other methods to us
+        if request.method == HttpMethod::Options {
+            let mut response = HttpResponse::new(StatusCode::NO_CONTENT, "text/plain", vec![]);
+            response.set_header(header::ALLOW, "GET, HEAD, OPTIONS");
+            return Some(response);
+        }
+        if !matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+            let mut response =
+                HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED, "text/plain", vec![]);
+            response.set_header(header::ALLOW, "GET, HEAD");
+            return Some(response);
+        }
+        if manifest {
+            let mut response = HttpResponse::new(
+                StatusCode::OK,
+                "application/json",
+                self.manifest_json().into_bytes(),
+            );
+            response.set_header(header::CACHE_CONTROL, "no-cache");
+            return Some(response);
+        }
+
+        let relative = relative?;
+        let (original, cache_control) = {
+            let index = self.index.read().unwrap();
+            match index.hashed.get(relative) {
+                Some(original) => (original.clone(), IMMUTABLE),
+                None if index.manifest.contains_key(relative) => (relative.to_string(), "no-cache"),
+                None => {
+                    return Some(HttpResponse::new(
+                        StatusCode::NOT_FOUND,
+                        "text/plain",
+                        vec![],
+                    ));
+                }
+            }
+        };
+
+        let file_path = self.config.directory.join(&original);
+        let response = match open(&file_path).await {
+            Ok(body) => {
+                let mut response =
+                    HttpResponse::with_body(StatusCode::OK, &mime::lookup(&original), body);
+                response.set_header(header::CACHE_CONTROL, cache_control);
+                response
+            }
+            // Deleted since the last scan
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::not_found(),
+            Err(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", vec![]),
+        };
+        Some(response)
+    }
+
+    fn manifest_json(&self) -> String {
+        let index = self.index.read().unwrap();
+        let entries: Vec<String> = index
+            .manifest
+            .iter()
+            .map(|(name, url)| format!("{}:{}", json_string(name), json_string(url)))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+impl Index {
+    fn scan(config: &AssetConfig) -> io::Result<Index> {
+        let mut files = Vec::new();
+        walk(&config.directory, "", &mut files)?;
+
+        let prefix = config.prefix.trim_end_matches('/');
+        let mut index = Index::default();
+        for relative in files {
+            let hash = hash_file(&config.directory.join(&relative))?;
+            let hashed = hashed_name(&relative, &format!("{hash:016x}")[..HASH_LENGTH]);
+            index
+                .manifest
+                .insert(relative.clone(), format!("{prefix}/{hashed}"));
+            index.hashed.insert(hashed, relative);
+        }
+        Ok(index)
+    }
+}
+
+// Relative, `/`-separated names of the files under `dir`; dotfiles are skipped
+fn walk(dir: &Path, base: &str, out: &mut Vec<String>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str().filter(|n| !n.starts_with('.')) else {
+            continue;
+        };
+        let relative = if base.is_empty() {
+            name.to_string()
+        } else {
+            format!("{base}/{name}")
+        };
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), &relative, out)?;
+        } else {
+            out.push(relative);
+        }
+    }
+    Ok(())
+}
+
+// `css/site.css` -> `css/site.<hash>.css`; the hash goes before the last extension
+fn hashed_name(relative: &str, hash: &str) -> String {
+    let (dir, file) = match relative.rsplit_once('/') {
+        Some((dir, file)) => (format!("{dir}/"), file),
+        None => (String::new(), relative),
+    };
+    match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{dir}{stem}.{hash}.{ext}"),
+        _ => format!("{dir}{file}.{hash}"),
+    }
+}
+
+// FNV-1a: stable across builds and platforms, which is all a cache-busting name needs
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buf = [0_u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for &byte in &buf[..n] {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+async fn open(path: &Path) -> io::Result<Body> {
+    let file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    Ok(Body::File {
+        file,
+        offset: 0,
+        len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{StatusCode, Uri};
+    use crate::utils::temp_dir;
+    use std::fs;
+
+    fn get(target: &str) -> HttpRequest {
+        HttpRequest {
+            uri: Uri::parse(target).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hashed_names_keep_the_extension() {
+        assert_eq!(hashed_name("app.js", "3f9a2c1d"), "app.3f9a2c1d.js");
+        assert_eq!(hashed_name("css/site.min.css", "ab"), "css/site.min.ab.css");
+        assert_eq!(hashed_name("LICENSE", "ab"), "LICENSE.ab");
+        assert_eq!(hashed_name(".env", "ab"), ".env.ab");
+    }
+
+    #[tokio::test]
+    async fn serves_hashed_urls_and_the_manifest() {
+        let dir = temp_dir("assets");
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+        fs::write(dir.join("css/site.css"), "body{}").unwrap();
+        let assets = Assets::new(AssetConfig {
+            prefix: "/assets".to_string(),
+            directory: dir.clone(),
+            manifest_path: None,
+        })
+        .unwrap();
+
+        let manifest = assets.respond(&get("/"), "/assets/manifest.json").await;
+        let manifest = format!("{:#}", manifest.unwrap());
+        let url = assets.index.read().unwrap().manifest["app.js"].clone();
+        assert!(
+            url.starts_with("/assets/app.") && url.ends_with(".js"),
+            "{url}"
+        );
+        assert!(manifest.contains(&format!(r#"\"app.js\":\"{url}\""#)));
+        assert!(manifest.contains(r#"\"css/site.css\":\"/assets/css/site."#));
+
+        let hashed = assets.respond(&get(&url), &url).await.unwrap();
+        assert_eq!(hashed.status_code(), StatusCode::OK);
+        assert_eq!(hashed.header(header::CACHE_CONTROL), Some(IMMUTABLE));
+        assert_eq!(hashed.header(header::CONTENT_TYPE), Some("text/javascript"));
+
+        let plain = assets.respond(&get("/"), "/assets/app.js").await.unwrap();
+        assert_eq!(plain.header(header::CACHE_CONTROL), Some("no-cache"));
+        let stale = "/assets/app.00000000.js";
+        let missing = assets.respond(&get(stale), stale).await.unwrap();
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+        assert!(assets.respond(&get("/other"), "/other").await.is_none());
+        let options = HttpRequest {
+            method: HttpMethod::Options,
+            ..get("/assets/app.js")
+        };
+        let options = assets.respond(&options, "/assets/app.js").await.unwrap();
+        assert_eq!(options.header(header::ALLOW), Some("GET, HEAD, OPTIONS"));
+
+        // New content, new name
+        fs::write(dir.join("app.js"), "console.log(2)").unwrap();
+        assets.rescan().unwrap();
+        assert_ne!(assets.index.read().unwrap().manifest["app.js"], url);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,545 @@
+use crate::http::header::{self, CacheControl};
+use crate::http::request::HttpMethod;
+use crate::http::{HttpRequest, HttpResponse, StatusCode, Uri, conditional};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Server-side cache of in-memory GET responses that declare a freshness lifetime
+// (`Cache-Control: max-age` or `s-maxage`). Clients can steer it with their own
+// Cache-Control: `no-cache` forces a fresh response, `max-age` caps the age they will
+// accept and `min-fresh` asks for a response that stays fresh for a while longer.
+//
+// Entries with a validator (ETag or Last-Modified) are kept past their lifetime and
+// revalidated: the handler runs with If-None-Match / If-Modified-Since, and a 304
+// from it refreshes the entry instead of replacing the body. Clients' own conditional
+// requests are answered with 304 from the cached validators.
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    capacity: usize,
+}
+
+struct Entry {
+    response: HttpResponse,
+    stored: Instant,
+    lifetime: Duration,
+}
+
+impl Entry {
+    fn revalidatable(&self) -> bool {
+        self.response.header(header::ETAG).is_some()
+            || self.response.header(header::LAST_MODIFIED).is_some()
+    }
+}
+
+enum Lookup {
+    Fresh(HttpResponse),
+    // Too old to serve as is, but the origin can confirm it is still current
+    Stale(HttpResponse),
+    Miss,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    // Answers from the cache when the client's directives allow it, otherwise runs
+    // `fetch` (revalidating a stale entry if there is one) and stores the result if it
+    // is cacheable. `fetch` gets the request to handle: the client's, or for GETs a
+    // copy carrying the cache's validators in place of the client's.
+    pub async fn get_or_fetch<F>(&self, request: &HttpRequest, fetch: F) -> HttpResponse
+    where
+        F: AsyncFnOnce(&HttpRequest) -> HttpResponse,
+    {
+        if !request.method.is_safe() {
+            let response = fetch(request).await;
+            invalidate_written(self, request, &response);
+            return response;
+        }
+        if request.method != HttpMethod::Get {
+            return fetch(request).await;
+        }
+        let directives = client_directives(request);
+        if directives.no_store {
+            return fetch(request).await;
+        }
+
+        let key = request.uri.to_string();
+        let stale = match self.lookup(&key, &directives, Instant::now()) {
+            Lookup::Fresh(response) => return conditional::evaluate(request, response),
+            Lookup::Stale(response) => Some(response),
+            Lookup::Miss => None,
+        };
+
+        // The origin sees our validators, not the client's: its 304 then means the
+        // cached copy is current, and a 200 is a full response worth storing
+        let origin = origin_request(request, stale.as_ref());
+        let response = fetch(&origin).await;
+        let response = match stale {
+            Some(stale) if response.status_code() == StatusCode::NOT_MODIFIED => {
+                self.refresh(&key, stale, &response, Instant::now())
+            }
+            _ => {
+                self.store(key, &response, Instant::now());
+                response
+            }
+        };
+        conditional::evaluate(request, response)
+    }
+
+    // Drops every entry whose target starts with `prefix`, query strings included
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(prefix));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Approximate bytes held by the cached responses, keys included
+    pub fn memory_size(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| key.len() + entry.response.memory_size())
+            .sum()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn lookup(&self, key: &str, directives: &CacheControl, now: Instant) -> Lookup {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(key) else {
+            return Lookup::Miss;
+        };
+        let Some(mut response) = entry.response.try_clone() else {
+            return Lookup::Miss;
+        };
+        let age = now.saturating_duration_since(entry.stored);
+        let min_fresh = Duration::from_secs(directives.min_fresh.unwrap_or(0));
+        let max_age = directives.max_age.map(Duration::from_secs);
+
+        // no-cache means the client wants the origin's word, not ours
+        let usable = !directives.no_cache
+            && age + min_fresh < entry.lifetime
+            && max_age.is_none_or(|max| age <= max);
+        if usable {
+            response.set_header(header::AGE, age.as_secs().to_string());
+            Lookup::Fresh(response)
+        } else if entry.revalidatable() {
+            Lookup::Stale(response)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    // The origin confirmed `stale` is current: its 304 headers (a new lifetime, say)
+    // are merged in and the clock restarts
+    fn refresh(
+        &self,
+        key: &str,
+        mut stale: HttpResponse,
+        not_modified: &HttpResponse,
+        now: Instant,
+    ) -> HttpResponse {
+        for (name, value) in not_modified.headers() {
+            stale.set_header(name, value);
+        }
+        stale.set_header(header::AGE, "0");
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(key) {
+            for (name, value) in not_modified.headers() {
+                entry.response.set_header(name, value);
+            }
+            entry.stored = now;
+            if let Some(lifetime) = lifetime(&entry.response) {
+                entry.lifetime = lifetime;
+            }
+        }
+        stale
+    }
+
+    fn store(&self, key: String, response: &HttpResponse, now: Instant) {
+        if self.capacity == 0 || response.status_code() != StatusCode::OK {
+            return;
+        }
+        // Entries are keyed on the target alone, so a response chosen by request headers
+        // (a precompressed companion, say) would go out to clients that never asked for it
+        if response.header(header::SET_COOKIE).is_some() || response.header(header::VARY).is_some()
+        {
+            return;
+        }
+        let Some(lifetime) = lifetime(response) else {
+            return;
+        };
+        let Some(copy) = response.try_clone() else {
+            return;
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| {
+            now.saturating_duration_since(e.stored) < e.lifetime || e.revalidatable()
+        });
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // Still full: make room by dropping the oldest
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.stored)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                response: copy,
+                stored: now,
+                lifetime,
+            },
+        );
+    }
+}
+
+// How long a response may be served from the cache, if it may be cached at all
+fn lifetime(response: &HttpResponse) -> Option<Duration> {
+    let directives = CacheControl::parse(response.header(header::CACHE_CONTROL)?);
+    if directives.no_store || directives.no_cache || directives.private {
+        return None;
+    }
+    directives
+        .s_maxage
+        .or(directives.max_age)
+        .map(Duration::from_secs)
+}
+
+fn origin_request(request: &HttpRequest, stale: Option<&HttpResponse>) -> HttpRequest {
+    let mut headers = request.headers.clone();
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    if let Some(stale) = stale {
+        if let Some(etag) = stale.header(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH.to_string(), etag.to_string());
+        }
+        if let Some(modified) = stale.header(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE.to_string(), modified.to_string());
+        }
+    }
+    HttpRequest {
+        method: request.method,
+        uri: request.uri.clone(),
+        headers,
+        connection_info: Arc::clone(&request.connection_info),
+        context: request.context.clone(),
+        extensions: request.extensions.clone(),
+        ..Default::default()
+    }
+}
+
+// A write that went through leaves the cached copy of its target out of date, and for
+// MOVE the copy of where it went too (RFC 9111 section 4.4). Errors changed nothing.
+fn invalidate_written(cache: &ResponseCache, request: &HttpRequest, response: &HttpResponse) {
+    let status = response.status_code();
+    if !status.is_success() && !status.is_redirect() {
+        return;
+    }
+    cache.invalidate_prefix(request.uri.path());
+    if request.method == HttpMethod::Move
+        && let Some(destination) = request.headers.get(header::DESTINATION)
+        && let Ok(destination) = Uri::parse(destination.trim())
+    {
+        cache.invalidate_prefix(destination.path());
+    }
+}
+
+// `Pragma: no-cache` is the HTTP/1.0 spelling of `Cache-Control: no-cache`
+fn client_directives(request: &HttpRequest) -> CacheControl {
+    let mut directives = request
+        .headers
+        .get(header::CACHE_CONTROL)
+        .map(CacheControl::parse)
+        .unwrap_or_default();
+    if !request.headers.contains_key(header::CACHE_CONTROL)
+        && request
+            .headers
+            .get(header::PRAGMA)
+            .is_some_and(|v| v.eq_ignore_ascii_case("no-cache"))
+    {
+        directives.no_cache = true;
+    }
+    directives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Headers;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn get(target: &str, cache_control: Option<&str>) -> HttpRequest {
+        let mut headers = Headers::new();
+        if let Some(cc) = cache_control {
+            headers.insert(header::CACHE_CONTROL.to_string(), cc.to_string());
+        }
+        HttpRequest {
+            uri: Uri::parse(target).unwrap(),
+            headers,
+            ..Default::default()
+        }
+    }
+
+    fn cacheable(max_age: u64) -> HttpResponse {
+        let mut response = HttpResponse::new(StatusCode::OK, "text/plain", b"hello".to_vec());
+        response.set_header(header::CACHE_CONTROL, format!("max-age={max_age}"));
+        response
+    }
+
+    // Pretends the entry for `key` was stored `secs` seconds ago
+    fn age_entry(cache: &ResponseCache, key: &str, secs: u64) {
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.get_mut(key).unwrap();
+        entry.stored = Instant::now() - Duration::from_secs(secs);
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_entries_with_age() {
+        let cache = ResponseCache::new(8);
+        let calls = AtomicUsize::new(0);
+        let fetch = async |_: &HttpRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            cacheable(60)
+        };
+
+        cache.get_or_fetch(&get("/a", None), fetch).await;
+        age_entry(&cache, "/a", 10);
+        let response = cache.get_or_fetch(&get("/a", None), fetch).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(response.header(header::AGE), Some("10"));
+    }
+
+    #[tokio::test]
+    async fn honours_client_directives() {
+        let cache = ResponseCache::new(8);
+        let calls = AtomicUsize::new(0);
+        let fetch = async |_: &HttpRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            cacheable(60)
+        };
+
+        cache.get_or_fetch(&get("/a", None), fetch).await;
+        for cc in ["no-cache", "max-age=10", "min-fresh=40"] {
+            age_entry(&cache, "/a", 30);
+            let response = cache.get_or_fetch(&get("/a", Some(cc)), fetch).await;
+            assert_eq!(response.header(header::AGE), None, "{cc}");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Looser limits still accept the 30 second old entry
+        age_entry(&cache, "/a", 30);
+        let response = cache
+            .get_or_fetch(&get("/a", Some("max-age=45, min-fresh=20")), fetch)
+            .await;
+        assert_eq!(response.header(header::AGE), Some("30"));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn skips_uncacheable_responses() {
+        let cache = ResponseCache::new(8);
+        let calls = AtomicUsize::new(0);
+        let fetch = async |_: &HttpRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            HttpResponse::new(StatusCode::OK, "text/plain", b"no lifetime".to_vec())
+        };
+
+        cache.get_or_fetch(&get("/a", None), fetch).await;
+        cache.get_or_fetch(&get("/a", None), fetch).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn negotiated_responses_are_not_shared() {
+        let cache = ResponseCache::new(8);
+        let origin = async |request: &HttpRequest| {
+            let mut response = cacheable(60);
+            response.set_header(header::VARY, "Accept-Encoding");
+            if request.headers.contains_key(header::ACCEPT_ENCODING) {
+                response.set_header(header::CONTENT_ENCODING, "br");
+            }
+            response
+        };
+
+        let mut br = get("/style.css", None);
+        br.headers
+            .insert(header::ACCEPT_ENCODING.to_string(), "br".to_string());
+        let response = cache.get_or_fetch(&br, &origin).await;
+        assert_eq!(response.header(header::CONTENT_ENCODING), Some("br"));
+
+        let response = cache.get_or_fetch(&get("/style.css", None), &origin).await;
+        assert_eq!(response.header(header::CONTENT_ENCODING), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entry_when_full() {
+        let cache = ResponseCache::new(2);
+        for target in ["/a", "/b", "/c"] {
+            cache
+                .get_or_fetch(&get(target, None), async |_| cacheable(60))
+                .await;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let entries = cache.entries.lock().unwrap();
+        assert!(!entries.contains_key("/a"));
+        assert!(entries.contains_key("/b") && entries.contains_key("/c"));
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_revalidated_with_the_origin() {
+        let cache = ResponseCache::new(8);
+        let seen = Mutex::new(Vec::new());
+        // An origin that honours conditionals, as the server's handlers do
+        let origin = async |request: &HttpRequest| {
+            seen.lock().unwrap().push(
+                request
+                    .headers
+                    .get(header::IF_NONE_MATCH)
+                    .map(str::to_string),
+            );
+            let mut response = cacheable(60);
+            response.set_header(header::ETAG, "\"v1\"");
+            conditional::evaluate(request, response)
+        };
+
+        cache.get_or_fetch(&get("/a", None), &origin).await;
+        age_entry(&cache, "/a", 90);
+        let response = cache.get_or_fetch(&get("/a", None), &origin).await;
+
+        // The handler was asked with the cached ETag, and its 304 refreshed the entry
+        assert_eq!(*seen.lock().unwrap(), [None, Some("\"v1\"".to_string())]);
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(format!("{response:#}").lines().last(), Some("hello"));
+        let response = cache.get_or_fetch(&get("/a", None), &origin).await;
+        assert_eq!(response.header(header::AGE), Some("0"));
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn conditional_clients_get_304_from_cached_validators() {
+        let cache = ResponseCache::new(8);
+        let calls = AtomicUsize::new(0);
+        let origin = async |_: &HttpRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let mut response = cacheable(60);
+            response.set_header(header::ETAG, "\"v1\"");
+            response
+        };
+
+        cache.get_or_fetch(&get("/a", None), &origin).await;
+        let mut request = get("/a", None);
+        request
+            .headers
+            .insert(header::IF_NONE_MATCH.to_string(), "\"v1\"".to_string());
+        let response = cache.get_or_fetch(&request, &origin).await;
+
+        assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.header(header::ETAG), Some("\"v1\""));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn successful_writes_invalidate_their_targets() {
+        let cache = ResponseCache::new(8);
+        let version = AtomicUsize::new(1);
+        let origin = async |request: &HttpRequest| match request.method {
+            HttpMethod::Get => {
+                let body = format!("v{}", version.load(Ordering::SeqCst));
+                let mut response = HttpResponse::new(StatusCode::OK, "text/plain", body.into());
+                response.set_header(header::CACHE_CONTROL, "max-age=60");
+                response
+            }
+            _ if request.headers.contains_key("x-fail") => {
+                HttpResponse::new(StatusCode::FORBIDDEN, "text/plain", vec![])
+            }
+            _ => {
+                version.fetch_add(1, Ordering::SeqCst);
+                HttpResponse::new(StatusCode::CREATED, "text/plain", vec![])
+            }
+        };
+        let body =
+            |response: HttpResponse| format!("{response:#}").lines().last().unwrap().to_string();
+        let write = |method, target: &str| HttpRequest {
+            method,
+            ..get(target, None)
+        };
+
+        assert_eq!(
+            body(
+                cache
+                    .get_or_fetch(&get("/files/a.txt", None), &origin)
+                    .await
+            ),
+            "v1"
+        );
+        cache
+            .get_or_fetch(&write(HttpMethod::Put, "/files/a.txt"), &origin)
+            .await;
+        assert_eq!(
+            body(
+                cache
+                    .get_or_fetch(&get("/files/a.txt", None), &origin)
+                    .await
+            ),
+            "v2"
+        );
+
+        // A refused write changed nothing, so the entry stays
+        let mut refused = write(HttpMethod::Put, "/files/a.txt");
+        refused.headers.insert("x-fail", "1");
+        cache.get_or_fetch(&refused, &origin).await;
+        assert_eq!(cache.len(), 1);
+
+        // A move invalidates where the file went as well as where it was
+        cache
+            .get_or_fetch(&get("/files/b.txt", None), &origin)
+            .await;
+        let mut moved = write(HttpMethod::Move, "/files/a.txt");
+        moved
+            .headers
+            .insert(header::DESTINATION, "http://localhost/files/b.txt");
+        cache.get_or_fetch(&moved, &origin).await;
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn invalidation_drops_matching_targets() {
+        let cache = ResponseCache::new(8);
+        for target in ["/files/a.txt", "/files/a.txt?v=2", "/files/b.txt"] {
+            cache
+                .get_or_fetch(&get(target, None), async |_| cacheable(60))
+                .await;
+        }
+
+        cache.invalidate_prefix("/files/a.txt");
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["/files/b.txt"]);
+    }
+}
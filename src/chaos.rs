@@ -0,0 +1,248 @@
+use crate::http::{HttpRequest, HttpResponse, StatusCode, header};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWrite;
+
+// What to do to a request picked for fault injection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // Hold the request this long before handling it
+    Delay(Duration),
+    // Answer with this status instead of routing; None picks 500 or 503 at random
+    Error(Option<StatusCode>),
+    // Send the head and only half the body, then close the connection
+    Truncate,
+    // Close the connection without answering
+    Drop,
+}
+
+impl Fault {
+    pub fn parse(name: &str, delay: Duration, status: Option<StatusCode>) -> Option<Fault> {
+        match name {
+            "delay" => Some(Fault::Delay(delay)),
+            "error" => Some(Fault::Error(status)),
+            "truncate" => Some(Fault::Truncate),
+            "drop" => Some(Fault::Drop),
+            _ => None,
+        }
+    }
+}
+
+// Injects `fault` into `percent` (0-100) of the requests under `prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChaosRule {
+    pub prefix: String,
+    pub percent: u8,
+    pub fault: Fault,
+}
+
+// Fault injection for testing how clients cope with a misbehaving server. Every rule
+// matching a request rolls independently, in order; the first that hits applies.
+pub struct Chaos {
+    rules: Vec<ChaosRule>,
+    // xorshift64 state; the faults only need to look random, not be unpredictable
+    state: AtomicU64,
+}
+
+impl Chaos {
+    pub fn new(rules: Vec<ChaosRule>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::with_seed(rules, seed)
+    }
+
+    fn with_seed(rules: Vec<ChaosRule>, seed: u64) -> Self {
+        Chaos {
+            rules,
+            // xorshift never leaves zero
+            state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    // The fault to inject into this request, if any. An `Error` always comes back with
+    // its status decided.
+    pub fn pick(&self, request: &HttpRequest) -> Option<Fault> {
+        let path = request.path();
+        let rule = self
+            .rules
+            .iter()
+            .filter(|r| path.starts_with(&r.prefix))
+            .find(|r| self.next() % 100 < u64::from(r.percent))?;
+        Some(match rule.fault {
+            Fault::Error(None) => Fault::Error(Some(if self.next().is_multiple_of(2) {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })),
+            fault => fault,
+        })
+    }
+
+    fn next(&self) -> u64 {
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let prev = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+            .unwrap();
+        step(prev)
+    }
+}
+
+pub fn error_response(status: StatusCode) -> HttpResponse {
+    let mut response = HttpResponse::new(status, "text/plain", vec![]);
+    response.set_header(header::CONNECTION, "close");
+    response
+}
+
+// Lets the response head through, then only half of the body its Content-Length
+// announces (none of a chunked body) and silently discards the rest; the caller
+// closes the connection afterwards. Disabled, it passes straight through.
+pub struct Truncating<S> {
+    inner: S,
+    state: Option<Truncation>,
+}
+
+struct Truncation {
+    head: Vec<u8>,
+    // Body bytes still allowed through, once the head is complete
+    budget: Option<u64>,
+}
+
+impl<S> Truncating<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        let state = enabled.then(|| Truncation {
+            head: Vec::new(),
+            budget: None,
+        });
+        Truncating { inner, state }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Truncating<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(state) = &mut this.state else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        match &mut state.budget {
+            // The head goes out in writes of its own, so everything here belongs to it
+            None => {
+                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+                state.head.extend_from_slice(&buf[..n]);
+                if state.head.ends_with(b"\r\n\r\n") {
+                    state.budget = Some(declared_length(&state.head) / 2);
+                }
+                Poll::Ready(Ok(n))
+            }
+            Some(0) => Poll::Ready(Ok(buf.len())),
+            Some(budget) => {
+                let allowed = buf.len().min(*budget as usize);
+                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+                *budget -= n as u64;
+                Poll::Ready(Ok(n))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn declared_length(head: &[u8]) -> u64 {
+    String::from_utf8_lossy(head)
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case(header::CONTENT_LENGTH))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Uri;
+    use tokio::io::AsyncWriteExt;
+
+    fn request(path: &str) -> HttpRequest {
+        HttpRequest {
+            uri: Uri::parse(path).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn rule(prefix: &str, percent: u8, fault: Fault) -> ChaosRule {
+        ChaosRule {
+            prefix: prefix.to_string(),
+            percent,
+            fault,
+        }
+    }
+
+    #[test]
+    fn injects_at_roughly_the_configured_rate() {
+        let chaos = Chaos::with_seed(vec![rule("/api", 25, Fault::Drop)], 42);
+        let hits = (0..10_000)
+            .filter(|_| chaos.pick(&request("/api/x")).is_some())
+            .count();
+        assert!((2_000..3_000).contains(&hits), "{hits}");
+        assert_eq!(chaos.pick(&request("/other")), None);
+    }
+
+    #[test]
+    fn rules_roll_in_order_and_errors_get_a_status() {
+        let chaos = Chaos::with_seed(
+            vec![
+                rule("/", 0, Fault::Drop),
+                rule("/", 100, Fault::Error(None)),
+                rule("/", 100, Fault::Truncate),
+            ],
+            7,
+        );
+        let mut seen = Vec::new();
+        for _ in 0..50 {
+            match chaos.pick(&request("/")) {
+                Some(Fault::Error(Some(status))) => seen.push(status),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert!(seen.contains(&StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(seen.contains(&StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn truncating_writer_cuts_the_body_in_half() {
+        let mut sink = Vec::new();
+        let mut stream = Truncating::new(&mut sink, true);
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n")
+            .await
+            .unwrap();
+        stream.write_all(b"0123456789").await.unwrap();
+        assert_eq!(sink, b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234");
+
+        let mut sink = Vec::new();
+        Truncating::new(&mut sink, false)
+            .write_all(b"HTTP/1.1 200 OK\r\n\r\nall")
+            .await
+            .unwrap();
+        assert!(sink.ends_with(b"all"));
+    }
+}
@@ -0,0 +1,825 @@
+use super::toml::{self, ParseError, Table, Value};
+use super::{
+    AccessRule, BandwidthRule, CacheControlRule, Config, Mount, RateLimitRule, Requirement,
+    Symlinks, TrailingSlash, User,
+};
+use crate::assets::AssetConfig;
+use crate::chaos::{ChaosRule, Fault};
+use crate::http::decode::DecompressionLimits;
+use crate::http::request::HttpMethod;
+use crate::http::{StatusCode, header};
+use crate::mirror::MirrorConfig;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read {path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// Reads a config file; anything it doesn't mention keeps its default. Durations are
+// whole seconds and sizes are bytes. Unknown keys are an error, so a typo can't
+// silently leave a limit unset.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.display().to_string(),
+        source,
+    })?;
+    parse(&text)
+}
+
+pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    let mut top = Fields::new(toml::parse(text)?, String::new());
+    let mut config = Config::default();
+
+    if let Some(secs) = top.integer("keep_alive_timeout")? {
+        config.keep_alive_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = top.integer("request_timeout")? {
+        config.request_timeout = Duration::from_secs(secs);
+    }
+    if let Some(secs) = top.integer("handler_timeout")? {
+        config.handler_timeout = Duration::from_secs(secs);
+    }
+    if let Some(n) = top.integer("max_requests_per_connection")? {
+        config.max_requests_per_connection = n as usize;
+    }
+    if let Some(policy) = top.string("trailing_slash")? {
+        config.trailing_slash = match policy.as_str() {
+            "strict" => TrailingSlash::Strict,
+            "ignore" => TrailingSlash::Ignore,
+            "redirect" => TrailingSlash::Redirect,
+            "directories" => TrailingSlash::Directories,
+            _ => {
+                return Err(top.invalid(
+                    "trailing_slash",
+                    "expected strict, ignore, redirect or directories",
+                ));
+            }
+        };
+    }
+    config.max_body_size = top.integer("max_body_size")?;
+    if let Some(n) = top.integer("max_request_line")? {
+        config.head_limits.max_request_line = n as usize;
+    }
+    if let Some(n) = top.integer("max_header_bytes")? {
+        config.head_limits.max_header_bytes = n as usize;
+    }
+    if let Some(n) = top.integer("max_headers")? {
+        config.head_limits.max_headers = n as usize;
+    }
+    if let Some(limit) = top.integer("body_buffer_limit")? {
+        config.body_buffer_limit = limit;
+    }
+    config.download_limit = top.integer("download_limit")?;
+    config.upload_limit = top.integer("upload_limit")?;
+    if let Some(entries) = top.integer("response_cache_entries")? {
+        config.response_cache_entries = entries as usize;
+    }
+    if let Some(size) = top.integer("file_cache_size")? {
+        config.file_cache_size = size;
+    }
+    if let Some(size) = top.integer("file_cache_max_file_size")? {
+        config.file_cache_max_file_size = size;
+    }
+    config.analytics_path = top.string("analytics_path")?;
+    if let Some(autoindex) = top.boolean("autoindex")? {
+        config.autoindex = autoindex;
+    }
+    if let Some(spa) = top.boolean("spa")? {
+        config.spa = spa;
+    }
+    if let Some(allow) = top.boolean("allow_dotfiles")? {
+        config.allow_dotfiles = allow;
+    }
+    if let Some(policy) = top.string("symlinks")? {
+        config.symlinks = match policy.as_str() {
+            "follow" => Symlinks::Follow,
+            "inside" => Symlinks::Inside,
+            "never" => Symlinks::Never,
+            _ => {
+                return Err(top.invalid("symlinks", "expected follow, inside or never"));
+            }
+        };
+    }
+    config.index_files = top.strings("index_files")?;
+    if config
+        .index_files
+        .iter()
+        .any(|name| name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']))
+    {
+        return Err(top.invalid("index_files", "expected plain file names"));
+    }
+    // Checked against the access rules once they are read
+    let file_manager = top.string("file_manager")?;
+    config.problem_json = top.strings("problem_json")?;
+    if let Some(addr) = top.string("admin_address")? {
+        let addr = addr
+            .parse()
+            .map_err(|_| top.invalid("admin_address", "expected ip:port"))?;
+        config.admin_address = Some(addr);
+    }
+    // A string replaces the Server header's value; false drops the header
+    match top.table.remove("server_header") {
+        None | Some(Value::Boolean(true)) => {}
+        Some(Value::Boolean(false)) => config.response_defaults.server = None,
+        Some(Value::String(s)) if header::is_valid_value(&s) => {
+            config.response_defaults.server = Some(s)
+        }
+        Some(Value::String(_)) => {
+            return Err(top.invalid("server_header", "contains a line break"));
+        }
+        Some(other) => return Err(top.mismatch("server_header", "a string or false", &other)),
+    }
+    // Every key in this table is a header name
+    if let Some(mut headers) = top.table("response_headers")? {
+        for (name, value) in std::mem::take(&mut headers.table) {
+            let Value::String(value) = value else {
+                return Err(headers.mismatch(&name, "a string", &value));
+            };
+            if !header::is_valid_name(&name) {
+                return Err(headers.invalid(&name, "invalid header name"));
+            }
+            if !header::is_valid_value(&value) {
+                return Err(headers.invalid(&name, "contains a line break"));
+            }
+            config.response_defaults.headers.push((name, value));
+        }
+    }
+    // Every key is a status code or class, every value a file in the served directory
+    if let Some(mut pages) = top.table("error_pages")? {
+        for (key, value) in std::mem::take(&mut pages.table) {
+            let Value::String(path) = value else {
+                return Err(pages.mismatch(&key, "a string", &value));
+            };
+            if !crate::error_pages::is_valid_key(&key) {
+                return Err(pages.invalid(&key, "expected an error status like 404 or 5xx"));
+            }
+            config.error_pages.push((key, path.into()));
+        }
+    }
+    // Every key is a file extension, every value the Content-Type it's served with
+    if let Some(mut types) = top.table("mime_types")? {
+        for (ext, value) in std::mem::take(&mut types.table) {
+            let Value::String(content_type) = value else {
+                return Err(types.mismatch(&ext, "a string", &value));
+            };
+            if !content_type.contains('/') || !header::is_valid_value(&content_type) {
+                return Err(types.invalid(&ext, "expected a media type like text/css"));
+            }
+            config.mime_types.push((ext, content_type));
+        }
+    }
+    config.default_charset = top.string("default_charset")?;
+    if let Some(path) = top.string("geoip_database")? {
+        if !cfg!(feature = "geoip") {
+            return Err(top.invalid("geoip_database", "built without the geoip feature"));
+        }
+        config.geoip_database = Some(path.into());
+    }
+
+    for mut mount in top.tables("mount")? {
+        let prefix = mount.required_string("prefix")?;
+        let directory = mount.required_string("directory")?;
+        let parsed = Mount::new(&prefix, &directory).map_err(|e| mount.invalid("prefix", &e))?;
+        config.mounts.push(parsed);
+        mount.finish()?;
+    }
+
+    for mut rule in top.tables("cache_control")? {
+        let pattern = rule.required_string("path")?;
+        let value = rule.required_string("value")?;
+        if !header::is_valid_value(&value) {
+            return Err(rule.invalid("value", "not a valid header value"));
+        }
+        config
+            .cache_control
+            .push(CacheControlRule { pattern, value });
+        rule.finish()?;
+    }
+
+    for mut rule in top.tables("bandwidth")? {
+        config.bandwidth_rules.push(BandwidthRule {
+            prefix: rule.required_string("prefix")?,
+            download: rule.integer("download")?,
+            upload: rule.integer("upload")?,
+        });
+        rule.finish()?;
+    }
+
+    for mut rule in top.tables("rate_limit")? {
+        let method = match rule.string("method")? {
+            Some(name) => Some(rule.method("method", &name)?),
+            None => None,
+        };
+        let limit = rule.required_integer("limit")?;
+        config.rate_limits.push(RateLimitRule {
+            method,
+            prefix: rule.string("prefix")?.unwrap_or_else(|| "/".to_string()),
+            limit: u32::try_from(limit).map_err(|_| rule.invalid("limit", "too large"))?,
+            window: Duration::from_secs(rule.required_integer("window")?),
+        });
+        rule.finish()?;
+    }
+
+    // The table's presence turns decompression on; its keys override the default limits
+    if let Some(mut limits) = top.table("request_decompression")? {
+        let defaults = DecompressionLimits::default();
+        let max_ratio = limits.integer("max_ratio")?.unwrap_or(defaults.max_ratio);
+        if max_ratio == 0 {
+            return Err(limits.invalid("max_ratio", "must be at least 1"));
+        }
+        config.request_decompression = Some(DecompressionLimits {
+            max_size: limits.integer("max_size")?.unwrap_or(defaults.max_size),
+            max_ratio,
+        });
+        limits.finish()?;
+    }
+
+    if let Some(mut mirror) = top.table("mirror")? {
+        let upstream = mirror.required_string("upstream")?;
+        let percent = mirror.required_integer("percent")?;
+        if percent > 100 {
+            return Err(mirror.invalid("percent", "must be between 0 and 100"));
+        }
+        config.mirror = Some(MirrorConfig {
+            upstream,
+            percent: percent as u8,
+        });
+        mirror.finish()?;
+    }
+
+    for mut rule in top.tables("chaos")? {
+        let percent = rule.required_integer("percent")?;
+        if percent > 100 {
+            return Err(rule.invalid("percent", "must be between 0 and 100"));
+        }
+        let delay = Duration::from_millis(rule.integer("delay_ms")?.unwrap_or(0));
+        let status = match rule.integer("status")? {
+            Some(code) => Some(
+                u16::try_from(code)
+                    .ok()
+                    .and_then(StatusCode::from_u16)
+                    .ok_or_else(|| rule.invalid("status", "not a status code"))?,
+            ),
+            None => None,
+        };
+        let name = rule.required_string("fault")?;
+        let fault = Fault::parse(&name, delay, status).ok_or_else(|| {
+            rule.invalid(
+                "fault",
+                &format!("unknown fault `{name}` (expected delay, error, truncate or drop)"),
+            )
+        })?;
+        if fault == Fault::Delay(Duration::ZERO) {
+            return Err(rule.invalid("delay_ms", "missing"));
+        }
+        config.chaos.push(ChaosRule {
+            prefix: rule.string("prefix")?.unwrap_or_else(|| "/".to_string()),
+            percent: percent as u8,
+            fault,
+        });
+        rule.finish()?;
+    }
+
+    if let Some(mut assets) = top.table("assets")? {
+        config.assets = Some(AssetConfig {
+            prefix: assets.required_string("prefix")?,
+            directory: assets.required_string("directory")?.into(),
+            manifest_path: assets.string("manifest_path")?,
+        });
+        assets.finish()?;
+    }
+
+    for mut user in top.tables("users")? {
+        config.users.push(User {
+            name: user.required_string("name")?,
+            password: user.required_string("password")?,
+            groups: user.strings("groups")?,
+        });
+        user.finish()?;
+    }
+
+    for mut rule in top.tables("access")? {
+        let methods = rule
+            .strings("methods")?
+            .iter()
+            .map(|name| rule.method("methods", name))
+            .collect::<Result<_, _>>()?;
+        let require = match rule.string("require")? {
+            Some(req) => {
+                let req = Requirement::parse(&req).ok_or_else(|| {
+                    rule.invalid("require", "expected basic-auth or basic-auth:<group>")
+                })?;
+                let Requirement::BasicAuth { group } = &req;
+                if let Some(group) = group
+                    && !config.users.iter().any(|u| u.groups.contains(group))
+                {
+                    return Err(rule.invalid("require", &format!("no user is in group `{group}`")));
+                }
+                Some(req)
+            }
+            None => None,
+        };
+        let allow_countries = rule.countries("allow_countries", &config)?;
+        let deny_countries = rule.countries("deny_countries", &config)?;
+        let allow_ips = rule
+            .strings("allow_ips")?
+            .iter()
+            .map(|net| {
+                net.parse()
+                    .map_err(|e: String| rule.invalid("allow_ips", &e))
+            })
+            .collect::<Result<_, _>>()?;
+        config.access_rules.push(AccessRule {
+            prefix: rule.required_string("prefix")?,
+            methods,
+            require,
+            allow_ips,
+            allow_countries,
+            deny_countries,
+        });
+        rule.finish()?;
+    }
+
+    // A page that can delete files must not be open to anyone, and neither may the API
+    // behind it
+    if let Some(path) = file_manager {
+        let guarded = |method, path: &str| {
+            config
+                .access_rules
+                .iter()
+                .any(|r| r.require.is_some() && r.matches(method, path))
+        };
+        let api = [
+            HttpMethod::Get,
+            HttpMethod::Post,
+            HttpMethod::Put,
+            HttpMethod::Delete,
+            HttpMethod::Move,
+        ];
+        if !guarded(HttpMethod::Get, &path) || !api.into_iter().all(|m| guarded(m, "/files/")) {
+            return Err(top.invalid(
+                "file_manager",
+                "needs [[access]] rules requiring basic-auth for it and all of /files",
+            ));
+        }
+        config.file_manager = Some(path);
+    }
+
+    top.finish()?;
+    Ok(config)
+}
+
+// The keys of one table, taken as they are read so leftovers can be reported as unknown
+struct Fields {
+    table: Table,
+    // Where this table is, for error messages: "" for the top level, "access[1]" etc.
+    context: String,
+}
+
+impl Fields {
+    fn new(table: Table, context: String) -> Self {
+        Fields { table, context }
+    }
+
+    fn name(&self, key: &str) -> String {
+        if self.context.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{key}", self.context)
+        }
+    }
+
+    fn invalid(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError::Invalid(format!("{}: {message}", self.name(key)))
+    }
+
+    fn mismatch(&self, key: &str, wanted: &str, found: &Value) -> ConfigError {
+        self.invalid(
+            key,
+            &format!("expected {wanted}, found {}", found.type_name()),
+        )
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(other) => Err(self.mismatch(key, "a string", &other)),
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String, ConfigError> {
+        self.string(key)?
+            .ok_or_else(|| self.invalid(key, "missing"))
+    }
+
+    fn boolean(&mut self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(b)),
+            Some(other) => Err(self.mismatch(key, "a boolean", &other)),
+        }
+    }
+
+    // Only non-negative integers are meaningful anywhere in the config
+    fn integer(&mut self, key: &str) -> Result<Option<u64>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) => u64::try_from(n)
+                .map(Some)
+                .map_err(|_| self.invalid(key, "must not be negative")),
+            Some(other) => Err(self.mismatch(key, "an integer", &other)),
+        }
+    }
+
+    fn required_integer(&mut self, key: &str) -> Result<u64, ConfigError> {
+        self.integer(key)?
+            .ok_or_else(|| self.invalid(key, "missing"))
+    }
+
+    // A missing list is an empty one
+    fn strings(&mut self, key: &str) -> Result<Vec<String>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s),
+                    other => Err(self.mismatch(key, "an array of strings", &other)),
+                })
+                .collect(),
+            Some(other) => Err(self.mismatch(key, "an array of strings", &other)),
+        }
+    }
+
+    fn table(&mut self, key: &str) -> Result<Option<Fields>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Table(t)) => Ok(Some(Fields::new(t, self.name(key)))),
+            Some(other) => Err(self.mismatch(key, "a table", &other)),
+        }
+    }
+
+    // `[[key]]` sections, or an array of inline tables
+    fn tables(&mut self, key: &str) -> Result<Vec<Fields>, ConfigError> {
+        match self.table.remove(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| match item {
+                    Value::Table(t) => Ok(Fields::new(t, format!("{}[{i}]", self.name(key)))),
+                    other => Err(self.mismatch(key, "an array of tables", &other)),
+                })
+                .collect(),
+            Some(other) => Err(self.mismatch(key, "an array of tables", &other)),
+        }
+    }
+
+    // Two-letter codes, upper-cased; only meaningful with a GeoIP database to resolve them
+    fn countries(&mut self, key: &str, config: &Config) -> Result<Vec<String>, ConfigError> {
+        let codes = self.strings(key)?;
+        if !codes.is_empty() && config.geoip_database.is_none() {
+            return Err(self.invalid(key, "needs geoip_database"));
+        }
+        codes
+            .into_iter()
+            .map(
+                |code| match code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()) {
+                    true => Ok(code.to_ascii_uppercase()),
+                    false => Err(self.invalid(key, &format!("invalid country code `{code}`"))),
+                },
+            )
+            .collect()
+    }
+
+    fn method(&self, key: &str, name: &str) -> Result<HttpMethod, ConfigError> {
+        HttpMethod::parse(name)
+            .ok_or_else(|| self.invalid(key, &format!("unknown method `{name}`")))
+    }
+
+    fn finish(self) -> Result<(), ConfigError> {
+        match self.table.keys().next() {
+            Some(key) => Err(self.invalid(key, "unknown setting")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::HeadLimits;
+
+    #[test]
+    fn empty_file_keeps_defaults() {
+        let config = parse("# nothing here\n").unwrap();
+        assert_eq!(
+            config.keep_alive_timeout,
+            Config::default().keep_alive_timeout
+        );
+        assert!(config.access_rules.is_empty());
+    }
+
+    #[test]
+    fn reads_settings_and_rules() {
+        let config = parse(
+            r#"
+            keep_alive_timeout = 15
+            trailing_slash = "redirect"
+            max_body_size = 10_485_760
+            max_headers = 50
+            analytics_path = "/admin/analytics"
+            file_cache_size = 16_777_216
+            autoindex = true
+            spa = true
+            allow_dotfiles = true
+            symlinks = "never"
+            index_files = ["index.html", "index.htm"]
+            problem_json = ["/api"]
+            admin_address = "127.0.0.1:9901"
+            server_header = false
+            default_charset = "utf-8"
+
+            [response_headers]
+            X-Environment = "staging"
+
+            [mime_types]
+            map = "application/json"
+
+            [error_pages]
+            404 = "errors/404.html"
+            5xx = "errors/oops.html"
+
+            [[mount]]
+            prefix = "/static"
+            directory = "/srv/static"
+
+            [[cache_control]]
+            path = "/assets/**"
+            value = "public, max-age=31536000, immutable"
+
+            [[cache_control]]
+            path = "*.html"
+            value = "no-cache"
+
+            [[rate_limit]]
+            method = "POST"
+            prefix = "/files"
+            limit = 10
+            window = 60
+
+            [request_decompression]
+            max_size = 1_000_000
+
+            [mirror]
+            upstream = "127.0.0.1:9000"
+            percent = 5
+
+            [assets]
+            prefix = "/assets"
+            directory = "public/assets"
+
+            [[users]]
+            name = "alice"
+            password = "s3cret"
+            groups = ["uploaders"]
+
+            [[access]]
+            prefix = "/files"
+            methods = ["POST", "DELETE"]
+            require = "basic-auth:uploaders"
+            allow_ips = ["10.0.0.0/8"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(15));
+        assert_eq!(config.trailing_slash, TrailingSlash::Redirect);
+        assert_eq!(config.max_body_size, Some(10 * 1024 * 1024));
+        assert_eq!(config.head_limits.max_headers, 50);
+        assert_eq!(
+            config.head_limits.max_request_line,
+            HeadLimits::default().max_request_line
+        );
+        assert_eq!(config.analytics_path.as_deref(), Some("/admin/analytics"));
+        assert!(config.autoindex);
+        assert_eq!(
+            config.mime_types,
+            [("map".to_string(), "application/json".to_string())]
+        );
+        assert_eq!(config.default_charset.as_deref(), Some("utf-8"));
+        assert_eq!(config.file_cache_size, 16 * 1024 * 1024);
+        assert_eq!(config.file_cache_max_file_size, 256 * 1024);
+        assert!(config.spa);
+        assert!(config.allow_dotfiles);
+        assert_eq!(config.symlinks, Symlinks::Never);
+        assert_eq!(
+            config.mounts,
+            [Mount::new("/static", "/srv/static").unwrap()]
+        );
+        assert_eq!(
+            config.cache_control,
+            [
+                CacheControlRule {
+                    pattern: "/assets/**".to_string(),
+                    value: "public, max-age=31536000, immutable".to_string(),
+                },
+                CacheControlRule {
+                    pattern: "*.html".to_string(),
+                    value: "no-cache".to_string(),
+                },
+            ]
+        );
+        assert_eq!(config.index_files, ["index.html", "index.htm"]);
+        assert_eq!(config.problem_json, ["/api"]);
+        assert_eq!(
+            config.admin_address,
+            Some("127.0.0.1:9901".parse().unwrap())
+        );
+        assert_eq!(config.response_defaults.server, None);
+        assert_eq!(
+            config.response_defaults.headers,
+            [("X-Environment".to_string(), "staging".to_string())]
+        );
+        assert_eq!(
+            config.error_pages,
+            [
+                ("404".to_string(), "errors/404.html".into()),
+                ("5xx".to_string(), "errors/oops.html".into())
+            ]
+        );
+        assert_eq!(config.rate_limits[0].method, Some(HttpMethod::Post));
+        assert_eq!(config.rate_limits[0].window, Duration::from_secs(60));
+        assert_eq!(
+            config.request_decompression,
+            Some(DecompressionLimits {
+                max_size: 1_000_000,
+                max_ratio: DecompressionLimits::default().max_ratio,
+            })
+        );
+        assert_eq!(config.mirror.unwrap().percent, 5);
+        assert_eq!(config.users[0].groups, ["uploaders"]);
+        assert_eq!(
+            config.assets,
+            Some(AssetConfig {
+                prefix: "/assets".to_string(),
+                directory: "public/assets".into(),
+                manifest_path: None,
+            })
+        );
+        assert_eq!(
+            config.access_rules,
+            [AccessRule {
+                prefix: "/files".to_string(),
+                methods: vec![HttpMethod::Post, HttpMethod::Delete],
+                require: Some(Requirement::BasicAuth {
+                    group: Some("uploaders".to_string())
+                }),
+                allow_ips: vec!["10.0.0.0/8".parse().unwrap()],
+                ..AccessRule::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn file_manager_needs_auth_over_it_and_the_api() {
+        let config = parse(
+            r#"
+            file_manager = "/manage"
+
+            [[users]]
+            name = "alice"
+            password = "s3cret"
+
+            [[access]]
+            prefix = "/manage"
+            require = "basic-auth"
+
+            [[access]]
+            prefix = "/files"
+            require = "basic-auth"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.file_manager.as_deref(), Some("/manage"));
+    }
+
+    #[test]
+    fn chaos_rules() {
+        let config = parse(
+            r#"
+            [[chaos]]
+            prefix = "/api"
+            fault = "delay"
+            delay_ms = 250
+            percent = 10
+
+            [[chaos]]
+            fault = "error"
+            status = 503
+            percent = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.chaos[0].fault,
+            Fault::Delay(Duration::from_millis(250))
+        );
+        assert_eq!(config.chaos[1].prefix, "/");
+        assert_eq!(
+            config.chaos[1].fault,
+            Fault::Error(Some(StatusCode::SERVICE_UNAVAILABLE))
+        );
+
+        let err = parse("[[chaos]]\nfault = \"explode\"\npercent = 1").unwrap_err();
+        assert!(err.to_string().contains("unknown fault `explode`"), "{err}");
+        let err = parse("[[chaos]]\nfault = \"delay\"\npercent = 1").unwrap_err();
+        assert!(err.to_string().contains("delay_ms"), "{err}");
+    }
+
+    #[test]
+    fn inline_table_arrays_work_too() {
+        let config =
+            parse(r#"access = [{ prefix = "/admin", allow_ips = ["127.0.0.1"] }]"#).unwrap();
+        assert_eq!(config.access_rules[0].prefix, "/admin");
+        assert!(config.access_rules[0].methods.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_values_with_their_location() {
+        let cases = [
+            (
+                "keep_alive_timeout = \"5\"",
+                "keep_alive_timeout: expected an integer, found string",
+            ),
+            (
+                "keep_alive_timout = 5",
+                "keep_alive_timout: unknown setting",
+            ),
+            ("upload_limit = -1", "upload_limit: must not be negative"),
+            (
+                "index_files = [\"../index.html\"]",
+                "index_files: expected plain file names",
+            ),
+            (
+                "[[mount]]\nprefix = \"static\"\ndirectory = \"/srv\"",
+                "mount[0].prefix: mount prefix `static` must start with /",
+            ),
+            (
+                "[[cache_control]]\npath = \"*.js\"\nvalue = \"max-age=1\\n\"",
+                "cache_control[0].value: not a valid header value",
+            ),
+            (
+                "[[access]]\nprefix = \"/x\"\nmethods = [\"FETCH\"]",
+                "access[0].methods: unknown method `FETCH`",
+            ),
+            (
+                "[[access]]\nprefix = \"/x\"\nallow_ips = [\"10.0.0.0/40\"]",
+                "access[0].allow_ips: invalid prefix length `40`",
+            ),
+            (
+                "[[access]]\nprefix = \"/x\"\nrequire = \"basic-auth:admins\"",
+                "access[0].require: no user is in group `admins`",
+            ),
+            (
+                "[[access]]\nprefix = \"/x\"\ndeny_countries = [\"KP\"]",
+                "access[0].deny_countries: needs geoip_database",
+            ),
+            (
+                "[mirror]\nupstream = \"x:1\"\npercent = 101",
+                "mirror.percent: must be between 0 and 100",
+            ),
+            ("[[users]]\nname = \"a\"", "users[0].password: missing"),
+            (
+                "file_manager = \"/manage\"\n[[users]]\nname = \"a\"\npassword = \"b\"\n\
+                 [[access]]\nprefix = \"/\"\nmethods = [\"GET\"]\nrequire = \"basic-auth\"",
+                "file_manager: needs [[access]] rules requiring basic-auth for it and all of /files",
+            ),
+            (
+                "admin_address = \"localhost:9901\"",
+                "admin_address: expected ip:port",
+            ),
+            (
+                "[mime_types]\nmd = \"markdown\"",
+                "mime_types.md: expected a media type like text/css",
+            ),
+            (
+                "[error_pages]\n200 = \"ok.html\"",
+                "error_pages.200: expected an error status like 404 or 5xx",
+            ),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse(text).unwrap_err().to_string(), expected, "{text}");
+        }
+    }
+}
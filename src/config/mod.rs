@@ -0,0 +1,375 @@
+mod file;
+mod toml;
+
+use crate::assets::AssetConfig;
+use crate::chaos::ChaosRule;
+use crate::http::decode::DecompressionLimits;
+use crate::http::request::{HeadLimits, HttpMethod};
+use crate::http::response::ResponseDefaults;
+use crate::mirror::MirrorConfig;
+use crate::policy::IpNet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub use file::load;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // How long an idle keep-alive connection may wait for its next request
+    pub keep_alive_timeout: Duration,
+    // How long a client may take to send a request once it has started
+    pub request_timeout: Duration,
+    // Deadline handed to handlers via the request context
+    pub handler_timeout: Duration,
+    // Requests served on one connection before it is closed
+    pub max_requests_per_connection: usize,
+    // Whether `/files` and `/files/` name the same route
+    pub trailing_slash: TrailingSlash,
+    // Largest request body accepted, advertised on `OPTIONS *`; None means unlimited
+    pub max_body_size: Option<u64>,
+    // Request line and header block limits, answered with 414 and 431
+    pub head_limits: HeadLimits,
+    // Gunzip `Content-Encoding: gzip` request bodies within these limits; None passes
+    // them to handlers still compressed
+    pub request_decompression: Option<DecompressionLimits>,
+    // Request bodies up to this size are read before the handler runs; larger ones are
+    // streamed to it as they arrive
+    pub body_buffer_limit: u64,
+    // Per-connection bandwidth caps in bytes/sec (None = unlimited)
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    // Overrides for requests under a path prefix; the first match wins
+    pub bandwidth_rules: Vec<BandwidthRule>,
+    // Request budgets per client IP for matching routes; the first match applies
+    pub rate_limits: Vec<RateLimitRule>,
+    // Shadow a sample of requests to a second backend
+    pub mirror: Option<MirrorConfig>,
+    // Faults injected into a share of matching requests, for client resilience testing
+    pub chaos: Vec<ChaosRule>,
+    // Responses kept by the server-side response cache; 0 disables it
+    pub response_cache_entries: usize,
+    // Bytes of small files from the served directory kept in memory; 0 disables it.
+    // Files over the size limit are always read from disk.
+    pub file_cache_size: u64,
+    pub file_cache_max_file_size: u64,
+    // Access rules checked before routing; every matching rule must pass
+    pub access_rules: Vec<AccessRule>,
+    // Accounts that Basic auth requirements check against
+    pub users: Vec<User>,
+    // Where the JSON traffic summary is served (e.g. "/admin/analytics"); None keeps
+    // analytics off. Lock it down with an access rule.
+    pub analytics_path: Option<String>,
+    // HTML pages for browsers asking for a directory under /files/ (`--autoindex`);
+    // other clients keep getting the JSON listing
+    pub autoindex: bool,
+    // Files served in place of a directory under /files/ that holds one, tried in
+    // order (e.g. "index.html"); empty keeps directories listed
+    pub index_files: Vec<String>,
+    // GETs under /files/ for a missing path without an extension get the directory's
+    // index.html, for apps that route on the client (`--spa`)
+    pub spa: bool,
+    // Paths under /files/ with a segment starting with a dot (`.git`, `.env`) answer
+    // 404 unless this is set
+    pub allow_dotfiles: bool,
+    // Which symlinks under the directory /files/ follows
+    pub symlinks: Symlinks,
+    // Cache-Control for files served from /files/, by the first rule whose pattern
+    // matches
+    pub cache_control: Vec<CacheControlRule>,
+    // Directories served under their own URL prefixes; without any, the server's
+    // directory is served under /files/
+    pub mounts: Vec<Mount>,
+    // Where the built-in file manager page is served (e.g. "/manage"); None leaves it
+    // off. Requires access rules that put both it and /files behind Basic auth.
+    pub file_manager: Option<String>,
+    // A second listener for operator endpoints (e.g. /resources), kept off the public
+    // port; None disables them
+    pub admin_address: Option<SocketAddr>,
+    // MaxMind DB file used to resolve client countries (feature "geoip")
+    pub geoip_database: Option<PathBuf>,
+    // A directory served with content-hashed file names for cache busting
+    pub assets: Option<AssetConfig>,
+    // Path prefixes whose error responses are problem+json documents (RFC 9457);
+    // elsewhere only clients that ask for them via Accept get them
+    pub problem_json: Vec<String>,
+    // Pages for the server's own error responses, keyed by status ("404") or class
+    // ("5xx"); paths are relative to the served directory
+    pub error_pages: Vec<(String, PathBuf)>,
+    // Content-Types by file extension, over the built-in table (see `mime::lookup`)
+    pub mime_types: Vec<(String, String)>,
+    // Charset added to text/* types that don't name one, e.g. "utf-8"
+    pub default_charset: Option<String>,
+    // Server header and extra headers added to every response
+    pub response_defaults: ResponseDefaults,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrailingSlash {
+    // Paths are matched exactly as sent
+    #[default]
+    Strict,
+    // A trailing slash is dropped before routing
+    Ignore,
+    // A trailing slash gets a 301 to the path without it
+    Redirect,
+    // A directory under /files asked for without its trailing slash gets a 301 to the
+    // path with it, so relative links in its listing resolve; nothing else changes
+    Directories,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Symlinks {
+    // Wherever they point
+    Follow,
+    // Only when the target, fully resolved, is still under the directory
+    #[default]
+    Inside,
+    // Not at all: a path through a symlink gets a 403, even one to a sibling file
+    Never,
+}
+
+// Files under `directory` served at `prefix` ("/static"), which has no trailing slash;
+// "" mounts the directory at the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub prefix: String,
+    pub directory: String,
+}
+
+impl Mount {
+    pub fn new(prefix: &str, directory: &str) -> Result<Mount, String> {
+        if !prefix.starts_with('/') {
+            return Err(format!("mount prefix `{prefix}` must start with /"));
+        }
+        if prefix.contains(['*', '{', '}', '?', '#']) {
+            return Err(format!("mount prefix `{prefix}` must be a plain path"));
+        }
+        if directory.is_empty() {
+            return Err(format!("mount `{prefix}` needs a directory"));
+        }
+        Ok(Mount {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            directory: directory.to_string(),
+        })
+    }
+
+    // `--mount` syntax: `/static=/srv/static`
+    pub fn parse(spec: &str) -> Result<Mount, String> {
+        let (prefix, directory) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("mount `{spec}` should look like /prefix=directory"))?;
+        Mount::new(prefix, directory)
+    }
+}
+
+// `pattern` is a glob over a file's path under the directory, with a leading `/`
+// ("/assets/**"), or over its name alone when it has no `/` ("*.html")
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheControlRule {
+    pub pattern: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthRule {
+    pub prefix: String,
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+// At most `limit` requests per `window` from one client, for requests matching
+// `method` (any if None) under `prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitRule {
+    pub method: Option<HttpMethod>,
+    pub prefix: String,
+    pub limit: u32,
+    pub window: Duration,
+}
+
+// Who may make requests matching `methods` (any if empty) under `prefix`: clients in
+// `allow_ips` (anyone if empty) and `allow_countries` (anywhere if empty), not in
+// `deny_countries`, who meet `require`. Country codes are ISO 3166 ("DE", "US").
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AccessRule {
+    pub prefix: String,
+    pub methods: Vec<HttpMethod>,
+    pub require: Option<Requirement>,
+    pub allow_ips: Vec<IpNet>,
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    // `basic-auth` accepts any configured user, `basic-auth:<group>` only its members
+    BasicAuth { group: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub password: String,
+    pub groups: Vec<String>,
+}
+
+impl AccessRule {
+    // Prefixes match whole segments, so `/files` covers `/files/a` but not `/filesystem`
+    pub fn matches(&self, method: HttpMethod, path: &str) -> bool {
+        let under_prefix = match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.ends_with('/'),
+            None => false,
+        };
+        // HEAD reveals what GET would, so rules on GET cover it too
+        let method_listed = self.methods.contains(&method)
+            || (method == HttpMethod::Head && self.methods.contains(&HttpMethod::Get));
+        under_prefix && (self.methods.is_empty() || method_listed)
+    }
+}
+
+impl Requirement {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "basic-auth" => Some(Requirement::BasicAuth { group: None }),
+            Some(("basic-auth", group)) if !group.is_empty() => Some(Requirement::BasicAuth {
+                group: Some(group.to_string()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    // The (download, upload) caps that apply to a request path
+    pub fn bandwidth_for(&self, path: &str) -> (Option<u64>, Option<u64>) {
+        match self
+            .bandwidth_rules
+            .iter()
+            .find(|r| path.starts_with(&r.prefix))
+        {
+            Some(rule) => (rule.download, rule.upload),
+            None => (self.download_limit, self.upload_limit),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            handler_timeout: Duration::from_secs(60),
+            max_requests_per_connection: 100,
+            trailing_slash: TrailingSlash::default(),
+            max_body_size: None,
+            head_limits: HeadLimits::default(),
+            request_decompression: None,
+            body_buffer_limit: 64 * 1024,
+            download_limit: None,
+            upload_limit: None,
+            bandwidth_rules: Vec::new(),
+            rate_limits: Vec::new(),
+            mirror: None,
+            chaos: Vec::new(),
+            response_cache_entries: 0,
+            file_cache_size: 0,
+            file_cache_max_file_size: 256 * 1024,
+            access_rules: Vec::new(),
+            users: Vec::new(),
+            analytics_path: None,
+            autoindex: false,
+            index_files: Vec::new(),
+            spa: false,
+            allow_dotfiles: false,
+            symlinks: Symlinks::default(),
+            cache_control: Vec::new(),
+            mounts: Vec::new(),
+            file_manager: None,
+            admin_address: None,
+            geoip_database: None,
+            assets: None,
+            problem_json: Vec::new(),
+            error_pages: Vec::new(),
+            mime_types: Vec::new(),
+            default_charset: None,
+            response_defaults: ResponseDefaults::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_rules_override_defaults_by_prefix() {
+        let config = Config {
+            download_limit: Some(1000),
+            bandwidth_rules: vec![BandwidthRule {
+                prefix: "/files/".to_string(),
+                download: Some(50),
+                upload: None,
+            }],
+            ..Config::default()
+        };
+        assert_eq!(config.bandwidth_for("/files/big.iso"), (Some(50), None));
+        assert_eq!(config.bandwidth_for("/echo/hi"), (Some(1000), None));
+    }
+
+    #[test]
+    fn access_rules_match_whole_segments() {
+        let rule = AccessRule {
+            prefix: "/files".to_string(),
+            methods: vec![HttpMethod::Post],
+            ..AccessRule::default()
+        };
+        assert!(rule.matches(HttpMethod::Post, "/files"));
+        assert!(rule.matches(HttpMethod::Post, "/files/a.txt"));
+        assert!(!rule.matches(HttpMethod::Post, "/filesystem"));
+        assert!(!rule.matches(HttpMethod::Get, "/files/a.txt"));
+    }
+
+    #[test]
+    fn rules_on_get_cover_head() {
+        let rule = AccessRule {
+            prefix: "/private".to_string(),
+            methods: vec![HttpMethod::Get],
+            ..AccessRule::default()
+        };
+        assert!(rule.matches(HttpMethod::Head, "/private/a"));
+        assert!(!rule.matches(HttpMethod::Post, "/private/a"));
+    }
+
+    #[test]
+    fn requirements_parse() {
+        assert_eq!(
+            Requirement::parse("basic-auth:uploaders"),
+            Some(Requirement::BasicAuth {
+                group: Some("uploaders".to_string())
+            })
+        );
+        assert_eq!(
+            Requirement::parse("basic-auth"),
+            Some(Requirement::BasicAuth { group: None })
+        );
+        assert_eq!(Requirement::parse("basic-auth:"), None);
+        assert_eq!(Requirement::parse("digest:admins"), None);
+    }
+
+    #[test]
+    fn mount_specs_parse() {
+        assert_eq!(
+            Mount::parse("/static/=/srv/static"),
+            Ok(Mount {
+                prefix: "/static".to_string(),
+                directory: "/srv/static".to_string(),
+            })
+        );
+        assert_eq!(Mount::parse("/=.").unwrap().prefix, "");
+        assert!(Mount::parse("/static").is_err());
+        assert!(Mount::parse("static=/srv").is_err());
+        assert!(Mount::parse("/files/*=/srv").is_err());
+        assert!(Mount::parse("/uploads=").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// Just enough TOML for the server's config file: `[table]` and `[[array]]` headers,
+// dotted keys, basic and literal strings, integers, booleans, arrays (which may span
+// lines) and single-line inline tables. Dates, floats and multi-line strings are not
+// supported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Error, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Table::new();
+    // Where `key = value` lines currently land
+    let mut current: Vec<String> = Vec::new();
+
+    loop {
+        parser.skip_blank_lines();
+        let Some(c) = parser.peek() else {
+            return Ok(root);
+        };
+        if c == '[' {
+            parser.bump();
+            let array = parser.eat('[');
+            parser.skip_spaces();
+            let path = parser.key_path()?;
+            parser.skip_spaces();
+            parser.expect(']')?;
+            if array {
+                parser.expect(']')?;
+            }
+            let (last, parents) = path.split_last().unwrap();
+            let parent = table_at(&mut root, parents).map_err(|m| parser.error(m))?;
+            if array {
+                match parent
+                    .entry(last.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    Value::Array(items) => items.push(Value::Table(Table::new())),
+                    _ => return Err(parser.error(format!("`{last}` is not an array of tables"))),
+                }
+            } else {
+                match parent
+                    .entry(last.clone())
+                    .or_insert_with(|| Value::Table(Table::new()))
+                {
+                    Value::Table(_) => {}
+                    _ => return Err(parser.error(format!("`{last}` is not a table"))),
+                }
+            }
+            current = path;
+        } else {
+            let path = parser.key_path()?;
+            parser.skip_spaces();
+            parser.expect('=')?;
+            parser.skip_spaces();
+            let value = parser.value()?;
+            let table = table_at(&mut root, &current).map_err(|m| parser.error(m))?;
+            insert(table, &path, value).map_err(|m| parser.error(m))?;
+        }
+        parser.end_of_line()?;
+    }
+}
+
+// Walks (creating as needed) to the table named by `path`; a path through an array
+// of tables means its most recent element
+fn table_at<'t>(mut table: &'t mut Table, path: &[String]) -> Result<&'t mut Table, String> {
+    for key in path {
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(t) => t,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(t)) => t,
+                _ => return Err(format!("`{key}` is not a table")),
+            },
+            _ => return Err(format!("`{key}` is not a table")),
+        };
+    }
+    Ok(table)
+}
+
+fn insert(table: &mut Table, path: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().unwrap();
+    let table = table_at(table, parents)?;
+    if table.contains_key(last) {
+        return Err(format!("duplicate key `{last}`"));
+    }
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{c}`")))
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn unexpected(&self, wanted: &str) -> ParseError {
+        match self.peek() {
+            Some('\n') | None => self.error(format!("expected {wanted} before end of line")),
+            Some(c) => self.error(format!("expected {wanted}, found `{c}`")),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), Some('\n') | None) {
+                self.bump();
+            }
+        }
+    }
+
+    // Whitespace, comments and newlines, as allowed between lines and inside arrays
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            if !(self.eat('\n') || self.eat('\r')) {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_spaces();
+        self.skip_comment();
+        self.eat('\r');
+        if self.peek().is_none() || self.eat('\n') {
+            Ok(())
+        } else {
+            Err(self.unexpected("end of line"))
+        }
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+            self.skip_spaces();
+            path.push(self.key()?);
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.bump();
+                }
+                if self.pos == start {
+                    return Err(self.unexpected("a key"));
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some('t' | 'f') => {
+                let word = self.key()?;
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => Err(self.error(format!("unknown value `{word}`"))),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => self.integer(),
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+
+    // The next character inside a string; strings can't run past the end of the line
+    fn string_char(&mut self) -> Result<char, ParseError> {
+        match self.peek() {
+            Some('\n') | None => Err(self.error("unterminated string")),
+            _ => Ok(self.bump().unwrap()),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.string_char()? {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = match self.string_char()? {
+                        '"' => '"',
+                        '\\' => '\\',
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        c => return Err(self.error(format!("unknown escape `\\{c}`"))),
+                    };
+                    out.push(escaped);
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut out = String::new();
+        loop {
+            match self.string_char()? {
+                '\'' => return Ok(out),
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if matches!(self.peek(), Some('-' | '+')) {
+            self.bump();
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '_') {
+            self.bump();
+        }
+        let text: String = self.chars[start..self.pos]
+            .iter()
+            .filter(|&&c| c != '_')
+            .collect();
+        text.parse()
+            .map(Value::Integer)
+            .map_err(|_| self.error(format!("invalid integer `{text}`")))
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank_lines();
+            if !self.eat(',') {
+                self.skip_blank_lines();
+                self.expect(']')?;
+                return Ok(Value::Array(items));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_spaces();
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let path = self.key_path()?;
+            self.skip_spaces();
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            insert(&mut table, &path, value).map_err(|m| self.error(m))?;
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Value::Table(table));
+            }
+            self.expect(',')?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn parses_tables_arrays_and_scalars() {
+        let doc = parse(
+            r#"
+            # top-level settings
+            keep_alive_timeout = 10
+            trailing_slash = "redirect"   # trailing comment
+
+            [mirror]
+            upstream = '127.0.0.1:9000'
+            percent = 5
+
+            [[access]]
+            prefix = "/files"
+            methods = [
+                "POST",
+                "DELETE", # still inside the array
+            ]
+
+            [[access]]
+            prefix = "/admin"
+            limits = { burst = 3, enabled = true }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(doc["keep_alive_timeout"], Value::Integer(10));
+        assert_eq!(doc["trailing_slash"], string("redirect"));
+        let Value::Table(mirror) = &doc["mirror"] else {
+            panic!("mirror is not a table");
+        };
+        assert_eq!(mirror["upstream"], string("127.0.0.1:9000"));
+
+        let Value::Array(access) = &doc["access"] else {
+            panic!("access is not an array");
+        };
+        assert_eq!(access.len(), 2);
+        let Value::Table(first) = &access[0] else {
+            panic!();
+        };
+        assert_eq!(
+            first["methods"],
+            Value::Array(vec![string("POST"), string("DELETE")])
+        );
+        let Value::Table(second) = &access[1] else {
+            panic!();
+        };
+        let Value::Table(limits) = &second["limits"] else {
+            panic!();
+        };
+        assert_eq!(limits["burst"], Value::Integer(3));
+        assert_eq!(limits["enabled"], Value::Boolean(true));
+    }
+
+    #[test]
+    fn dotted_keys_and_escapes() {
+        let doc = parse("a.b = \"x\\\"y\"\n[c.d]\ne = -1_000\n").unwrap();
+        let Value::Table(a) = &doc["a"] else { panic!() };
+        assert_eq!(a["b"], string("x\"y"));
+        let Value::Table(c) = &doc["c"] else { panic!() };
+        let Value::Table(d) = &c["d"] else { panic!() };
+        assert_eq!(d["e"], Value::Integer(-1000));
+    }
+
+    #[test]
+    fn reports_the_failing_line() {
+        let err = parse("a = 1\nb = \"open\nc = 3\n").unwrap_err();
+        assert_eq!(err.line, 2);
+
+        let err = parse("a = 1\na = 2\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: duplicate key `a`");
+
+        let err = parse("a = 1 2\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: expected end of line, found `2`");
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// What a connection is doing right now
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    // Keep-alive, waiting for the next request
+    Idle,
+    // Part way through reading a request
+    Reading,
+    // Running the handler for this request line
+    Handling(String),
+    // Sending the response
+    Writing,
+    // Handed over to an upgrade handler, no longer HTTP
+    Upgraded,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Idle => write!(f, "idle"),
+            ConnectionState::Reading => write!(f, "reading request"),
+            ConnectionState::Handling(request) => write!(f, "handling {request}"),
+            ConnectionState::Writing => write!(f, "writing response"),
+            ConnectionState::Upgraded => write!(f, "upgraded"),
+        }
+    }
+}
+
+// Registry of the open connections, so their state can be reported on demand
+#[derive(Debug, Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Entry>>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    peer: SocketAddr,
+    opened: Instant,
+    state: ConnectionState,
+    changed: Instant,
+    requests: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    pub peer: SocketAddr,
+    pub age: Duration,
+    pub state: ConnectionState,
+    // How long it has been in `state`
+    pub state_age: Duration,
+    pub requests: usize,
+}
+
+impl Connections {
+    // The connection stays listed until the returned handle is dropped
+    pub fn register(&self, peer: SocketAddr) -> Tracked<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        self.open.lock().unwrap().insert(
+            id,
+            Entry {
+                peer,
+                opened: now,
+                state: ConnectionState::Idle,
+                changed: now,
+                requests: 0,
+            },
+        );
+        Tracked {
+            connections: self,
+            id,
+        }
+    }
+
+    // Oldest first
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let now = Instant::now();
+        let mut entries: Vec<Entry> = self.open.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|e| e.opened);
+        entries
+            .into_iter()
+            .map(|e| ConnectionSnapshot {
+                peer: e.peer,
+                age: now - e.opened,
+                state: e.state,
+                state_age: now - e.changed,
+                requests: e.requests,
+            })
+            .collect()
+    }
+}
+
+pub struct Tracked<'a> {
+    connections: &'a Connections,
+    id: u64,
+}
+
+impl Tracked<'_> {
+    pub fn set(&self, state: ConnectionState) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(entry) = open.get_mut(&self.id) {
+            if matches!(state, ConnectionState::Handling(_)) {
+                entry.requests += 1;
+            }
+            entry.state = state;
+            entry.changed = Instant::now();
+        }
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_state_until_dropped() {
+        let connections = Connections::default();
+        let first = connections.register("10.0.0.1:5000".parse().unwrap());
+        let second = connections.register("10.0.0.2:5000".parse().unwrap());
+        second.set(ConnectionState::Handling("GET /files/a".to_string()));
+        second.set(ConnectionState::Writing);
+        second.set(ConnectionState::Handling("GET /".to_string()));
+
+        let snapshot = connections.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].state, ConnectionState::Idle);
+        assert_eq!(snapshot[1].requests, 2);
+        assert_eq!(snapshot[1].state.to_string(), "handling GET /");
+
+        drop(first);
+        let snapshot = connections.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].peer.to_string(), "10.0.0.2:5000");
+    }
+}
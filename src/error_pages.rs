@@ -0,0 +1,164 @@
+use crate::http::{HttpRequest, HttpResponse};
+use crate::logging::error;
+use crate::mime;
+use crate::problem;
+use std::path::Path;
+use std::sync::Arc;
+
+// Gives a bodiless error response a body, e.g. a JSON envelope for API routes. Only
+// called while the response is still empty; setting a body ends the search.
+pub type ErrorHandler = dyn Fn(&HttpRequest, &mut HttpResponse) + Send + Sync;
+
+// What the server's own error responses (404, 405, 500, ...) turn into: the first
+// registered handler that sets a body, then a problem+json document where one is
+// wanted, then the configured page for the status or its class. Responses handlers
+// gave a body of their own, and successes, are left alone.
+#[derive(Default)]
+pub struct ErrorPages {
+    handlers: Vec<Arc<ErrorHandler>>,
+    problem_json: Vec<String>,
+    // (key, content type, body), read once at startup
+    pages: Vec<(String, String, Vec<u8>)>,
+}
+
+// "404", or a class: "4xx", "5xx"
+pub fn is_valid_key(key: &str) -> bool {
+    match key.as_bytes() {
+        [b'4' | b'5', b'x', b'x'] => true,
+        [b'4' | b'5', tens, units] => tens.is_ascii_digit() && units.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+impl ErrorPages {
+    // A page that can't be read is logged and left out
+    pub fn new(
+        handlers: Vec<Arc<ErrorHandler>>,
+        problem_json: Vec<String>,
+        pages: &[(String, std::path::PathBuf)],
+        directory: &str,
+    ) -> Self {
+        let pages = pages
+            .iter()
+            .filter_map(|(key, path)| {
+                let path = Path::new(directory).join(path);
+                let body = std::fs::read(&path)
+                    .inspect_err(|e| error!("could not read error page {}: {e}", path.display()))
+                    .ok()?;
+                let content_type = mime::lookup(&path);
+                Some((key.clone(), content_type, body))
+            })
+            .collect();
+        ErrorPages {
+            handlers,
+            problem_json,
+            pages,
+        }
+    }
+
+    // Without a request (it couldn't be parsed) only the configured pages apply
+    pub fn render(&self, response: &mut HttpResponse, request: Option<&HttpRequest>) {
+        let status = response.status_code();
+        if !(status.is_client_error() || status.is_server_error()) || !response.body_is_empty() {
+            return;
+        }
+        if let Some(request) = request {
+            for handler in &self.handlers {
+                handler(request, response);
+                if !response.body_is_empty() {
+                    return;
+                }
+            }
+            if problem::wanted(request, &self.problem_json) {
+                problem::render(response, request);
+                return;
+            }
+        }
+        let code = status.as_u16().to_string();
+        let class = format!("{}xx", &code[..1]);
+        let page = self
+            .pages
+            .iter()
+            .find(|(key, _, _)| *key == code)
+            .or_else(|| self.pages.iter().find(|(key, _, _)| *key == class));
+        if let Some((_, content_type, body)) = page {
+            response.set_body(content_type, body.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{StatusCode, Uri, header};
+    use std::fs;
+
+    fn request(target: &str) -> HttpRequest {
+        HttpRequest {
+            uri: Uri::parse(target).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn rendered(pages: &ErrorPages, status: StatusCode, target: Option<&str>) -> HttpResponse {
+        let mut response = HttpResponse::new(status, "text/plain", vec![]);
+        pages.render(&mut response, target.map(request).as_ref());
+        response
+    }
+
+    #[test]
+    fn keys_are_error_statuses_or_classes() {
+        for key in ["404", "500", "4xx", "5xx"] {
+            assert!(is_valid_key(key), "{key}");
+        }
+        for key in ["200", "3xx", "40", "4XX", "x04", "404 "] {
+            assert!(!is_valid_key(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn handlers_then_problem_json_then_pages() {
+        let dir = crate::utils::temp_dir("error_pages");
+        fs::write(dir.join("404.html"), "<h1>Not here</h1>").unwrap();
+        fs::write(dir.join("oops.txt"), "oops").unwrap();
+        let envelope: Arc<ErrorHandler> = Arc::new(|request, response| {
+            if request.path().starts_with("/v2/") {
+                let body = format!("{{\"error\":{}}}", response.status_code().as_u16());
+                response.set_body("application/json", body.into_bytes());
+            }
+        });
+        let pages = ErrorPages::new(
+            vec![envelope],
+            vec!["/api".to_string()],
+            &[
+                ("404".to_string(), "404.html".into()),
+                ("5xx".to_string(), "oops.txt".into()),
+                ("403".to_string(), "missing.html".into()),
+            ],
+            dir.to_str().unwrap(),
+        );
+
+        let v2 = rendered(&pages, StatusCode::NOT_FOUND, Some("/v2/x"));
+        assert_eq!(v2.header(header::CONTENT_TYPE), Some("application/json"));
+        let api = rendered(&pages, StatusCode::NOT_FOUND, Some("/api/x"));
+        assert_eq!(
+            api.header(header::CONTENT_TYPE),
+            Some(problem::PROBLEM_JSON)
+        );
+        let page = rendered(&pages, StatusCode::NOT_FOUND, Some("/x"));
+        assert_eq!(page.header(header::CONTENT_TYPE), Some("text/html"));
+        assert!(format!("{page:#}").ends_with("<h1>Not here</h1>"));
+        let class = rendered(&pages, StatusCode::BAD_GATEWAY, None);
+        assert!(format!("{class:#}").ends_with("oops"));
+
+        // No page for it, or the file couldn't be read
+        for status in [StatusCode::BAD_REQUEST, StatusCode::FORBIDDEN] {
+            assert!(rendered(&pages, status, Some("/x")).body_is_empty());
+        }
+        let mut own = HttpResponse::new(StatusCode::NOT_FOUND, "text/plain", b"mine".to_vec());
+        pages.render(&mut own, Some(&request("/x")));
+        assert!(format!("{own:#}").ends_with("mine"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use crate::http::date::HttpDate;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Small files from the served directories, kept in memory with the validators their
+// responses carry, so hot assets skip the open, stat and read. Entries are keyed by
+// their path on disk ("public/css/site.css"), so mounts don't collide; once `budget`
+// bytes are held, the least recently served go first. Changes on disk are dropped by
+// the watcher (feature "watch"), by writes through /files/ and on reload.
+pub struct FileCache {
+    inner: Mutex<Inner>,
+    budget: u64,
+    max_file_size: u64,
+}
+
+pub struct CachedFile {
+    pub body: Vec<u8>,
+    pub content_type: String,
+    pub etag: String,
+    pub last_modified: Option<HttpDate>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // Bytes of file content held
+    used: u64,
+    // Bumped on every hit, so the smallest `last_used` is the least recently used
+    clock: u64,
+}
+
+struct Entry {
+    file: Arc<CachedFile>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(budget: u64, max_file_size: u64) -> Self {
+        FileCache {
+            inner: Mutex::new(Inner::default()),
+            budget,
+            max_file_size,
+        }
+    }
+
+    // Larger files are always read from disk
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.min(self.budget)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<CachedFile>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(name)?;
+        entry.last_used = clock;
+        Some(Arc::clone(&entry.file))
+    }
+
+    pub fn insert(&self, name: &str, file: Arc<CachedFile>) {
+        let size = file.body.len() as u64;
+        if size > self.max_file_size() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.entries.remove(name) {
+            inner.used -= old.file.body.len() as u64;
+        }
+        while inner.used + size > self.budget {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            let evicted = inner.entries.remove(&oldest).unwrap();
+            inner.used -= evicted.file.body.len() as u64;
+        }
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.used += size;
+        inner
+            .entries
+            .insert(name.to_string(), Entry { file, last_used });
+    }
+
+    // Drops `prefix` and, for a directory, everything under it
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { entries, used, .. } = &mut *inner;
+        entries.retain(|name, entry| {
+            let keep = !name.starts_with(prefix);
+            if !keep {
+                *used -= entry.file.body.len() as u64;
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.used = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    // Bytes of file content held, against the budget
+    pub fn used(&self) -> u64 {
+        self.inner.lock().unwrap().used
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(size: usize) -> Arc<CachedFile> {
+        Arc::new(CachedFile {
+            body: vec![b'x'; size],
+            content_type: "text/plain".to_string(),
+            etag: format!("\"{size}\""),
+            last_modified: None,
+        })
+    }
+
+    #[test]
+    fn least_recently_served_files_make_room() {
+        let cache = FileCache::new(100, 60);
+        cache.insert("a", file(40));
+        cache.insert("b", file(40));
+        assert!(cache.get("a").is_some());
+        // Over the budget: b was served longest ago
+        cache.insert("c", file(40));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.used(), 80);
+
+        // Too big to be worth it
+        cache.insert("big", file(61));
+        assert!(cache.get("big").is_none());
+        // Replacing an entry frees what the old version held
+        cache.insert("a", file(10));
+        assert_eq!(cache.used(), 50);
+    }
+
+    #[test]
+    fn invalidation_covers_directories() {
+        let cache = FileCache::new(1000, 1000);
+        for name in ["css/site.css", "css/print.css", "js/app.js"] {
+            cache.insert(name, file(10));
+        }
+        cache.invalidate_prefix("css");
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.used(), 10);
+        cache.clear();
+        assert_eq!((cache.len(), cache.used()), (0, 0));
+    }
+}
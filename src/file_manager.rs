@@ -0,0 +1,54 @@
+use crate::http::request::HttpMethod;
+use crate::http::{HttpRequest, HttpResponse, StatusCode, header};
+
+// Single-page file manager over the /files API, compiled into the binary
+const PAGE: &str = include_str!("file_manager.html");
+
+pub fn respond(request: &HttpRequest) -> HttpResponse {
+    match request.method {
+        HttpMethod::Get | HttpMethod::Head => {
+            let mut response = HttpResponse::new(
+                StatusCode::OK,
+                "text/html; charset=utf-8",
+                PAGE.as_bytes().to_vec(),
+            );
+            response.set_header(header::CACHE_CONTROL, "no-cache");
+            // The page only talks to this server and never needs to be framed
+            response.set_header(
+                header::CONTENT_SECURITY_POLICY,
+                "default-src 'self'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; \
+                 frame-ancestors 'none'",
+            );
+            response
+        }
+        _ => {
+            let mut response =
+                HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED, "text/plain", vec![]);
+            response.set_header(header::ALLOW, "GET, HEAD");
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+
+    #[test]
+    fn serves_the_embedded_page() {
+        let page = respond(&HttpRequest::default());
+        assert_eq!(page.status_code(), StatusCode::OK);
+        assert_eq!(
+            page.header(header::CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        assert!(PAGE.contains("method: \"MOVE\""));
+
+        let post = HttpRequest {
+            method: HttpMethod::Post,
+            ..Default::default()
+        };
+        assert_eq!(respond(&post).status_code(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
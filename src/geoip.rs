@@ -0,0 +1,461 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use thiserror::Error;
+
+// Marks the start of the metadata section, which sits at the end of the file
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+// Bytes between the search tree and the data section
+const DATA_SEPARATOR: usize = 16;
+
+// Nesting allowed in a record, so a crafted file with a pointer loop can't recurse forever
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum GeoIpError {
+    #[error("cannot read database: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid database: {0}")]
+    Invalid(&'static str),
+}
+
+// Country lookups in a MaxMind DB (`.mmdb`) file, such as GeoLite2-Country. The whole
+// file is held in memory; lookups walk the binary search tree one address bit at a
+// time and decode just the record they land on.
+pub struct GeoIp {
+    db: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    // Offset of the data section
+    data_start: usize,
+    // Node reached after the 96 zero bits of an IPv4 address in an IPv6 tree
+    ipv4_start: usize,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self, GeoIpError> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(db: Vec<u8>) -> Result<Self, GeoIpError> {
+        let marker = db
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(GeoIpError::Invalid("no metadata section"))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            db: &db,
+            base: metadata_start,
+        }
+        .decode(metadata_start)?;
+
+        let field = |name| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or(GeoIpError::Invalid("metadata is missing a field"))
+        };
+        let node_count = usize::try_from(field("node_count")?)
+            .map_err(|_| GeoIpError::Invalid("node count out of range"))?;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")? as u16;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(GeoIpError::Invalid("unsupported record size"));
+        }
+        if !matches!(ip_version, 4 | 6) {
+            return Err(GeoIpError::Invalid("unsupported IP version"));
+        }
+        // node_count is untrusted: a tree too large to address is as bad as a truncated one
+        let data_start = node_count
+            .checked_mul(record_size * 2 / 8)
+            .and_then(|tree_size| tree_size.checked_add(DATA_SEPARATOR))
+            .filter(|&data_start| data_start <= marker)
+            .ok_or(GeoIpError::Invalid("search tree runs past the data"))?;
+
+        let mut geoip = GeoIp {
+            db,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = geoip.record(node, 0);
+            }
+            geoip.ipv4_start = node;
+        }
+        Ok(geoip)
+    }
+
+    // The ISO 3166 country code for an address, e.g. "DE"; falls back to the country
+    // the network is registered in when the database has no physical location
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let offset = self.find(ip.to_canonical())?;
+        let decoder = Decoder {
+            db: &self.db,
+            base: self.data_start,
+        };
+        let (record, _) = decoder.decode(self.data_start + offset).ok()?;
+        ["country", "registered_country"].iter().find_map(|key| {
+            match record.get(key)?.get("iso_code")? {
+                Value::String(code) => Some(code.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    // Offset of the address's record within the data section
+    fn find(&self, ip: IpAddr) -> Option<usize> {
+        let (bits, len, mut node) = match ip {
+            IpAddr::V4(v4) => {
+                let start = if self.ip_version == 6 {
+                    self.ipv4_start
+                } else {
+                    0
+                };
+                (u32::from(v4) as u128, 32, start)
+            }
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(v6) => (u128::from(v6), 128, 0),
+        };
+        for i in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, ((bits >> i) & 1) as usize);
+        }
+        // node_count itself means "no data"; larger values point into the data section
+        if node <= self.node_count {
+            return None;
+        }
+        (node - self.node_count).checked_sub(DATA_SEPARATOR)
+    }
+
+    // The left (0) or right (1) record of a search tree node
+    fn record(&self, node: usize, side: usize) -> usize {
+        let bytes = self.record_size * 2 / 8;
+        let n = &self.db[node * bytes..(node + 1) * bytes];
+        let be = |b: &[u8]| b.iter().fold(0_usize, |acc, &x| (acc << 8) | x as usize);
+        match (self.record_size, side) {
+            (24, 0) => be(&n[0..3]),
+            (24, _) => be(&n[3..6]),
+            // The middle byte holds the high nibble of each record
+            (28, 0) => ((n[3] as usize & 0xF0) << 20) | be(&n[0..3]),
+            (28, _) => ((n[3] as usize & 0x0F) << 24) | be(&n[4..7]),
+            (_, 0) => be(&n[0..4]),
+            (_, _) => be(&n[4..8]),
+        }
+    }
+}
+
+// The subset of MMDB data types a lookup needs to walk; the rest are decoded and kept
+// only so records containing them still parse
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+struct Decoder<'a> {
+    db: &'a [u8],
+    // Where pointers are measured from: the data section, or the metadata itself
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, at: usize, len: usize) -> Result<&[u8], GeoIpError> {
+        self.db
+            .get(at..at + len)
+            .ok_or(GeoIpError::Invalid("record runs past the end of the file"))
+    }
+
+    // Decodes the value at `at`, returning it and the offset just past it
+    fn decode(&self, at: usize) -> Result<(Value, usize), GeoIpError> {
+        self.decode_nested(at, 0)
+    }
+
+    fn decode_nested(&self, at: usize, depth: usize) -> Result<(Value, usize), GeoIpError> {
+        if depth > MAX_DEPTH {
+            return Err(GeoIpError::Invalid("record is nested too deeply"));
+        }
+        let ctrl = self.bytes(at, 1)?[0];
+        let mut pos = at + 1;
+        let mut kind = (ctrl >> 5) as usize;
+
+        if kind == 1 {
+            // A pointer; the value it points at is decoded in place, and decoding
+            // continues after the pointer itself
+            let size = ((ctrl >> 3) & 0x3) as usize;
+            let high = (ctrl & 0x7) as usize;
+            let b = self.bytes(pos, size + 1)?;
+            let be = b.iter().fold(0_usize, |acc, &x| (acc << 8) | x as usize);
+            let target = match size {
+                0 => (high << 8) | be,
+                1 => ((high << 16) | be) + 2048,
+                2 => ((high << 24) | be) + 526_336,
+                _ => be,
+            };
+            let (value, _) = self.decode_nested(self.base + target, depth + 1)?;
+            return Ok((value, pos + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(pos, 1)?[0] as usize;
+            pos += 1;
+        }
+
+        let mut size = (ctrl & 0x1F) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let b = self.bytes(pos, extra)?;
+            let be = b.iter().fold(0_usize, |acc, &x| (acc << 8) | x as usize);
+            size = match extra {
+                1 => 29 + be,
+                2 => 285 + be,
+                _ => 65_821 + be,
+            };
+            pos += extra;
+        }
+
+        let uint = |b: &[u8]| b.iter().fold(0_u128, |acc, &x| (acc << 8) | x as u128);
+        let value = match kind {
+            2 => {
+                let s = std::str::from_utf8(self.bytes(pos, size)?)
+                    .map_err(|_| GeoIpError::Invalid("string is not UTF-8"))?;
+                pos += size;
+                Value::String(s.to_string())
+            }
+            3 => {
+                let b = self.bytes(pos, 8)?;
+                pos += 8;
+                Value::Double(f64::from_be_bytes(b.try_into().unwrap()))
+            }
+            4 => {
+                let b = self.bytes(pos, size)?.to_vec();
+                pos += size;
+                Value::Bytes(b)
+            }
+            5 | 6 | 9 | 10 => {
+                let n = uint(self.bytes(pos, size)?);
+                pos += size;
+                Value::Uint(n)
+            }
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode_nested(pos, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err(GeoIpError::Invalid("map key is not a string"));
+                    };
+                    let (value, next) = self.decode_nested(next, depth + 1)?;
+                    entries.push((key, value));
+                    pos = next;
+                }
+                Value::Map(entries)
+            }
+            8 => {
+                // Stored in as few bytes as needed; sign comes from the full 32 bits
+                let n = uint(self.bytes(pos, size)?) as u32 as i32;
+                pos += size;
+                Value::Int(n)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (item, next) = self.decode_nested(pos, depth + 1)?;
+                    items.push(item);
+                    pos = next;
+                }
+                Value::Array(items)
+            }
+            14 => Value::Bool(size != 0),
+            15 => {
+                let b = self.bytes(pos, 4)?;
+                pos += 4;
+                Value::Double(f32::from_be_bytes(b.try_into().unwrap()) as f64)
+            }
+            _ => return Err(GeoIpError::Invalid("unknown data type")),
+        };
+        Ok((value, pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Control byte plus payload for the short forms the tests need
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![(2 << 5) | s.len() as u8];
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![(7 << 5) | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        let mut out = vec![(5 << 5) | 2];
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+
+    fn uint64(n: u64) -> Vec<u8> {
+        // Extended type 9, stored as its offset from 7
+        let mut out = vec![8, 9 - 7];
+        out.extend_from_slice(&n.to_be_bytes());
+        out
+    }
+
+    // An IPv4 database with 24-bit records mapping 10.0.0.0/8 to DE and
+    // 192.0.0.0/8 to a record holding only `registered_country` (reached through a
+    // pointer to the first record's country map)
+    fn test_db() -> Vec<u8> {
+        let de = map(&[("country", map(&[("iso_code", string("DE"))]))]);
+        // Pointer (type 1, size 0) to offset 1 + 1 + 7 = 9: the inner map of `de`
+        let registered = {
+            let mut out = vec![(7 << 5) | 1];
+            out.extend(string("registered_country"));
+            out.extend([1 << 5, 9]);
+            out
+        };
+
+        // The root branches on the first bit; nodes 1..=7 follow the remaining bits of
+        // 10 and nodes 9..=15 those of 192, ending in a pointer into the data section
+        let node_count = 16_usize;
+        let none = node_count;
+        let to_data = |offset: usize| node_count + DATA_SEPARATOR + offset;
+        let mut nodes = vec![(none, none); node_count];
+        nodes[0] = (1, 9);
+        let mut walk = |first: usize, octet: u8, data: usize| {
+            for (node, bit) in (first..first + 7).zip((0..7).rev()) {
+                let next = if bit == 0 { to_data(data) } else { node + 1 };
+                nodes[node] = if (octet >> bit) & 1 == 0 {
+                    (next, none)
+                } else {
+                    (none, next)
+                };
+            }
+        };
+        walk(1, 10, 0);
+        walk(9, 192, de.len());
+
+        let mut db = Vec::new();
+        for (left, right) in nodes {
+            db.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+            db.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        }
+        db.extend([0; DATA_SEPARATOR]);
+        db.extend(&de);
+        db.extend(&registered);
+        db.extend(METADATA_MARKER);
+        db.extend(map(&[
+            ("node_count", uint16(node_count as u16)),
+            ("record_size", uint16(24)),
+            ("ip_version", uint16(4)),
+        ]));
+        db
+    }
+
+    #[test]
+    fn looks_up_countries() {
+        let geoip = GeoIp::from_bytes(test_db()).unwrap();
+        assert_eq!(
+            geoip.country("10.1.2.3".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(
+            geoip.country("::ffff:10.9.9.9".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(
+            geoip.country("192.0.2.1".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(geoip.country("11.0.0.1".parse().unwrap()), None);
+        assert_eq!(geoip.country("2001:db8::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn rejects_files_without_metadata() {
+        assert!(matches!(
+            GeoIp::from_bytes(b"not a database".to_vec()),
+            Err(GeoIpError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_node_counts_are_rejected() {
+        let mut db = vec![0; 64];
+        db.extend(METADATA_MARKER);
+        db.extend(map(&[
+            ("node_count", uint64(u64::MAX)),
+            ("record_size", uint16(32)),
+            ("ip_version", uint16(4)),
+        ]));
+        assert!(matches!(GeoIp::from_bytes(db), Err(GeoIpError::Invalid(_))));
+    }
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        // A one-entry map whose value points back at the map itself
+        let mut db = vec![(7 << 5) | 1];
+        db.extend(string("k"));
+        db.extend([1 << 5, 0]);
+        let decoder = Decoder { db: &db, base: 0 };
+        assert!(matches!(decoder.decode(0), Err(GeoIpError::Invalid(_))));
+    }
+
+    #[test]
+    fn decodes_extended_types_and_long_sizes() {
+        let long = "x".repeat(40);
+        let mut db = vec![(2 << 5) | 29, 40 - 29];
+        db.extend(long.as_bytes());
+        // Extended type 11 (array) of two booleans (type 14)
+        db.extend([2, 11 - 7, 1, 14 - 7, 0, 14 - 7]);
+        let decoder = Decoder { db: &db, base: 0 };
+
+        let (value, next) = decoder.decode(0).unwrap();
+        assert_eq!(value, Value::String(long));
+        let (value, _) = decoder.decode(next).unwrap();
+        assert_eq!(
+            value,
+            Value::Array(vec![Value::Bool(true), Value::Bool(false)])
+        );
+    }
+}
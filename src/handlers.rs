@@ -1,43 +1,794 @@
+use crate::config::{Config, Mount, Symlinks};
+use crate::file_cache::{CachedFile, FileCache};
+use crate::http::date::HttpDate;
+use crate::http::header::{self, AcceptEncoding, Range};
+use crate::http::multipart::{self, Multipart};
 use crate::http::request::HttpMethod;
-use crate::http::{HttpRequest, HttpResponse};
+use crate::http::{Body, HttpRequest, HttpResponse, StatusCode, Uri};
+use crate::mime;
+use crate::router::HandlerError;
+use crate::utils::{encode_path, glob_match, html_escape, json_string, percent_encode};
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+// What /files/ answers to, for Allow headers
+pub const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, MOVE";
+
+// Precompressed companions of a file, by extension and coding, in order of preference
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gz", "gzip")];
+
+// A directory is answered with the first of `config.index_files` in it, or else its
+// listing: an HTML page for browsers with `config.autoindex`, JSON otherwise. Small
+// files are served from `cache` where there is one, and writes drop what they touch.
 pub async fn handle_file_request(
     path: &str,
     request: &HttpRequest,
-    directory: &str,
-) -> HttpResponse {
-    let filename = &path[7..];
-    let file_path = std::path::Path::new(directory).join(filename);
+    mount: &Mount,
+    config: &Config,
+    cache: Option<&FileCache>,
+) -> Result<HttpResponse, HandlerError> {
+    let directory = mount.directory.as_str();
+    // `*name` comes from the normalized path, so it can't climb out of the directory
+    let filename = request.param("name").unwrap_or_default();
+    // `.git`, `.env` and the like don't exist as far as clients can tell, unless allowed
+    if hidden(&filename) && !config.allow_dotfiles {
+        return Err(HandlerError::Status(StatusCode::NOT_FOUND));
+    }
+    let file_path = std::path::Path::new(directory).join(&filename);
+    if !reachable(directory, &file_path, config.symlinks).await {
+        return Err(HandlerError::Status(StatusCode::FORBIDDEN));
+    }
 
     match request.method {
-        HttpMethod::Get => {
-            if file_path.exists() {
-                match tokio::fs::read(file_path).await {
-                    Ok(content) => HttpResponse::new("200 OK", "application/octet-stream", content),
-                    Err(_) => HttpResponse::new("500 Internal Server Error", "text/plain", vec![]),
+        // HEAD runs the GET arms; the body is dropped when the response is sent
+        HttpMethod::Get | HttpMethod::Head if file_path.is_dir() => {
+            // Scripts asking for the listing get it even where there's an index page
+            if !wants_json(request)
+                && let Some(index) = config
+                    .index_files
+                    .iter()
+                    .find(|name| file_path.join(name).is_file())
+            {
+                let index = Path::new(&filename).join(index);
+                let index = index.to_str().unwrap_or_default();
+                let response =
+                    serve_cached(index, directory, request, cache, config.symlinks).await?;
+                return Ok(with_cache_control(response, index, config));
+            }
+            // Listings are opt-in: with autoindex, or as JSON for the file manager
+            let autoindex = config.autoindex;
+            let listed = autoindex || (config.file_manager.is_some() && wants_json(request));
+            if !listed {
+                return Err(HandlerError::Status(StatusCode::NOT_FOUND));
+            }
+            let entries = read_entries(&file_path).await?;
+            let mut response = match wants_json(request) {
+                true => list_directory(&entries),
+                false => index_page(path, &mount.prefix, &entries),
+            };
+            if autoindex {
+                response.set_header(header::VARY, "Accept");
+            }
+            Ok(response)
+        }
+        // A client-side route of a single-page app: the app's shell, which reads the URL
+        HttpMethod::Get | HttpMethod::Head
+            if config.spa && !file_path.exists() && file_path.extension().is_none() =>
+        {
+            let response =
+                serve_cached("index.html", directory, request, cache, config.symlinks).await?;
+            Ok(with_cache_control(response, "index.html", config))
+        }
+        HttpMethod::Get | HttpMethod::Head => {
+            let response =
+                serve_encoded(&filename, directory, request, cache, config.symlinks).await?;
+            Ok(with_cache_control(response, &filename, config))
+        }
+        _ => {
+            let response = write_file(path, request, mount, &file_path, config).await;
+            // Whatever the outcome, the cached copies may be out of date now
+            if let Some(cache) = cache {
+                cache.invalidate_prefix(&file_path.to_string_lossy());
+                if request.method == HttpMethod::Move
+                    && let Some(destination) = destination(request, &mount.prefix)
+                {
+                    cache.invalidate_prefix(
+                        &Path::new(directory).join(destination).to_string_lossy(),
+                    );
                 }
+            }
+            response
+        }
+    }
+}
+
+// The configured Cache-Control for the file served as `name`, if a rule covers it
+fn with_cache_control(mut response: HttpResponse, name: &str, config: &Config) -> HttpResponse {
+    let path = format!("/{name}");
+    if let Some(rule) = config
+        .cache_control
+        .iter()
+        .find(|rule| glob_match(&rule.pattern, &path))
+    {
+        response.set_header(header::CACHE_CONTROL, &rule.value);
+    }
+    response
+}
+
+// The methods that change the directory
+async fn write_file(
+    path: &str,
+    request: &HttpRequest,
+    mount: &Mount,
+    file_path: &Path,
+    config: &Config,
+) -> Result<HttpResponse, HandlerError> {
+    match request.method {
+        // Writes are conditional on the file still being the version the client saw
+        HttpMethod::Post | HttpMethod::Put | HttpMethod::Delete | HttpMethod::Move
+            if !preconditions_hold(request, tokio::fs::metadata(file_path).await.ok().as_ref()) =>
+        {
+            Err(HandlerError::Status(StatusCode::PRECONDITION_FAILED))
+        }
+        // A form upload into a directory, one file per part
+        HttpMethod::Post if file_path.is_dir() => {
+            let saved = save_form(file_path, path, request).await?;
+            let saved: Vec<String> = saved.iter().map(|p| json_string(p)).collect();
+            let body = format!("[{}]", saved.join(","));
+            Ok(HttpResponse::new(
+                StatusCode::CREATED,
+                "application/json",
+                body.into_bytes(),
+            ))
+        }
+        HttpMethod::Delete => {
+            tokio::fs::remove_file(file_path).await?;
+            Ok(HttpResponse::new(
+                StatusCode::NO_CONTENT,
+                "text/plain",
+                vec![],
+            ))
+        }
+        HttpMethod::Post | HttpMethod::Put => {
+            let existed = file_path.exists();
+            save_upload(file_path, request).await?;
+            // PUT over an existing file replaces it; anything else creates one
+            let mut response = if existed && request.method == HttpMethod::Put {
+                HttpResponse::new(StatusCode::NO_CONTENT, "text/plain", vec![])
             } else {
-                HttpResponse::new("404 Not Found", "text/plain", vec![])
+                HttpResponse::new(StatusCode::CREATED, "text/plain", vec![])
+            };
+            // The new version's validators, for the client's next conditional write
+            if let Ok(meta) = tokio::fs::metadata(file_path).await {
+                set_validators(&mut response, &meta);
+            }
+            Ok(response)
+        }
+        HttpMethod::Move
+            if destination(request, &mount.prefix).is_some_and(|to| hidden(&to))
+                && !config.allow_dotfiles =>
+        {
+            Err(HandlerError::Status(StatusCode::FORBIDDEN))
+        }
+        HttpMethod::Move => move_file(file_path, request, mount, config.symlinks).await,
+        _ => {
+            let mut response =
+                HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED, "text/plain", vec![]);
+            response.set_header(header::ALLOW, ALLOWED_METHODS);
+            Ok(response)
+        }
+    }
+}
+
+// For a GET or HEAD of a directory without its trailing slash, the 301 that adds it
+pub fn directory_redirect(request: &HttpRequest, directory: &str) -> Option<HttpResponse> {
+    let path = request.path();
+    let name = request.param("name").unwrap_or_default();
+    let listing = matches!(request.method, HttpMethod::Get | HttpMethod::Head)
+        && !path.ends_with('/')
+        && Path::new(directory).join(name).is_dir();
+    if !listing {
+        return None;
+    }
+    // The path is decoded; it goes back out encoded so it can't break the header
+    let path = encode_path(path);
+    let location = match request.uri.query() {
+        Some(query) => format!("{path}/?{query}"),
+        None => format!("{path}/"),
+    };
+    Some(HttpResponse::redirect(location, true))
+}
+
+// The directory's entries, subdirectories first. Dotfiles are left out, which also
+// hides uploads still being written.
+async fn read_entries(dir: &Path) -> io::Result<Vec<(String, Metadata)>> {
+    let mut entries = Vec::new();
+    let mut reader = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = reader.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        entries.push((name, entry.metadata().await?));
+    }
+    entries
+        .sort_by(|(a, a_meta), (b, b_meta)| b_meta.is_dir().cmp(&a_meta.is_dir()).then(a.cmp(b)));
+    Ok(entries)
+}
+
+// JSON array of the entries. `size` is null for directories, and `mtime` is in Unix
+// seconds.
+fn list_directory(entries: &[(String, Metadata)]) -> HttpResponse {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|(name, meta)| {
+            let size = match meta.is_dir() {
+                true => "null".to_string(),
+                false => meta.len().to_string(),
+            };
+            format!(
+                "{{\"name\":{},\"size\":{size},\"mtime\":{},\"is_dir\":{}}}",
+                json_string(name),
+                last_modified(meta).map_or("null".to_string(), |date| date.0.to_string()),
+                meta.is_dir(),
+            )
+        })
+        .collect();
+    let mut response = HttpResponse::new(
+        StatusCode::OK,
+        "application/json",
+        format!("[{}]", items.join(",")).into_bytes(),
+    );
+    response.set_header(header::CACHE_CONTROL, "no-cache");
+    response
+}
+
+// `?format=json`, or JSON named in Accept, picks the JSON listing over the HTML page
+fn wants_json(request: &HttpRequest) -> bool {
+    let format_json = request
+        .uri
+        .query()
+        .is_some_and(|query| query.split('&').any(|pair| pair == "format=json"));
+    format_json || accepts(request, "application/json")
+}
+
+// Only an explicit media type counts, so `*/*` gets the HTML page
+fn accepts(request: &HttpRequest, media_type: &str) -> bool {
+    let Some(accept) = request.headers.get(header::ACCEPT) else {
+        return false;
+    };
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let media = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        media.eq_ignore_ascii_case(media_type) && quality > 0.0
+    })
+}
+
+// An HTML page linking each entry, with its size and modification time. Links are
+// absolute, so they work whether or not the URL ended in a slash.
+fn index_page(url_path: &str, prefix: &str, entries: &[(String, Metadata)]) -> HttpResponse {
+    let base = url_path.trim_end_matches('/');
+    let title = html_escape(&format!("{base}/"));
+    let mut rows = String::new();
+    // No way up from the top of the mount
+    if let Some((parent, _)) = base.rsplit_once('/')
+        && base != prefix
+    {
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+            html_escape(parent)
+        ));
+    }
+    for (name, meta) in entries {
+        let (slash, size) = match meta.is_dir() {
+            true => ("/", "-".to_string()),
+            false => ("", meta.len().to_string()),
+        };
+        let modified = last_modified(meta).map_or(String::new(), |date| date.to_string());
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}/{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>\n",
+            html_escape(base),
+            percent_encode(name),
+            html_escape(name),
+        ));
+    }
+    let page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{rows}</table>\n</body>\n</html>\n"
+    );
+    let mut response = HttpResponse::new(
+        StatusCode::OK,
+        "text/html; charset=utf-8",
+        page.into_bytes(),
+    );
+    response.set_header(header::CACHE_CONTROL, "no-cache");
+    response
+}
+
+// Saves every file part of a multipart/form-data body into `dir`, under the part's
+// file name without any directories the client put in it. Returns the saved files'
+// URL paths; fields that aren't files are ignored.
+async fn save_form(dir: &Path, url_dir: &str, request: &HttpRequest) -> io::Result<Vec<String>> {
+    let boundary = request
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(multipart::boundary)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "not multipart/form-data"))?;
+    let mut form = Multipart::new(request.body_reader(), &boundary);
+    let mut saved = Vec::new();
+    while let Some(part) = form.next_part().await? {
+        let Some(filename) = part.filename else {
+            continue;
+        };
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+        if name.is_empty() || name.starts_with('.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unusable file name `{filename}`"),
+            ));
+        }
+        write_atomically(&dir.join(name), async |file| {
+            while let Some(chunk) = form.read_chunk().await? {
+                file.write_all(&chunk).await?;
             }
+            Ok(())
+        })
+        .await?;
+        saved.push(format!("{}/{name}", url_dir.trim_end_matches('/')));
+    }
+    Ok(saved)
+}
+
+// Whether any segment of a name under the directory is a dotfile or dot-directory
+fn hidden(name: &str) -> bool {
+    name.split('/').any(|segment| segment.starts_with('.'))
+}
+
+// The name under the directory that a MOVE's Destination names, which must be under
+// the same mount's `prefix`
+fn destination(request: &HttpRequest, prefix: &str) -> Option<String> {
+    // Destination may be a full URL; Uri keeps only its normalized path
+    let uri = Uri::parse(request.headers.get(header::DESTINATION)?.trim()).ok()?;
+    let name = uri.path().strip_prefix(prefix)?.strip_prefix('/')?;
+    let name = name.trim_end_matches('/');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// WebDAV MOVE: renames the file (or directory) to the path in Destination, under the
+// same mount. An existing target is replaced unless the client sent `Overwrite: F`.
+async fn move_file(
+    from: &Path,
+    request: &HttpRequest,
+    mount: &Mount,
+    symlinks: Symlinks,
+) -> Result<HttpResponse, HandlerError> {
+    let directory = mount.directory.as_str();
+    let target = destination(request, &mount.prefix).map(|name| Path::new(directory).join(name));
+    let Some(to) = target else {
+        return Err(HandlerError::Status(StatusCode::BAD_REQUEST));
+    };
+    if !from.exists() {
+        return Err(HandlerError::Status(StatusCode::NOT_FOUND));
+    }
+    if !reachable(directory, &to, symlinks).await {
+        return Err(HandlerError::Status(StatusCode::FORBIDDEN));
+    }
+    let existed = to.exists();
+    let overwrite = request
+        .headers
+        .get(header::OVERWRITE)
+        .is_none_or(|v| !v.trim().eq_ignore_ascii_case("f"));
+    if existed && !overwrite {
+        return Err(HandlerError::Status(StatusCode::PRECONDITION_FAILED));
+    }
+    match tokio::fs::rename(from, &to).await {
+        Ok(()) if existed => Ok(HttpResponse::new(
+            StatusCode::NO_CONTENT,
+            "text/plain",
+            vec![],
+        )),
+        Ok(()) => Ok(HttpResponse::new(StatusCode::CREATED, "text/plain", vec![])),
+        // The target's directory doesn't exist
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(HandlerError::Status(StatusCode::CONFLICT))
         }
-        HttpMethod::Post => match tokio::fs::write(file_path, &request.body).await {
-            Ok(_) => HttpResponse::new("201 Created", "text/plain", vec![]),
-            Err(_) => HttpResponse::new("500 Internal Server Error", "text/plain", vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Whether the file really is under `directory`, symlinks resolved. The URL path can't
+// climb out (Uri drops `..`, percent-encoded or not, and refuses backslashes), but a
+// symlink can point anywhere. A file that doesn't exist yet is judged by its
+// directory, and a missing directory counts as inside; whatever comes next fails
+// with 404.
+async fn inside(directory: &str, path: &Path) -> bool {
+    let resolved = match tokio::fs::canonicalize(path).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match path.parent() {
+            Some(parent) => tokio::fs::canonicalize(parent).await,
+            None => return false,
         },
+        resolved => resolved,
+    };
+    // "" serves the working directory, as joining onto it does
+    let root = match directory {
+        "" => ".",
+        directory => directory,
+    };
+    match (tokio::fs::canonicalize(root).await, resolved) {
+        (Ok(root), Ok(resolved)) => resolved.starts_with(root),
+        (_, Err(e)) => e.kind() == io::ErrorKind::NotFound,
+        (Err(_), _) => false,
+    }
+}
+
+// Whether `path` may be served or written under `symlinks`
+async fn reachable(directory: &str, path: &Path, symlinks: Symlinks) -> bool {
+    match symlinks {
+        Symlinks::Follow => true,
+        Symlinks::Inside => inside(directory, path).await,
+        Symlinks::Never => inside(directory, path).await && !through_symlink(directory, path).await,
     }
 }
 
+// Whether any component of `path` below `directory` is a symlink; the directory itself
+// may be one. Components that don't exist yet aren't.
+async fn through_symlink(directory: &str, path: &Path) -> bool {
+    let Ok(below) = path.strip_prefix(directory) else {
+        return true;
+    };
+    let mut current = Path::new(directory).to_path_buf();
+    for component in below.components() {
+        current.push(component);
+        match tokio::fs::symlink_metadata(&current).await {
+            Ok(meta) if meta.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+// Writes the body to a temp file next to the destination and renames it into place,
+// so readers never see a half-written file. Large bodies are copied chunk by chunk as
+// they arrive rather than being buffered.
+async fn save_upload(path: &Path, request: &HttpRequest) -> io::Result<()> {
+    write_atomically(path, async |file| {
+        tokio::io::copy(&mut request.body_reader(), file).await?;
+        Ok(())
+    })
+    .await
+}
+
+// Has `fill` write a temp file next to `path`, then renames it into place
+async fn write_atomically<F>(path: &Path, fill: F) -> io::Result<()>
+where
+    F: AsyncFnOnce(&mut File) -> io::Result<()>,
+{
+    static UPLOADS: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(
+        ".{name}.{}.{}.part",
+        std::process::id(),
+        UPLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = async {
+        let mut file = File::create(&tmp).await?;
+        fill(&mut file).await?;
+        file.flush().await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result
+}
+
+// A file with a precompressed companion next to it ("style.css.br", "style.css.gz")
+// goes out as that companion to clients accepting its coding, and as itself to the rest.
+// Companions the symlink policy rules out are ignored.
+async fn serve_encoded(
+    name: &str,
+    directory: &str,
+    request: &HttpRequest,
+    cache: Option<&FileCache>,
+    symlinks: Symlinks,
+) -> io::Result<HttpResponse> {
+    let path = Path::new(directory).join(name);
+    if !path.is_file() {
+        return serve_cached(name, directory, request, cache, symlinks).await;
+    }
+    let mut companions: Vec<(String, &str)> = Vec::new();
+    for (ext, coding) in PRECOMPRESSED {
+        let companion = format!("{name}.{ext}");
+        let companion_path = Path::new(directory).join(&companion);
+        if companion_path.is_file() && reachable(directory, &companion_path, symlinks).await {
+            companions.push((companion, *coding));
+        }
+    }
+    let accepted = request
+        .headers
+        .get(header::ACCEPT_ENCODING)
+        .map(AcceptEncoding::parse);
+    let chosen = companions
+        .iter()
+        .find(|(_, coding)| accepted.as_ref().is_some_and(|ae| ae.accepts(coding)));
+    let mut response = match chosen {
+        Some((companion, coding)) => {
+            let mut response = serve_cached(companion, directory, request, cache, symlinks).await?;
+            if response.status_code().is_success() {
+                response.set_header(header::CONTENT_TYPE, mime::lookup(&path));
+                response.set_header(header::CONTENT_ENCODING, *coding);
+            }
+            response
+        }
+        None => serve_cached(name, directory, request, cache, symlinks).await?,
+    };
+    if !companions.is_empty() {
+        response.set_header(header::VARY, "Accept-Encoding");
+    }
+    Ok(response)
+}
+
+// `name` under the directory, from `cache` when it's there. A miss small enough to
+// cache is read whole and stored; ranges always come from disk. Whatever is opened is
+// held to the symlink policy, as it may not be the file the URL named (an index page,
+// a companion), and gets a 403 if the policy rules it out.
+async fn serve_cached(
+    name: &str,
+    directory: &str,
+    request: &HttpRequest,
+    cache: Option<&FileCache>,
+    symlinks: Symlinks,
+) -> io::Result<HttpResponse> {
+    let path = Path::new(directory).join(name);
+    let cache = cache.filter(|_| !request.headers.contains_key(header::RANGE));
+    let key = path.to_string_lossy();
+    if let Some(cached) = cache.and_then(|cache| cache.get(&key)) {
+        return Ok(cached_response(&cached));
+    }
+    if !reachable(directory, &path, symlinks).await {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    let Some(cache) = cache else {
+        return serve_file(&path, request).await;
+    };
+    let mut file = File::open(&path).await?;
+    let meta = file.metadata().await?;
+    if meta.len() > cache.max_file_size() {
+        return serve_file(&path, request).await;
+    }
+    let mut body = Vec::with_capacity(meta.len() as usize);
+    file.read_to_end(&mut body).await?;
+    let cached = Arc::new(CachedFile {
+        body,
+        content_type: mime::lookup(&path),
+        etag: etag(&meta),
+        last_modified: last_modified(&meta),
+    });
+    cache.insert(&key, Arc::clone(&cached));
+    Ok(cached_response(&cached))
+}
+
+// What `serve_file` would answer for the whole file. Not gzipped, as files from disk
+// aren't, so the ETag names one representation either way.
+fn cached_response(cached: &CachedFile) -> HttpResponse {
+    let mut response = HttpResponse::new(StatusCode::OK, &cached.content_type, cached.body.clone());
+    response.no_compress();
+    response.set_header(header::ETAG, &cached.etag);
+    if let Some(date) = cached.last_modified {
+        response.set_header(header::LAST_MODIFIED, date.to_string());
+    }
+    response.set_header(header::ACCEPT_RANGES, "bytes");
+    response
+}
+
+// Hands the open file to the response so it's streamed rather than read into memory.
+// The file, or the single byte range of it the client asked for
+async fn serve_file(path: &Path, request: &HttpRequest) -> io::Result<HttpResponse> {
+    let file = tokio::fs::File::open(path).await?;
+    let meta = file.metadata().await?;
+    let len = meta.len();
+    let mut response = match requested_range(request, &meta) {
+        None => HttpResponse::with_body(
+            StatusCode::OK,
+            &mime::lookup(path),
+            Body::File {
+                file,
+                offset: 0,
+                len,
+            },
+        ),
+        Some(Some((start, end))) => {
+            let body = Body::File {
+                file,
+                offset: start,
+                len: end - start + 1,
+            };
+            let mut response =
+                HttpResponse::with_body(StatusCode::PARTIAL_CONTENT, &mime::lookup(path), body);
+            response.set_header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+            response
+        }
+        Some(None) => {
+            let mut response =
+                HttpResponse::new(StatusCode::RANGE_NOT_SATISFIABLE, "text/plain", vec![]);
+            response.set_header(header::CONTENT_RANGE, format!("bytes */{len}"));
+            return Ok(response);
+        }
+    };
+    set_validators(&mut response, &meta);
+    response.set_header(header::ACCEPT_RANGES, "bytes");
+    Ok(response)
+}
+
+// The inclusive byte range to send: None for the whole file, Some(None) when the
+// range lies past the end. Several ranges would need a multipart/byteranges body, so
+// they get the whole file, as do an unparsable Range and an If-Range naming another
+// version (RFC 9110 section 13.1.5).
+fn requested_range(request: &HttpRequest, meta: &Metadata) -> Option<Option<(u64, u64)>> {
+    let range = Range::parse(request.headers.get(header::RANGE)?)?;
+    if let Some(if_range) = request.headers.get(header::IF_RANGE) {
+        let current = match HttpDate::parse(if_range) {
+            Some(date) => last_modified(meta) == Some(date),
+            // Strong comparison: a weak tag never matches
+            None => *if_range == etag(meta),
+        };
+        if !current {
+            return None;
+        }
+    }
+    let [range] = range.ranges.as_slice() else {
+        return None;
+    };
+    Some(range.resolve(meta.len()))
+}
+
+// A strong ETag without hashing the content. Uploads are renamed into place, so on
+// unix every write gets a new inode; that covers filesystems whose timestamps are too
+// coarse to tell two quick writes apart.
+fn etag(meta: &Metadata) -> String {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(meta);
+    #[cfg(not(unix))]
+    let inode = 0;
+    format!("\"{inode:x}-{:x}-{:x}\"", meta.len(), modified.as_nanos())
+}
+
+fn last_modified(meta: &Metadata) -> Option<HttpDate> {
+    HttpDate::from_system_time(meta.modified().ok()?)
+}
+
+fn set_validators(response: &mut HttpResponse, meta: &Metadata) {
+    response.set_header(header::ETAG, etag(meta));
+    if let Some(date) = last_modified(meta) {
+        response.set_header(header::LAST_MODIFIED, date.to_string());
+    }
+}
+
+// RFC 9110 section 13.2.2: If-Match takes precedence, and If-Unmodified-Since is only
+// looked at without it. `meta` is None when the file doesn't exist.
+fn preconditions_hold(request: &HttpRequest, meta: Option<&Metadata>) -> bool {
+    if let Some(if_match) = request.headers.get(header::IF_MATCH) {
+        let Some(meta) = meta else {
+            return false;
+        };
+        let current = etag(meta);
+        // Strong comparison: weak tags never match
+        return if_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == current);
+    }
+    if let Some(since) = request.headers.get(header::IF_UNMODIFIED_SINCE)
+        // An unparsable date is ignored, as is the header for a file that isn't there
+        && let Some(since) = HttpDate::parse(since)
+        && let Some(modified) = meta.and_then(last_modified)
+    {
+        return modified <= since;
+    }
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::CacheControlRule;
     use crate::http::request::HttpMethod;
-    use std::collections::HashMap;
+    use crate::http::response::ResponseDefaults;
+    use crate::http::{Body, Headers, StatusCode, Uri};
+    use crate::router::Router;
+    use crate::utils::temp_dir;
     use std::fs;
-    use std::path::PathBuf;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::time::UNIX_EPOCH;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
 
+    // Through a router, as the server calls it, so `*name` gets captured
+    async fn handle_file_request(
+        path: &str,
+        request: &HttpRequest,
+        directory: &str,
+    ) -> HttpResponse {
+        with_autoindex(path, request, directory, false).await
+    }
+
+    async fn with_autoindex(
+        path: &str,
+        request: &HttpRequest,
+        directory: &str,
+        autoindex: bool,
+    ) -> HttpResponse {
+        let config = Config {
+            autoindex,
+            ..Default::default()
+        };
+        with_config(path, request, directory, config).await
+    }
+
+    async fn with_config(
+        path: &str,
+        request: &HttpRequest,
+        directory: &str,
+        config: Config,
+    ) -> HttpResponse {
+        routed(path, request, directory, config, None).await
+    }
+
+    async fn with_cache(
+        path: &str,
+        request: &HttpRequest,
+        directory: &str,
+        cache: &Arc<FileCache>,
+    ) -> HttpResponse {
+        let cache = Some(Arc::clone(cache));
+        routed(path, request, directory, Config::default(), cache).await
+    }
+
+    async fn routed(
+        path: &str,
+        request: &HttpRequest,
+        directory: &str,
+        config: Config,
+        cache: Option<Arc<FileCache>>,
+    ) -> HttpResponse {
+        let mut router: Router<(Mount, Config, Option<Arc<FileCache>>)> = Router::new();
+        router.route(ALLOWED_METHODS, "/files/*name", "files", |routed| {
+            let (mount, config, cache) = routed.state;
+            Box::pin(super::handle_file_request(
+                routed.path,
+                routed.request,
+                mount,
+                config,
+                cache.as_deref(),
+            ))
+        });
+        let mount = Mount {
+            prefix: "/files".to_string(),
+            directory: directory.to_string(),
+        };
+        let state = (mount, config, cache);
+        router.respond(request, path, &state).await
+    }
+
     async fn connected_pair() -> (TcpStream, TcpStream) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -68,48 +819,41 @@ mod tests {
         (&resp[..idx], &resp[idx + needle.len()..])
     }
 
-    fn make_temp_dir() -> PathBuf {
-        let mut dir = std::env::temp_dir();
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        dir.push(format!("cc_http_server_test_{nanos}"));
-        fs::create_dir_all(&dir).unwrap();
-        dir
-    }
-
     fn req_for_send() -> crate::http::HttpRequest {
         // Make the server echo Connection: close so tests can read to end after shutdown
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
         headers.insert("connection".to_string(), "close".to_string());
 
         crate::http::HttpRequest {
             method: HttpMethod::Get,
-            path: "/".to_string(),
+            uri: Uri::parse("/").unwrap(),
             headers,
             body: vec![],
+            ..Default::default()
         }
     }
 
     #[tokio::test]
     async fn file_get_existing_returns_200_and_body() {
-        let dir = make_temp_dir();
+        let dir = temp_dir("handlers");
         let file_path = dir.join("a.txt");
         fs::write(&file_path, b"abc").unwrap();
 
         let request = crate::http::HttpRequest {
             method: HttpMethod::Get,
-            path: "/files/a.txt".to_string(),
-            headers: HashMap::new(),
+            uri: Uri::parse("/files/a.txt").unwrap(),
+            headers: Headers::new(),
             body: vec![],
+            ..Default::default()
         };
 
         let resp = handle_file_request("/files/a.txt", &request, dir.to_str().unwrap()).await;
 
         let (mut server, client) = connected_pair().await;
         let req = req_for_send();
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_to_end(client).await;
@@ -124,20 +868,23 @@ mod tests {
 
     #[tokio::test]
     async fn file_get_missing_returns_404() {
-        let dir = make_temp_dir();
+        let dir = temp_dir("handlers");
 
         let request = crate::http::HttpRequest {
             method: HttpMethod::Get,
-            path: "/files/missing.txt".to_string(),
-            headers: HashMap::new(),
+            uri: Uri::parse("/files/missing.txt").unwrap(),
+            headers: Headers::new(),
             body: vec![],
+            ..Default::default()
         };
 
         let resp = handle_file_request("/files/missing.txt", &request, dir.to_str().unwrap()).await;
 
         let (mut server, client) = connected_pair().await;
         let req = req_for_send();
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_to_end(client).await;
@@ -151,13 +898,14 @@ mod tests {
 
     #[tokio::test]
     async fn file_post_creates_file_and_returns_201() {
-        let dir = make_temp_dir();
+        let dir = temp_dir("handlers");
 
         let request = crate::http::HttpRequest {
             method: HttpMethod::Post,
-            path: "/files/new.txt".to_string(),
-            headers: HashMap::new(),
+            uri: Uri::parse("/files/new.txt").unwrap(),
+            headers: Headers::new(),
             body: b"hello".to_vec(),
+            ..Default::default()
         };
 
         let resp = handle_file_request("/files/new.txt", &request, dir.to_str().unwrap()).await;
@@ -169,7 +917,9 @@ mod tests {
         // verify status 201
         let (mut server, client) = connected_pair().await;
         let req = req_for_send();
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_to_end(client).await;
@@ -180,4 +930,878 @@ mod tests {
 
         let _ = fs::remove_dir_all(&dir);
     }
+
+    #[tokio::test]
+    async fn file_put_replaces_existing_file_and_returns_204() {
+        let dir = temp_dir("handlers");
+        fs::write(dir.join("old.txt"), b"old").unwrap();
+
+        let request = crate::http::HttpRequest {
+            method: HttpMethod::Put,
+            uri: Uri::parse("/files/old.txt").unwrap(),
+            body: b"new".to_vec(),
+            ..Default::default()
+        };
+
+        let resp = handle_file_request("/files/old.txt", &request, dir.to_str().unwrap()).await;
+
+        assert_eq!(resp.status_code(), crate::http::StatusCode::NO_CONTENT);
+        assert_eq!(fs::read(dir.join("old.txt")).unwrap(), b"new");
+        // Only the destination is left behind, no temp files
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn directories_list_and_accept_form_uploads() {
+        let dir = temp_dir("handlers");
+        fs::create_dir(dir.join("docs")).unwrap();
+        let root = dir.to_str().unwrap();
+        let body = b"--b0undary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\tmp\\\\a.txt\"\r\n\r\n\
+            alpha\r\n\
+            --b0undary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"b.txt\"\r\n\r\n\
+            beta\r\n\
+            --b0undary--\r\n";
+        let upload = HttpRequest {
+            method: HttpMethod::Post,
+            headers: Headers::from([(
+                "content-type".to_string(),
+                "multipart/form-data; boundary=b0undary".to_string(),
+            )]),
+            body: body.to_vec(),
+            ..Default::default()
+        };
+        let resp = handle_file_request("/files/docs/", &upload, root).await;
+        assert_eq!(resp.status_code(), StatusCode::CREATED);
+        assert!(format!("{resp:#}").contains(r#"[\"/files/docs/a.txt\",\"/files/docs/b.txt\"]"#));
+        assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"alpha");
+        assert_eq!(fs::read(dir.join("docs/b.txt")).unwrap(), b"beta");
+
+        let plain = HttpRequest {
+            method: HttpMethod::Post,
+            body: b"raw".to_vec(),
+            ..Default::default()
+        };
+        let resp = handle_file_request("/files/docs/", &plain, root).await;
+        assert_eq!(resp.status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        fs::write(dir.join(".hidden"), b"").unwrap();
+        // Listings are only there for the file manager, or with autoindex
+        let hidden = handle_file_request("/files/", &HttpRequest::default(), root).await;
+        assert_eq!(hidden.status_code(), StatusCode::NOT_FOUND);
+        let manager = Config {
+            file_manager: Some("/manager".to_string()),
+            ..Default::default()
+        };
+        let page = with_config("/files/", &HttpRequest::default(), root, manager.clone()).await;
+        assert_eq!(page.status_code(), StatusCode::NOT_FOUND);
+        let script = HttpRequest {
+            headers: Headers::from([("accept", "application/json")]),
+            ..Default::default()
+        };
+        let listing = with_config("/files/", &script, root, manager).await;
+        assert_eq!(
+            listing.header(header::CONTENT_TYPE),
+            Some("application/json")
+        );
+        let listing = format!("{listing:#}");
+        assert!(
+            listing.contains(r#"[{\"name\":\"docs\",\"size\":null,\"mtime\":"#),
+            "{listing}"
+        );
+        assert!(!listing.contains("hidden"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn browsers_get_an_index_page_when_enabled() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("docs/sub")).unwrap();
+        fs::write(dir.join("docs/a <b>.txt"), b"12345").unwrap();
+        let root = dir.to_str().unwrap();
+        let browser = HttpRequest {
+            headers: Headers::from([(
+                "accept".to_string(),
+                "text/html,application/xhtml+xml,*/*;q=0.8".to_string(),
+            )]),
+            ..Default::default()
+        };
+
+        let page = with_autoindex("/files/docs", &browser, root, true).await;
+        assert_eq!(page.status_code(), StatusCode::OK);
+        assert_eq!(
+            page.header(header::CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(page.header(header::VARY), Some("Accept"));
+        let Body::Bytes(body) = page.body() else {
+            panic!("not an in-memory body");
+        };
+        let page = String::from_utf8_lossy(body);
+        assert!(
+            page.contains("<title>Index of /files/docs/</title>"),
+            "{page}"
+        );
+        assert!(page.contains(r#"<a href="/files/">../</a>"#), "{page}");
+        assert!(
+            page.contains(r#"<a href="/files/docs/sub/">sub/</a>"#),
+            "{page}"
+        );
+        assert!(
+            page.contains(
+                r#"<a href="/files/docs/a%20%3Cb%3E.txt">a &lt;b&gt;.txt</a></td><td>5</td>"#
+            ),
+            "{page}"
+        );
+        let top = with_autoindex("/files/", &browser, root, true).await;
+        let Body::Bytes(body) = top.body() else {
+            panic!("not an in-memory body");
+        };
+        assert!(!String::from_utf8_lossy(body).contains("../"));
+
+        // JSON only for those asking for it; with autoindex off there's no listing at all
+        let any = HttpRequest {
+            headers: Headers::from([("accept".to_string(), "*/*".to_string())]),
+            ..Default::default()
+        };
+        let listing = with_autoindex("/files/docs", &any, root, true).await;
+        assert_eq!(
+            listing.header(header::CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        let api = HttpRequest {
+            headers: Headers::from([("accept".to_string(), "application/json".to_string())]),
+            ..Default::default()
+        };
+        let listing = with_autoindex("/files/docs", &api, root, true).await;
+        assert_eq!(
+            listing.header(header::CONTENT_TYPE),
+            Some("application/json")
+        );
+        let listing = with_autoindex("/files/docs", &browser, root, false).await;
+        assert_eq!(listing.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn directories_with_an_index_file_serve_it() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("site")).unwrap();
+        fs::create_dir_all(dir.join("plain")).unwrap();
+        fs::write(dir.join("site/index.htm"), b"<p>old</p>").unwrap();
+        fs::write(dir.join("plain/notes.txt"), b"").unwrap();
+        let root = dir.to_str().unwrap();
+        let config = || Config {
+            index_files: vec!["index.html".to_string(), "index.htm".to_string()],
+            autoindex: true,
+            ..Default::default()
+        };
+        let body = |response: &HttpResponse| match response.body() {
+            Body::File { len, .. } => format!("file of {len} bytes"),
+            Body::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            _ => panic!("unexpected body"),
+        };
+
+        let page = with_config("/files/site/", &HttpRequest::default(), root, config()).await;
+        assert_eq!(page.status_code(), StatusCode::OK);
+        assert_eq!(body(&page), "file of 10 bytes");
+        fs::write(dir.join("site/index.html"), b"<p>new</p>!").unwrap();
+        let page = with_config("/files/site/", &HttpRequest::default(), root, config()).await;
+        assert_eq!(body(&page), "file of 11 bytes");
+
+        let script = HttpRequest {
+            uri: Uri::parse("/files/site/?format=json").unwrap(),
+            ..Default::default()
+        };
+        let listing = with_config("/files/site/", &script, root, config()).await;
+        assert!(body(&listing).contains(r#""name":"index.html""#));
+        let listing = with_config("/files/plain/", &script, root, config()).await;
+        assert!(body(&listing).contains(r#""name":"notes.txt""#));
+        // Index files are off by default, and so are listings
+        let listing = handle_file_request("/files/site/", &HttpRequest::default(), root).await;
+        assert_eq!(listing.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn spa_routes_fall_back_to_the_shell() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("static")).unwrap();
+        fs::write(dir.join("index.html"), b"<div id=app>").unwrap();
+        fs::write(dir.join("static/app.js"), b"run()").unwrap();
+        let root = dir.to_str().unwrap();
+        let spa = || Config {
+            spa: true,
+            ..Default::default()
+        };
+        let get = HttpRequest::default();
+        let len = |response: &HttpResponse| match response.body() {
+            Body::File { len, .. } => *len,
+            _ => panic!("not a file body"),
+        };
+
+        let shell = with_config("/files/users/42", &get, root, spa()).await;
+        assert_eq!(shell.status_code(), StatusCode::OK);
+        assert_eq!(len(&shell), 12);
+        let asset = with_config("/files/static/app.js", &get, root, spa()).await;
+        assert_eq!(len(&asset), 5);
+        let missing = with_config("/files/static/gone.js", &get, root, spa()).await;
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+        let off = handle_file_request("/files/users/42", &get, root).await;
+        assert_eq!(off.status_code(), StatusCode::NOT_FOUND);
+        // Only reads fall back
+        let delete = HttpRequest {
+            method: HttpMethod::Delete,
+            ..Default::default()
+        };
+        let missing = with_config("/files/users/42", &delete, root, spa()).await;
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn scripts_can_ask_for_the_json_listing() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), b"12345").unwrap();
+        let root = dir.to_str().unwrap();
+        let browser = |uri: &str, accept: &str| HttpRequest {
+            uri: Uri::parse(uri).unwrap(),
+            headers: Headers::from([("accept".to_string(), accept.to_string())]),
+            ..Default::default()
+        };
+
+        for request in [
+            browser("/files/?format=json", "text/html"),
+            browser("/files/", "text/html;q=0.9, application/json"),
+        ] {
+            let listing = with_autoindex("/files/", &request, root, true).await;
+            assert_eq!(
+                listing.header(header::CONTENT_TYPE),
+                Some("application/json")
+            );
+            let Body::Bytes(body) = listing.body() else {
+                panic!("not an in-memory body");
+            };
+            let listing = String::from_utf8_lossy(body);
+            let mtime = fs::metadata(dir.join("a.txt"))
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            assert!(
+                listing.starts_with(r#"[{"name":"sub","size":null,"mtime":"#),
+                "{listing}"
+            );
+            assert!(
+                listing.contains(&format!(
+                    r#"{{"name":"a.txt","size":5,"mtime":{mtime},"is_dir":false}}"#
+                )),
+                "{listing}"
+            );
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn small_files_are_served_from_memory_until_written() {
+        let dir = temp_dir("handlers");
+        fs::write(dir.join("a.css"), b"body{}").unwrap();
+        fs::write(dir.join("big.bin"), [0_u8; 100]).unwrap();
+        let root = dir.to_str().unwrap();
+        let cache = Arc::new(FileCache::new(1000, 50));
+        let get = HttpRequest::default();
+        let body = |response: &HttpResponse| match response.body() {
+            Body::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            Body::File { len, .. } => format!("file of {len} bytes"),
+            _ => panic!("unexpected body"),
+        };
+
+        let first = with_cache("/files/a.css", &get, root, &cache).await;
+        assert_eq!(body(&first), "body{}");
+        assert!(first.header(header::ETAG).is_some());
+        // Changed behind the server's back (no watcher here): still the cached copy
+        fs::write(dir.join("a.css"), b"p{}").unwrap();
+        let again = with_cache("/files/a.css", &get, root, &cache).await;
+        assert_eq!(body(&again), "body{}");
+        assert_eq!(again.header(header::ETAG), first.header(header::ETAG));
+        // Ranges and big files come from disk
+        let range = HttpRequest {
+            headers: Headers::from([("range".to_string(), "bytes=0-0".to_string())]),
+            ..Default::default()
+        };
+        let partial = with_cache("/files/a.css", &range, root, &cache).await;
+        assert_eq!(partial.status_code(), StatusCode::PARTIAL_CONTENT);
+        let big = with_cache("/files/big.bin", &get, root, &cache).await;
+        assert_eq!(body(&big), "file of 100 bytes");
+        assert_eq!(cache.len(), 1);
+
+        // Writes through /files/ drop what they touch, MOVE's destination included
+        let put = HttpRequest {
+            method: HttpMethod::Put,
+            body: b"h1{}".to_vec(),
+            ..Default::default()
+        };
+        with_cache("/files/a.css", &put, root, &cache).await;
+        let fresh = with_cache("/files/a.css", &get, root, &cache).await;
+        assert_eq!(body(&fresh), "h1{}");
+        fs::write(dir.join("b.css"), b"em{}").unwrap();
+        with_cache("/files/b.css", &get, root, &cache).await;
+        let rename = HttpRequest {
+            method: HttpMethod::Move,
+            headers: Headers::from([("destination".to_string(), "/files/b.css".to_string())]),
+            ..Default::default()
+        };
+        with_cache("/files/a.css", &rename, root, &cache).await;
+        let moved = with_cache("/files/b.css", &get, root, &cache).await;
+        assert_eq!(body(&moved), "h1{}");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn precompressed_companions_go_to_clients_that_accept_them() {
+        let dir = temp_dir("handlers");
+        fs::write(dir.join("site.css"), b"body { margin: 0 }").unwrap();
+        fs::write(dir.join("site.css.gz"), b"gzipped").unwrap();
+        fs::write(dir.join("site.css.br"), b"brotli").unwrap();
+        fs::write(dir.join("app.js"), b"run()").unwrap();
+        fs::write(dir.join("orphan.js.gz"), b"gzipped").unwrap();
+        let root = dir.to_str().unwrap();
+        let accepting = |codings: &str| HttpRequest {
+            headers: Headers::from([("accept-encoding".to_string(), codings.to_string())]),
+            ..Default::default()
+        };
+        let len = |response: &HttpResponse| match response.body() {
+            Body::File { len, .. } => *len,
+            _ => panic!("not a file body"),
+        };
+
+        let br = handle_file_request("/files/site.css", &accepting("gzip, br"), root).await;
+        assert_eq!(br.header(header::CONTENT_ENCODING), Some("br"));
+        assert_eq!(br.header(header::VARY), Some("Accept-Encoding"));
+        assert_eq!(len(&br), 6);
+        let gzip = handle_file_request("/files/site.css", &accepting("gzip, br;q=0"), root).await;
+        assert_eq!(gzip.header(header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(len(&gzip), 7);
+        assert_ne!(gzip.header(header::ETAG), br.header(header::ETAG));
+
+        let plain = handle_file_request("/files/site.css", &HttpRequest::default(), root).await;
+        assert_eq!(plain.header(header::CONTENT_ENCODING), None);
+        assert_eq!(plain.header(header::VARY), Some("Accept-Encoding"));
+        assert_eq!(len(&plain), 18);
+        // Nothing to choose between, and no file to be a companion of
+        let alone = handle_file_request("/files/app.js", &accepting("gzip"), root).await;
+        assert_eq!(alone.header(header::VARY), None);
+        let orphan = handle_file_request("/files/orphan.js", &accepting("gzip"), root).await;
+        assert_eq!(orphan.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_are_typed_by_extension() {
+        let dir = temp_dir("handlers");
+        fs::write(dir.join("logo.png"), b"png").unwrap();
+        fs::write(dir.join("app.js.map"), b"{}").unwrap();
+        fs::write(dir.join("app.js.map.gz"), b"gzipped").unwrap();
+        fs::write(dir.join("LICENSE"), b"mit").unwrap();
+        let root = dir.to_str().unwrap();
+        let get = HttpRequest::default();
+        let content_type = |response: &HttpResponse| {
+            response
+                .header(header::CONTENT_TYPE)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let png = handle_file_request("/files/logo.png", &get, root).await;
+        assert_eq!(content_type(&png), "image/png");
+        let license = handle_file_request("/files/LICENSE", &get, root).await;
+        assert_eq!(content_type(&license), "application/octet-stream");
+        // A precompressed companion is still the original's type
+        let gzip = HttpRequest {
+            headers: Headers::from([("accept-encoding".to_string(), "gzip".to_string())]),
+            ..Default::default()
+        };
+        let map = handle_file_request("/files/app.js.map", &gzip, root).await;
+        assert_eq!(map.header(header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(content_type(&map), "application/json");
+        // And so is a cached copy
+        let cache = Arc::new(FileCache::new(1000, 1000));
+        for _ in 0..2 {
+            let png = with_cache("/files/logo.png", &get, root, &cache).await;
+            assert_eq!(content_type(&png), "image/png");
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn cache_control_comes_from_the_first_matching_rule() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("assets/js")).unwrap();
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("assets/js/app.js"), b"app").unwrap();
+        fs::write(dir.join("assets/page.html"), b"<p>").unwrap();
+        fs::write(dir.join("docs/index.html"), b"<p>").unwrap();
+        fs::write(dir.join("notes.txt"), b"notes").unwrap();
+        let root = dir.to_str().unwrap();
+        let rule = |pattern: &str, value: &str| CacheControlRule {
+            pattern: pattern.to_string(),
+            value: value.to_string(),
+        };
+        let config = || Config {
+            cache_control: vec![
+                rule("/assets/**", "public, max-age=31536000, immutable"),
+                rule("*.html", "no-cache"),
+            ],
+            index_files: vec!["index.html".to_string()],
+            ..Default::default()
+        };
+        let get = HttpRequest::default();
+
+        for (path, expected) in [
+            (
+                "/files/assets/js/app.js",
+                Some("public, max-age=31536000, immutable"),
+            ),
+            (
+                "/files/assets/page.html",
+                Some("public, max-age=31536000, immutable"),
+            ),
+            ("/files/docs/index.html", Some("no-cache")),
+            // The index page served for the directory
+            ("/files/docs/", Some("no-cache")),
+            ("/files/notes.txt", None),
+        ] {
+            let response = with_config(path, &get, root, config()).await;
+            assert_eq!(response.status_code(), StatusCode::OK, "{path}");
+            assert_eq!(response.header(header::CACHE_CONTROL), expected, "{path}");
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn dotfiles_are_not_found_unless_allowed() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git/config"), b"[core]").unwrap();
+        fs::write(dir.join(".env"), b"SECRET=1").unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        let root = dir.to_str().unwrap();
+        let request = |method, destination: Option<&str>| HttpRequest {
+            method,
+            headers: destination
+                .map(|d| Headers::from([("destination".to_string(), d.to_string())]))
+                .unwrap_or_default(),
+            body: b"x".to_vec(),
+            ..Default::default()
+        };
+
+        for (method, path) in [
+            (HttpMethod::Get, "/files/.env"),
+            (HttpMethod::Head, "/files/.git/config"),
+            (HttpMethod::Get, "/files/.git/"),
+            (HttpMethod::Put, "/files/.env"),
+            (HttpMethod::Delete, "/files/.env"),
+        ] {
+            let response = handle_file_request(path, &request(method, None), root).await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND, "{path}");
+        }
+        let hide = request(HttpMethod::Move, Some("/files/.hidden"));
+        let response = handle_file_request("/files/a.txt", &hide, root).await;
+        assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(fs::read(dir.join(".env")).unwrap(), b"SECRET=1");
+        assert!(dir.join("a.txt").exists());
+
+        let allowed = || Config {
+            allow_dotfiles: true,
+            ..Default::default()
+        };
+        let env = with_config(
+            "/files/.env",
+            &request(HttpMethod::Get, None),
+            root,
+            allowed(),
+        )
+        .await;
+        assert_eq!(env.status_code(), StatusCode::OK);
+        let moved = with_config("/files/a.txt", &hide, root, allowed()).await;
+        assert_eq!(moved.status_code(), StatusCode::CREATED);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn move_renames_within_the_files_directory() {
+        let dir = temp_dir("handlers");
+        let root = dir.to_str().unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("b.txt"), b"b").unwrap();
+        let move_to = |destination: &str, overwrite: Option<&str>| {
+            let mut headers = Headers::from([("destination".to_string(), destination.to_string())]);
+            if let Some(overwrite) = overwrite {
+                headers.insert("overwrite".to_string(), overwrite.to_string());
+            }
+            HttpRequest {
+                method: HttpMethod::Move,
+                headers,
+                ..Default::default()
+            }
+        };
+
+        let refused = move_to("/files/b.txt", Some("F"));
+        let resp = handle_file_request("/files/a.txt", &refused, root).await;
+        assert_eq!(resp.status_code(), StatusCode::PRECONDITION_FAILED);
+
+        let escape = move_to("/files/../../etc/x", None);
+        let resp = handle_file_request("/files/a.txt", &escape, root).await;
+        assert_eq!(resp.status_code(), StatusCode::BAD_REQUEST);
+
+        let renamed = move_to("http://localhost:4221/files/c%20d.txt", None);
+        let resp = handle_file_request("/files/a.txt", &renamed, root).await;
+        assert_eq!(resp.status_code(), StatusCode::CREATED);
+        assert_eq!(fs::read(dir.join("c d.txt")).unwrap(), b"a");
+        assert!(!dir.join("a.txt").exists());
+
+        let resp = handle_file_request("/files/a.txt", &renamed, root).await;
+        assert_eq!(resp.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn delete_refuses_files_behind_symlinks_out_of_the_directory() {
+        let dir = temp_dir("handlers");
+        let outside = temp_dir("handlers").join("elsewhere");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("keep.txt"), b"keep").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        let delete = HttpRequest {
+            method: HttpMethod::Delete,
+            ..Default::default()
+        };
+
+        let resp =
+            handle_file_request("/files/link/keep.txt", &delete, dir.to_str().unwrap()).await;
+        assert_eq!(resp.status_code(), StatusCode::FORBIDDEN);
+        assert!(outside.join("keep.txt").exists());
+
+        let resp = handle_file_request("/files/gone/x.txt", &delete, dir.to_str().unwrap()).await;
+        assert_eq!(resp.status_code(), StatusCode::NOT_FOUND);
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(outside.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_symlink_policy_decides_which_links_are_followed() {
+        let dir = temp_dir("handlers");
+        let outside = temp_dir("handlers").join("elsewhere");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        fs::write(dir.join("real.txt"), b"real").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real.txt"), dir.join("sibling.txt")).unwrap();
+        let root = dir.to_str().unwrap();
+        let get = HttpRequest::default();
+        let status = async |path, symlinks| {
+            let config = Config {
+                symlinks,
+                ..Default::default()
+            };
+            with_config(path, &get, root, config).await.status_code()
+        };
+
+        for (symlinks, escaping, sibling) in [
+            (Symlinks::Follow, StatusCode::OK, StatusCode::OK),
+            (Symlinks::Inside, StatusCode::FORBIDDEN, StatusCode::OK),
+            (
+                Symlinks::Never,
+                StatusCode::FORBIDDEN,
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            assert_eq!(status("/files/out/secret.txt", symlinks).await, escaping);
+            assert_eq!(status("/files/sibling.txt", symlinks).await, sibling);
+            assert_eq!(status("/files/real.txt", symlinks).await, StatusCode::OK);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(outside.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn files_served_in_place_of_the_url_follow_the_symlink_policy() {
+        let dir = temp_dir("handlers");
+        let outside = temp_dir("handlers").join("elsewhere");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("d/index.html")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("index.html")).unwrap();
+        fs::write(dir.join("site.css"), b"body { color: red }").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("site.css.gz")).unwrap();
+        let root = dir.to_str().unwrap();
+        let config = || Config {
+            index_files: vec!["index.html".to_string()],
+            spa: true,
+            ..Default::default()
+        };
+
+        // An index page
+        let index = with_config("/files/d/", &HttpRequest::default(), root, config()).await;
+        assert_eq!(index.status_code(), StatusCode::FORBIDDEN);
+        // The single-page app's shell
+        let shell = with_config("/files/route", &HttpRequest::default(), root, config()).await;
+        assert_eq!(shell.status_code(), StatusCode::FORBIDDEN);
+        // A precompressed companion: skipped, so the file itself goes out
+        let gzip = HttpRequest {
+            headers: Headers::from([("accept-encoding", "gzip")]),
+            ..Default::default()
+        };
+        let css = with_config("/files/site.css", &gzip, root, config()).await;
+        assert_eq!(css.status_code(), StatusCode::OK);
+        assert_eq!(css.header(header::CONTENT_ENCODING), None);
+        assert!(matches!(css.body(), Body::File { len: 19, .. }));
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(outside.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_directory_are_refused_for_every_method() {
+        let dir = temp_dir("handlers");
+        let outside = temp_dir("handlers").join("elsewhere");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), dir.join("alias.txt")).unwrap();
+        fs::write(dir.join("mine.txt"), b"mine").unwrap();
+        let root = dir.to_str().unwrap();
+
+        for (method, path) in [
+            (HttpMethod::Get, "/files/alias.txt"),
+            (HttpMethod::Get, "/files/link/secret.txt"),
+            (HttpMethod::Get, "/files/link/"),
+            (HttpMethod::Put, "/files/link/new.txt"),
+            (HttpMethod::Put, "/files/alias.txt"),
+        ] {
+            let request = HttpRequest {
+                method,
+                body: b"overwritten".to_vec(),
+                ..Default::default()
+            };
+            let resp = handle_file_request(path, &request, root).await;
+            assert_eq!(resp.status_code(), StatusCode::FORBIDDEN, "{method} {path}");
+        }
+        let mut moved = HttpRequest {
+            method: HttpMethod::Move,
+            ..Default::default()
+        };
+        moved
+            .headers
+            .insert(header::DESTINATION, "/files/link/mine.txt");
+        let resp = handle_file_request("/files/mine.txt", &moved, root).await;
+        assert_eq!(resp.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(fs::read(outside.join("secret.txt")).unwrap(), b"secret");
+        assert!(!outside.join("new.txt").exists() && !outside.join("mine.txt").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(outside.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn dot_segments_cannot_climb_out_however_they_are_spelled() {
+        let dir = temp_dir("handlers");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let root = dir.join("sub");
+        fs::write(dir.join("secret.txt"), b"secret").unwrap();
+        let get = HttpRequest::default();
+
+        // Dot segments normalize to a path outside /files/, which no route matches
+        for target in [
+            "/files/../secret.txt",
+            "/files/%2e%2e/secret.txt",
+            "/files/%2E%2E/%2e%2e/secret.txt",
+            "/files/.%2e/secret.txt",
+            "/files/x/../../secret.txt",
+        ] {
+            let uri = Uri::parse(target).unwrap();
+            assert_eq!(uri.path(), "/secret.txt", "{target}");
+            let resp = handle_file_request(uri.path(), &get, root.to_str().unwrap()).await;
+            assert_eq!(resp.status_code(), StatusCode::NOT_FOUND, "{target}");
+        }
+        // Encoded slashes and backslashes are refused before routing, with a 400
+        for target in [
+            "/files/..%2fsecret.txt",
+            "/files/..%5csecret.txt",
+            "/files/..\\secret.txt",
+        ] {
+            assert!(Uri::parse(target).is_err(), "{target}");
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_partially() {
+        let dir = temp_dir("handlers");
+        let root = dir.to_str().unwrap();
+        fs::write(dir.join("movie.bin"), b"0123456789").unwrap();
+        let get = |headers: &[(&str, &str)]| HttpRequest {
+            method: HttpMethod::Get,
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let sent = async |response: HttpResponse, request: &HttpRequest| {
+            let mut wire = Vec::new();
+            response
+                .send(&mut wire, request, &ResponseDefaults::default())
+                .await
+                .unwrap();
+            String::from_utf8(wire).unwrap()
+        };
+
+        let whole = handle_file_request("/files/movie.bin", &get(&[]), root).await;
+        assert_eq!(whole.header(header::ACCEPT_RANGES), Some("bytes"));
+        let etag = whole.header(header::ETAG).unwrap().to_string();
+
+        let request = get(&[("range", "bytes=2-4")]);
+        let part = handle_file_request("/files/movie.bin", &request, root).await;
+        assert_eq!(part.status_code(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(part.header(header::CONTENT_RANGE), Some("bytes 2-4/10"));
+        assert!(sent(part, &request).await.ends_with("\r\n\r\n234"));
+
+        let request = get(&[("range", "bytes=-3"), ("if-range", &etag)]);
+        let tail = handle_file_request("/files/movie.bin", &request, root).await;
+        assert!(sent(tail, &request).await.ends_with("\r\n\r\n789"));
+
+        // Another version, or several ranges: the whole file
+        let stale = get(&[("range", "bytes=0-1"), ("if-range", "\"old\"")]);
+        let resp = handle_file_request("/files/movie.bin", &stale, root).await;
+        assert_eq!(resp.status_code(), StatusCode::OK);
+        let several = get(&[("range", "bytes=0-1,4-5")]);
+        let resp = handle_file_request("/files/movie.bin", &several, root).await;
+        assert_eq!(resp.status_code(), StatusCode::OK);
+
+        let past = get(&[("range", "bytes=10-")]);
+        let resp = handle_file_request("/files/movie.bin", &past, root).await;
+        assert_eq!(resp.status_code(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.header(header::CONTENT_RANGE), Some("bytes */10"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_refuse_stale_versions() {
+        let dir = temp_dir("handlers");
+        let root = dir.to_str().unwrap();
+        fs::write(dir.join("doc.txt"), b"v1").unwrap();
+        let request = |method, headers: &[(&str, &str)], body: &[u8]| crate::http::HttpRequest {
+            method,
+            uri: Uri::parse("/files/doc.txt").unwrap(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: body.to_vec(),
+            ..Default::default()
+        };
+
+        let get =
+            handle_file_request("/files/doc.txt", &request(HttpMethod::Get, &[], b""), root).await;
+        let v1 = get.header(header::ETAG).unwrap().to_string();
+        let modified = get.header(header::LAST_MODIFIED).unwrap().to_string();
+        assert!(HttpDate::parse(&modified).is_some());
+
+        // The first editor saves against the version they read...
+        let put = request(HttpMethod::Put, &[("if-match", &v1)], b"v2");
+        let resp = handle_file_request("/files/doc.txt", &put, root).await;
+        assert_eq!(resp.status_code(), StatusCode::NO_CONTENT);
+        assert_ne!(resp.header(header::ETAG), Some(v1.as_str()));
+
+        // ...so the second, holding the same ETag, is turned away
+        let put = request(HttpMethod::Put, &[("if-match", &v1)], b"v2-other");
+        let resp = handle_file_request("/files/doc.txt", &put, root).await;
+        assert_eq!(resp.status_code(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(fs::read(dir.join("doc.txt")).unwrap(), b"v2");
+
+        let delete = request(
+            HttpMethod::Delete,
+            &[("if-unmodified-since", "Thu, 01 Jan 1970 00:00:00 GMT")],
+            b"",
+        );
+        let resp = handle_file_request("/files/doc.txt", &delete, root).await;
+        assert_eq!(resp.status_code(), StatusCode::PRECONDITION_FAILED);
+
+        // If-Match wins over If-Unmodified-Since, and `*` matches any existing file
+        let delete = request(
+            HttpMethod::Delete,
+            &[
+                ("if-match", "*"),
+                ("if-unmodified-since", "Thu, 01 Jan 1970 00:00:00 GMT"),
+            ],
+            b"",
+        );
+        let resp = handle_file_request("/files/doc.txt", &delete, root).await;
+        assert_eq!(resp.status_code(), StatusCode::NO_CONTENT);
+        assert!(!dir.join("doc.txt").exists());
+
+        // Nothing left to match
+        let put = request(HttpMethod::Put, &[("if-match", "*")], b"v3");
+        let resp = handle_file_request("/files/doc.txt", &put, root).await;
+        assert_eq!(resp.status_code(), StatusCode::PRECONDITION_FAILED);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn gzip_uploads_are_decoded_and_bombs_refused() {
+        let dir = temp_dir("handlers");
+        let gzip_post = |target: &str, body: Vec<u8>| crate::http::HttpRequest {
+            method: HttpMethod::Post,
+            uri: Uri::parse(target).unwrap(),
+            headers: Headers::from([("content-encoding".to_string(), "gzip".to_string())]),
+            body,
+            decompression: Some(crate::http::decode::DecompressionLimits::default()),
+            ..Default::default()
+        };
+
+        let request = gzip_post("/files/a.txt", crate::utils::compress_body(b"unzipped"));
+        let resp = handle_file_request("/files/a.txt", &request, dir.to_str().unwrap()).await;
+        assert_eq!(resp.status_code(), crate::http::StatusCode::CREATED);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"unzipped");
+
+        let bomb = crate::utils::compress_body(&vec![0; 16 * 1024 * 1024]);
+        let request = gzip_post("/files/bomb.bin", bomb);
+        let resp = handle_file_request("/files/bomb.bin", &request, dir.to_str().unwrap()).await;
+        assert_eq!(
+            resp.status_code(),
+            crate::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Neither the destination nor the temp file survives
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
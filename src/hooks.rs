@@ -0,0 +1,29 @@
+use crate::http::{ConnectionInfo, HttpRequest, HttpResponse};
+use std::time::Duration;
+
+// Summary handed to on_disconnect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub requests: usize,
+    pub duration: Duration,
+}
+
+// Server-level callbacks for custom metrics, connection tagging or bespoke security
+// checks. Every method has a no-op default, so implementors only override what they need.
+pub trait Hooks: Send + Sync {
+    // Called right after accept; returning false drops the connection unanswered
+    fn on_connect(&self, _info: &ConnectionInfo) -> bool {
+        true
+    }
+
+    // Called once the request is parsed; returning a response skips routing entirely
+    fn on_request(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    // Called just before the response is written
+    fn on_response(&self, _request: &HttpRequest, _response: &mut HttpResponse) {}
+
+    // Called when the connection closes, whatever the reason
+    fn on_disconnect(&self, _info: &ConnectionInfo, _stats: &ConnectionStats) {}
+}
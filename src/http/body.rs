@@ -0,0 +1,365 @@
+use crate::http::Headers;
+use crate::http::header;
+use crate::utils;
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // A region of an open file, so ranges can be served without reading the whole file
+    File { file: File, offset: u64, len: u64 },
+    // Length unknown up front, sent with chunked encoding
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+impl Body {
+    pub fn stream(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Body::Stream(Box::new(reader))
+    }
+
+    // Size for the Content-Length header, None when the body has to be chunked
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+
+    // A stream may still turn out empty, but it isn't known to be
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // Returns the number of payload bytes written (excluding chunk framing). Trailers
+    // only go out after a chunked stream; other bodies have nowhere to put them.
+    pub(crate) async fn write_to<W>(
+        self,
+        stream: &mut W,
+        trailers: Option<&Trailers>,
+    ) -> tokio::io::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Body::Empty => Ok(0),
+            Body::Bytes(bytes) => {
+                stream.write_all(&bytes).await?;
+                Ok(bytes.len() as u64)
+            }
+            Body::File { file, offset, len } => write_file_range(stream, file, offset, len).await,
+            Body::Stream(reader) => write_chunked(stream, reader, trailers).await,
+        }
+    }
+}
+
+impl Body {
+    // For streams whose length was declared up front: exactly `len` bytes must come
+    // out, since that is what the Content-Length header promised
+    pub(crate) async fn write_sized_to<W>(self, stream: &mut W, len: u64) -> tokio::io::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let Body::Stream(mut reader) = self else {
+            return self.write_to(stream, None).await;
+        };
+        let copied = tokio::io::copy(&mut (&mut reader).take(len), stream).await?;
+        if copied < len {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::UnexpectedEof,
+                "stream ended before the declared Content-Length",
+            ));
+        }
+        // The client got a well-framed response, but the handler had more to say
+        if reader.read(&mut [0_u8; 1]).await? > 0 {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidData,
+                "stream is longer than the declared Content-Length",
+            ));
+        }
+        Ok(copied)
+    }
+}
+
+// Through a userspace buffer; see sendfile for the zero-copy path
+pub(crate) async fn write_file_range<W>(
+    stream: &mut W,
+    mut file: File,
+    offset: u64,
+    len: u64,
+) -> tokio::io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    file.seek(SeekFrom::Start(offset)).await?;
+    let copied = tokio::io::copy(&mut file.take(len), stream).await?;
+    if copied < len {
+        // The file shrank underneath us; the Content-Length we promised can't be honoured
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::UnexpectedEof,
+            "file ended before the advertised length",
+        ));
+    }
+    Ok(copied)
+}
+
+async fn write_chunked<W>(
+    stream: &mut W,
+    mut reader: Box<dyn AsyncRead + Send + Unpin>,
+    trailers: Option<&Trailers>,
+) -> tokio::io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0_u8; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        stream.write_all(format!("{n:x}\r\n").as_bytes()).await?;
+        stream.write_all(&buf[..n]).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    // The stream has ended, so whatever it meant to add to the trailers is there now
+    let mut last = "0\r\n".to_string();
+    for (name, value) in trailers.map(Trailers::fields).unwrap_or_default().iter() {
+        last.push_str(&format!("{}: {value}\r\n", header::canonical_name(name)));
+    }
+    last.push_str("\r\n");
+    stream.write_all(last.as_bytes()).await?;
+    Ok(total)
+}
+
+// Fields sent after the last chunk of a streamed body, for values only known once all
+// of it has been produced (a checksum, say). Clones share the same fields, so the
+// stream can hold one and add to it as it finishes.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(Arc<Mutex<TrailerFields>>);
+
+#[derive(Debug, Default)]
+struct TrailerFields {
+    declared: Vec<String>,
+    fields: Headers,
+}
+
+// Fields a recipient must not take from a trailer: framing, routing, request
+// modifiers, authentication and anything needed before the body is read
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    header::AGE,
+    header::AUTHORIZATION,
+    header::CACHE_CONTROL,
+    header::CONNECTION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::COOKIE,
+    header::DATE,
+    header::EXPECT,
+    header::EXPIRES,
+    header::HOST,
+    header::IF_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_NONE_MATCH,
+    header::IF_RANGE,
+    header::IF_UNMODIFIED_SINCE,
+    header::KEEP_ALIVE,
+    header::LOCATION,
+    header::PRAGMA,
+    header::RANGE,
+    header::RETRY_AFTER,
+    header::SET_COOKIE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::VARY,
+    header::WWW_AUTHENTICATE,
+    "max-forwards",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+];
+
+// Whether `name` may be sent as a trailer field at all
+fn allowed_in_trailer(name: &str) -> bool {
+    !FORBIDDEN_TRAILERS
+        .iter()
+        .any(|forbidden| forbidden.eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TrailerError {
+    #[error("{0:?} is not allowed in a trailer")]
+    Forbidden(String),
+    #[error("trailer {0:?} was not declared in the Trailer header")]
+    Undeclared(String),
+}
+
+impl Trailers {
+    // Only fields declared up front (see `HttpResponse::trailers`) may be added
+    pub fn add_trailer(&self, name: &str, value: impl Into<String>) -> Result<(), TrailerError> {
+        if !allowed_in_trailer(name) {
+            return Err(TrailerError::Forbidden(name.to_string()));
+        }
+        let mut trailers = self.0.lock().unwrap();
+        if !trailers
+            .declared
+            .iter()
+            .any(|declared| declared.eq_ignore_ascii_case(name))
+        {
+            return Err(TrailerError::Undeclared(name.to_string()));
+        }
+        trailers.fields.append(name, value);
+        Ok(())
+    }
+
+    // Records the fields to expect, leaving out forbidden ones; returns those kept
+    pub(crate) fn declare(&self, names: &[&str]) -> Vec<String> {
+        let declared: Vec<String> = names
+            .iter()
+            .filter(|name| allowed_in_trailer(name))
+            .map(|name| name.to_string())
+            .collect();
+        self.0.lock().unwrap().declared = declared.clone();
+        declared
+    }
+
+    fn fields(&self) -> Headers {
+        self.0.lock().unwrap().fields.clone()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() {
+            Body::Empty
+        } else {
+            Body::Bytes(bytes)
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::File { offset, len, .. } => f
+                .debug_struct("File")
+                .field("offset", offset)
+                .field("len", len)
+                .finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => utils::fmt_body(f, &[]),
+            Body::Bytes(bytes) => utils::fmt_body(f, bytes),
+            Body::File { offset, len, .. } => {
+                write!(f, "[{len} bytes from file at offset {offset}]")
+            }
+            Body::Stream(_) => f.write_str("[streamed body]"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_vec_becomes_empty_body() {
+        assert!(matches!(Body::from(vec![]), Body::Empty));
+        assert!(matches!(Body::from(b"x".to_vec()), Body::Bytes(_)));
+    }
+
+    #[test]
+    fn stream_has_no_length() {
+        let body = Body::stream(&b"abc"[..]);
+        assert_eq!(body.len(), None);
+    }
+
+    #[tokio::test]
+    async fn stream_is_written_chunked() {
+        let mut out = Vec::new();
+        Body::stream(&b"hello"[..])
+            .write_to(&mut out, None)
+            .await
+            .unwrap();
+        assert_eq!(out, b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn trailers_follow_the_last_chunk() {
+        let trailers = Trailers::default();
+        trailers.declare(&["x-checksum"]);
+        // Added while the body is being produced, as a checksum would be
+        let reader = ChecksumOnEof {
+            data: &b"hello"[..],
+            trailers: trailers.clone(),
+        };
+        let mut out = Vec::new();
+        Body::stream(reader)
+            .write_to(&mut out, Some(&trailers))
+            .await
+            .unwrap();
+        assert_eq!(out, b"5\r\nhello\r\n0\r\nX-Checksum: 5\r\n\r\n");
+    }
+
+    struct ChecksumOnEof {
+        data: &'static [u8],
+        trailers: Trailers,
+    }
+
+    impl AsyncRead for ChecksumOnEof {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.data.is_empty() {
+                self.trailers.add_trailer("x-checksum", "5").unwrap();
+            }
+            let n = self.data.len().min(buf.remaining());
+            buf.put_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn file_writes_only_the_requested_range() {
+        let dir = crate::utils::temp_dir("body");
+        let path = dir.join("range.bin");
+        tokio::fs::write(&path, b"0123456789").await.unwrap();
+
+        let file = File::open(&path).await.unwrap();
+        let mut out = Vec::new();
+        Body::File {
+            file,
+            offset: 2,
+            len: 3,
+        }
+        .write_to(&mut out, None)
+        .await
+        .unwrap();
+        assert_eq!(out, b"234");
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
@@ -0,0 +1,110 @@
+use crate::http::date::HttpDate;
+use crate::http::header;
+use crate::http::request::HttpMethod;
+use crate::http::{HttpRequest, HttpResponse, StatusCode};
+
+// Conditional GET (RFC 9110 section 13): a client that sent the validators of the
+// copy it holds gets a 304 instead of the body when that copy is still current
+pub fn evaluate(request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    if matches!(request.method, HttpMethod::Get | HttpMethod::Head)
+        && matches!(
+            response.status_code(),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT
+        )
+        && is_current(request, &response)
+    {
+        response.not_modified()
+    } else {
+        response
+    }
+}
+
+// If-None-Match takes precedence; If-Modified-Since is only looked at without it
+fn is_current(request: &HttpRequest, response: &HttpResponse) -> bool {
+    if let Some(if_none_match) = request.headers.get(header::IF_NONE_MATCH) {
+        return response
+            .header(header::ETAG)
+            .is_some_and(|etag| matches_any(if_none_match, etag));
+    }
+    let since = request
+        .headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(HttpDate::parse);
+    let modified = response
+        .header(header::LAST_MODIFIED)
+        .and_then(HttpDate::parse);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// Weak comparison: `W/"x"` and `"x"` are the same representation for a GET
+fn matches_any(list: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    list.split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Headers;
+
+    fn get(headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Headers>(),
+            ..Default::default()
+        }
+    }
+
+    fn page() -> HttpResponse {
+        let mut response = HttpResponse::new(StatusCode::OK, "text/html", b"<p>hi</p>".to_vec());
+        response.set_header(header::ETAG, "\"v1\"");
+        response.set_header(header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT");
+        response.set_header(header::CACHE_CONTROL, "max-age=60");
+        response
+    }
+
+    fn status(request: &HttpRequest) -> StatusCode {
+        evaluate(request, page()).status_code()
+    }
+
+    #[test]
+    fn etags_compare_weakly_and_win_over_dates() {
+        let not_modified = StatusCode::NOT_MODIFIED;
+        assert_eq!(
+            status(&get(&[("if-none-match", "\"v0\", W/\"v1\"")])),
+            not_modified
+        );
+        assert_eq!(status(&get(&[("if-none-match", "*")])), not_modified);
+        assert_eq!(
+            status(&get(&[
+                ("if-none-match", "\"v0\""),
+                ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ])),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn dates_compare_by_second() {
+        let since = |date| status(&get(&[("if-modified-since", date)]));
+        assert_eq!(
+            since("Sun, 06 Nov 1994 08:49:37 GMT"),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(since("Sun, 06 Nov 1994 08:49:36 GMT"), StatusCode::OK);
+        assert_eq!(since("not a date"), StatusCode::OK);
+    }
+
+    #[test]
+    fn the_304_keeps_validators_but_not_the_body() {
+        let response = evaluate(&get(&[("if-none-match", "\"v1\"")]), page());
+        assert_eq!(response.header(header::ETAG), Some("\"v1\""));
+        assert_eq!(response.header(header::CACHE_CONTROL), Some("max-age=60"));
+        assert_eq!(response.header(header::CONTENT_TYPE), None);
+        assert!(response.body_is_empty());
+    }
+}
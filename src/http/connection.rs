@@ -0,0 +1,36 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+// What a handler can know about the transport a request arrived on. Shared by every
+// request on the same connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    // TLS details; plain TCP connections leave these unset
+    pub tls: bool,
+    pub sni: Option<String>,
+    pub alpn: Option<Vec<u8>>,
+    // ISO country code of the peer, when a GeoIP database is loaded (feature "geoip")
+    pub country: Option<String>,
+}
+
+impl ConnectionInfo {
+    pub fn tcp(peer_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self {
+            peer_addr,
+            local_addr,
+            tls: false,
+            sni: None,
+            alpn: None,
+            country: None,
+        }
+    }
+}
+
+// Requests built outside a connection (tests, internal error responses) get unspecified addresses
+impl Default for ConnectionInfo {
+    fn default() -> Self {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        Self::tcp(unspecified, unspecified)
+    }
+}
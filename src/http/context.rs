@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+// Fired by the server when the client goes away; cloned handles share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub async fn cancelled(&self) {
+        loop {
+            // Register before checking the flag so a cancel() in between isn't missed
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Per-request state for long-running handlers: stop working once the deadline passes
+// or the client disconnects
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    deadline: Option<Instant>,
+    token: CancellationToken,
+}
+
+impl RequestContext {
+    pub fn new(deadline: Option<Instant>) -> Self {
+        Self {
+            deadline,
+            token: CancellationToken::default(),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled() || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    // Resolves when the request is cancelled or its deadline passes
+    pub async fn cancelled(&self) {
+        match self.deadline {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = self.token.cancelled() => {}
+                    _ = time::sleep_until(deadline) => {}
+                }
+            }
+            None => self.token.cancelled().await,
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancel_wakes_waiters() {
+        let ctx = RequestContext::new(None);
+        let waiter = ctx.clone();
+        let task = tokio::spawn(async move { waiter.cancelled().await });
+
+        tokio::task::yield_now().await;
+        assert!(!ctx.is_cancelled());
+        ctx.cancel();
+
+        task.await.unwrap();
+        assert!(ctx.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn deadline_counts_as_cancellation() {
+        let ctx = RequestContext::new(Some(Instant::now() + Duration::from_millis(20)));
+        assert!(!ctx.is_cancelled());
+
+        ctx.cancelled().await;
+        assert!(ctx.is_cancelled());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::http::date::HttpDate;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    // Browsers only keep these over HTTPS, so the cookie is sent with Secure too
+    None,
+}
+
+// A Set-Cookie value (RFC 6265 section 4.1), built up attribute by attribute:
+// `Cookie::new("sid", id).path("/").http_only().same_site(SameSite::Lax)`. The value
+// goes out as given, so anything outside cookie-octets (spaces, quotes, `;`, `,`)
+// needs encoding by the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<HttpDate>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    // Tells the client to drop its cookie of that name; give it the path and domain
+    // the cookie was set with
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "")
+            .max_age(Duration::ZERO)
+            .expires(HttpDate(0))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    // Wins over Expires in clients that understand both
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: HttpDate) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={expires}")?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax"),
+            Some(SameSite::None) => f.write_str("; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_follow_the_pair() {
+        assert_eq!(Cookie::new("a", "1").to_string(), "a=1");
+        let full = Cookie::new("sid", "abc")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .expires(HttpDate(784111777))
+            .secure()
+            .http_only()
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            full.to_string(),
+            "sid=abc; Path=/; Domain=example.com; Max-Age=3600; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; SameSite=Lax"
+        );
+        let cross_site = Cookie::new("t", "x").same_site(SameSite::None);
+        assert_eq!(cross_site.to_string(), "t=x; Secure; SameSite=None");
+        assert_eq!(
+            Cookie::removal("sid").path("/").to_string(),
+            "sid=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// An HTTP-date, as whole seconds since the Unix epoch. Only the IMF-fixdate form
+// ("Sun, 06 Nov 1994 08:49:37 GMT") is parsed; it's the only one senders may generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HttpDate(pub u64);
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+impl HttpDate {
+    // None for times before the epoch
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let since = time.duration_since(UNIX_EPOCH).ok()?;
+        Some(HttpDate(since.as_secs()))
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let [weekday, day, month, year, time, "GMT"] = parts[..] else {
+            return None;
+        };
+        let weekday = weekday.strip_suffix(',')?;
+        let day: u64 = day.parse().ok()?;
+        let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+        let year: u64 = year.parse().ok()?;
+        let mut hms = time.split(':').map(|n| n.parse::<u64>().ok());
+        let (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) =
+            (hms.next(), hms.next(), hms.next(), hms.next())
+        else {
+            return None;
+        };
+        if year < 1970 || !(1..=31).contains(&day) || h > 23 || m > 59 || s > 60 {
+            return None;
+        }
+        let days = days_from_civil(year, month, day);
+        if WEEKDAYS[((days + 4) % 7) as usize] != weekday {
+            return None;
+        }
+        Some(HttpDate(days * 86_400 + h * 3600 + m * 60 + s))
+    }
+}
+
+// The current time, formatted for the Date header. Each thread formats it at most once
+// a second and hands out copies in between.
+pub fn now() -> String {
+    thread_local! {
+        static CACHED: RefCell<(u64, String)> = const { RefCell::new((u64::MAX, String::new())) };
+    }
+    let secs = HttpDate::from_system_time(SystemTime::now()).map_or(0, |date| date.0);
+    CACHED.with_borrow_mut(|(at, value)| {
+        if *at != secs {
+            *at = secs;
+            *value = HttpDate(secs).to_string();
+        }
+        value.clone()
+    })
+}
+
+impl fmt::Display for HttpDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.0 / 86_400;
+        let secs = self.0 % 86_400;
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[((days + 4) % 7) as usize],
+            MONTHS[month as usize - 1],
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date (years from 1970 on)
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_dates_round_trip() {
+        let date = HttpDate::parse("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date, HttpDate(784_111_777));
+        assert_eq!(date.to_string(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(HttpDate(0).to_string(), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            HttpDate(951_782_400).to_string(),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+
+        // Wrong weekday, obsolete formats and junk are all rejected
+        assert!(HttpDate::parse("Mon, 06 Nov 1994 08:49:37 GMT").is_none());
+        assert!(HttpDate::parse("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+        assert!(HttpDate::parse("Sun, 06 Nov 1994 08:49 GMT").is_none());
+        assert!(HttpDate::parse("yesterday").is_none());
+    }
+
+    #[test]
+    fn system_times_truncate_to_seconds() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(784_111_777_900);
+        assert_eq!(
+            HttpDate::from_system_time(time),
+            Some(HttpDate(784_111_777))
+        );
+        let before = UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert_eq!(HttpDate::from_system_time(before), None);
+    }
+
+    #[test]
+    fn now_is_a_parseable_current_date() {
+        let before = HttpDate::from_system_time(SystemTime::now()).unwrap();
+        let now = HttpDate::parse(&now()).unwrap();
+        let after = HttpDate::from_system_time(SystemTime::now()).unwrap();
+        assert!(before <= now && now <= after);
+    }
+}
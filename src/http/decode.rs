@@ -0,0 +1,184 @@
+use flate2::write::GzDecoder;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use thiserror::Error;
+use tokio::io::{AsyncRead, ReadBuf};
+
+// Compressed bytes fed to the decoder at a time. Deflate tops out around 1000:1, so
+// this also bounds how much one step can expand to before the limits are checked.
+const INPUT_CHUNK: usize = 1024;
+
+// Below this much output the ratio isn't checked, so small, highly repetitive bodies
+// aren't mistaken for bombs
+const RATIO_FLOOR: u64 = 64 * 1024;
+
+// Caps on what a compressed request body may expand to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecompressionLimits {
+    // Decompressed bytes allowed in total
+    pub max_size: u64,
+    // Decompressed bytes allowed per compressed byte
+    pub max_ratio: u64,
+}
+
+impl Default for DecompressionLimits {
+    fn default() -> Self {
+        DecompressionLimits {
+            max_size: 100 * 1024 * 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+// Carried inside the io::Error a body read fails with, so handlers can answer 413
+#[derive(Debug, Error)]
+#[error("decompressed body exceeds limits ({decompressed} bytes from {compressed})")]
+pub struct DecompressionBomb {
+    pub compressed: u64,
+    pub decompressed: u64,
+}
+
+pub fn is_decompression_bomb(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<DecompressionBomb>())
+}
+
+// Gunzips a request body as it is read, failing as soon as the output outgrows the
+// limits rather than after it has all been produced
+pub struct GzipBody<R> {
+    inner: R,
+    // Decompressed output lands in the Vec; `out_pos` is how much has been handed out
+    decoder: GzDecoder<Vec<u8>>,
+    out_pos: usize,
+    compressed: u64,
+    decompressed: u64,
+    limits: DecompressionLimits,
+    finished: bool,
+}
+
+impl<R> GzipBody<R> {
+    pub fn new(inner: R, limits: DecompressionLimits) -> Self {
+        GzipBody {
+            inner,
+            decoder: GzDecoder::new(Vec::new()),
+            out_pos: 0,
+            compressed: 0,
+            decompressed: 0,
+            limits,
+            finished: false,
+        }
+    }
+
+    fn check_limits(&self) -> io::Result<()> {
+        let too_big = self.decompressed > self.limits.max_size;
+        let too_dense = self.decompressed > RATIO_FLOOR
+            && self.decompressed > self.compressed.saturating_mul(self.limits.max_ratio);
+        if too_big || too_dense {
+            return Err(io::Error::other(DecompressionBomb {
+                compressed: self.compressed,
+                decompressed: self.decompressed,
+            }));
+        }
+        Ok(())
+    }
+
+    // Counts what the last write produced and enforces the limits on it
+    fn account(&mut self, before: usize) -> io::Result<()> {
+        self.decompressed += (self.decoder.get_ref().len() - before) as u64;
+        self.check_limits()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for GzipBody<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let pending = &this.decoder.get_ref()[this.out_pos..];
+            if !pending.is_empty() {
+                let n = pending.len().min(buf.remaining());
+                buf.put_slice(&pending[..n]);
+                this.out_pos += n;
+                if this.out_pos == this.decoder.get_ref().len() {
+                    this.decoder.get_mut().clear();
+                    this.out_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0_u8; INPUT_CHUNK];
+            let mut input = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut input))?;
+            let before = this.decoder.get_ref().len();
+            if input.filled().is_empty() {
+                // A body that stops mid-stream fails here rather than passing as complete
+                this.decoder.try_finish()?;
+                this.finished = true;
+            } else {
+                this.compressed += input.filled().len() as u64;
+                this.decoder.write_all(input.filled())?;
+            }
+            this.account(before)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::compress_body;
+    use tokio::io::AsyncReadExt;
+
+    async fn gunzip(compressed: &[u8], limits: DecompressionLimits) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        GzipBody::new(compressed, limits)
+            .read_to_end(&mut out)
+            .await
+            .map(|_| out)
+    }
+
+    #[tokio::test]
+    async fn decodes_within_limits() {
+        let text = b"hello, compressed world".repeat(100);
+        let out = gunzip(&compress_body(&text), DecompressionLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[tokio::test]
+    async fn stops_bombs_by_ratio_and_size() {
+        // 16 MiB of zeros squeezes into a few KiB
+        let bomb = compress_body(&vec![0; 16 * 1024 * 1024]);
+        let err = gunzip(&bomb, DecompressionLimits::default())
+            .await
+            .unwrap_err();
+        assert!(is_decompression_bomb(&err));
+
+        let text = b"0123456789".repeat(20_000);
+        let limits = DecompressionLimits {
+            max_size: 100_000,
+            max_ratio: u64::MAX,
+        };
+        let err = gunzip(&compress_body(&text), limits).await.unwrap_err();
+        assert!(is_decompression_bomb(&err));
+    }
+
+    #[tokio::test]
+    async fn truncated_streams_are_errors() {
+        let compressed = compress_body(&b"hello".repeat(1000));
+        let err = gunzip(
+            &compressed[..compressed.len() / 2],
+            DecompressionLimits::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(!is_decompression_bomb(&err));
+    }
+}
@@ -0,0 +1,75 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+type Entry = Arc<dyn Any + Send + Sync>;
+
+// Values attached to a request, one per type, so middleware can hand what it worked
+// out (the authenticated user, a request ID, a parsed session) to the handler.
+// Middleware only gets `&HttpRequest`, hence the lock. Clones share the values.
+#[derive(Default, Clone)]
+pub struct Extensions {
+    map: Arc<Mutex<HashMap<TypeId, Entry>>>,
+}
+
+impl Extensions {
+    // Returns the value of the same type this replaces, if any
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<Arc<T>> {
+        let old = self
+            .map
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Arc::new(value));
+        old.and_then(|old| old.downcast().ok())
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let entry = self.map.lock().unwrap().get(&TypeId::of::<T>()).cloned();
+        entry.and_then(|entry| entry.downcast().ok())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let old = self.map.lock().unwrap().remove(&TypeId::of::<T>());
+        old.and_then(|old| old.downcast().ok())
+    }
+}
+
+// The values are opaque; how many there are is all that can be shown
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.map.lock().unwrap().len();
+        write!(f, "Extensions({len})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(&'static str);
+
+    #[derive(Debug, PartialEq)]
+    struct RequestId(u64);
+
+    #[test]
+    fn values_are_kept_one_per_type() {
+        let extensions = Extensions::default();
+        assert_eq!(extensions.insert(User("alice")), None);
+        extensions.insert(RequestId(7));
+        assert_eq!(extensions.get::<User>().as_deref(), Some(&User("alice")));
+        assert_eq!(
+            extensions.get::<RequestId>().as_deref(),
+            Some(&RequestId(7))
+        );
+        assert_eq!(extensions.get::<String>(), None);
+
+        let old = extensions.insert(User("bob"));
+        assert_eq!(old.as_deref(), Some(&User("alice")));
+        let shared = extensions.clone();
+        assert_eq!(shared.remove::<User>().as_deref(), Some(&User("bob")));
+        assert_eq!(extensions.get::<User>(), None);
+        assert_eq!(format!("{extensions:?}"), "Extensions(1)");
+    }
+}
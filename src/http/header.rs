@@ -0,0 +1,551 @@
+// Header names are stored lowercased (request headers are lowercased while parsing),
+// and only turned into their canonical Title-Case when a response is written.
+
+pub const ACCEPT: &str = "accept";
+pub const ACCEPT_ENCODING: &str = "accept-encoding";
+pub const ACCEPT_RANGES: &str = "accept-ranges";
+pub const AGE: &str = "age";
+pub const AUTHORIZATION: &str = "authorization";
+pub const ALLOW: &str = "allow";
+pub const CACHE_CONTROL: &str = "cache-control";
+pub const CONNECTION: &str = "connection";
+pub const CONTENT_ENCODING: &str = "content-encoding";
+pub const CONTENT_LENGTH: &str = "content-length";
+pub const CONTENT_LOCATION: &str = "content-location";
+pub const CONTENT_RANGE: &str = "content-range";
+pub const CONTENT_SECURITY_POLICY: &str = "content-security-policy";
+pub const CONTENT_TYPE: &str = "content-type";
+pub const COOKIE: &str = "cookie";
+pub const DATE: &str = "date";
+pub const DESTINATION: &str = "destination";
+pub const ETAG: &str = "etag";
+pub const EXPECT: &str = "expect";
+pub const EXPIRES: &str = "expires";
+pub const HOST: &str = "host";
+pub const IF_MATCH: &str = "if-match";
+pub const IF_MODIFIED_SINCE: &str = "if-modified-since";
+pub const IF_NONE_MATCH: &str = "if-none-match";
+pub const IF_RANGE: &str = "if-range";
+pub const IF_UNMODIFIED_SINCE: &str = "if-unmodified-since";
+pub const KEEP_ALIVE: &str = "keep-alive";
+pub const LAST_MODIFIED: &str = "last-modified";
+pub const LOCATION: &str = "location";
+pub const OVERWRITE: &str = "overwrite";
+pub const PRAGMA: &str = "pragma";
+pub const RANGE: &str = "range";
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+pub const RETRY_AFTER: &str = "retry-after";
+pub const SERVER: &str = "server";
+pub const SET_COOKIE: &str = "set-cookie";
+pub const TRAILER: &str = "trailer";
+pub const TRANSFER_ENCODING: &str = "transfer-encoding";
+pub const UPGRADE: &str = "upgrade";
+pub const USER_AGENT: &str = "user-agent";
+pub const VARY: &str = "vary";
+pub const WWW_AUTHENTICATE: &str = "www-authenticate";
+
+// "content-type" -> "Content-Type", apart from the few names whose registered
+// spelling isn't plain Title-Case
+pub fn canonical_name(name: &str) -> String {
+    match name {
+        ETAG => return "ETag".to_string(),
+        WWW_AUTHENTICATE => return "WWW-Authenticate".to_string(),
+        _ => {}
+    }
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Field names are RFC 9110 tokens: visible ASCII without separators
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// Field values may not contain line breaks (or NUL), which would split the header
+pub fn is_valid_value(value: &str) -> bool {
+    !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | 0))
+}
+
+// Every field goes through here on its way to the wire, so a handler copying request
+// data into a header can't end the field early and start one of its own
+fn without_line_breaks(text: String) -> String {
+    match is_valid_value(&text) {
+        true => text,
+        false => text.replace(['\r', '\n', '\0'], ""),
+    }
+}
+
+// Splits a comma-separated list header, dropping empty elements
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+// A header block in arrival order. Names are matched case-insensitively; a field sent
+// more than once keeps every value, which `get` sees comma-joined (RFC 9110 section
+// 5.3) and `get_all` one line at a time. Set-Cookie is the one field that can't be
+// joined, so responses write each value on its own line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field {
+    name: String,
+    values: Vec<String>,
+    // Only built once there is more than one value
+    joined: Option<String>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let field = self.field(name)?;
+        Some(field.joined.as_deref().unwrap_or(&field.values[0]))
+    }
+
+    pub fn get_all(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.field(name)
+            .into_iter()
+            .flat_map(|field| field.values.iter().map(String::as_str))
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.field(name).is_some()
+    }
+
+    // Replaces any values the field already had
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = without_line_breaks(name.into()).to_ascii_lowercase();
+        let value = without_line_breaks(value.into());
+        match self.fields.iter_mut().find(|field| field.name == name) {
+            Some(field) => {
+                field.values = vec![value];
+                field.joined = None;
+            }
+            None => self.fields.push(Field {
+                name,
+                values: vec![value],
+                joined: None,
+            }),
+        }
+    }
+
+    // Adds another value, keeping the ones already there
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = without_line_breaks(name.into()).to_ascii_lowercase();
+        let value = without_line_breaks(value.into());
+        match self.fields.iter_mut().find(|field| field.name == name) {
+            Some(field) => {
+                field.values.push(value);
+                field.joined = Some(field.values.join(", "));
+            }
+            None => self.insert(name, value),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields
+            .retain(|field| !field.name.eq_ignore_ascii_case(name));
+    }
+
+    // Every (name, value) line, repeated fields once per value
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().flat_map(|field| {
+            field
+                .values
+                .iter()
+                .map(|value| (field.name.as_str(), value.as_str()))
+        })
+    }
+
+    fn field(&self, name: &str) -> Option<&Field> {
+        self.fields
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut headers = Headers::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for Headers {
+    fn from(fields: [(K, V); N]) -> Self {
+        fields.into_iter().collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLength(pub u64);
+
+impl ContentLength {
+    // Only plain digits are valid; "+5" or "5, 5" are rejected rather than guessed at
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        value.parse().ok().map(ContentLength)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    pub mime: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';');
+        let mime = parts.next()?.trim().to_lowercase();
+        if !mime.contains('/') {
+            return None;
+        }
+
+        let params = parts
+            .filter_map(|p| p.split_once('='))
+            .map(|(k, v)| {
+                (
+                    k.trim().to_lowercase(),
+                    v.trim().trim_matches('"').to_string(),
+                )
+            })
+            .collect();
+
+        Some(ContentType { mime, params })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    // bytes=0-499
+    FromTo(u64, u64),
+    // bytes=500-
+    From(u64),
+    // bytes=-500 (the last 500 bytes)
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // Resolves against the representation length into an inclusive (start, end) pair,
+    // or None when the range can't be satisfied
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::FromTo(start, end) if start < len => Some((start, end.min(len - 1))),
+            ByteRange::From(start) if start < len => Some((start, len - 1)),
+            ByteRange::Suffix(n) if n > 0 && len > 0 => Some((len.saturating_sub(n), len - 1)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub ranges: Vec<ByteRange>,
+}
+
+impl Range {
+    pub fn parse(value: &str) -> Option<Self> {
+        let specs = value.trim().strip_prefix("bytes=")?;
+        let ranges = list(specs)
+            .map(|spec| {
+                let (start, end) = spec.split_once('-')?;
+                let (start, end) = (start.trim(), end.trim());
+                match (start.is_empty(), end.is_empty()) {
+                    (true, false) => Some(ByteRange::Suffix(end.parse().ok()?)),
+                    (false, true) => Some(ByteRange::From(start.parse().ok()?)),
+                    (false, false) => {
+                        let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                        (start <= end).then_some(ByteRange::FromTo(start, end))
+                    }
+                    (true, true) => None,
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        (!ranges.is_empty()).then_some(Range { ranges })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptEncoding {
+    // (coding, quality) pairs, codings lowercased
+    pub codings: Vec<(String, f32)>,
+}
+
+impl AcceptEncoding {
+    pub fn parse(value: &str) -> Self {
+        let codings = list(value)
+            .map(|item| {
+                let mut parts = item.split(';');
+                let coding = parts.next().unwrap_or("").trim().to_lowercase();
+                let quality = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (coding, quality)
+            })
+            .collect();
+
+        AcceptEncoding { codings }
+    }
+
+    // A coding is acceptable if listed (or covered by `*`) with a non-zero quality
+    pub fn accepts(&self, coding: &str) -> bool {
+        let exact = self
+            .codings
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(coding));
+        let wildcard = self.codings.iter().find(|(c, _)| c == "*");
+
+        match exact.or(wildcard) {
+            Some((_, q)) => *q > 0.0,
+            None => false,
+        }
+    }
+}
+
+// The directives the response cache acts on; the rest are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheControl {
+    pub no_cache: bool,
+    pub no_store: bool,
+    pub private: bool,
+    pub no_transform: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub min_fresh: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(value: &str) -> Self {
+        let mut cc = CacheControl::default();
+        for directive in list(value) {
+            let (name, arg) = match directive.split_once('=') {
+                Some((n, a)) => (n.trim(), Some(a.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = arg.and_then(|a| a.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-cache" => cc.no_cache = true,
+                "no-store" => cc.no_store = true,
+                "private" => cc.private = true,
+                "no-transform" => cc.no_transform = true,
+                "max-age" => cc.max_age = seconds,
+                "s-maxage" => cc.s_maxage = seconds,
+                "min-fresh" => cc.min_fresh = seconds,
+                _ => {}
+            }
+        }
+        cc
+    }
+}
+
+// The name=value pairs of a Cookie header (RFC 6265 section 4.2.1), in the order
+// sent. Pairs without a name or `=` are dropped; values lose their surrounding quotes
+// but keep any percent-encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Cookies {
+    pub pairs: Vec<(String, String)>,
+}
+
+impl Cookies {
+    pub fn parse(value: &str) -> Self {
+        let pairs = value
+            .split(';')
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let name = name.trim();
+                let value = value.trim();
+                let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                    Some(unquoted) => unquoted,
+                    None => value,
+                };
+                (!name.is_empty()).then(|| (name.to_string(), value.to_string()))
+            })
+            .collect();
+        Cookies { pairs }
+    }
+
+    // The first value sent under `name`; browsers send the most specific path first
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_fields_keep_every_value() {
+        let mut headers = Headers::from([("Accept", "text/html"), ("cookie", "a=1")]);
+        headers.append("Cookie", "b=2");
+        assert_eq!(headers.get("COOKIE"), Some("a=1, b=2"));
+        assert_eq!(headers.get_all(COOKIE).collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.get(ACCEPT), Some("text/html"));
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("accept", "text/html"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2")
+            ]
+        );
+
+        headers.insert(COOKIE, "c=3");
+        assert_eq!(headers.get_all(COOKIE).collect::<Vec<_>>(), ["c=3"]);
+        headers.remove("Accept");
+        assert!(!headers.contains_key(ACCEPT));
+        assert_eq!(headers.get_all(ACCEPT).count(), 0);
+    }
+
+    #[test]
+    fn canonical_name_title_cases_each_part() {
+        assert_eq!(canonical_name(CONTENT_TYPE), "Content-Type");
+        assert_eq!(canonical_name("x-custom-HEADER"), "X-Custom-Header");
+        assert_eq!(canonical_name(ETAG), "ETag");
+        assert_eq!(canonical_name(WWW_AUTHENTICATE), "WWW-Authenticate");
+    }
+
+    #[test]
+    fn validates_names_and_values() {
+        assert!(is_valid_name("X-Environment"));
+        assert!(!is_valid_name("Bad Name"));
+        assert!(!is_valid_name(""));
+        assert!(is_valid_value("staging; region=eu"));
+        assert!(!is_valid_value("a\r\nSet-Cookie: x"));
+    }
+
+    #[test]
+    fn line_breaks_never_reach_the_block() {
+        let mut headers = Headers::new();
+        headers.insert("Location", "/a\r\nSet-Cookie: evil=1");
+        headers.append("X-Echo\r\nSet-Cookie", "b\n\0");
+        assert_eq!(headers.get(LOCATION), Some("/aSet-Cookie: evil=1"));
+        assert_eq!(headers.get("x-echoset-cookie"), Some("b"));
+        assert!(!headers.contains_key(SET_COOKIE));
+    }
+
+    #[test]
+    fn content_length_rejects_non_digits() {
+        assert_eq!(ContentLength::parse("42"), Some(ContentLength(42)));
+        assert_eq!(ContentLength::parse("-1"), None);
+        assert_eq!(ContentLength::parse("+5"), None);
+        assert_eq!(ContentLength::parse("5, 5"), None);
+        assert_eq!(ContentLength::parse(""), None);
+    }
+
+    #[test]
+    fn content_type_parses_params() {
+        let ct = ContentType::parse("Text/HTML; charset=\"UTF-8\"; q=1").unwrap();
+        assert_eq!(ct.mime, "text/html");
+        assert_eq!(ct.charset(), Some("UTF-8"));
+        assert_eq!(ct.param("Q"), Some("1"));
+        assert!(ContentType::parse("garbage").is_none());
+    }
+
+    #[test]
+    fn range_parses_all_forms() {
+        let range = Range::parse("bytes=0-4, 10-, -3").unwrap();
+        assert_eq!(
+            range.ranges,
+            vec![
+                ByteRange::FromTo(0, 4),
+                ByteRange::From(10),
+                ByteRange::Suffix(3)
+            ]
+        );
+        assert!(Range::parse("bytes=5-1").is_none());
+        assert!(Range::parse("items=0-1").is_none());
+        assert!(Range::parse("bytes=-").is_none());
+    }
+
+    #[test]
+    fn byte_range_resolves_against_length() {
+        assert_eq!(ByteRange::FromTo(0, 99).resolve(10), Some((0, 9)));
+        assert_eq!(ByteRange::From(4).resolve(10), Some((4, 9)));
+        assert_eq!(ByteRange::Suffix(3).resolve(10), Some((7, 9)));
+        assert_eq!(ByteRange::Suffix(30).resolve(10), Some((0, 9)));
+        assert_eq!(ByteRange::From(10).resolve(10), None);
+    }
+
+    #[test]
+    fn accept_encoding_honours_quality_and_wildcard() {
+        let ae = AcceptEncoding::parse("br;q=0.5, gzip;q=0, *");
+        assert!(ae.accepts("br"));
+        assert!(!ae.accepts("gzip"));
+        assert!(ae.accepts("deflate"));
+
+        assert!(AcceptEncoding::parse("GZIP").accepts("gzip"));
+        assert!(!AcceptEncoding::parse("").accepts("gzip"));
+    }
+
+    #[test]
+    fn cache_control_parses_directives() {
+        let cc = CacheControl::parse("No-Cache, max-age=\"60\", min-fresh=5, foo=bar");
+        assert!(cc.no_cache);
+        assert!(!cc.no_store);
+        assert_eq!(cc.max_age, Some(60));
+        assert_eq!(cc.min_fresh, Some(5));
+        assert_eq!(CacheControl::parse("max-age=soon").max_age, None);
+    }
+
+    #[test]
+    fn cookies_split_unquote_and_keep_order() {
+        let cookies = Cookies::parse(r#"sid=abc123; theme="dark" ;empty=; flag; =x; sid=other"#);
+        assert_eq!(
+            cookies.pairs,
+            [
+                ("sid".to_string(), "abc123".to_string()),
+                ("theme".to_string(), "dark".to_string()),
+                ("empty".to_string(), String::new()),
+                ("sid".to_string(), "other".to_string()),
+            ]
+        );
+        assert_eq!(cookies.get("sid"), Some("abc123"));
+        assert_eq!(cookies.get("SID"), None);
+        assert_eq!(Cookies::parse("q=\"").get("q"), Some("\""));
+        assert!(Cookies::parse("").pairs.is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use super::decode::GzipBody;
+use super::request::ChunkedBody;
+use bytes::Bytes;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Chunks in flight between the connection and the handler; this is what bounds memory
+// use for uploads, however large the body is
+const CHANNEL_DEPTH: usize = 4;
+
+// A request body that is still arriving. The connection task reads it off the socket
+// (see `pump`) while the handler consumes it, so it is never held in memory whole.
+pub struct IncomingBody {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+    len: u64,
+}
+
+pub(crate) type BodySender = mpsc::Sender<io::Result<Bytes>>;
+
+impl IncomingBody {
+    pub(crate) fn channel(len: u64) -> (IncomingBody, BodySender) {
+        let (tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+        let body = IncomingBody {
+            rx,
+            chunk: Bytes::new(),
+            len,
+        };
+        (body, tx)
+    }
+
+    // The declared Content-Length
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsyncRead for IncomingBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.chunk.is_empty() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunk = chunk,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                // Sender gone after the last chunk: end of body
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = self.chunk.len().min(buf.remaining());
+        let chunk = self.chunk.split_to(n);
+        buf.put_slice(&chunk);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl fmt::Debug for IncomingBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IncomingBody({} bytes)", self.len)
+    }
+}
+
+// What `HttpRequest::body_reader` hands out: the same interface whether the body was
+// small enough to buffer, is still coming off the connection, or is being gunzipped
+pub enum BodyReader<'a> {
+    Buffered(&'a [u8]),
+    Streaming(IncomingBody),
+    Gzip(Box<GzipBody<BodyReader<'a>>>),
+}
+
+impl AsyncRead for BodyReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BodyReader::Buffered(bytes) => Pin::new(bytes).poll_read(cx, buf),
+            BodyReader::Streaming(body) => Pin::new(body).poll_read(cx, buf),
+            BodyReader::Gzip(body) => Pin::new(body.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+// What is left of a streamed body on the connection
+pub(crate) enum Remaining {
+    Length(u64),
+    Chunked(ChunkedBody),
+}
+
+// Copies the rest of the body from the connection into the channel: exactly `len`
+// bytes, or decoded chunks up to the last one. Returns true once the whole body has
+// been read off the socket; false means the handler stopped reading (or the client
+// went away) and the connection can't be reused.
+pub(crate) async fn pump<R>(reader: &mut R, remaining: Remaining, tx: BodySender) -> bool
+where
+    R: AsyncRead + Unpin,
+{
+    let (mut len, mut decoder) = match remaining {
+        Remaining::Length(len) => (len, None),
+        Remaining::Chunked(decoder) => (0, Some(decoder)),
+    };
+    loop {
+        let read = match &mut decoder {
+            None if len == 0 => return true,
+            None => {
+                let mut chunk = vec![0_u8; CHUNK_SIZE.min(len as usize)];
+                match reader.read(&mut chunk).await {
+                    Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        len -= n as u64;
+                        Ok(chunk)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Some(decoder) => {
+                let mut chunk = vec![0_u8; CHUNK_SIZE];
+                match decoder.read(reader, &mut chunk).await {
+                    Ok(0) => return true,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Ok(chunk)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        let chunk = match read {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return false;
+            }
+        };
+        if tx.send(Ok(chunk.into())).await.is_err() {
+            // Nobody is reading any more
+            return decoder.is_none() && len == 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pumps_exactly_the_declared_length() {
+        let mut source: &[u8] = b"hello world, and the next request";
+        let (mut body, tx) = IncomingBody::channel(11);
+
+        let (complete, read) = tokio::join!(pump(&mut source, Remaining::Length(11), tx), async {
+            let mut out = Vec::new();
+            body.read_to_end(&mut out).await.map(|_| out)
+        });
+
+        assert!(complete);
+        assert_eq!(read.unwrap(), b"hello world");
+        assert_eq!(source, b", and the next request");
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_an_error() {
+        let mut source: &[u8] = b"short";
+        let (mut body, tx) = IncomingBody::channel(100);
+
+        let (complete, read) = tokio::join!(pump(&mut source, Remaining::Length(100), tx), async {
+            let mut out = Vec::new();
+            body.read_to_end(&mut out).await
+        });
+
+        assert!(!complete);
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn pumps_a_chunked_body_up_to_the_last_chunk() {
+        let mut source: &[u8] = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\nGET / HTTP/1.1";
+        let (mut body, tx) = IncomingBody::channel(0);
+
+        let decoder = ChunkedBody::new(None);
+        let (complete, read) =
+            tokio::join!(pump(&mut source, Remaining::Chunked(decoder), tx), async {
+                let mut out = Vec::new();
+                body.read_to_end(&mut out).await.map(|_| out)
+            });
+
+        assert!(complete);
+        assert_eq!(read.unwrap(), b"hello world");
+        assert_eq!(source, b"GET / HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn body_reader_reads_either_source() {
+        let mut out = Vec::new();
+        BodyReader::Buffered(b"small")
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"small");
+
+        let (body, tx) = IncomingBody::channel(5);
+        tx.send(Ok(Bytes::from_static(b"la"))).await.unwrap();
+        tx.send(Ok(Bytes::from_static(b"rge"))).await.unwrap();
+        drop(tx);
+        out.clear();
+        BodyReader::Streaming(body)
+            .read_to_end(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, b"large");
+    }
+}
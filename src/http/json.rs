@@ -0,0 +1,127 @@
+use crate::http::header::{self, ContentType};
+use crate::http::{HttpRequest, HttpResponse, StatusCode};
+use crate::logging::error;
+use crate::router::HandlerError;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+
+pub const JSON_UTF8: &str = "application/json; charset=utf-8";
+
+// Why `request.json()` failed. Handlers can `?` it: the router answers 415 or 400.
+#[derive(Debug, Error)]
+pub enum JsonError {
+    // Content-Type isn't application/json or a `+json` type
+    #[error("expected a JSON body")]
+    NotJson,
+    #[error("invalid JSON body: {0}")]
+    Invalid(#[from] serde_json::Error),
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<JsonError> for HandlerError {
+    fn from(e: JsonError) -> Self {
+        match e {
+            JsonError::NotJson => HandlerError::Status(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            JsonError::Invalid(_) => HandlerError::Status(StatusCode::BAD_REQUEST),
+            JsonError::Io(e) => HandlerError::Io(e),
+        }
+    }
+}
+
+impl HttpRequest {
+    // The body, deserialized; buffered or streamed, and gunzipped where configured
+    pub async fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        let is_json = self
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(ContentType::parse)
+            .is_some_and(|ct| ct.mime == "application/json" || ct.mime.ends_with("+json"));
+        if !is_json {
+            return Err(JsonError::NotJson);
+        }
+        let mut body = Vec::new();
+        self.body_reader().read_to_end(&mut body).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl HttpResponse {
+    // A 200 with `value` serialized; `json` takes a document serialized already. A value
+    // that can't be serialized (a map with non-string keys, say) is a 500.
+    pub fn json_value<T: Serialize + ?Sized>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => HttpResponse::new(StatusCode::OK, JSON_UTF8, body),
+            Err(e) => {
+                error!("could not serialize response: {e}");
+                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", vec![])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        admin: bool,
+    }
+
+    fn request(content_type: &str, body: &str) -> HttpRequest {
+        let mut request = HttpRequest {
+            body: body.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        request.headers.insert(header::CONTENT_TYPE, content_type);
+        request
+    }
+
+    #[tokio::test]
+    async fn request_bodies_deserialize_when_declared_json() {
+        let body = r#"{"name":"alice","admin":true}"#;
+        for content_type in [
+            "application/json",
+            "application/merge-patch+json; charset=utf-8",
+        ] {
+            let user: User = request(content_type, body).json().await.unwrap();
+            assert_eq!(user.name, "alice");
+            assert!(user.admin);
+        }
+
+        let err = request("text/plain", body)
+            .json::<User>()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            HandlerError::from(err).status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let err = request("application/json", r#"{"name":1}"#)
+            .json::<User>()
+            .await
+            .unwrap_err();
+        assert_eq!(HandlerError::from(err).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn responses_serialize_with_a_charset() {
+        let user = User {
+            name: "alice".to_string(),
+            admin: false,
+        };
+        let response = HttpResponse::json_value(&user);
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.header(header::CONTENT_TYPE), Some(JSON_UTF8));
+        let crate::http::Body::Bytes(body) = response.body() else {
+            panic!("not an in-memory body");
+        };
+        assert_eq!(body, br#"{"name":"alice","admin":false}"#);
+    }
+}
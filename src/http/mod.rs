@@ -1,5 +1,32 @@
+pub mod body;
+pub mod conditional;
+pub mod connection;
+pub mod context;
+pub mod cookie;
+pub mod date;
+pub mod decode;
+pub mod extensions;
+pub mod header;
+pub mod incoming;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod multipart;
 pub mod request;
 pub mod response;
+mod sendfile;
+pub mod status;
+pub mod upgrade;
+pub mod uri;
 
+pub use body::Body;
+pub use connection::ConnectionInfo;
+pub use context::RequestContext;
+pub use cookie::Cookie;
+pub use extensions::Extensions;
+pub use header::Headers;
+pub use incoming::IncomingBody;
 pub use request::HttpRequest;
 pub use response::HttpResponse;
+pub use status::StatusCode;
+pub use upgrade::Upgraded;
+pub use uri::Uri;
@@ -0,0 +1,238 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Largest header block accepted for one part
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+const READ_SIZE: usize = 16 * 1024;
+
+// The boundary parameter of a `multipart/form-data` Content-Type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+// One part's headers; its content follows from `Multipart::read_chunk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+// Streaming `multipart/form-data` reader (RFC 7578): parts are handed out one at a
+// time and their content in chunks, so uploads never have to fit in memory
+pub struct Multipart<R> {
+    reader: R,
+    // "\r\n--<boundary>"
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Just past a delimiter, before the part headers or the closing "--"
+    Delimiter,
+    Content,
+    Done,
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first delimiter has no line break before it; pretend it does
+            buf: b"\r\n".to_vec(),
+            state: State::Content,
+        }
+    }
+
+    // The next part, skipping whatever is left of the current one; None after the last
+    pub async fn next_part(&mut self) -> io::Result<Option<Part>> {
+        while self.state == State::Content {
+            self.read_chunk().await?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        let end = loop {
+            // "--" right after a delimiter closes the body; anything after is epilogue
+            if self.buf.starts_with(b"--") {
+                self.state = State::Done;
+                return Ok(None);
+            }
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_PART_HEADERS {
+                return Err(malformed("part headers too large"));
+            }
+            self.fill().await?;
+        };
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end + 4);
+        self.state = State::Content;
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+        };
+        // The delimiter's line may carry trailing whitespace before its CRLF
+        for line in head.split("\r\n").skip(1) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                part.name = param(value, "name");
+                part.filename = param(value, "filename");
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+        Ok(Some(part))
+    }
+
+    // The next piece of the current part's content; None once it is all read
+    pub async fn read_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.state != State::Content {
+            return Ok(None);
+        }
+        loop {
+            if let Some(at) = find(&self.buf, &self.delimiter) {
+                let chunk: Vec<u8> = self.buf.drain(..at).collect();
+                self.buf.drain(..self.delimiter.len());
+                self.state = State::Delimiter;
+                return Ok((!chunk.is_empty()).then_some(chunk));
+            }
+            // Everything but a tail that could be the start of the delimiter is content
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(Some(self.buf.drain(..safe).collect()));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> io::Result<()> {
+        let start = self.buf.len();
+        self.buf.resize(start + READ_SIZE, 0);
+        let n = self.reader.read(&mut self.buf[start..]).await?;
+        self.buf.truncate(start + n);
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "multipart body ended early",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// `name="value"` out of a Content-Disposition header
+fn param(value: &str, wanted: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|p| {
+        let (name, value) = p.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case(wanted)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"--XyZ\r\n\
+        Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--XyW is not it\r\n\
+        --XyZ--\r\n";
+
+    async fn collect<R: AsyncRead + Unpin>(multipart: &mut Multipart<R>) -> Vec<u8> {
+        let mut content = Vec::new();
+        while let Some(chunk) = multipart.read_chunk().await.unwrap() {
+            content.extend(chunk);
+        }
+        content
+    }
+
+    #[test]
+    fn reads_the_boundary_parameter() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("text/plain; boundary=x"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[tokio::test]
+    async fn splits_parts_across_small_reads() {
+        // A one-byte reader puts delimiters across every possible read boundary
+        let reader = trickle(BODY);
+        let mut multipart = Multipart::new(reader, "XyZ");
+
+        let note = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(note.name.as_deref(), Some("note"));
+        assert_eq!(note.filename, None);
+        assert_eq!(collect(&mut multipart).await, b"hello");
+
+        let file = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(file.filename.as_deref(), Some("a b.txt"));
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            collect(&mut multipart).await,
+            b"line one\r\n--XyW is not it"
+        );
+        assert_eq!(multipart.next_part().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn skips_unread_content_and_rejects_truncated_bodies() {
+        let mut multipart = Multipart::new(BODY, "XyZ");
+        multipart.next_part().await.unwrap();
+        let file = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(file.name.as_deref(), Some("file"));
+
+        let mut cut = Multipart::new(&BODY[..60], "XyZ");
+        cut.next_part().await.unwrap();
+        let err = cut.next_part().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    // Yields one byte per read
+    fn trickle(data: &'static [u8]) -> impl AsyncRead + Unpin {
+        let (mut tx, rx) = tokio::io::duplex(1);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            let _ = tx.write_all(data).await;
+        });
+        rx
+    }
+}
@@ -1,87 +1,549 @@
-use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
-use tokio::net::TcpStream;
+use crate::http::decode::{DecompressionLimits, GzipBody};
+use crate::http::header::{self, ContentLength, Cookies, Headers};
+use crate::http::incoming::BodyReader;
+use crate::http::{ConnectionInfo, Extensions, IncomingBody, RequestContext, Uri};
+use crate::session::Session;
+use crate::utils;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
 
-#[derive(Debug)]
+// The methods defined by RFC 9110 plus PATCH (RFC 5789)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum HttpMethod {
+    #[default]
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    // WebDAV (RFC 4918), for renaming files
+    Move,
 }
 
-#[derive(Debug)]
+impl HttpMethod {
+    // Method names are case-sensitive, so "get" is not GET
+    pub fn parse(name: &str) -> Option<Self> {
+        let method = match name {
+            "GET" => HttpMethod::Get,
+            "HEAD" => HttpMethod::Head,
+            "POST" => HttpMethod::Post,
+            "PUT" => HttpMethod::Put,
+            "DELETE" => HttpMethod::Delete,
+            "CONNECT" => HttpMethod::Connect,
+            "OPTIONS" => HttpMethod::Options,
+            "TRACE" => HttpMethod::Trace,
+            "PATCH" => HttpMethod::Patch,
+            "MOVE" => HttpMethod::Move,
+            _ => return None,
+        };
+        Some(method)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Connect => "CONNECT",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Trace => "TRACE",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Move => "MOVE",
+        }
+    }
+
+    // Safe methods are read-only: caches may serve them and CSRF checks can skip them
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            HttpMethod::Get | HttpMethod::Head | HttpMethod::Options | HttpMethod::Trace
+        )
+    }
+
+    // Idempotent methods can be retried automatically after a dropped connection
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, HttpMethod::Put | HttpMethod::Delete)
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RequestError {
+    // The client closed the connection before sending a request
+    #[error("connection closed")]
+    ConnectionClosed,
+    // Answered with 400; the reason goes to the log
+    #[error("malformed request: {0}")]
+    Malformed(&'static str),
+    // A well-formed method this server doesn't implement; the rest was left unread
+    #[error("unknown method")]
+    UnknownMethod,
+    // An `Expect` other than 100-continue
+    #[error("expectation failed")]
+    ExpectationFailed,
+    // The request line is longer than `HeadLimits::max_request_line`
+    #[error("request line too long")]
+    UriTooLong,
+    // Over `HeadLimits::max_header_bytes` or `max_headers`
+    #[error("request headers too large")]
+    HeadersTooLarge,
+    // Content-Length, or the chunks read so far, are over the configured limit; the
+    // rest of the body was left unread
+    #[error("request body too large")]
+    BodyTooLarge,
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Default)]
 pub struct HttpRequest {
     pub method: HttpMethod,
-    pub path: String,
-    pub headers: HashMap<String, String>,
+    pub uri: Uri,
+    pub headers: Headers,
+    // The body, when it was small enough to read up front; `body_reader` covers both cases
     pub body: Vec<u8>,
+    // Bytes of a larger body still on the connection; the server streams them to the
+    // handler through `body_stream`
+    pub(crate) unread_body: u64,
+    // Set when a chunked body outgrew the buffer: where decoding stopped, so the server
+    // can stream the rest
+    pub(crate) chunked: Option<ChunkedBody>,
+    pub(crate) body_stream: Mutex<Option<IncomingBody>>,
+    // What the matched route's pattern captured; the router sets them before dispatch
+    pub(crate) params: Mutex<Vec<(String, String)>>,
+    // When set, gzip-encoded bodies are decompressed by `body_reader` within these limits
+    pub(crate) decompression: Option<DecompressionLimits>,
+    // Peer/local addresses and TLS details of the connection this request came in on
+    pub connection_info: Arc<ConnectionInfo>,
+    // Deadline and cancellation signal for long-running handlers
+    pub context: RequestContext,
+    // Whatever middleware attached for the handler
+    pub extensions: Extensions,
 }
 
-impl HttpRequest {
-    pub async fn from_stream(reader: &mut BufReader<TcpStream>) -> Option<Self> {
-        let mut first_line = String::new();
-        reader.read_line(&mut first_line).await.ok()?;
-        if first_line.is_empty() {
-            return None;
+// Bounds on the request line and header block, so a client can't make the server
+// buffer an endless one. Line lengths include the CRLF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadLimits {
+    pub max_request_line: usize,
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
         }
+    }
+}
+
+impl HttpRequest {
+    // Bodies declaring more than `max_body_size` bytes are refused before being read.
+    // Bodies over `buffer_limit` are left on the connection to be streamed. Any stream
+    // will do (TCP, TLS, a Unix socket, an in-memory pipe): it is only written to for
+    // `100 Continue`.
+    pub async fn from_stream<S: AsyncRead + AsyncWrite + Unpin>(
+        reader: &mut BufReader<S>,
+        max_body_size: Option<u64>,
+        buffer_limit: u64,
+        limits: &HeadLimits,
+    ) -> Result<Self, RequestError> {
+        let first_line = match read_line_within(reader, limits.max_request_line).await? {
+            Some(line) if line.is_empty() => return Err(RequestError::ConnectionClosed),
+            Some(line) => line,
+            None => return Err(RequestError::UriTooLong),
+        };
 
         // Parse Metadata
-        let (method, path) = Self::parse_request_line(&first_line)?;
-        let headers = Self::parse_headers(reader).await?;
+        let (method, uri) = Self::parse_request_line(&first_line)?;
+        let headers = Self::parse_headers(reader, limits).await?;
 
-        // Handle Body (including multi-read)
-        let body = Self::read_body(reader, &headers).await?;
+        // Framing both ways, or a transfer coding other than plain chunked, is how
+        // requests get smuggled past a proxy that reads the framing differently
+        // (RFC 9112 section 6.3)
+        let chunked = match headers.get(header::TRANSFER_ENCODING) {
+            Some(_) if headers.contains_key(header::CONTENT_LENGTH) => {
+                return Err(RequestError::Malformed(
+                    "both transfer-encoding and content-length",
+                ));
+            }
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return Err(RequestError::Malformed("unsupported transfer-encoding")),
+            None => false,
+        };
+        let len = match headers.get(header::CONTENT_LENGTH) {
+            _ if chunked => 0,
+            Some(value) => {
+                let ContentLength(len) = ContentLength::parse(value)
+                    .ok_or(RequestError::Malformed("invalid content-length"))?;
+                len
+            }
+            None => 0,
+        };
+        if max_body_size.is_some_and(|max| len > max) {
+            return Err(RequestError::BodyTooLarge);
+        }
 
-        Some(HttpRequest {
+        // A client sending `Expect: 100-continue` holds the body back until told to go
+        // ahead (RFC 9110 section 10.1.1). Oversized bodies were answered above instead.
+        if let Some(expect) = headers.get(header::EXPECT) {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                return Err(RequestError::ExpectationFailed);
+            }
+            if chunked || len > 0 {
+                let stream = reader.get_mut();
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                stream.flush().await?;
+            }
+        }
+
+        let (body, unread_body, chunked) = if chunked {
+            let (body, rest) = Self::read_chunked(reader, max_body_size, buffer_limit).await?;
+            (body, 0, rest)
+        } else if len > buffer_limit {
+            (Vec::new(), len, None)
+        } else {
+            let mut body = vec![0_u8; len as usize];
+            reader.read_exact(&mut body).await?;
+            (body, 0, None)
+        };
+
+        Ok(HttpRequest {
             method,
-            path,
+            uri,
             headers,
             body,
+            unread_body,
+            chunked,
+            body_stream: Mutex::default(),
+            params: Mutex::default(),
+            decompression: None,
+            connection_info: Arc::default(),
+            context: RequestContext::default(),
+            extensions: Extensions::default(),
         })
     }
 
+    // Whether part of the body is still on the connection rather than in `body`
+    pub(crate) fn body_pending(&self) -> bool {
+        self.unread_body > 0 || self.chunked.is_some()
+    }
+
+    // Decodes a chunked body up to `buffer_limit` bytes; the decoder comes back when
+    // there is more to stream
+    async fn read_chunked<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        max_body_size: Option<u64>,
+        buffer_limit: u64,
+    ) -> Result<(Vec<u8>, Option<ChunkedBody>), RequestError> {
+        let mut decoder = ChunkedBody::new(max_body_size);
+        let mut body = Vec::new();
+        let mut buf = [0_u8; 8 * 1024];
+        loop {
+            let room = buffer_limit.saturating_sub(body.len() as u64);
+            if room == 0 {
+                return Ok((body, Some(decoder)));
+            }
+            let want = buf.len().min(usize::try_from(room).unwrap_or(usize::MAX));
+            let n = decoder
+                .read(reader, &mut buf[..want])
+                .await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::FileTooLarge => RequestError::BodyTooLarge,
+                    io::ErrorKind::InvalidData => RequestError::Malformed("invalid chunked body"),
+                    _ => e.into(),
+                })?;
+            if n == 0 {
+                return Ok((body, None));
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    // The body as a reader, so handlers can process it incrementally (hashing, parsing
+    // NDJSON) without caring whether it was buffered. A streamed body is read straight
+    // off the connection, bounded by Content-Length, and can only be taken once: later
+    // calls see an empty body.
+    pub fn body_reader(&self) -> BodyReader<'_> {
+        let raw = match self.body_stream.lock().unwrap().take() {
+            Some(stream) => BodyReader::Streaming(stream),
+            None => BodyReader::Buffered(&self.body),
+        };
+        let gzipped = self
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("gzip"));
+        match self.decompression {
+            Some(limits) if gzipped => BodyReader::Gzip(Box::new(GzipBody::new(raw, limits))),
+            _ => raw,
+        }
+    }
+
+    // The request as it arrived, reframed for a one-shot connection: the buffered body
+    // with its Content-Length, and `Connection: close`. Used to forward and record
+    // requests.
+    pub fn to_wire(&self) -> Vec<u8> {
+        // The path goes out encoded again: decoded, a space would break the request line
+        // and a `%3F` would turn into the start of the query
+        let mut target = match self.uri.is_asterisk() {
+            true => "*".to_string(),
+            false => utils::encode_path(self.path()),
+        };
+        if let Some(query) = self.uri.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let mut wire = format!("{} {target} HTTP/1.1\r\n", self.method);
+        for (name, value) in self.headers.iter() {
+            if !matches!(
+                name,
+                header::CONNECTION | header::CONTENT_LENGTH | header::TRANSFER_ENCODING
+            ) {
+                wire.push_str(&format!("{}: {}\r\n", header::canonical_name(name), value));
+            }
+        }
+        wire.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        wire.push_str("Connection: close\r\n\r\n");
+
+        let mut wire = wire.into_bytes();
+        wire.extend_from_slice(&self.body);
+        wire
+    }
+
+    // The decoded, normalized path; routing and file lookups go through this
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    // What the route captured, e.g. "id" for `/users/{id}`
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.params
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    // Every Cookie header's pairs, in the order sent
+    pub fn cookies(&self) -> Cookies {
+        let pairs = self
+            .headers
+            .get_all(header::COOKIE)
+            .flat_map(|line| Cookies::parse(line).pairs)
+            .collect();
+        Cookies { pairs }
+    }
+
+    // The session the `Sessions` middleware loaded; None without it
+    pub fn session(&self) -> Option<Arc<Session>> {
+        self.extensions.get::<Session>()
+    }
+
     // Helper: Parse first line
-    fn parse_request_line(line: &str) -> Option<(HttpMethod, String)> {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        let method = match parts.first()? {
-            &"POST" => HttpMethod::Post,
-            _ => HttpMethod::Get,
+    fn parse_request_line(line: &str) -> Result<(HttpMethod, Uri), RequestError> {
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(RequestError::Malformed("invalid request line"));
         };
-        let path = parts.get(1)?.to_string();
-        Some((method, path))
+        let uri =
+            Uri::parse(target).map_err(|_| RequestError::Malformed("invalid request target"))?;
+        // Any token is a valid method name (RFC 9110 section 9.1), the same grammar as a
+        // field name; one that isn't known here is a 501, not a bad request
+        if !header::is_valid_name(method) {
+            return Err(RequestError::Malformed("invalid method"));
+        }
+        let method = HttpMethod::parse(method).ok_or(RequestError::UnknownMethod)?;
+        Ok((method, uri))
     }
 
-    // Helper: Parse headers into HashMap using functional style
-    async fn parse_headers(reader: &mut BufReader<TcpStream>) -> Option<HashMap<String, String>> {
-        let mut headers = HashMap::new();
+    // Helper: Parse the header block, keeping every value of a repeated field
+    async fn parse_headers<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        limits: &HeadLimits,
+    ) -> Result<Headers, RequestError> {
+        let mut headers = Headers::new();
+        let mut budget = limits.max_header_bytes;
+        let mut count = 0;
         loop {
-            let mut line = String::new();
-            reader.read_line(&mut line).await.ok()?;
+            let line = read_line_within(reader, budget)
+                .await?
+                .ok_or(RequestError::HeadersTooLarge)?;
+            if line.is_empty() {
+                // EOF before the blank line: the request was cut off
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            budget -= line.len();
 
             if line == "\r\n" || line == "\n" {
                 break;
             }
+            count += 1;
+            if count > limits.max_headers {
+                return Err(RequestError::HeadersTooLarge);
+            }
 
-            if let Some((k, v)) = line.split_once(": ") {
-                headers.insert(k.to_lowercase(), v.trim().to_string());
+            // No whitespace before the colon (RFC 9112 section 5.1), and no obsolete
+            // line folding, which starts with whitespace and so has an invalid name
+            let (name, value) = line
+                .split_once(':')
+                .filter(|(name, _)| header::is_valid_name(name))
+                .ok_or(RequestError::Malformed("invalid header line"))?;
+            let (name, value) = (name.to_lowercase(), value.trim());
+            // Repeated Content-Lengths are only harmless when they agree; any other
+            // repeat is kept, so a second Transfer-Encoding can't hide behind the first
+            match headers.get(&name) {
+                Some(first) if name == header::CONTENT_LENGTH && first != value => {
+                    return Err(RequestError::Malformed("conflicting content-length"));
+                }
+                Some(_) if name == header::CONTENT_LENGTH => {}
+                _ => headers.append(name, value),
             }
         }
 
-        Some(headers)
+        Ok(headers)
+    }
+}
+
+// One line, line break included, read no further than `limit` bytes: empty at EOF,
+// None when the line doesn't end within the limit
+async fn read_line_within<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let n = (&mut *reader)
+        .take(limit as u64)
+        .read_line(&mut line)
+        .await?;
+    Ok((n < limit || line.ends_with('\n')).then_some(line))
+}
+
+// Longest chunk-size or trailer line accepted
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+
+// Decoder for a `Transfer-Encoding: chunked` body (RFC 9112 section 7.1). Chunk
+// extensions and trailer fields are read and dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkedBody {
+    // Data bytes left in the current chunk; 0 at a chunk-size line
+    remaining: u64,
+    // Data bytes decoded so far, held to `max`
+    total: u64,
+    max: Option<u64>,
+    done: bool,
+}
+
+impl ChunkedBody {
+    pub(crate) fn new(max: Option<u64>) -> Self {
+        ChunkedBody {
+            remaining: 0,
+            total: 0,
+            max,
+            done: false,
+        }
+    }
+
+    // Decodes into `buf`; 0 once the last chunk and trailers have been read. Bad
+    // framing is InvalidData, a body over the limit FileTooLarge. Reads nothing past
+    // the end of the body, so the connection can carry on with the next request.
+    pub(crate) async fn read<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = read_chunk_line(reader).await?;
+            // Extensions follow the size after a ';'
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .ok()
+                .filter(|_| !size.starts_with('+'))
+                .ok_or_else(|| malformed("bad chunk size"))?;
+            if size == 0 {
+                // The last chunk, then trailer fields up to a blank line
+                while !read_chunk_line(reader).await?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+            self.total = self.total.saturating_add(size);
+            if self.max.is_some_and(|max| self.total > max) {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "chunked body too large",
+                ));
+            }
+            self.remaining = size;
+        }
+
+        let want = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = reader.read(&mut buf[..want]).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 && !read_chunk_line(reader).await?.is_empty() {
+            return Err(malformed("chunk data longer than its size"));
+        }
+        Ok(n)
+    }
+}
+
+// One CRLF-terminated line, without the line break. Read a byte at a time so nothing
+// after the body is consumed; these lines are short.
+async fn read_chunk_line<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        match reader.read_u8().await? {
+            b'\n' => break,
+            byte if line.len() < MAX_CHUNK_LINE => line.push(byte),
+            _ => return Err(malformed("chunk line too long")),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
     }
+    String::from_utf8(line).map_err(|_| malformed("chunk line is not text"))
+}
 
-    // Helper: Complete the body read
-    async fn read_body(
-        reader: &mut BufReader<TcpStream>,
-        headers: &HashMap<String, String>,
-    ) -> Option<Vec<u8>> {
-        let len = headers
-            .get("content-length")
-            .and_then(|v| v.parse::<usize>().ok())
-            .unwrap_or(0);
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
 
-        let mut body = vec![0_u8; len];
-        reader.read_exact(&mut body).await.ok()?;
-        Some(body)
+// Renders the request the way it looked on the wire, with the body summarised.
+// Use `{:#}` to include a preview of the body bytes.
+impl fmt::Display for HttpRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} HTTP/1.1\r\n", self.method, self.uri)?;
+        for (key, value) in self.headers.iter() {
+            write!(f, "{}: {}\r\n", key, value)?;
+        }
+        f.write_str("\r\n")?;
+        utils::fmt_body(f, &self.body)
     }
 }
 
@@ -116,14 +578,66 @@ mod tests {
     fn parse_request_line_get_defaults_to_get() {
         let (m, path) = HttpRequest::parse_request_line("GET /hello HTTP/1.1\r\n").unwrap();
         assert!(matches!(m, HttpMethod::Get));
-        assert_eq!(path, "/hello");
+        assert_eq!(path.path(), "/hello");
     }
 
     #[test]
     fn parse_request_line_post() {
         let (m, path) = HttpRequest::parse_request_line("POST /files/a.txt HTTP/1.1\r\n").unwrap();
         assert!(matches!(m, HttpMethod::Post));
-        assert_eq!(path, "/files/a.txt");
+        assert_eq!(path.path(), "/files/a.txt");
+    }
+
+    #[test]
+    fn parse_request_line_normalizes_the_target() {
+        let (_, uri) =
+            HttpRequest::parse_request_line("GET /files/../echo//hi%21?x=1 HTTP/1.1\r\n").unwrap();
+        assert_eq!(uri.path(), "/echo/hi!");
+        assert_eq!(uri.query(), Some("x=1"));
+    }
+
+    #[test]
+    fn parse_request_line_rejects_invalid_target() {
+        assert!(HttpRequest::parse_request_line("GET /%zz HTTP/1.1\r\n").is_err());
+        assert!(HttpRequest::parse_request_line("GET relative HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_request_line_refuses_unknown_methods() {
+        let unknown = HttpRequest::parse_request_line("BREW /pot HTTP/1.1\r\n");
+        assert!(matches!(unknown, Err(RequestError::UnknownMethod)));
+        let garbage = HttpRequest::parse_request_line("GE(T /pot HTTP/1.1\r\n");
+        assert!(matches!(
+            garbage,
+            Err(RequestError::Malformed("invalid method"))
+        ));
+    }
+
+    #[test]
+    fn parse_request_line_recognises_all_methods() {
+        for name in [
+            "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+        ] {
+            let (m, _) =
+                HttpRequest::parse_request_line(&format!("{name} / HTTP/1.1\r\n")).unwrap();
+            assert_eq!(m.as_str(), name);
+        }
+    }
+
+    #[test]
+    fn method_names_are_case_sensitive() {
+        assert_eq!(HttpMethod::parse("DELETE"), Some(HttpMethod::Delete));
+        assert_eq!(HttpMethod::parse("delete"), None);
+    }
+
+    #[test]
+    fn method_safety_and_idempotency() {
+        assert!(HttpMethod::Get.is_safe() && HttpMethod::Get.is_idempotent());
+        assert!(HttpMethod::Head.is_safe());
+        assert!(!HttpMethod::Put.is_safe() && HttpMethod::Put.is_idempotent());
+        assert!(!HttpMethod::Delete.is_safe() && HttpMethod::Delete.is_idempotent());
+        assert!(!HttpMethod::Post.is_safe() && !HttpMethod::Post.is_idempotent());
+        assert!(!HttpMethod::Patch.is_idempotent());
     }
 
     #[tokio::test]
@@ -134,18 +648,14 @@ mod tests {
         write_request(req_bytes, client).await;
 
         let mut reader = BufReader::new(server);
-        let req = HttpRequest::from_stream(&mut reader).await.unwrap();
+        let req = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
 
         assert!(matches!(req.method, HttpMethod::Get));
-        assert_eq!(req.path, "/echo/hello");
-        assert_eq!(
-            req.headers.get("host").map(|s| s.as_str()),
-            Some("localhost")
-        );
-        assert_eq!(
-            req.headers.get("user-agent").map(|s| s.as_str()),
-            Some("curl")
-        );
+        assert_eq!(req.path(), "/echo/hello");
+        assert_eq!(req.headers.get("host"), Some("localhost"));
+        assert_eq!(req.headers.get("user-agent"), Some("curl"));
         assert!(req.body.is_empty());
     }
 
@@ -163,13 +673,15 @@ mod tests {
         write_request(req.as_bytes(), client).await;
 
         let mut reader = BufReader::new(server);
-        let req = HttpRequest::from_stream(&mut reader).await.unwrap();
+        let req = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
 
         assert!(matches!(req.method, HttpMethod::Post));
-        assert_eq!(req.path, "/files/x.txt");
+        assert_eq!(req.path(), "/files/x.txt");
         assert_eq!(
             req.headers.get("content-length").unwrap(),
-            &body.len().to_string()
+            body.len().to_string()
         );
         assert_eq!(req.body, body);
     }
@@ -182,22 +694,439 @@ mod tests {
         write_request(req_bytes, client).await;
 
         let mut reader = BufReader::new(server);
-        let req = HttpRequest::from_stream(&mut reader).await.unwrap();
+        let req = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
 
         assert_eq!(req.headers.get("user-agent").unwrap(), "TestUA");
         assert_eq!(req.headers.get("x-custom").unwrap(), "Value");
-        assert!(req.headers.get("User-Agent").is_none());
+        let names: Vec<_> = req.headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["user-agent", "x-custom"]);
+    }
+
+    #[test]
+    fn cookies_come_from_every_cookie_header() {
+        let mut req = HttpRequest::default();
+        req.headers
+            .append(header::COOKIE, "sid=abc; theme=\"dark\"");
+        req.headers.append(header::COOKIE, "lang=en");
+        let cookies = req.cookies();
+        assert_eq!(cookies.get("theme"), Some("dark"));
+        assert_eq!(cookies.get("lang"), Some("en"));
+        assert_eq!(cookies.pairs.len(), 3);
+        assert!(HttpRequest::default().cookies().pairs.is_empty());
     }
 
     #[tokio::test]
-    async fn returns_none_on_closed_connection() {
+    async fn repeated_headers_keep_every_value() {
+        let (server, client) = connected_pair().await;
+        let req_bytes =
+            b"GET / HTTP/1.1\r\nCookie: a=1\r\nAccept: text/html\r\ncookie: b=2\r\n\r\n";
+
+        write_request(req_bytes, client).await;
+
+        let mut reader = BufReader::new(server);
+        let req = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
+
+        assert_eq!(req.headers.get(header::COOKIE), Some("a=1, b=2"));
+        let cookies: Vec<_> = req.headers.get_all(header::COOKIE).collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(req.headers.get(header::ACCEPT), Some("text/html"));
+    }
+
+    #[test]
+    fn display_renders_wire_format_with_body_summary() {
+        let headers = Headers::from([("host", "localhost")]);
+        let req = HttpRequest {
+            method: HttpMethod::Post,
+            uri: Uri::parse("/files/a.bin").unwrap(),
+            headers,
+            body: vec![0, 1, b'a'],
+            ..Default::default()
+        };
+
+        let plain = req.to_string();
+        assert!(plain.starts_with("POST /files/a.bin HTTP/1.1\r\nhost: localhost\r\n\r\n"));
+        assert!(plain.ends_with("[3 bytes]"));
+
+        let detailed = format!("{req:#}");
+        assert!(detailed.ends_with("[3 bytes]\r\n\\x00\\x01a"));
+    }
+
+    #[tokio::test]
+    async fn wire_form_keeps_the_target_encoded() {
+        let req = HttpRequest {
+            uri: Uri::parse("/files/a%20b%3Fc.txt?q=1").unwrap(),
+            ..Default::default()
+        };
+        let wire = req.to_wire();
+        assert!(wire.starts_with(b"GET /files/a%20b%3Fc.txt?q=1 HTTP/1.1\r\n"));
+
+        // Parsed back, it names the same file with the same query
+        let (server, client) = connected_pair().await;
+        write_request(&wire, client).await;
+        let mut reader = BufReader::new(server);
+        let parsed = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(parsed.path(), "/files/a b?c.txt");
+        assert_eq!(parsed.uri.query(), Some("q=1"));
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_an_error_not_a_hang() {
+        let (server, client) = connected_pair().await;
+        write_request(b"GET / HTTP/1.1\r\nHost: localhost\r\n", client).await;
+
+        let mut reader = BufReader::new(server);
+        let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RequestError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn malformed_request_line_is_reported() {
+        let (server, client) = connected_pair().await;
+        write_request(b"GARBAGE\r\n\r\n", client).await;
+
+        let mut reader = BufReader::new(server);
+        let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RequestError::Malformed(_)));
+    }
+
+    #[tokio::test]
+    async fn bad_headers_and_lengths_are_reported() {
+        let cases: [(&[u8], &str); 4] = [
+            (
+                b"GET / HTTP/1.1\r\nno colon here\r\n\r\n",
+                "invalid header line",
+            ),
+            (b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", "invalid header line"),
+            (
+                b"GET / HTTP/1.1\r\nX-A: 1\r\n folded\r\n\r\n",
+                "invalid header line",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+                "invalid content-length",
+            ),
+        ];
+        for (request, reason) in cases {
+            let (server, client) = connected_pair().await;
+            write_request(request, client).await;
+
+            let mut reader = BufReader::new(server);
+            let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, RequestError::Malformed(r) if r == reason),
+                "{err}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_connection_closed_on_closed_connection() {
         let (server, client) = connected_pair().await;
         // Immediately close client's write side without sending anything
         let mut client = client;
         client.shutdown().await.unwrap();
 
         let mut reader = BufReader::new(server);
-        let req = HttpRequest::from_stream(&mut reader).await;
-        assert!(req.is_none());
+        let req =
+            HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default()).await;
+        assert!(matches!(req, Err(RequestError::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_refused_before_reading() {
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"POST /files/a HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world",
+            client,
+        )
+        .await;
+
+        let mut reader = BufReader::new(server);
+        let err = HttpRequest::from_stream(&mut reader, Some(10), u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RequestError::BodyTooLarge));
+    }
+
+    #[tokio::test]
+    async fn large_body_is_left_on_the_connection() {
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"POST /files/a HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world",
+            client,
+        )
+        .await;
+
+        let mut reader = BufReader::new(server);
+        let req = HttpRequest::from_stream(&mut reader, None, 4, &HeadLimits::default())
+            .await
+            .unwrap();
+        assert!(req.body.is_empty());
+        assert_eq!(req.unread_body, 11);
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "hello world");
+    }
+
+    #[tokio::test]
+    async fn chunked_body_is_decoded() {
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\nnext",
+            client,
+        )
+        .await;
+
+        let mut reader = BufReader::new(server);
+        let req = HttpRequest::from_stream(&mut reader, Some(11), u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(req.body, b"hello world");
+        assert!(!req.body_pending());
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+    }
+
+    #[tokio::test]
+    async fn ambiguous_framing_is_refused() {
+        let cases: [(&[u8], &str); 4] = [
+            (
+                b"Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+                "both transfer-encoding and content-length",
+            ),
+            (
+                b"Content-Length: 5\r\nContent-Length: 6\r\n",
+                "conflicting content-length",
+            ),
+            (
+                b"Transfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n",
+                "unsupported transfer-encoding",
+            ),
+            (
+                b"Transfer-Encoding: gzip\r\n",
+                "unsupported transfer-encoding",
+            ),
+        ];
+        for (headers, reason) in cases {
+            let (server, client) = connected_pair().await;
+            let mut req = b"POST /a HTTP/1.1\r\n".to_vec();
+            req.extend_from_slice(headers);
+            req.extend_from_slice(b"\r\n0\r\n\r\n");
+            write_request(&req, client).await;
+
+            let mut reader = BufReader::new(server);
+            let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, RequestError::Malformed(r) if r == reason),
+                "{err}"
+            );
+        }
+
+        // The same length twice is just redundant
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"POST /a HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi",
+            client,
+        )
+        .await;
+        let mut reader = BufReader::new(server);
+        let req = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(req.body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn bad_or_oversized_chunked_bodies_are_refused() {
+        let cases: [(&[u8], Option<u64>); 3] = [
+            (b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", Some(10)),
+            (b"zz\r\nhello\r\n0\r\n\r\n", None),
+            (b"3\r\nhello\r\n0\r\n\r\n", None),
+        ];
+        for (i, (body, max)) in cases.into_iter().enumerate() {
+            let (server, client) = connected_pair().await;
+            let mut req = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            req.extend_from_slice(body);
+            write_request(&req, client).await;
+
+            let mut reader = BufReader::new(server);
+            let err = HttpRequest::from_stream(&mut reader, max, u64::MAX, &HeadLimits::default())
+                .await
+                .unwrap_err();
+            let expected = if i == 0 {
+                matches!(err, RequestError::BodyTooLarge)
+            } else {
+                matches!(err, RequestError::Malformed(_))
+            };
+            assert!(expected, "case {i}: {err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn large_chunked_body_keeps_its_decoder() {
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              b\r\nhello world\r\n0\r\n\r\n",
+            client,
+        )
+        .await;
+
+        let mut reader = BufReader::new(server);
+        let req = HttpRequest::from_stream(&mut reader, None, 4, &HeadLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(req.body, b"hell");
+        assert!(req.body_pending());
+
+        let mut decoder = req.chunked.unwrap();
+        let mut rest = [0_u8; 16];
+        let n = decoder.read(&mut reader, &mut rest).await.unwrap();
+        assert_eq!(&rest[..n], b"o world");
+        assert_eq!(decoder.read(&mut reader, &mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_answered_before_the_body() {
+        let (server, mut client) = connected_pair().await;
+        client
+            .write_all(
+                b"PUT /files/a HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut reader = BufReader::new(server);
+        let limits = HeadLimits::default();
+        let (req, interim) = tokio::join!(
+            HttpRequest::from_stream(&mut reader, None, u64::MAX, &limits),
+            async {
+                let mut interim = [0_u8; 25];
+                client.read_exact(&mut interim).await.unwrap();
+                client.write_all(b"hello").await.unwrap();
+                interim
+            }
+        );
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(req.unwrap().body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn requests_arriving_a_byte_at_a_time_are_parsed() {
+        let (server, mut client) = tokio::io::duplex(1);
+        let request = b"POST /echo/x HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi";
+        let mut reader = BufReader::new(server);
+        let limits = HeadLimits::default();
+        let (req, ()) = tokio::join!(
+            HttpRequest::from_stream(&mut reader, None, u64::MAX, &limits),
+            async {
+                for byte in request {
+                    client.write_all(&[*byte]).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }
+        );
+        let req = req.unwrap();
+        assert_eq!(req.path(), "/echo/x");
+        assert_eq!(req.headers.get("host"), Some("a"));
+        assert_eq!(req.body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn any_stream_can_carry_a_request() {
+        let (server, mut client) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"POST /echo/x HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut reader = BufReader::new(server);
+        let limits = HeadLimits::default();
+        let (req, interim) = tokio::join!(
+            HttpRequest::from_stream(&mut reader, None, u64::MAX, &limits),
+            async {
+                let mut interim = [0_u8; 25];
+                client.read_exact(&mut interim).await.unwrap();
+                client.write_all(b"hi").await.unwrap();
+                interim
+            }
+        );
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        let req = req.unwrap();
+        assert_eq!(req.path(), "/echo/x");
+        assert_eq!(req.body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn unknown_expectations_fail() {
+        let (server, client) = connected_pair().await;
+        write_request(
+            b"PUT /files/a HTTP/1.1\r\nContent-Length: 5\r\nExpect: 200-ok\r\n\r\nhello",
+            client,
+        )
+        .await;
+
+        let mut reader = BufReader::new(server);
+        let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &HeadLimits::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RequestError::ExpectationFailed));
+    }
+
+    #[tokio::test]
+    async fn oversized_heads_are_refused() {
+        let limits = HeadLimits {
+            max_request_line: 32,
+            max_header_bytes: 64,
+            max_headers: 2,
+        };
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        let cases = [
+            long_target,
+            format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "b".repeat(60)),
+            "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n".to_string(),
+        ];
+        for (i, request) in cases.iter().enumerate() {
+            let (server, client) = connected_pair().await;
+            write_request(request.as_bytes(), client).await;
+
+            let mut reader = BufReader::new(server);
+            let err = HttpRequest::from_stream(&mut reader, None, u64::MAX, &limits)
+                .await
+                .unwrap_err();
+            let expected = match i {
+                0 => matches!(err, RequestError::UriTooLong),
+                _ => matches!(err, RequestError::HeadersTooLarge),
+            };
+            assert!(expected, "case {i}: {err:?}");
+        }
+
+        // Right at the limits is fine
+        let (server, client) = connected_pair().await;
+        write_request(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n", client).await;
+        let mut reader = BufReader::new(server);
+        HttpRequest::from_stream(&mut reader, None, u64::MAX, &limits)
+            .await
+            .unwrap();
     }
 }
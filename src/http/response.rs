@@ -1,80 +1,510 @@
-use crate::http::HttpRequest;
+use crate::http::body::Trailers;
+use crate::http::date;
+use crate::http::header::{self, CacheControl, ContentLength};
+use crate::http::request::HttpMethod;
+use crate::http::sendfile;
+use crate::http::upgrade::OnUpgrade;
+use crate::http::{Body, Cookie, Headers, HttpRequest, StatusCode, Upgraded};
+use crate::logging::trace;
 use crate::utils;
-use std::collections::HashMap;
-use tokio::io::AsyncWriteExt;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+// A Content-Length set by the handler that the body can't honour
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FramingError {
+    #[error("invalid Content-Length {0:?}")]
+    InvalidLength(String),
+    #[error("Content-Length {declared} does not match the {actual}-byte body")]
+    LengthMismatch { declared: u64, actual: u64 },
+}
+
+// Written first, in this order, so every response's head reads the same way
+const LEADING_HEADERS: &[&str] = &[
+    header::DATE,
+    header::SERVER,
+    header::CONNECTION,
+    header::KEEP_ALIVE,
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::CONTENT_ENCODING,
+];
+
+// Headers added to every response on its way out, unless the handler set them itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseDefaults {
+    // Value of the Server header; None leaves it out
+    pub server: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for ResponseDefaults {
+    fn default() -> Self {
+        ResponseDefaults {
+            server: Some(env!("CARGO_PKG_NAME").to_string()),
+            headers: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpResponse {
-    status: String,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
+    status: StatusCode,
+    headers: Headers,
+    body: Body,
+    upgrade: Option<OnUpgrade>,
+    no_compress: bool,
+    trailers: Option<Trailers>,
 }
 
 impl HttpResponse {
     // A helper to make creating common responses easier
-    pub fn new(status: &str, content_type: &str, body: Vec<u8>) -> Self {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type".to_string(), content_type.to_string());
+    pub fn new(status: StatusCode, content_type: &str, body: Vec<u8>) -> Self {
+        Self::with_body(status, content_type, body.into())
+    }
+
+    // For bodies that don't live in memory (files, streams)
+    pub fn with_body(status: StatusCode, content_type: &str, body: Body) -> Self {
+        let headers = Headers::from([(header::CONTENT_TYPE, content_type)]);
 
         Self {
-            status: status.to_string(),
+            status,
             headers,
             body,
+            upgrade: None,
+            no_compress: false,
+            trailers: None,
+        }
+    }
+
+    // 101 Switching Protocols. Once this is written the connection leaves the HTTP loop
+    // and `on_upgrade` owns it (WebSockets, h2c, custom protocols).
+    pub fn switching_protocols<F, Fut>(protocol: &str, on_upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let headers = Headers::from([(header::CONNECTION, "upgrade"), (header::UPGRADE, protocol)]);
+
+        Self {
+            status: StatusCode::SWITCHING_PROTOCOLS,
+            headers,
+            body: Body::Empty,
+            upgrade: Some(OnUpgrade::new(on_upgrade)),
+            no_compress: false,
+            trailers: None,
         }
     }
 
-    pub async fn send(
+    // Starts from an empty 200 with no headers at all
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: HttpResponse {
+                status: StatusCode::OK,
+                headers: Headers::new(),
+                body: Body::Empty,
+                upgrade: None,
+                no_compress: false,
+                trailers: None,
+            },
+        }
+    }
+
+    // Empty, like the server's own responses
+    pub fn ok() -> Self {
+        Self::new(StatusCode::OK, "text/plain", vec![])
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "text/plain", vec![])
+    }
+
+    // A 200 with a document the caller serialized
+    pub fn json(body: impl Into<String>) -> Self {
+        Self::new(StatusCode::OK, "application/json", body.into().into_bytes())
+    }
+
+    // A 301 or 302, which clients may follow with a GET whatever the method was; fine
+    // for pages and the like
+    pub fn redirect(location: impl Into<String>, permanent: bool) -> Self {
+        let status = match permanent {
+            true => StatusCode::MOVED_PERMANENTLY,
+            false => StatusCode::FOUND,
+        };
+        Self::located(status, location.into())
+    }
+
+    // A 308 or 307, which clients follow with the same method and body; for redirecting
+    // uploads and API calls
+    pub fn redirect_preserving_method(location: impl Into<String>, permanent: bool) -> Self {
+        let status = match permanent {
+            true => StatusCode::PERMANENT_REDIRECT,
+            false => StatusCode::TEMPORARY_REDIRECT,
+        };
+        Self::located(status, location.into())
+    }
+
+    fn located(status: StatusCode, location: String) -> Self {
+        let mut response = Self::new(status, "text/plain", vec![]);
+        response.set_header(header::LOCATION, location);
+        response
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter()
+    }
+
+    // Names are stored lowercased, matching the header:: constants
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers.insert(name, value);
+    }
+
+    // Adds a value without replacing the field's others; each goes out on its own line
+    pub fn append_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers.append(name, value);
+    }
+
+    // One Set-Cookie line per cookie, however many are added
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.headers.append(header::SET_COOKIE, cookie.to_string());
+    }
+
+    // Declares the named trailer fields for a streamed body. Values added through the
+    // returned handle, up to the moment the stream ends, go out after its last chunk.
+    // Fields that can't be trailers (Content-Length, Transfer-Encoding, ...) are left out.
+    pub fn trailers(&mut self, names: &[&str]) -> Trailers {
+        let trailers = self.trailers.get_or_insert_default().clone();
+        let declared = trailers.declare(names);
+        self.set_header(header::TRAILER, declared.join(", "));
+        trailers
+    }
+
+    // Replaces the body and its Content-Type, keeping the status and other headers
+    pub fn set_body(&mut self, content_type: &str, body: Vec<u8>) {
+        self.body = body.into();
+        self.set_header(header::CONTENT_TYPE, content_type);
+    }
+
+    // Bytes this response holds in memory: headers plus a buffered body; files and
+    // streams are not counted
+    pub fn memory_size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let body = match &self.body {
+            Body::Bytes(bytes) => bytes.len(),
+            _ => 0,
+        };
+        headers + body
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn body_is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+
+    // Sends the body exactly as given, whatever the client accepts
+    pub fn no_compress(&mut self) {
+        self.no_compress = true;
+    }
+
+    // Explicit opt-out, Cache-Control: no-transform, or a body that is already encoded
+    // or is a byte range of the representation: none of these may be recompressed
+    pub(crate) fn compressible(&self) -> bool {
+        let no_transform = self
+            .header(header::CACHE_CONTROL)
+            .is_some_and(|v| CacheControl::parse(v).no_transform);
+        !self.no_compress
+            && !no_transform
+            && !self.headers.contains_key(header::CONTENT_ENCODING)
+            && !self.headers.contains_key(header::CONTENT_RANGE)
+    }
+
+    // Only in-memory bodies are compressed; files and streams go out as they are
+    pub(crate) fn gzip(&mut self) {
+        if let Body::Bytes(bytes) = &self.body {
+            self.body = Body::Bytes(utils::compress_body(bytes));
+            self.headers.insert(header::CONTENT_ENCODING, "gzip");
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    // The 304 for a client that already holds this response: its validators and
+    // caching headers, without the body or anything describing it
+    pub fn not_modified(&self) -> HttpResponse {
+        const KEPT: &[&str] = &[
+            header::CACHE_CONTROL,
+            header::CONTENT_LOCATION,
+            header::DATE,
+            header::ETAG,
+            header::EXPIRES,
+            header::LAST_MODIFIED,
+            header::VARY,
+            header::AGE,
+        ];
+        let headers = self
+            .headers
+            .iter()
+            .filter(|(name, _)| KEPT.contains(name))
+            .collect();
+        HttpResponse {
+            status: StatusCode::NOT_MODIFIED,
+            headers,
+            body: Body::Empty,
+            upgrade: None,
+            no_compress: false,
+            trailers: None,
+        }
+    }
+
+    // Only in-memory responses can be duplicated (e.g. by the response cache)
+    pub(crate) fn try_clone(&self) -> Option<HttpResponse> {
+        let body = match &self.body {
+            Body::Empty => Body::Empty,
+            Body::Bytes(bytes) => Body::Bytes(bytes.clone()),
+            Body::File { .. } | Body::Stream(_) => return None,
+        };
+        if self.upgrade.is_some() {
+            return None;
+        }
+        Some(HttpResponse {
+            status: self.status,
+            headers: self.headers.clone(),
+            body,
+            upgrade: None,
+            no_compress: self.no_compress,
+            trailers: self.trailers.clone(),
+        })
+    }
+
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade.take()
+    }
+
+    // Checks an explicitly set Content-Length against the body. Streams can't be
+    // checked up front; they are held to the declared length while being written.
+    pub fn check_length(&self) -> Result<(), FramingError> {
+        let Some(value) = self.header(header::CONTENT_LENGTH) else {
+            return Ok(());
+        };
+        let ContentLength(declared) = ContentLength::parse(value)
+            .ok_or_else(|| FramingError::InvalidLength(value.to_string()))?;
+        match self.body.len() {
+            Some(actual) if actual != declared => {
+                Err(FramingError::LengthMismatch { declared, actual })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the number of body bytes written, for the access log
+    pub async fn send<W>(
         mut self,
-        stream: &mut TcpStream,
+        stream: &mut W,
         req: &HttpRequest,
-    ) -> tokio::io::Result<()> {
-        // Handle GZIP Compression
-        let accept_encoding = req
-            .headers
-            .get("accept-encoding")
-            .map(|s| s.as_str())
-            .unwrap_or("");
+        defaults: &ResponseDefaults,
+    ) -> tokio::io::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let declared = self.send_head(stream, req, defaults).await?;
+        // HEAD gets the headers GET would, without the body
+        let written = match declared {
+            _ if req.method == HttpMethod::Head => 0,
+            Some(ContentLength(len)) => self.body.write_sized_to(stream, len).await?,
+            None => self.body.write_to(stream, self.trailers.as_ref()).await?,
+        };
+        stream.flush().await?; // Critical for persistent connections!
 
-        if accept_encoding.split(',').any(|s| s.trim() == "gzip") {
-            self.body = utils::compress_body(&self.body);
-            self.headers
-                .insert("Content-Encoding".to_string(), "gzip".to_string());
+        Ok(written)
+    }
+
+    // `send` to the connection's own stream, with nothing (throttling, capture) in
+    // between: file bodies then skip userspace with sendfile(2) when it is a TCP socket
+    pub(crate) async fn send_direct<S>(
+        mut self,
+        stream: &mut S,
+        req: &HttpRequest,
+        defaults: &ResponseDefaults,
+    ) -> tokio::io::Result<u64>
+    where
+        S: AsyncWrite + Unpin + Any,
+    {
+        let direct = matches!(self.body, Body::File { .. }) && req.method != HttpMethod::Head;
+        let Some(socket) = (stream as &mut dyn Any)
+            .downcast_mut::<TcpStream>()
+            .filter(|_| direct)
+        else {
+            return self.send(stream, req, defaults).await;
+        };
+        self.send_head(socket, req, defaults).await?;
+        let Body::File { file, offset, len } = std::mem::replace(&mut self.body, Body::Empty)
+        else {
+            unreachable!("checked above");
+        };
+        let written = sendfile::send_file(socket, file, offset, len).await?;
+        socket.flush().await?;
+        Ok(written)
+    }
+
+    // Everything before the body; returns the length a streamed body was declared with.
+    // Nothing is written if the framing would be wrong.
+    async fn send_head<W>(
+        &mut self,
+        stream: &mut W,
+        req: &HttpRequest,
+        defaults: &ResponseDefaults,
+    ) -> tokio::io::Result<Option<ContentLength>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.check_length()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let declared = match self.body {
+            Body::Stream(_) => self
+                .header(header::CONTENT_LENGTH)
+                .and_then(ContentLength::parse),
+            _ => None,
+        };
+        self.apply_defaults(defaults);
+        self.prepare(req);
+        trace!("response:\n{:#}", self);
+        stream.write_all(self.head().as_bytes()).await?;
+        Ok(declared)
+    }
+
+    fn apply_defaults(&mut self, defaults: &ResponseDefaults) {
+        let server = defaults.server.as_ref().map(|s| (header::SERVER, s));
+        let extra = defaults.headers.iter().map(|(k, v)| (k.as_str(), v));
+        for (name, value) in server.into_iter().chain(extra) {
+            if !self.headers.contains_key(name) {
+                self.headers.insert(name, value);
+            }
         }
+        if !self.headers.contains_key(header::DATE) {
+            self.headers.insert(header::DATE, date::now());
+        }
+    }
 
-        // Update Content-Length based on the final body size
-        self.headers
-            .insert("Content-Length".to_string(), self.body.len().to_string());
+    // Applies the per-request framing headers so the response matches exactly what
+    // goes on the wire. Compression happened earlier, in the `Gzip` middleware.
+    fn prepare(&mut self, req: &HttpRequest) {
+        // Known sizes get Content-Length, everything else is framed with chunked encoding.
+        // 1xx, 204 and 304 responses have no body and so no framing at all.
+        let status = self.status;
+        let bodiless = status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED;
+        match self.body.len() {
+            _ if bodiless => {}
+            Some(len) => {
+                self.headers
+                    .insert(header::CONTENT_LENGTH.to_string(), len.to_string());
+            }
+            // A stream with a declared length; send() holds it to that length
+            None if self.headers.contains_key(header::CONTENT_LENGTH) => {}
+            None => {
+                self.headers
+                    .insert(header::TRANSFER_ENCODING.to_string(), "chunked".to_string());
+            }
+        }
+        // Trailers need the chunked framing; without it there is nowhere to send them
+        if !self.headers.contains_key(header::TRANSFER_ENCODING) {
+            self.headers.remove(header::TRAILER);
+            self.trailers = None;
+        }
 
         // If the client asked to close, we should echo that back
-        if let Some(conn) = req.headers.get("connection")
+        if let Some(conn) = req.headers.get(header::CONNECTION)
             && conn.to_lowercase() == "close"
         {
             self.headers
-                .insert("Connection".to_string(), "close".to_string());
+                .insert(header::CONNECTION.to_string(), "close".to_string());
         }
+    }
 
-        // Construct the header string
-        let mut response_string = format!("HTTP/1.1 {}\r\n", self.status);
-        for (key, value) in &self.headers {
-            response_string.push_str(&format!("{}: {}\r\n", key, value));
+    // Construct the header string
+    fn head(&self) -> String {
+        // The reason phrase may be empty, but the space before it may not
+        let mut response_string = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or_default()
+        );
+        // Well-known fields lead in a fixed order, the rest follow as they were set
+        let mut lines: Vec<_> = self.headers.iter().collect();
+        lines.sort_by_key(|(name, _)| {
+            LEADING_HEADERS
+                .iter()
+                .position(|leading| leading == name)
+                .unwrap_or(LEADING_HEADERS.len())
+        });
+        for (key, value) in lines {
+            response_string.push_str(&format!("{}: {}\r\n", header::canonical_name(key), value));
         }
         response_string.push_str("\r\n"); // The critical empty line
+        response_string
+    }
+}
 
-        // Send everything
-        stream.write_all(response_string.as_bytes()).await?;
-        stream.write_all(&self.body).await?;
-        stream.flush().await?; // Critical for persistent connections!
+// `HttpResponse::builder().status(..).header(..).body(..)`, for responses that need
+// more than a status, a Content-Type and a body
+pub struct ResponseBuilder {
+    response: HttpResponse,
+}
 
-        Ok(())
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    // Replaces an earlier value for the same field
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.response.set_header(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> HttpResponse {
+        self.response.body = body.into();
+        self.response
+    }
+
+    // The response without a body
+    pub fn build(self) -> HttpResponse {
+        self.response
+    }
+}
+
+// Renders the status line and headers verbatim, with the body summarised.
+// Use `{:#}` to include a preview of the body bytes.
+impl fmt::Display for HttpResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.head())?;
+        fmt::Display::fmt(&self.body, f)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http::Uri;
+    use crate::http::body::TrailerError;
     use crate::http::request::HttpMethod;
-    use std::collections::HashMap;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
 
@@ -112,44 +542,109 @@ mod tests {
     fn get_header_value(headers: &str, name: &str) -> Option<String> {
         let wanted = name.to_lowercase();
         for line in headers.lines() {
-            if let Some((k, v)) = line.split_once(": ") {
-                if k.to_lowercase() == wanted {
-                    return Some(v.to_string());
-                }
+            if let Some((k, v)) = line.split_once(": ")
+                && k.to_lowercase() == wanted
+            {
+                return Some(v.to_string());
             }
         }
         None
     }
 
-    fn make_request(headers: HashMap<String, String>) -> HttpRequest {
+    fn make_request(headers: Headers) -> HttpRequest {
         HttpRequest {
             method: HttpMethod::Get,
-            path: "/".to_string(),
+            uri: Uri::parse("/").unwrap(),
             headers,
             body: vec![],
+            ..Default::default()
         }
     }
 
     #[test]
     fn new_sets_status_content_type_and_body() {
-        let resp = HttpResponse::new("200 OK", "text/plain", b"hello".to_vec());
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hello".to_vec());
 
-        assert_eq!(resp.status, "200 OK");
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.headers.get(header::CONTENT_TYPE), Some("text/plain"));
+        assert!(matches!(&resp.body, Body::Bytes(b) if b == b"hello"));
+    }
+
+    #[test]
+    fn status_line_uses_the_canonical_reason() {
+        let resp = HttpResponse::new(StatusCode::NOT_FOUND, "text/plain", vec![]);
+        assert!(resp.head().starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(resp.status_code().is_client_error());
+
+        // Codes without a known reason keep the space before the empty phrase
+        let custom = HttpResponse::new(StatusCode::from_u16(299).unwrap(), "text/plain", vec![]);
+        assert!(custom.head().starts_with("HTTP/1.1 299 \r\n"));
+    }
+
+    #[test]
+    fn builder_sets_status_headers_and_body() {
+        let mut resp = HttpResponse::builder()
+            .status(StatusCode::CREATED)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header("X-Foo", "bar")
+            .header("x-foo", "baz")
+            .body(b"made".to_vec());
+        resp.prepare(&make_request(Headers::new()));
         assert_eq!(
-            resp.headers.get("Content-Type").map(|s| s.as_str()),
-            Some("text/plain")
+            resp.to_string(),
+            "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\
+             X-Foo: baz\r\n\r\n[4 bytes]"
         );
-        assert_eq!(resp.body, b"hello");
+
+        let bare = HttpResponse::builder()
+            .status(StatusCode::NO_CONTENT)
+            .build();
+        assert!(bare.body_is_empty());
+        assert_eq!(bare.headers().count(), 0);
+        assert_eq!(
+            HttpResponse::not_found().status_code(),
+            StatusCode::NOT_FOUND
+        );
+        let json = HttpResponse::json("{}");
+        assert_eq!(json.header(header::CONTENT_TYPE), Some("application/json"));
+    }
+
+    #[test]
+    fn redirects_carry_their_location() {
+        for (response, status) in [
+            (HttpResponse::redirect("/a/", true), 301),
+            (HttpResponse::redirect("/a/", false), 302),
+            (HttpResponse::redirect_preserving_method("/a/", false), 307),
+            (HttpResponse::redirect_preserving_method("/a/", true), 308),
+        ] {
+            assert_eq!(response.status_code().as_u16(), status);
+            assert_eq!(response.header(header::LOCATION), Some("/a/"));
+            assert!(response.body_is_empty());
+        }
+    }
+
+    #[test]
+    fn display_shows_prepared_wire_format() {
+        let mut resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hello".to_vec());
+        resp.prepare(&make_request(Headers::new()));
+
+        let plain = resp.to_string();
+        assert!(plain.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(plain.contains("Content-Length: 5\r\n"));
+        assert!(plain.ends_with("\r\n\r\n[5 bytes]"));
+        assert!(format!("{resp:#}").ends_with("[5 bytes]\r\nhello"));
     }
 
     #[tokio::test]
     async fn send_writes_status_headers_and_body() {
         let (mut server, client) = connected_pair().await;
 
-        let req = make_request(HashMap::new());
-        let resp = HttpResponse::new("200 OK", "text/plain", b"hello".to_vec());
+        let req = make_request(Headers::new());
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hello".to_vec());
 
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_all(client).await;
@@ -165,20 +660,77 @@ mod tests {
             get_header_value(headers_str, "Content-Length").as_deref(),
             Some("5")
         );
+        let date = get_header_value(headers_str, "Date").unwrap();
+        assert!(crate::http::date::HttpDate::parse(&date).is_some());
+        assert!(get_header_value(headers_str, "Server").is_some());
         assert_eq!(body, b"hello");
     }
 
+    #[test]
+    fn well_known_headers_lead_and_the_rest_keep_their_order() {
+        let mut resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hi".to_vec());
+        resp.set_header("x-second", "2");
+        resp.set_header(header::ETAG, "\"v1\"");
+        resp.set_header("x-first", "1");
+        resp.apply_defaults(&ResponseDefaults::default());
+        resp.prepare(&make_request(Headers::new()));
+
+        let head = resp.head();
+        let names: Vec<_> = head
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':').map(|(name, _)| name))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Date",
+                "Server",
+                "Content-Type",
+                "Content-Length",
+                "X-Second",
+                "ETag",
+                "X-First"
+            ]
+        );
+    }
+
+    #[test]
+    fn appended_headers_go_out_on_separate_lines() {
+        let mut resp = HttpResponse::new(StatusCode::OK, "text/plain", Vec::new());
+        resp.append_header(
+            header::SET_COOKIE,
+            "a=1; Expires=Sun, 06 Nov 1994 08:49:37 GMT",
+        );
+        resp.append_header(header::SET_COOKIE, "b=2");
+        let head = resp.head();
+        assert!(head.contains("Set-Cookie: a=1; Expires=Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+        assert!(head.contains("Set-Cookie: b=2\r\n"));
+    }
+
+    #[test]
+    fn each_cookie_gets_its_own_line() {
+        let mut resp = HttpResponse::ok();
+        resp.add_cookie(Cookie::new("sid", "abc").http_only());
+        resp.add_cookie(Cookie::new("theme", "dark").path("/"));
+        let head = resp.head();
+        assert!(head.contains("Set-Cookie: sid=abc; HttpOnly\r\n"));
+        assert!(head.contains("Set-Cookie: theme=dark; Path=/\r\n"));
+    }
+
     #[tokio::test]
     async fn send_adds_connection_close_if_requested() {
         let (mut server, client) = connected_pair().await;
 
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
         headers.insert("connection".to_string(), "close".to_string());
 
         let req = make_request(headers);
-        let resp = HttpResponse::new("200 OK", "text/plain", vec![]);
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", vec![]);
 
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_all(client).await;
@@ -192,19 +744,16 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn send_gzips_body_when_accept_encoding_contains_gzip() {
-        use flate2::read::GzDecoder;
-        use std::io::Read;
-
+    async fn send_streams_body_with_chunked_encoding() {
         let (mut server, client) = connected_pair().await;
 
-        let mut headers = HashMap::new();
-        headers.insert("accept-encoding".to_string(), "gzip".to_string());
+        let req = make_request(Headers::new());
+        let resp =
+            HttpResponse::with_body(StatusCode::OK, "text/plain", Body::stream(&b"streamed"[..]));
 
-        let req = make_request(headers);
-        let resp = HttpResponse::new("200 OK", "text/plain", b"hello gzip".to_vec());
-
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_all(client).await;
@@ -212,66 +761,120 @@ mod tests {
         let headers_str = std::str::from_utf8(headers).unwrap();
 
         assert_eq!(
-            get_header_value(headers_str, "Content-Encoding").as_deref(),
-            Some("gzip")
+            get_header_value(headers_str, "Transfer-Encoding").as_deref(),
+            Some("chunked")
         );
+        assert_eq!(get_header_value(headers_str, "Content-Length"), None);
+        assert_eq!(body, b"8\r\nstreamed\r\n0\r\n\r\n");
+    }
 
-        let content_length = get_header_value(headers_str, "Content-Length")
-            .expect("missing Content-Length")
-            .parse::<usize>()
-            .unwrap();
-        assert_eq!(content_length, body.len());
+    #[tokio::test]
+    async fn declared_trailers_follow_a_streamed_body() {
+        let (mut server, client) = connected_pair().await;
 
-        let mut decoder = GzDecoder::new(body);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed).unwrap();
+        let mut resp =
+            HttpResponse::with_body(StatusCode::OK, "text/plain", Body::stream(&b"streamed"[..]));
+        let trailers = resp.trailers(&["x-checksum"]);
+        trailers.add_trailer("x-checksum", "abc").unwrap();
+        resp.send(
+            &mut server,
+            &make_request(Headers::new()),
+            &ResponseDefaults::default(),
+        )
+        .await
+        .unwrap();
+        server.shutdown().await.unwrap();
 
-        assert_eq!(decompressed, b"hello gzip");
-    }
+        let raw = read_all(client).await;
+        let (headers, body) = split_headers_body(&raw);
+        let headers_str = std::str::from_utf8(headers).unwrap();
+        assert_eq!(
+            get_header_value(headers_str, "Trailer").as_deref(),
+            Some("x-checksum")
+        );
+        assert_eq!(body, b"8\r\nstreamed\r\n0\r\nX-Checksum: abc\r\n\r\n");
 
-    #[tokio::test]
-    async fn send_gzips_body_when_accept_encoding_is_a_list_containing_gzip() {
-        use flate2::read::GzDecoder;
-        use std::io::Read;
+        // A body of known length isn't chunked, so the declaration is dropped
+        let mut sized = HttpResponse::new(StatusCode::OK, "text/plain", b"hi".to_vec());
+        sized.trailers(&["x-checksum"]);
+        sized.prepare(&make_request(Headers::new()));
+        assert_eq!(sized.header(header::TRAILER), None);
+    }
 
-        let (mut server, client) = connected_pair().await;
+    #[test]
+    fn only_declared_and_permitted_trailers_are_accepted() {
+        let mut resp =
+            HttpResponse::with_body(StatusCode::OK, "text/plain", Body::stream(&b"data"[..]));
+        let trailers = resp.trailers(&["x-checksum", "Content-Length", "transfer-encoding"]);
+        assert_eq!(resp.header(header::TRAILER), Some("x-checksum"));
 
-        let mut headers = HashMap::new();
-        headers.insert(
-            "accept-encoding".to_string(),
-            "br, gzip, deflate".to_string(),
+        assert_eq!(trailers.add_trailer("X-Checksum", "abc"), Ok(()));
+        assert_eq!(
+            trailers.add_trailer("x-other", "1"),
+            Err(TrailerError::Undeclared("x-other".to_string()))
         );
+        assert_eq!(
+            trailers.add_trailer("content-length", "4"),
+            Err(TrailerError::Forbidden("content-length".to_string()))
+        );
+        assert_eq!(
+            trailers.add_trailer("Transfer-Encoding", "gzip"),
+            Err(TrailerError::Forbidden("Transfer-Encoding".to_string()))
+        );
+    }
 
-        let req = make_request(headers);
-        let resp = HttpResponse::new("200 OK", "text/plain", b"abc123".to_vec());
+    #[tokio::test]
+    async fn head_gets_the_get_headers_without_a_body() {
+        let (mut server, client) = connected_pair().await;
+
+        let mut req = make_request(Headers::new());
+        req.method = HttpMethod::Head;
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", b"not sent".to_vec());
 
-        resp.send(&mut server, &req).await.unwrap();
+        let written = resp
+            .send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_all(client).await;
         let (headers, body) = split_headers_body(&raw);
         let headers_str = std::str::from_utf8(headers).unwrap();
 
+        assert_eq!(written, 0);
         assert_eq!(
-            get_header_value(headers_str, "Content-Encoding").as_deref(),
-            Some("gzip")
+            get_header_value(headers_str, "Content-Length").as_deref(),
+            Some("8")
         );
+        assert!(body.is_empty());
+    }
 
-        let mut decoder = GzDecoder::new(body);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed).unwrap();
+    #[tokio::test]
+    async fn send_to_disconnected_client_reports_a_disconnect() {
+        let (mut server, client) = connected_pair().await;
+        drop(client);
 
-        assert_eq!(decompressed, b"abc123");
+        // Big enough that it can't all sit in the socket buffer
+        let req = make_request(Headers::new());
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", vec![b'x'; 8 * 1024 * 1024]);
+
+        let err = resp
+            .send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap_err();
+        assert!(utils::is_disconnect(&err), "unexpected error: {err}");
     }
 
     #[tokio::test]
     async fn send_does_not_gzip_when_not_requested() {
         let (mut server, client) = connected_pair().await;
 
-        let req = make_request(HashMap::new());
-        let resp = HttpResponse::new("200 OK", "text/plain", b"plain body".to_vec());
+        let req = make_request(Headers::new());
+        let resp = HttpResponse::new(StatusCode::OK, "text/plain", b"plain body".to_vec());
 
-        resp.send(&mut server, &req).await.unwrap();
+        resp.send(&mut server, &req, &ResponseDefaults::default())
+            .await
+            .unwrap();
         server.shutdown().await.unwrap();
 
         let raw = read_all(client).await;
@@ -281,4 +884,109 @@ mod tests {
         assert_eq!(get_header_value(headers_str, "Content-Encoding"), None);
         assert_eq!(body, b"plain body");
     }
+
+    #[tokio::test]
+    async fn mismatched_content_length_is_refused_before_writing() {
+        let (mut server, client) = connected_pair().await;
+        let mut resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hello".to_vec());
+        resp.set_header(header::CONTENT_LENGTH, "10");
+        assert_eq!(
+            resp.check_length(),
+            Err(FramingError::LengthMismatch {
+                declared: 10,
+                actual: 5
+            })
+        );
+
+        let err = resp
+            .send(
+                &mut server,
+                &make_request(Headers::new()),
+                &ResponseDefaults::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        server.shutdown().await.unwrap();
+        assert!(read_all(client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_with_declared_length_is_not_chunked() {
+        let (mut server, client) = connected_pair().await;
+        let mut resp =
+            HttpResponse::with_body(StatusCode::OK, "text/plain", Body::stream(&b"hello"[..]));
+        resp.set_header(header::CONTENT_LENGTH, "5");
+
+        resp.send(
+            &mut server,
+            &make_request(Headers::new()),
+            &ResponseDefaults::default(),
+        )
+        .await
+        .unwrap();
+        server.shutdown().await.unwrap();
+
+        let raw = read_all(client).await;
+        let (headers, body) = split_headers_body(&raw);
+        let headers = std::str::from_utf8(headers).unwrap();
+        assert_eq!(get_header_value(headers, "Transfer-Encoding"), None);
+        assert_eq!(body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn short_stream_fails_the_response() {
+        let (mut server, _client) = connected_pair().await;
+        let mut resp =
+            HttpResponse::with_body(StatusCode::OK, "text/plain", Body::stream(&b"hello"[..]));
+        resp.set_header(header::CONTENT_LENGTH, "6");
+
+        let err = resp
+            .send(
+                &mut server,
+                &make_request(Headers::new()),
+                &ResponseDefaults::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn send_adds_default_headers_without_overriding() {
+        let (mut server, client) = connected_pair().await;
+        let defaults = ResponseDefaults {
+            server: Some("edge".to_string()),
+            headers: vec![
+                ("X-Environment".to_string(), "staging".to_string()),
+                ("Cache-Control".to_string(), "no-cache".to_string()),
+            ],
+        };
+
+        let mut resp = HttpResponse::new(StatusCode::OK, "text/plain", b"hi".to_vec());
+        resp.set_header(header::CACHE_CONTROL, "max-age=60");
+        resp.send(&mut server, &make_request(Headers::new()), &defaults)
+            .await
+            .unwrap();
+        drop(server);
+
+        let text = String::from_utf8(read_all(client).await).unwrap();
+        assert!(text.contains("Server: edge\r\n"));
+        assert!(text.contains("X-Environment: staging\r\n"));
+        assert!(text.contains("Cache-Control: max-age=60\r\n"));
+        assert!(!text.contains("no-cache"));
+
+        let (mut server, client) = connected_pair().await;
+        let suppressed = ResponseDefaults {
+            server: None,
+            headers: Vec::new(),
+        };
+        HttpResponse::new(StatusCode::OK, "text/plain", vec![])
+            .send(&mut server, &make_request(Headers::new()), &suppressed)
+            .await
+            .unwrap();
+        drop(server);
+        let text = String::from_utf8(read_all(client).await).unwrap();
+        assert!(!text.contains("Server:"));
+    }
 }
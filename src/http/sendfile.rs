@@ -0,0 +1,160 @@
+// File bodies sent with sendfile(2), straight from the page cache to the socket, on
+// Linux and macOS. Elsewhere, and for files the kernel won't sendfile from, the bytes
+// go through a userspace buffer as for any other writer.
+use crate::http::body::write_file_range;
+use std::io;
+use tokio::fs::File;
+use tokio::net::TcpStream;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) async fn send_file(
+    stream: &mut TcpStream,
+    file: File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let (socket, source) = (stream.as_raw_fd(), file.as_raw_fd());
+    let mut sent = 0;
+    while sent < len {
+        stream.writable().await?;
+        let result = stream.try_io(Interest::WRITABLE, || {
+            sys::sendfile(socket, source, offset + sent, len - sent)
+        });
+        match result {
+            Ok(0) => {
+                // The file shrank underneath us; the Content-Length we promised can't be honoured
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file ended before the advertised length",
+                ));
+            }
+            Ok(n) => sent += n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) => {}
+            // Not a file the kernel can send from (some filesystems, special files)
+            Err(e) if sent == 0 && sys::unsupported(&e) => {
+                return write_file_range(stream, file, offset, len).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) async fn send_file(
+    stream: &mut TcpStream,
+    file: File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    write_file_range(stream, file, offset, len).await
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::fd::RawFd;
+
+    // Linux sends at most this much per call
+    const MAX_COUNT: u64 = 0x7fff_f000;
+
+    pub fn sendfile(socket: RawFd, file: RawFd, offset: u64, len: u64) -> io::Result<u64> {
+        let mut offset = offset as libc::off_t;
+        let count = len.min(MAX_COUNT) as usize;
+        // SAFETY: both descriptors are open for the duration of the call, and `offset`
+        // outlives it
+        let n = unsafe { libc::sendfile(socket, file, &mut offset, count) };
+        match n {
+            -1 => Err(io::Error::last_os_error()),
+            n => Ok(n as u64),
+        }
+    }
+
+    pub fn unsupported(e: &io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use std::io;
+    use std::os::fd::RawFd;
+
+    pub fn sendfile(socket: RawFd, file: RawFd, offset: u64, len: u64) -> io::Result<u64> {
+        // In: how much to send. Out: how much was, even when the call fails.
+        let mut sent = len as libc::off_t;
+        // SAFETY: both descriptors are open for the duration of the call, `sent`
+        // outlives it, and no header/trailer vectors are passed
+        let result = unsafe {
+            libc::sendfile(
+                file,
+                socket,
+                offset as libc::off_t,
+                &mut sent,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        match result {
+            -1 if sent > 0 => Ok(sent as u64),
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(sent as u64),
+        }
+    }
+
+    pub fn unsupported(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EINVAL | libc::ENOTSUP | libc::ENOTSOCK)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn file_ranges_arrive_intact() {
+        let dir = crate::utils::temp_dir("sendfile");
+        let path = dir.join("data.bin");
+        let data: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (mut client, (mut server, _)) = (client.unwrap(), accepted.unwrap());
+
+        let file = File::open(&path).await.unwrap();
+        let sending = tokio::spawn(async move {
+            let sent = send_file(&mut server, file, 1000, 150_000).await;
+            drop(server);
+            sent
+        });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(sending.await.unwrap().unwrap(), 150_000);
+        assert!(received == data[1000..151_000]);
+
+        // A promise the file can't keep is an error, not a short response
+        let file = File::open(&path).await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (_client, (mut server, _)) = (client.unwrap(), accepted.unwrap());
+        let err = send_file(&mut server, file, 199_000, 2000)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
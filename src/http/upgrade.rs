@@ -0,0 +1,105 @@
+use crate::http::ConnectionInfo;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+type UpgradeFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Takes over the connection once a 101 response has been written
+pub struct OnUpgrade(Box<dyn FnOnce(Upgraded) -> UpgradeFuture + Send>);
+
+impl OnUpgrade {
+    pub fn new<F, Fut>(on_upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        OnUpgrade(Box::new(move |io| Box::pin(on_upgrade(io))))
+    }
+
+    pub(crate) async fn run(self, io: Upgraded) {
+        (self.0)(io).await
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnUpgrade(..)")
+    }
+}
+
+// Whatever stream the connection was served over: a TCP or Unix socket, TLS, or an
+// in-memory pipe. Upcast to `Box<dyn Any + Send>` to get the concrete type back.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + Any {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Any> Io for T {}
+
+// The HTTP reader with its stream type erased, so it can be split up again later
+trait Buffered: AsyncRead + AsyncWrite + Unpin + Send {
+    fn into_parts(self: Box<Self>) -> (Box<dyn Io>, Vec<u8>);
+}
+
+impl<S: Io> Buffered for BufReader<S> {
+    fn into_parts(self: Box<Self>) -> (Box<dyn Io>, Vec<u8>) {
+        let buffered = self.buffer().to_vec();
+        (Box::new(self.into_inner()), buffered)
+    }
+}
+
+// The connection after it has left HTTP. Reads start with any bytes the client sent
+// straight after the upgrade request, which the HTTP parser may already have buffered.
+pub struct Upgraded {
+    io: Box<dyn Buffered>,
+    info: Arc<ConnectionInfo>,
+}
+
+impl Upgraded {
+    pub(crate) fn new<S: Io>(io: BufReader<S>, info: Arc<ConnectionInfo>) -> Self {
+        Upgraded {
+            io: Box::new(io),
+            info,
+        }
+    }
+
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    // The raw stream plus whatever was already read from it but not yet consumed
+    pub fn into_parts(self) -> (Box<dyn Io>, Vec<u8>) {
+        self.io.into_parts()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
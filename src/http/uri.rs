@@ -0,0 +1,264 @@
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum UriError {
+    #[error("request target is empty")]
+    Empty,
+    #[error("request target must be an absolute path")]
+    NotAbsolutePath,
+    #[error("invalid character in request target")]
+    InvalidCharacter,
+    #[error("invalid percent-encoding in request target")]
+    InvalidPercentEncoding,
+}
+
+// A parsed request target, or `*` for server-wide OPTIONS. The path is percent-decoded
+// and normalized, so `.`/`..` segments and duplicate slashes are already resolved and
+// can never climb above `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    path: String,
+    segments: Vec<String>,
+    query: Option<String>,
+    fragment: Option<String>,
+}
+
+impl Uri {
+    pub fn parse(target: &str) -> Result<Self, UriError> {
+        if target.is_empty() {
+            return Err(UriError::Empty);
+        }
+        // Asterisk-form, only meaningful for server-wide OPTIONS
+        if target == "*" {
+            return Ok(Uri {
+                path: "*".to_string(),
+                ..Uri::default()
+            });
+        }
+        if !target.bytes().all(is_allowed) {
+            return Err(UriError::InvalidCharacter);
+        }
+
+        // Absolute-form (proxies send "http://host/path"): keep only the path onwards
+        let target = match target.split_once("://") {
+            Some((scheme, rest)) if !scheme.contains('/') => {
+                rest.find('/').map_or("/", |i| &rest[i..])
+            }
+            _ => target,
+        };
+        if !target.starts_with('/') {
+            return Err(UriError::NotAbsolutePath);
+        }
+
+        let (target, fragment) = match target.split_once('#') {
+            Some((t, f)) => (t, Some(f.to_string())),
+            None => (target, None),
+        };
+        let (raw_path, query) = match target.split_once('?') {
+            Some((p, q)) => (p, Some(q.to_string())),
+            None => (target, None),
+        };
+
+        let mut segments: Vec<String> = Vec::new();
+        let mut trailing_slash = false;
+        for raw in raw_path.split('/').skip(1) {
+            let segment = decode_segment(raw)?;
+            // A trailing "/", "." or ".." means the path names a directory
+            trailing_slash = matches!(segment.as_str(), "" | "." | "..");
+            match segment.as_str() {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+
+        let mut path = format!("/{}", segments.join("/"));
+        if trailing_slash && !segments.is_empty() {
+            path.push('/');
+        }
+
+        Ok(Uri {
+            path,
+            segments,
+            query,
+            fragment,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_asterisk(&self) -> bool {
+        self.path == "*"
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+}
+
+impl Default for Uri {
+    fn default() -> Self {
+        Uri {
+            path: "/".to_string(),
+            segments: Vec::new(),
+            query: None,
+            fragment: None,
+        }
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)?;
+        if let Some(query) = &self.query {
+            write!(f, "?{query}")?;
+        }
+        Ok(())
+    }
+}
+
+// RFC 3986 unreserved, reserved and '%' are allowed; controls, spaces and
+// characters such as `"<>\^`{|}` are not.
+fn is_allowed(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&'()*+,;=%".contains(&b)
+}
+
+pub fn percent_decode(input: &str) -> Result<Vec<u8>, UriError> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or(UriError::InvalidPercentEncoding)?;
+            out.push(hex);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+// Decoded segments must be UTF-8 and may not smuggle in a separator (`%2F`), which
+// could escape normalization once joined back into a path, or a control character
+// (`%0D%0A`), which could end up in a header or a log line
+fn decode_segment(raw: &str) -> Result<String, UriError> {
+    let decoded = percent_decode(raw)?;
+    if decoded
+        .iter()
+        .any(|&b| b == b'/' || b == b'\\' || b < 0x20 || b == 0x7f)
+    {
+        return Err(UriError::InvalidCharacter);
+    }
+    String::from_utf8(decoded).map_err(|_| UriError::InvalidPercentEncoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_path_query_and_fragment() {
+        let uri = Uri::parse("/files/a.txt?download=1#top").unwrap();
+        assert_eq!(uri.path(), "/files/a.txt");
+        assert_eq!(uri.segments(), ["files", "a.txt"]);
+        assert_eq!(uri.query(), Some("download=1"));
+        assert_eq!(uri.fragment(), Some("top"));
+    }
+
+    #[test]
+    fn normalizes_dots_and_duplicate_slashes() {
+        assert_eq!(Uri::parse("//a/./b//c").unwrap().path(), "/a/b/c");
+        assert_eq!(Uri::parse("/a/b/../c").unwrap().path(), "/a/c");
+        assert_eq!(
+            Uri::parse("/../../etc/passwd").unwrap().path(),
+            "/etc/passwd"
+        );
+        assert_eq!(Uri::parse("/a/b/..").unwrap().path(), "/a/");
+        assert_eq!(Uri::parse("/").unwrap().path(), "/");
+    }
+
+    #[test]
+    fn keeps_trailing_slash() {
+        assert_eq!(Uri::parse("/files/").unwrap().path(), "/files/");
+        assert_eq!(Uri::parse("/files").unwrap().path(), "/files");
+    }
+
+    #[test]
+    fn decodes_before_normalizing() {
+        assert_eq!(
+            Uri::parse("/echo/hello%20world").unwrap().path(),
+            "/echo/hello world"
+        );
+        assert_eq!(
+            Uri::parse("/files/%2e%2e/secret").unwrap().path(),
+            "/secret"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        assert_eq!(Uri::parse(""), Err(UriError::Empty));
+        assert_eq!(Uri::parse("files"), Err(UriError::NotAbsolutePath));
+        assert_eq!(Uri::parse("*x"), Err(UriError::NotAbsolutePath));
+        assert_eq!(Uri::parse("/a b"), Err(UriError::InvalidCharacter));
+        assert_eq!(Uri::parse("/a\\b"), Err(UriError::InvalidCharacter));
+        assert_eq!(Uri::parse("/%zz"), Err(UriError::InvalidPercentEncoding));
+        assert_eq!(Uri::parse("/%4"), Err(UriError::InvalidPercentEncoding));
+        assert_eq!(Uri::parse("/a%2Fb"), Err(UriError::InvalidCharacter));
+        assert_eq!(Uri::parse("/%ff"), Err(UriError::InvalidPercentEncoding));
+    }
+
+    #[test]
+    fn rejects_encoded_control_characters() {
+        for target in [
+            "/echo/a%0d%0aSet-Cookie:%20x=1",
+            "/a%0Db",
+            "/a%0ab",
+            "/a%00b",
+            "/a%09b",
+            "/a%7Fb",
+        ] {
+            assert_eq!(
+                Uri::parse(target),
+                Err(UriError::InvalidCharacter),
+                "{target}"
+            );
+        }
+        // Above the controls is fine
+        assert_eq!(Uri::parse("/a%20b").unwrap().path(), "/a b");
+    }
+
+    #[test]
+    fn accepts_absolute_form() {
+        let uri = Uri::parse("http://example.com/echo/hi?x=1").unwrap();
+        assert_eq!(uri.path(), "/echo/hi");
+        assert_eq!(uri.query(), Some("x=1"));
+    }
+
+    #[test]
+    fn accepts_asterisk_form() {
+        let uri = Uri::parse("*").unwrap();
+        assert!(uri.is_asterisk());
+        assert_eq!(uri.to_string(), "*");
+        assert!(!Uri::parse("/").unwrap().is_asterisk());
+    }
+}
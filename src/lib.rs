@@ -0,0 +1,42 @@
+// The server as a library, so other programs can embed it: build a `Server`, register
+// `Hooks` and `Middleware` to answer or adjust requests, and `run` it. The binary in
+// main.rs is a thin command-line wrapper around the same API.
+
+mod access_log;
+mod analytics;
+mod assets;
+mod cache;
+mod chaos;
+pub mod config;
+mod connections;
+mod crypto;
+mod error_pages;
+mod file_cache;
+mod file_manager;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+mod handlers;
+pub mod hooks;
+pub mod http;
+pub mod logging;
+mod metrics;
+pub mod middleware;
+pub mod mime;
+mod mirror;
+mod policy;
+mod problem;
+mod rate_limit;
+pub mod record;
+mod resources;
+mod router;
+pub mod server;
+pub mod session;
+mod throttle;
+mod utils;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use hooks::Hooks;
+pub use http::{HttpRequest, HttpResponse};
+pub use middleware::Middleware;
+pub use server::Server;
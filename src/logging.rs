@@ -0,0 +1,85 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            other => Err(format!("unknown log level: {other}")),
+        }
+    }
+}
+
+// Errors and warnings go to stderr, everything else to stdout like the original println! calls
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level) {
+            if $level <= $crate::logging::LogLevel::Warn {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::logging::log!($crate::logging::LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::logging::log!($crate::logging::LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::logging::log!($crate::logging::LogLevel::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::logging::log!($crate::logging::LogLevel::Trace, $($arg)*) };
+}
+
+pub(crate) use {debug, error, info, log, trace};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_levels_case_insensitively() {
+        assert_eq!("TRACE".parse::<LogLevel>(), Ok(LogLevel::Trace));
+        assert_eq!("warn".parse::<LogLevel>(), Ok(LogLevel::Warn));
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn levels_are_ordered_by_verbosity() {
+        assert!(LogLevel::Error < LogLevel::Info);
+        assert!(LogLevel::Debug < LogLevel::Trace);
+    }
+}
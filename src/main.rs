@@ -1,22 +1,312 @@
-mod handlers;
-mod http;
-mod server;
-mod utils;
-
+use codecrafters_http_server::config::{self, Config, Mount};
+#[cfg(feature = "geoip")]
+use codecrafters_http_server::geoip;
+use codecrafters_http_server::logging::{self, LogLevel};
+use codecrafters_http_server::{record, server};
 use std::env;
+use std::path::Path;
+use std::process;
+
+const COMMANDS: &str = "serve, check-config, routes or replay";
+
+// Flags shared by the subcommands; each uses the ones that apply to it
+struct Options {
+    address: String,
+    port: u16,
+    directory: String,
+    config_path: Option<String>,
+    record: Option<String>,
+    autoindex: bool,
+    spa: bool,
+    mounts: Vec<Mount>,
+}
 
 #[tokio::main]
 async fn main() {
+    // Without a subcommand the flags are for `serve`, as they always were
+    let mut args = env::args().skip(1).peekable();
+    let command = match args.peek() {
+        Some(arg) if !arg.starts_with('-') => args.next().unwrap(),
+        _ => "serve".to_string(),
+    };
+    let code = match command.as_str() {
+        "serve" => serve(parse_options(args)).await,
+        "check-config" => check_config(parse_options(args)),
+        "routes" => routes(parse_options(args)),
+        "replay" => replay(args).await,
+        other => {
+            eprintln!("unknown command `{other}` (expected {COMMANDS})");
+            2
+        }
+    };
+    process::exit(code);
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Options {
+    let mut options = Options {
+        address: "127.0.0.1".to_string(),
+        port: 4221,
+        directory: ".".to_string(), // Default to current dir
+        config_path: None,
+        record: None,
+        autoindex: false,
+        spa: false,
+        mounts: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--directory" => {
+                if let Some(dir) = args.next() {
+                    options.directory = dir;
+                }
+            }
+            "--log-level" => match args.next().map(|v| v.parse::<LogLevel>()) {
+                Some(Ok(level)) => logging::set_level(level),
+                Some(Err(e)) => {
+                    eprintln!("{e}");
+                    process::exit(2);
+                }
+                None => {
+                    eprintln!("--log-level requires a value");
+                    process::exit(2);
+                }
+            },
+            "--address" => match args.next() {
+                Some(address) => options.address = address,
+                None => {
+                    eprintln!("--address requires a value");
+                    process::exit(2);
+                }
+            },
+            "--port" => match args.next().map(|v| v.parse::<u16>()) {
+                Some(Ok(port)) => options.port = port,
+                Some(Err(_)) => {
+                    eprintln!("--port must be a number from 0 to 65535");
+                    process::exit(2);
+                }
+                None => {
+                    eprintln!("--port requires a value");
+                    process::exit(2);
+                }
+            },
+            "--config" => match args.next() {
+                Some(path) => options.config_path = Some(path),
+                None => {
+                    eprintln!("--config requires a path");
+                    process::exit(2);
+                }
+            },
+            "--record" => match args.next() {
+                Some(dir) => options.record = Some(dir),
+                None => {
+                    eprintln!("--record requires a directory");
+                    process::exit(2);
+                }
+            },
+            "--mount" => match args.next().map(|spec| Mount::parse(&spec)) {
+                Some(Ok(mount)) => options.mounts.push(mount),
+                Some(Err(e)) => {
+                    eprintln!("{e}");
+                    process::exit(2);
+                }
+                None => {
+                    eprintln!("--mount requires /prefix=directory");
+                    process::exit(2);
+                }
+            },
+            "--autoindex" => options.autoindex = true,
+            "--spa" => options.spa = true,
+            _ => {}
+        }
+    }
+    options
+}
+
+// The config file if one was given, else the defaults, with the --mount flags added
+fn load_config(options: &Options) -> Result<Config, String> {
+    let mut config = match &options.config_path {
+        Some(path) => config::load(Path::new(path)).map_err(|e| format!("invalid config: {e}"))?,
+        None => Config::default(),
+    };
+    config.mounts.extend(options.mounts.iter().cloned());
+    Ok(config)
+}
+
+async fn serve(options: Options) -> i32 {
     // You can use print statements as follows for debugging, they'll be visible when running tests.
     println!("Logs from your program will appear here!");
 
-    let args: Vec<String> = env::args().collect();
-    let directory = if args.len() > 2 && args[1] == "--directory" {
-        args[2].clone()
+    let mut config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return 2;
+        }
+    };
+    config.autoindex |= options.autoindex;
+    config.spa |= options.spa;
+
+    let recorder = match options.record.as_deref().map(record::Recorder::new) {
+        Some(Ok(recorder)) => Some(recorder),
+        Some(Err(e)) => {
+            eprintln!("{}: {e}", options.record.unwrap_or_default());
+            return 2;
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "geoip")]
+    let geoip = match &config.geoip_database {
+        Some(path) => match geoip::GeoIp::open(path) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                return 2;
+            }
+        },
+        None => None,
+    };
+
+    // IPv6 addresses need brackets to be told apart from the port
+    let addr = if options.address.contains(':') {
+        format!("[{}]:{}", options.address, options.port)
+    } else {
+        format!("{}:{}", options.address, options.port)
+    };
+    let mut server = server::Server::builder()
+        .bind(&addr)
+        .directory(options.directory)
+        .config(config)
+        .build();
+    #[cfg(feature = "geoip")]
+    if let Some(db) = geoip {
+        server.set_geoip(db);
+    }
+    if let Some(recorder) = recorder {
+        server.set_recorder(recorder);
+    }
+    if let Err(e) = server.run().await {
+        eprintln!("cannot listen on {addr}: {e}");
+        return 1;
+    }
+    0
+}
+
+// `check-config --config <path>`: everything `serve` would reject at startup, without
+// binding the port, so a deployment can be checked before a restart
+fn check_config(options: Options) -> i32 {
+    let Some(path) = &options.config_path else {
+        eprintln!("usage: check-config --config <path>");
+        return 2;
+    };
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return 1;
+        }
+    };
+    let mut problems = Vec::new();
+    #[cfg(feature = "geoip")]
+    if let Some(db) = &config.geoip_database
+        && let Err(e) = geoip::GeoIp::open(db)
+    {
+        problems.push(format!("geoip_database {}: {e}", db.display()));
+    }
+    if let Some(assets) = &config.assets
+        && !assets.directory.is_dir()
+    {
+        problems.push(format!(
+            "assets directory {} does not exist",
+            assets.directory.display()
+        ));
+    }
+    for mount in &config.mounts {
+        if !Path::new(&mount.directory).is_dir() {
+            problems.push(format!(
+                "mount {}/ directory {} does not exist",
+                mount.prefix, mount.directory
+            ));
+        }
+    }
+    for problem in &problems {
+        eprintln!("{path}: {problem}");
+    }
+    if problems.is_empty() {
+        println!("{path}: ok");
+        0
     } else {
-        ".".to_string() // Default to current dir
+        1
+    }
+}
+
+// `routes [--config <path>] [--directory <dir>]`: the route table `serve` would use
+fn routes(options: Options) -> i32 {
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return 2;
+        }
     };
+    for (methods, path, target) in server::Server::route_table(&config, &options.directory) {
+        println!("{methods:<28} {path:<32} {target}");
+    }
+    0
+}
+
+// `replay <dir> [--target host:port] [--concurrency n]`: re-sends requests captured with
+// --record and reports each answer, flagging status lines that differ from the
+// recording. Exits non-zero if any request failed or changed.
+async fn replay(mut args: impl Iterator<Item = String>) -> i32 {
+    let Some(dir) = args.next() else {
+        eprintln!("usage: replay <dir> [--target host:port] [--concurrency n]");
+        return 2;
+    };
+    let mut target = "127.0.0.1:4221".to_string();
+    let mut concurrency = 1;
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--target", Some(value)) => target = value,
+            ("--concurrency", Some(value)) => match value.parse() {
+                Ok(n) if n > 0 => concurrency = n,
+                _ => {
+                    eprintln!("--concurrency must be a positive number");
+                    return 2;
+                }
+            },
+            _ => {
+                eprintln!("unexpected argument: {arg}");
+                return 2;
+            }
+        }
+    }
 
-    let server = server::Server::new("127.0.0.1:4221".to_string());
-    server.run(directory).await;
+    let results = match record::replay(Path::new(&dir), &target, concurrency).await {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("{dir}: {e}");
+            return 2;
+        }
+    };
+    let (mut failed, mut changed) = (0, 0);
+    for r in &results {
+        match &r.result {
+            Ok(status) if r.changed() => {
+                changed += 1;
+                let was = r.recorded.as_deref().unwrap_or_default();
+                println!("{}: {status} (recorded: {was})", r.name);
+            }
+            Ok(status) => println!("{}: {status}", r.name),
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed: {e}", r.name);
+            }
+        }
+    }
+    println!(
+        "replayed {} requests: {changed} changed, {failed} failed",
+        results.len()
+    );
+    if failed + changed > 0 { 1 } else { 0 }
 }
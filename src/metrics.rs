@@ -0,0 +1,93 @@
+use crate::analytics::Analytics;
+use crate::http::StatusCode;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Server-wide counters, updated by the request logger for every completed request
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    bytes_sent: AtomicU64,
+    handler_micros: AtomicU64,
+    // Indexed by status class: 1xx..5xx
+    status_classes: [AtomicU64; 5],
+    // Per-path and per-client aggregates, only kept when the analytics endpoint is on
+    analytics: Option<Analytics>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub handler_time: Duration,
+    pub status_classes: [u64; 5],
+}
+
+impl Metrics {
+    pub fn with_analytics() -> Self {
+        Metrics {
+            analytics: Some(Analytics::new()),
+            ..Metrics::default()
+        }
+    }
+
+    pub fn analytics(&self) -> Option<&Analytics> {
+        self.analytics.as_ref()
+    }
+
+    pub fn record_request(
+        &self,
+        client: IpAddr,
+        path: &str,
+        status: Option<StatusCode>,
+        bytes: u64,
+        latency: Duration,
+    ) {
+        self.record(status, bytes, latency);
+        if let Some(analytics) = &self.analytics {
+            analytics.record(client, path, status, bytes);
+        }
+    }
+
+    pub fn record(&self, status: Option<StatusCode>, bytes: u64, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.handler_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+
+        if let Some(class) = status.map(|s| s.as_u16() / 100)
+            && (1..=5).contains(&class)
+        {
+            self.status_classes[class as usize - 1].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            handler_time: Duration::from_micros(self.handler_micros.load(Ordering::Relaxed)),
+            status_classes: std::array::from_fn(|i| self.status_classes[i].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_accumulates_by_status_class() {
+        let metrics = Metrics::default();
+        metrics.record(Some(StatusCode::OK), 10, Duration::from_millis(2));
+        metrics.record(Some(StatusCode::NOT_FOUND), 0, Duration::from_millis(1));
+        metrics.record(None, 5, Duration::ZERO);
+
+        let snap = metrics.snapshot();
+        assert_eq!(snap.requests, 3);
+        assert_eq!(snap.bytes_sent, 15);
+        assert_eq!(snap.handler_time, Duration::from_millis(3));
+        assert_eq!(snap.status_classes, [0, 1, 0, 1, 0]);
+    }
+}
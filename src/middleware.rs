@@ -0,0 +1,182 @@
+use crate::http::header::{self, AcceptEncoding};
+use crate::http::{HttpRequest, HttpResponse};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type ResponseFuture<'a> = Pin<Box<dyn Future<Output = HttpResponse> + Send + 'a>>;
+
+// Wraps request handling: code before `next.run` sees the request on its way in, code
+// after it the response on its way out, and not calling it at all answers the request
+// on the spot. Middleware runs in registration order, the first outermost.
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a>;
+}
+
+type Endpoint<'a> = Box<dyn FnOnce(&'a HttpRequest) -> ResponseFuture<'a> + Send + 'a>;
+
+// The rest of the chain, ending in the server's own handling of the request
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+    endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(chain: &'a [Arc<dyn Middleware>], endpoint: Endpoint<'a>) -> Self {
+        Next { chain, endpoint }
+    }
+
+    pub async fn run(self, request: &'a HttpRequest) -> HttpResponse {
+        match self.chain.split_first() {
+            Some((first, rest)) => {
+                let next = Next {
+                    chain: rest,
+                    endpoint: self.endpoint,
+                };
+                first.handle(request, next).await
+            }
+            None => (self.endpoint)(request).await,
+        }
+    }
+}
+
+// Gzips in-memory bodies for clients that accept it. Installed by default.
+pub struct Gzip;
+
+impl Middleware for Gzip {
+    fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            let mut response = next.run(request).await;
+            let accepted = request
+                .headers
+                .get(header::ACCEPT_ENCODING)
+                .map(AcceptEncoding::parse)
+                .is_some_and(|ae| ae.accepts("gzip"));
+            if accepted && response.compressible() {
+                response.gzip();
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Body, StatusCode};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::Mutex;
+
+    fn request(accept_encoding: Option<&str>) -> HttpRequest {
+        let mut request = HttpRequest::default();
+        if let Some(value) = accept_encoding {
+            request.headers.insert(header::ACCEPT_ENCODING, value);
+        }
+        request
+    }
+
+    async fn run(
+        chain: &[Arc<dyn Middleware>],
+        request: &HttpRequest,
+        response: HttpResponse,
+    ) -> HttpResponse {
+        Next::new(chain, Box::new(|_| Box::pin(async { response })))
+            .run(request)
+            .await
+    }
+
+    fn gunzip(response: &HttpResponse) -> Vec<u8> {
+        let Body::Bytes(bytes) = response.body() else {
+            panic!("not an in-memory body");
+        };
+        let mut decompressed = Vec::new();
+        GzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    // Notes when it runs, relative to the rest of the chain
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Trace {
+        fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a> {
+            Box::pin(async move {
+                self.1.lock().unwrap().push(format!("{} in", self.0));
+                let response = next.run(request).await;
+                self.1.lock().unwrap().push(format!("{} out", self.0));
+                response
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn middleware_wraps_in_registration_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let chain: Vec<Arc<dyn Middleware>> = vec![
+            Arc::new(Trace("outer", Arc::clone(&seen))),
+            Arc::new(Trace("inner", Arc::clone(&seen))),
+        ];
+        let ok = HttpResponse::new(StatusCode::OK, "text/plain", vec![]);
+        run(&chain, &request(None), ok).await;
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["outer in", "inner in", "inner out", "outer out"]
+        );
+    }
+
+    struct Authenticate;
+
+    impl Middleware for Authenticate {
+        fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a> {
+            request.extensions.insert(String::from("alice"));
+            Box::pin(next.run(request))
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_read_what_middleware_attached() {
+        let chain: Vec<Arc<dyn Middleware>> = vec![Arc::new(Authenticate)];
+        let endpoint: Endpoint = Box::new(|request| {
+            let user = request.extensions.get::<String>().unwrap_or_default();
+            Box::pin(async move {
+                HttpResponse::new(StatusCode::OK, "text/plain", user.as_bytes().to_vec())
+            })
+        });
+        let response = Next::new(&chain, endpoint).run(&request(None)).await;
+        assert!(format!("{response:#}").ends_with("\r\nalice"));
+    }
+
+    #[tokio::test]
+    async fn gzip_compresses_for_clients_that_accept_it() {
+        let chain: Vec<Arc<dyn Middleware>> = vec![Arc::new(Gzip)];
+        for accept in ["gzip", "br, gzip, deflate"] {
+            let body = HttpResponse::new(StatusCode::OK, "text/plain", b"hello gzip".to_vec());
+            let response = run(&chain, &request(Some(accept)), body).await;
+            assert_eq!(response.header(header::CONTENT_ENCODING), Some("gzip"));
+            assert_eq!(gunzip(&response), b"hello gzip");
+        }
+
+        let body = HttpResponse::new(StatusCode::OK, "text/plain", b"plain".to_vec());
+        let response = run(&chain, &request(None), body).await;
+        assert_eq!(response.header(header::CONTENT_ENCODING), None);
+    }
+
+    #[tokio::test]
+    async fn opted_out_responses_are_not_gzipped() {
+        let chain: Vec<Arc<dyn Middleware>> = vec![Arc::new(Gzip)];
+        let mut explicit = HttpResponse::new(StatusCode::OK, "text/plain", b"body".to_vec());
+        explicit.no_compress();
+        let mut no_transform = HttpResponse::new(StatusCode::OK, "text/plain", b"body".to_vec());
+        no_transform.set_header(header::CACHE_CONTROL, "max-age=60, No-Transform");
+        let mut precompressed = HttpResponse::new(StatusCode::OK, "text/plain", b"body".to_vec());
+        precompressed.set_header(header::CONTENT_ENCODING, "br");
+
+        for response in [explicit, no_transform, precompressed] {
+            let response = run(&chain, &request(Some("gzip")), response).await;
+            assert_ne!(response.header(header::CONTENT_ENCODING), Some("gzip"));
+            assert!(matches!(response.body(), Body::Bytes(b) if b == b"body"));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::Path;
+use std::sync::RwLock;
+
+// What `lookup` adds to the built-in table, set once at startup from the config
+struct Overrides {
+    // (extension, content type), extensions lowercase and without the dot
+    types: Vec<(String, String)>,
+    // Added to text/* types that don't name a charset
+    charset: Option<String>,
+}
+
+static OVERRIDES: RwLock<Overrides> = RwLock::new(Overrides {
+    types: Vec::new(),
+    charset: None,
+});
+
+// Extends or replaces the built-in types by extension (`("map", "application/json")`)
+// and sets the charset for text types; later calls replace earlier ones
+pub fn configure(types: Vec<(String, String)>, charset: Option<String>) {
+    let types = types
+        .into_iter()
+        .map(|(ext, content_type)| {
+            (
+                ext.trim_start_matches('.').to_ascii_lowercase(),
+                content_type,
+            )
+        })
+        .collect();
+    *OVERRIDES.write().unwrap() = Overrides { types, charset };
+}
+
+// The Content-Type for a file, from its extension; unknown ones are
+// application/octet-stream
+pub fn lookup(path: impl AsRef<Path>) -> String {
+    let ext = path
+        .as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    OVERRIDES.read().unwrap().resolve(&ext)
+}
+
+impl Overrides {
+    fn resolve(&self, ext: &str) -> String {
+        let content_type = self
+            .types
+            .iter()
+            .find(|(e, _)| e == ext)
+            .map_or_else(|| builtin(ext).to_string(), |(_, t)| t.clone());
+        match &self.charset {
+            Some(charset) if content_type.starts_with("text/") && !content_type.contains(';') => {
+                format!("{content_type}; charset={charset}")
+            }
+            _ => content_type,
+        }
+    }
+}
+
+fn builtin(ext: &str) -> &'static str {
+    match ext {
+        "js" | "mjs" => "text/javascript",
+        "css" => "text/css",
+        "html" | "htm" => "text/html",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "xml" => "application/xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_come_from_the_extension() {
+        assert_eq!(lookup("site/app.JS"), "text/javascript");
+        assert_eq!(lookup("logo.svg"), "image/svg+xml");
+        assert_eq!(lookup("README"), "application/octet-stream");
+
+        // Process-wide, so only with an extension no other test serves
+        assert_eq!(lookup("archive.tar.zst"), "application/octet-stream");
+        configure(
+            vec![(".ZST".to_string(), "application/zstd".to_string())],
+            None,
+        );
+        assert_eq!(lookup("archive.tar.zst"), "application/zstd");
+        configure(Vec::new(), None);
+    }
+
+    #[test]
+    fn overrides_replace_builtin_types_and_add_a_charset() {
+        let overrides = Overrides {
+            types: vec![
+                ("txt".to_string(), "text/markdown".to_string()),
+                ("csv".to_string(), "text/csv; charset=latin1".to_string()),
+            ],
+            charset: Some("utf-8".to_string()),
+        };
+        assert_eq!(overrides.resolve("txt"), "text/markdown; charset=utf-8");
+        assert_eq!(overrides.resolve("css"), "text/css; charset=utf-8");
+        assert_eq!(overrides.resolve("csv"), "text/csv; charset=latin1");
+        assert_eq!(overrides.resolve("png"), "image/png");
+    }
+}
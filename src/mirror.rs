@@ -0,0 +1,118 @@
+use crate::http::HttpRequest;
+use crate::logging::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// A mirrored request that takes longer than this is abandoned
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorConfig {
+    // host:port of the shadow backend
+    pub upstream: String,
+    // Share of requests copied to it, 0-100
+    pub percent: u8,
+}
+
+// Copies a sample of incoming requests to a shadow backend, fire-and-forget: the
+// client's response never waits on it and whatever the upstream answers is discarded.
+pub struct Mirror {
+    config: MirrorConfig,
+    seen: AtomicU64,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Self {
+        Mirror {
+            config,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn maybe_mirror(&self, request: &HttpRequest) {
+        // A streamed body is consumed by the handler, so there is nothing to copy
+        if request.body_pending() || !self.sampled() {
+            return;
+        }
+        let upstream = self.config.upstream.clone();
+        let wire = request.to_wire();
+        tokio::spawn(async move {
+            match timeout(MIRROR_TIMEOUT, send(&upstream, &wire)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("mirror to {upstream} failed: {e}"),
+                Err(_) => debug!("mirror to {upstream} timed out"),
+            }
+        });
+    }
+
+    // Spreads the sample evenly: with 25% every fourth request goes, not 25 in a row
+    fn sampled(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        let percent = u64::from(self.config.percent.min(100));
+        (n + 1) * percent / 100 > n * percent / 100
+    }
+}
+
+async fn send(upstream: &str, wire: &[u8]) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(upstream).await?;
+    stream.write_all(wire).await?;
+    stream.shutdown().await?;
+    // Drain the reply so the upstream sees an orderly close rather than a reset
+    tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::HttpMethod;
+    use crate::http::{Headers, Uri};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn samples_the_configured_share() {
+        let mirror = Mirror::new(MirrorConfig {
+            upstream: String::new(),
+            percent: 25,
+        });
+        let picks: Vec<bool> = (0..8).map(|_| mirror.sampled()).collect();
+        assert_eq!(
+            picks,
+            [false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_the_request_to_the_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mirror = Mirror::new(MirrorConfig {
+            upstream: upstream.local_addr().unwrap().to_string(),
+            percent: 100,
+        });
+
+        let mut headers = Headers::new();
+        headers.insert("host".to_string(), "example.com".to_string());
+        headers.insert("connection".to_string(), "keep-alive".to_string());
+        mirror.maybe_mirror(&HttpRequest {
+            method: HttpMethod::Post,
+            uri: Uri::parse("/files/a?x=1").unwrap(),
+            headers,
+            body: b"data".to_vec(),
+            ..Default::default()
+        });
+
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+
+        assert!(received.starts_with("POST /files/a?x=1 HTTP/1.1\r\n"));
+        assert!(received.contains("Host: example.com\r\n"));
+        assert!(received.contains("Connection: close\r\n"));
+        assert!(!received.contains("keep-alive"));
+        assert!(received.ends_with("Content-Length: 4\r\nConnection: close\r\n\r\ndata"));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::config::{AccessRule, Requirement, User};
+use crate::http::header;
+use crate::http::{HttpRequest, HttpResponse, StatusCode};
+use crate::utils;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address block such as `10.0.0.0/8` or `::1/128`; a bare address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net).into(), self.prefix, 32)
+                    == masked(u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(net.into(), self.prefix, 128) == masked(ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Keeps the top `prefix` bits of a `width`-bit address
+fn masked(bits: u128, prefix: u8, width: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        bits >> (width - prefix)
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address `{addr}`"))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= width)
+                .ok_or_else(|| format!("invalid prefix length `{p}`"))?,
+            None => width,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// Declarative access control, checked before routing. Every rule matching a request
+// must pass: a client outside `allow_ips` gets 403, missing or wrong credentials get
+// 401, and a known user outside the required group gets 403.
+pub struct Policy {
+    rules: Vec<AccessRule>,
+    users: Vec<User>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<AccessRule>, users: Vec<User>) -> Self {
+        Policy { rules, users }
+    }
+
+    // None lets the request through to routing
+    pub fn check(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let path = request.path();
+        let client = request.connection_info.peer_addr.ip();
+
+        for rule in self
+            .rules
+            .iter()
+            .filter(|r| r.matches(request.method, path))
+        {
+            if !rule.allow_ips.is_empty() && !rule.allow_ips.iter().any(|n| n.contains(client)) {
+                return Some(forbidden());
+            }
+            if !country_allowed(rule, request.connection_info.country.as_deref()) {
+                return Some(forbidden());
+            }
+            let Some(Requirement::BasicAuth { group }) = &rule.require else {
+                continue;
+            };
+            let Some(user) = self.authenticate(request) else {
+                return Some(unauthorized(group.as_deref().unwrap_or("restricted")));
+            };
+            if let Some(group) = group
+                && !user.groups.iter().any(|g| g == group)
+            {
+                return Some(forbidden());
+            }
+        }
+        None
+    }
+
+    // The configured user named by valid Basic credentials, if any
+    fn authenticate(&self, request: &HttpRequest) -> Option<&User> {
+        let value = request.headers.get(header::AUTHORIZATION)?;
+        let (scheme, credentials) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = utils::base64_decode(credentials.trim())?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        self.users
+            .iter()
+            .find(|u| u.name == name && utils::constant_time_eq(&u.password, password))
+    }
+}
+
+// Clients whose country couldn't be resolved only pass rules without an allow list
+fn country_allowed(rule: &AccessRule, country: Option<&str>) -> bool {
+    let listed = |list: &[String]| country.is_some_and(|c| list.iter().any(|l| l == c));
+    (rule.allow_countries.is_empty() || listed(&rule.allow_countries))
+        && !listed(&rule.deny_countries)
+}
+
+fn forbidden() -> HttpResponse {
+    HttpResponse::new(StatusCode::FORBIDDEN, "text/plain", b"Forbidden".to_vec())
+}
+
+fn unauthorized(realm: &str) -> HttpResponse {
+    let mut response = HttpResponse::new(
+        StatusCode::UNAUTHORIZED,
+        "text/plain",
+        b"Unauthorized".to_vec(),
+    );
+    response.set_header(
+        header::WWW_AUTHENTICATE,
+        format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::HttpMethod;
+    use crate::http::{ConnectionInfo, Headers, StatusCode, Uri};
+    use std::sync::Arc;
+
+    fn request(method: HttpMethod, target: &str, peer: &str, auth: Option<&str>) -> HttpRequest {
+        let mut headers = Headers::new();
+        if let Some(auth) = auth {
+            headers.insert(header::AUTHORIZATION.to_string(), auth.to_string());
+        }
+        HttpRequest {
+            method,
+            uri: Uri::parse(target).unwrap(),
+            headers,
+            connection_info: Arc::new(ConnectionInfo::tcp(
+                format!("{peer}:50000").parse().unwrap(),
+                "127.0.0.1:4221".parse().unwrap(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn uploads_policy() -> Policy {
+        Policy::new(
+            vec![AccessRule {
+                prefix: "/files".to_string(),
+                methods: vec![HttpMethod::Post, HttpMethod::Delete],
+                require: Some(Requirement::BasicAuth {
+                    group: Some("uploaders".to_string()),
+                }),
+                allow_ips: vec!["10.0.0.0/8".parse().unwrap()],
+                ..AccessRule::default()
+            }],
+            vec![
+                User {
+                    name: "alice".to_string(),
+                    password: "s3cret".to_string(),
+                    groups: vec!["uploaders".to_string()],
+                },
+                User {
+                    name: "bob".to_string(),
+                    password: "hunter2".to_string(),
+                    groups: Vec::new(),
+                },
+            ],
+        )
+    }
+
+    fn status(response: Option<HttpResponse>) -> Option<StatusCode> {
+        response.map(|r| r.status_code())
+    }
+
+    #[test]
+    fn ip_nets_match_by_prefix() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.200.1.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+
+        let one: IpNet = "::1".parse().unwrap();
+        assert_eq!(one.to_string(), "::1/128");
+        assert!(one.contains("::1".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.0.2.7".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not-an-ip".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn unmatched_requests_pass() {
+        let policy = uploads_policy();
+        // Reads are not covered by the rule, nor are paths outside the prefix
+        let get = request(HttpMethod::Get, "/files/a.txt", "192.0.2.1", None);
+        assert!(policy.check(&get).is_none());
+        let other = request(HttpMethod::Post, "/filesystem", "192.0.2.1", None);
+        assert!(policy.check(&other).is_none());
+    }
+
+    #[test]
+    fn writes_need_an_allowed_ip_and_a_group_member() {
+        let policy = uploads_policy();
+        // "alice:s3cret" and "bob:hunter2"
+        let alice = Some("Basic YWxpY2U6czNjcmV0");
+        let bob = Some("Basic Ym9iOmh1bnRlcjI=");
+
+        let outside = request(HttpMethod::Post, "/files/a", "192.0.2.1", alice);
+        assert_eq!(status(policy.check(&outside)), Some(StatusCode::FORBIDDEN));
+
+        let anonymous = request(HttpMethod::Post, "/files/a", "10.1.2.3", None);
+        let response = policy.check(&anonymous).unwrap();
+        assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.header(header::WWW_AUTHENTICATE),
+            Some("Basic realm=\"uploaders\", charset=\"UTF-8\"")
+        );
+
+        let wrong_password = request(
+            HttpMethod::Delete,
+            "/files/a",
+            "10.1.2.3",
+            Some("Basic YWxpY2U6bm9wZQ=="),
+        );
+        assert_eq!(
+            status(policy.check(&wrong_password)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+
+        let not_in_group = request(HttpMethod::Post, "/files/a", "10.1.2.3", bob);
+        assert_eq!(
+            status(policy.check(&not_in_group)),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        let allowed = request(HttpMethod::Post, "/files/a", "10.1.2.3", alice);
+        assert!(policy.check(&allowed).is_none());
+    }
+
+    #[test]
+    fn country_lists_filter_clients() {
+        let policy = Policy::new(
+            vec![
+                AccessRule {
+                    prefix: "/".to_string(),
+                    deny_countries: vec!["KP".to_string()],
+                    ..AccessRule::default()
+                },
+                AccessRule {
+                    prefix: "/files".to_string(),
+                    allow_countries: vec!["DE".to_string(), "FR".to_string()],
+                    ..AccessRule::default()
+                },
+            ],
+            Vec::new(),
+        );
+        let from = |target: &str, country: Option<&str>| {
+            let mut request = request(HttpMethod::Get, target, "192.0.2.1", None);
+            request.connection_info = Arc::new(ConnectionInfo {
+                country: country.map(str::to_string),
+                ..ConnectionInfo::clone(&request.connection_info)
+            });
+            status(policy.check(&request))
+        };
+
+        assert_eq!(from("/echo/x", None), None);
+        assert_eq!(from("/echo/x", Some("KP")), Some(StatusCode::FORBIDDEN));
+        assert_eq!(from("/files/a", Some("FR")), None);
+        assert_eq!(from("/files/a", Some("US")), Some(StatusCode::FORBIDDEN));
+        // Unresolved clients can't satisfy an allow list
+        assert_eq!(from("/files/a", None), Some(StatusCode::FORBIDDEN));
+    }
+}
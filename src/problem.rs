@@ -0,0 +1,137 @@
+use crate::http::{HttpRequest, HttpResponse, StatusCode, header};
+use crate::utils::json_string;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// Whether errors for this request are rendered as RFC 9457 problem documents: the
+// path is under one of the configured API prefixes, or the client asked for them
+pub fn wanted(request: &HttpRequest, prefixes: &[String]) -> bool {
+    let path = request.path();
+    let in_scope = prefixes.iter().any(|prefix| {
+        path.strip_prefix(prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+    });
+    in_scope
+        || request
+            .headers
+            .get(header::ACCEPT)
+            .is_some_and(accepts_problem)
+}
+
+// Only an explicit, non-zero-quality mention counts; `*/*` doesn't, so browsers keep
+// the plain responses
+fn accepts_problem(accept: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let media = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        media.eq_ignore_ascii_case(PROBLEM_JSON) && quality > 0.0
+    })
+}
+
+// Turns one of the server's own bodiless error responses into a problem document.
+// Responses a handler gave a body of its own, and successes, are left alone.
+pub fn render(response: &mut HttpResponse, request: &HttpRequest) {
+    let status = response.status_code();
+    if !(status.is_client_error() || status.is_server_error()) || !response.body_is_empty() {
+        return;
+    }
+    let title = status.canonical_reason().unwrap_or("Error");
+    let mut body = format!(
+        "{{\"type\":\"about:blank\",\"title\":{},\"status\":{}",
+        json_string(title),
+        status.as_u16()
+    );
+    if let Some(detail) = detail(status, request, response) {
+        body.push_str(&format!(",\"detail\":{}", json_string(&detail)));
+    }
+    body.push_str(&format!(
+        ",\"instance\":{}}}",
+        json_string(&request.uri.to_string())
+    ));
+    response.set_body(PROBLEM_JSON, body.into_bytes());
+}
+
+fn detail(status: StatusCode, request: &HttpRequest, response: &HttpResponse) -> Option<String> {
+    Some(match status {
+        StatusCode::NOT_FOUND => format!("No resource exists at {}", request.path()),
+        StatusCode::METHOD_NOT_ALLOWED => match response.header(header::ALLOW) {
+            Some(allow) => format!("{} is not supported here; use {allow}", request.method),
+            None => format!("{} is not supported here", request.method),
+        },
+        StatusCode::PAYLOAD_TOO_LARGE => "The request body exceeds the allowed size".to_string(),
+        StatusCode::TOO_MANY_REQUESTS => match response.header(header::RETRY_AFTER) {
+            Some(secs) => format!("Request limit reached; retry in {secs} seconds"),
+            None => "Request limit reached".to_string(),
+        },
+        StatusCode::INTERNAL_SERVER_ERROR => {
+            "The server failed while handling the request".to_string()
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Headers, Uri};
+
+    fn request(target: &str, accept: Option<&str>) -> HttpRequest {
+        let mut headers = Headers::new();
+        if let Some(accept) = accept {
+            headers.insert(header::ACCEPT.to_string(), accept.to_string());
+        }
+        HttpRequest {
+            uri: Uri::parse(target).unwrap(),
+            headers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chosen_by_prefix_or_accept() {
+        let api = vec!["/api".to_string()];
+        assert!(wanted(&request("/api/users", None), &api));
+        assert!(!wanted(&request("/apidocs", None), &api));
+        assert!(wanted(
+            &request("/x", Some("application/json, application/problem+json")),
+            &api
+        ));
+        assert!(!wanted(&request("/x", Some("*/*")), &api));
+        assert!(!wanted(
+            &request("/x", Some("application/problem+json;q=0")),
+            &api
+        ));
+    }
+
+    #[test]
+    fn renders_bodiless_errors_only() {
+        let req = request("/api/missing?x=1", None);
+        let mut response = HttpResponse::new(StatusCode::NOT_FOUND, "text/plain", vec![]);
+        render(&mut response, &req);
+        assert_eq!(response.header(header::CONTENT_TYPE), Some(PROBLEM_JSON));
+        assert!(format!("{response:#}").contains(
+            r#"{\"type\":\"about:blank\",\"title\":\"Not Found\",\"status\":404,\"detail\":\"No resource exists at /api/missing\",\"instance\":\"/api/missing?x=1\"}"#
+        ));
+
+        let mut limited = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS, "text/plain", vec![]);
+        limited.set_header(header::RETRY_AFTER, "3");
+        render(&mut limited, &req);
+        assert!(format!("{limited:#}").contains("retry in 3 seconds"));
+
+        let mut own = HttpResponse::new(
+            StatusCode::NOT_FOUND,
+            "text/html",
+            b"<h1>gone</h1>".to_vec(),
+        );
+        render(&mut own, &req);
+        assert_eq!(own.header(header::CONTENT_TYPE), Some("text/html"));
+
+        let mut ok = HttpResponse::new(StatusCode::OK, "text/plain", vec![]);
+        render(&mut ok, &req);
+        assert_eq!(ok.header(header::CONTENT_TYPE), Some("text/plain"));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::config::RateLimitRule;
+use crate::http::header;
+use crate::http::{HttpRequest, HttpResponse, StatusCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Windows are pruned once this many are tracked, so one-off clients don't pile up
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// Fixed-window request counters, one per (rule, client IP), so each route's budget
+// is independent of the others
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    windows: Mutex<HashMap<(usize, IpAddr), Window>>,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+// The outcome for one request, reported back in RateLimit-* headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        RateLimiter {
+            rules,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // None when no rule covers the request
+    // (rule, client) windows currently held; expired ones are pruned lazily
+    pub fn tracked(&self) -> usize {
+        self.windows.lock().unwrap().len()
+    }
+
+    pub fn check(&self, request: &HttpRequest) -> Option<Decision> {
+        self.check_at(request, Instant::now())
+    }
+
+    fn check_at(&self, request: &HttpRequest, now: Instant) -> Option<Decision> {
+        let (index, rule) = self.rules.iter().enumerate().find(|(_, rule)| {
+            rule.method.is_none_or(|m| m == request.method)
+                && request.path().starts_with(&rule.prefix)
+        })?;
+        let client = request.connection_info.peer_addr.ip();
+
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_THRESHOLD {
+            windows.retain(|(i, _), w| now.duration_since(w.start) < self.rules[*i].window);
+        }
+        let window = windows.entry((index, client)).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= rule.window {
+            *window = Window {
+                start: now,
+                count: 0,
+            };
+        }
+
+        let allowed = window.count < rule.limit;
+        if allowed {
+            window.count += 1;
+        }
+        Some(Decision {
+            allowed,
+            limit: rule.limit,
+            remaining: rule.limit - window.count,
+            reset: rule.window.saturating_sub(now.duration_since(window.start)),
+        })
+    }
+}
+
+impl Decision {
+    pub fn apply(&self, response: &mut HttpResponse) {
+        response.set_header(header::RATELIMIT_LIMIT, self.limit.to_string());
+        response.set_header(header::RATELIMIT_REMAINING, self.remaining.to_string());
+        response.set_header(header::RATELIMIT_RESET, self.reset_secs().to_string());
+    }
+
+    pub fn too_many_requests(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS, "text/plain", vec![]);
+        response.set_header(header::RETRY_AFTER, self.reset_secs().to_string());
+        response
+    }
+
+    // Whole seconds, rounded up so clients never retry early
+    fn reset_secs(&self) -> u64 {
+        self.reset.as_millis().div_ceil(1000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::request::HttpMethod;
+    use crate::http::{ConnectionInfo, Uri};
+    use std::sync::Arc;
+
+    fn request(method: HttpMethod, target: &str, ip: &str) -> HttpRequest {
+        let peer = format!("{ip}:5000").parse().unwrap();
+        HttpRequest {
+            method,
+            uri: Uri::parse(target).unwrap(),
+            connection_info: Arc::new(ConnectionInfo::tcp(peer, "127.0.0.1:4221".parse().unwrap())),
+            ..Default::default()
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(vec![
+            RateLimitRule {
+                method: Some(HttpMethod::Post),
+                prefix: "/files/".to_string(),
+                limit: 2,
+                window: Duration::from_secs(60),
+            },
+            RateLimitRule {
+                method: None,
+                prefix: "/".to_string(),
+                limit: 100,
+                window: Duration::from_secs(1),
+            },
+        ])
+    }
+
+    #[test]
+    fn each_rule_and_client_has_its_own_budget() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let upload = request(HttpMethod::Post, "/files/a", "10.0.0.1");
+
+        assert!(limiter.check_at(&upload, now).unwrap().allowed);
+        assert!(limiter.check_at(&upload, now).unwrap().allowed);
+        let third = limiter.check_at(&upload, now).unwrap();
+        assert!(!third.allowed);
+        assert_eq!((third.limit, third.remaining), (2, 0));
+
+        // Another client, and GETs on the same path, are unaffected
+        let other = request(HttpMethod::Post, "/files/a", "10.0.0.2");
+        assert!(limiter.check_at(&other, now).unwrap().allowed);
+        let get = limiter
+            .check_at(&request(HttpMethod::Get, "/files/a", "10.0.0.1"), now)
+            .unwrap();
+        assert_eq!((get.allowed, get.limit), (true, 100));
+    }
+
+    #[test]
+    fn window_resets_after_it_elapses() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let upload = request(HttpMethod::Post, "/files/a", "10.0.0.1");
+        for _ in 0..3 {
+            limiter.check_at(&upload, now);
+        }
+
+        let later = limiter
+            .check_at(&upload, now + Duration::from_secs(61))
+            .unwrap();
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 1);
+    }
+
+    #[test]
+    fn rejection_carries_retry_after() {
+        let decision = Decision {
+            allowed: false,
+            limit: 5,
+            remaining: 0,
+            reset: Duration::from_millis(1500),
+        };
+        let mut response = decision.too_many_requests();
+        decision.apply(&mut response);
+        assert_eq!(response.header(header::RETRY_AFTER), Some("2"));
+        assert_eq!(response.header(header::RATELIMIT_LIMIT), Some("5"));
+        assert_eq!(response.header(header::RATELIMIT_REMAINING), Some("0"));
+    }
+}
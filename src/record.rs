@@ -0,0 +1,304 @@
+use crate::http::HttpRequest;
+use crate::logging::{debug, error};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+// Response bytes kept per exchange; a large download is recorded truncated
+const MAX_RECORDED_RESPONSE: usize = 1024 * 1024;
+
+// A replayed request that gets no full answer within this counts as failed
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Writes each exchange to `dir` as a pair of files, NNNNNN.request and NNNNNN.response,
+// holding the raw bytes as they would go over the wire
+pub struct Recorder {
+    dir: PathBuf,
+    next: AtomicU64,
+}
+
+impl Recorder {
+    // Numbering carries on after any exchanges already in the directory, so a restart
+    // doesn't overwrite an earlier session
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut next = 1;
+        for entry in std::fs::read_dir(&dir)? {
+            if let Some(n) = exchange_number(&entry?.path()) {
+                next = next.max(n + 1);
+            }
+        }
+        Ok(Recorder {
+            dir,
+            next: AtomicU64::new(next),
+        })
+    }
+
+    // A streamed body was consumed by the handler, so there is nothing to record
+    pub fn wants(&self, request: &HttpRequest) -> bool {
+        !request.body_pending()
+    }
+
+    pub async fn save(&self, request: &[u8], response: &[u8]) {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let base = self.dir.join(format!("{n:06}"));
+        let result = async {
+            tokio::fs::write(base.with_extension("request"), request).await?;
+            tokio::fs::write(base.with_extension("response"), response).await
+        };
+        if let Err(e) = result.await {
+            error!("could not record exchange to {}: {e}", base.display());
+        }
+    }
+}
+
+fn exchange_number(path: &Path) -> Option<u64> {
+    if !matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("request" | "response")
+    ) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// Copies what is written through it into `capture` (up to MAX_RECORDED_RESPONSE);
+// with no capture buffer it passes straight through
+pub struct Capture<'a, S> {
+    inner: S,
+    capture: Option<&'a mut Vec<u8>>,
+}
+
+impl<'a, S> Capture<'a, S> {
+    pub fn new(inner: S, capture: Option<&'a mut Vec<u8>>) -> Self {
+        Capture { inner, capture }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Capture<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(capture) = this.capture.as_deref_mut() {
+            let room = MAX_RECORDED_RESPONSE.saturating_sub(capture.len());
+            capture.extend_from_slice(&buf[..n.min(room)]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// What happened to one recorded request when it was sent again
+#[derive(Debug)]
+pub struct Replayed {
+    pub name: String,
+    // The status line the server answers with now, or why there was no answer
+    pub result: Result<String, String>,
+    // The status line in the recording, when the response was recorded
+    pub recorded: Option<String>,
+}
+
+impl Replayed {
+    pub fn changed(&self) -> bool {
+        match (&self.result, &self.recorded) {
+            (Ok(now), Some(then)) => now != then,
+            _ => false,
+        }
+    }
+}
+
+// Sends every recorded request in `dir` to `target` again, in file order, with up to
+// `concurrency` in flight. Results come back in file order too.
+pub async fn replay(dir: &Path, target: &str, concurrency: usize) -> io::Result<Vec<Replayed>> {
+    let mut requests = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "request") {
+            requests.push(path);
+        }
+    }
+    requests.sort();
+
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (i, path) in requests.into_iter().enumerate() {
+        let permits = Arc::clone(&permits);
+        let target = target.to_string();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await.unwrap();
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let recorded = tokio::fs::read(path.with_extension("response"))
+                .await
+                .ok()
+                .and_then(|bytes| status_line(&bytes));
+            let result = match tokio::fs::read(&path).await {
+                Ok(wire) => match timeout(REPLAY_TIMEOUT, send(&target, &wire)).await {
+                    Ok(Ok(response)) => {
+                        status_line(&response).ok_or_else(|| "no response".to_string())
+                    }
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+            debug!("replayed {name}: {result:?}");
+            (
+                i,
+                Replayed {
+                    name,
+                    result,
+                    recorded,
+                },
+            )
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(done) = tasks.join_next().await {
+        results.push(done.map_err(io::Error::other)?);
+    }
+    results.sort_by_key(|(i, _)| *i);
+    Ok(results.into_iter().map(|(_, r)| r).collect())
+}
+
+// Recorded requests ask for `Connection: close`, so the answer is everything up to EOF
+async fn send(target: &str, wire: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(target).await?;
+    stream.write_all(wire).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+fn status_line(response: &[u8]) -> Option<String> {
+    let end = response.windows(2).position(|w| w == b"\r\n")?;
+    Some(String::from_utf8_lossy(&response[..end]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_dir;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn numbering_continues_after_existing_recordings() {
+        let dir = temp_dir("numbering");
+        let recorder = Recorder::new(&dir).unwrap();
+        recorder
+            .save(b"GET / HTTP/1.1\r\n\r\n", b"HTTP/1.1 200 OK\r\n\r\n")
+            .await;
+        assert_eq!(
+            std::fs::read(dir.join("000001.request")).unwrap(),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+
+        let recorder = Recorder::new(&dir).unwrap();
+        recorder.save(b"req", b"resp").await;
+        assert_eq!(std::fs::read(dir.join("000002.response")).unwrap(), b"resp");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn capture_is_capped_and_passes_writes_through() {
+        let mut sink = Vec::new();
+        let mut captured = Vec::new();
+        let body = vec![7_u8; MAX_RECORDED_RESPONSE + 10];
+        Capture::new(&mut sink, Some(&mut captured))
+            .write_all(&body)
+            .await
+            .unwrap();
+        assert_eq!(sink.len(), body.len());
+        assert_eq!(captured.len(), MAX_RECORDED_RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn encoded_targets_replay_as_they_were_sent() {
+        let dir = temp_dir("encoded");
+        let recorder = Recorder::new(&dir).unwrap();
+        let request = HttpRequest {
+            uri: crate::http::Uri::parse("/files/a%20b%3Fc.txt?q=1").unwrap(),
+            ..Default::default()
+        };
+        recorder
+            .save(&request.to_wire(), b"HTTP/1.1 200 OK\r\n\r\n")
+            .await;
+
+        // Answers with 200 only if the request line is the one the client sent
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let reply: &[u8] =
+                match buf[..n].starts_with(b"GET /files/a%20b%3Fc.txt?q=1 HTTP/1.1\r\n") {
+                    true => b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+                    false => b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n",
+                };
+            let _ = stream.write_all(reply).await;
+        });
+
+        let results = replay(&dir, &target, 1).await.unwrap();
+        assert_eq!(results[0].result.as_deref(), Ok("HTTP/1.1 200 OK"));
+        assert!(!results[0].changed());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_reports_status_changes() {
+        let dir = temp_dir("replay");
+        let recorder = Recorder::new(&dir).unwrap();
+        let request = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        recorder.save(request, b"HTTP/1.1 200 OK\r\n\r\n").await;
+        recorder
+            .save(request, b"HTTP/1.1 404 Not Found\r\n\r\n")
+            .await;
+
+        // Stands in for the server under test: answers everything with 200
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let results = replay(&dir, &target, 2).await.unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["000001.request", "000002.request"]);
+        assert_eq!(results[0].result.as_deref(), Ok("HTTP/1.1 200 OK"));
+        assert!(!results[0].changed());
+        assert!(results[1].changed());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
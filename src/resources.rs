@@ -0,0 +1,73 @@
+use crate::utils::json_string;
+use std::fmt::Write;
+
+// One in-memory store and its figures, e.g. ("response_cache", [("entries", 12), ...])
+pub type Section = (&'static str, Vec<(&'static str, u64)>);
+
+// Resident set size of this process, from /proc; None where there is no /proc
+pub fn rss_bytes() -> Option<u64> {
+    vm_rss(&std::fs::read_to_string("/proc/self/status").ok()?)
+}
+
+pub fn open_fds() -> Option<u64> {
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    // read_dir's own handle is one of the entries
+    Some(entries.saturating_sub(1))
+}
+
+fn vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?;
+    let kib: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+// {"rss_bytes":..,"open_fds":..,"<section>":{"<figure>":..},...}; figures the platform
+// can't provide are null
+pub fn to_json(rss_bytes: Option<u64>, open_fds: Option<u64>, sections: &[Section]) -> String {
+    let number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+    let mut out = format!(
+        "{{\"rss_bytes\":{},\"open_fds\":{}",
+        number(rss_bytes),
+        number(open_fds)
+    );
+    for (name, figures) in sections {
+        let _ = write!(out, ",{}:{{", json_string(name));
+        for (i, (figure, value)) in figures.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}:{value}", json_string(figure));
+        }
+        out.push('}');
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_rss_from_proc_status() {
+        let status = "Name:\tserver\nVmPeak:\t  20000 kB\nVmRSS:\t    5120 kB\nThreads:\t4\n";
+        assert_eq!(vm_rss(status), Some(5120 * 1024));
+        assert_eq!(vm_rss("Name:\tserver\n"), None);
+    }
+
+    #[test]
+    fn renders_sections_and_missing_figures() {
+        let json = to_json(
+            None,
+            Some(12),
+            &[
+                ("response_cache", vec![("entries", 2), ("bytes", 300)]),
+                ("rate_limiter", vec![("windows", 0)]),
+            ],
+        );
+        assert_eq!(
+            json,
+            r#"{"rss_bytes":null,"open_fds":12,"response_cache":{"entries":2,"bytes":300},"rate_limiter":{"windows":0}}"#
+        );
+    }
+}
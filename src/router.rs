@@ -0,0 +1,623 @@
+use crate::http::decode::is_decompression_bomb;
+use crate::http::request::HttpMethod;
+use crate::http::{HttpRequest, HttpResponse, StatusCode, header};
+use crate::logging::error;
+use crate::middleware::{Middleware, Next, ResponseFuture};
+use std::cmp::Reverse;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub type BoxFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<HttpResponse, HandlerError>> + Send + 'a>>;
+
+// The methods of a mounted handler, which takes them all
+pub const ANY_METHOD: &str = "*";
+
+// What routes are served by. Closures taking a `Routed` are handlers too.
+pub trait Handler<S>: Send + Sync {
+    fn handle<'a>(&'a self, routed: Routed<'a, S>) -> BoxFuture<'a>;
+}
+
+impl<S, F> Handler<S> for F
+where
+    F: for<'a> Fn(Routed<'a, S>) -> BoxFuture<'a> + Send + Sync,
+{
+    fn handle<'a>(&'a self, routed: Routed<'a, S>) -> BoxFuture<'a> {
+        self(routed)
+    }
+}
+
+// Why a handler gave up. The router answers with the matching status, so handlers
+// can `?` through I/O instead of building each error response themselves.
+#[derive(Debug, Error)]
+pub enum HandlerError {
+    #[error("{0}")]
+    Status(StatusCode),
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl HandlerError {
+    pub fn status(&self) -> StatusCode {
+        let e = match self {
+            HandlerError::Status(status) => return *status,
+            HandlerError::Io(e) => e,
+        };
+        match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            // The client hung up, sent less than it declared or a malformed body
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            // A chunked body past the size limit, or a gzipped one inflating past its own
+            io::ErrorKind::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ if is_decompression_bomb(e) => StatusCode::PAYLOAD_TOO_LARGE,
+            io::ErrorKind::Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let status = self.status();
+        if status == StatusCode::INTERNAL_SERVER_ERROR {
+            error!("handler failed: {self}");
+        }
+        HttpResponse::new(status, "text/plain", vec![])
+    }
+}
+
+// What a handler is called with: the request, with what the pattern captured in
+// `request.param`, the path it was matched on (after trailing-slash handling) and the
+// router's state
+pub struct Routed<'a, S> {
+    pub request: &'a HttpRequest,
+    pub path: &'a str,
+    pub state: &'a S,
+}
+
+// Routes as (methods, pattern, handler). A path goes to the most specific route that
+// takes the request's method, the first registered one on a tie; OPTIONS and other
+// methods on a path some route matches are answered from the routes' methods, and
+// paths no route matches are 404s. Mounted handlers take every method and answer
+// OPTIONS and 405s themselves. Middleware added to a router wraps its routes' handlers
+// only, once a route has been picked.
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+struct Route<S> {
+    // As in an Allow header: "GET, HEAD"
+    methods: &'static str,
+    pattern: Pattern,
+    description: String,
+    handler: Box<dyn Handler<S>>,
+    // From the scopes the route was added in, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn route<F>(
+        &mut self,
+        methods: &'static str,
+        pattern: &str,
+        description: impl Into<String>,
+        handler: F,
+    ) where
+        F: for<'a> Fn(Routed<'a, S>) -> BoxFuture<'a> + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            methods,
+            pattern: Pattern::parse(pattern),
+            description: description.into(),
+            handler: Box::new(handler),
+            middleware: Vec::new(),
+        });
+    }
+
+    // A GET (and HEAD) route answered by a plain function of the request
+    pub fn get<F>(&mut self, pattern: &str, description: impl Into<String>, handler: F)
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route("GET, HEAD", pattern, description, move |routed| {
+            let response = handler(routed.request);
+            Box::pin(async move { Ok(response) })
+        });
+    }
+
+    // Hands everything under `prefix` to one handler, e.g. a static-file server at
+    // "/assets"; the remainder of the path is `request.param("rest")`. The prefix itself
+    // without a trailing slash isn't included.
+    pub fn mount<F>(&mut self, prefix: &str, description: impl Into<String>, handler: F)
+    where
+        F: for<'a> Fn(Routed<'a, S>) -> BoxFuture<'a> + Send + Sync + 'static,
+    {
+        let pattern = format!("{}/*rest", prefix.trim_end_matches('/'));
+        self.route(ANY_METHOD, &pattern, description, handler);
+    }
+
+    // Wraps every route of this router, whether added before or after, inside what
+    // was added before
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Routes added in `f` go under `prefix` ("/" there being the prefix itself) and
+    // behind whatever middleware `f` adds, inside this router's own
+    pub fn scope(&mut self, prefix: &str, f: impl FnOnce(&mut Router<S>)) -> &mut Self {
+        let mut scope = Router::new();
+        f(&mut scope);
+        self.nest(prefix, scope);
+        self
+    }
+
+    // Takes over another router's routes, after this one's, under `prefix`
+    pub fn nest(&mut self, prefix: &str, other: Router<S>) {
+        let prefix = prefix.trim_end_matches('/');
+        for mut route in other.routes {
+            let pattern = match route.pattern.source.as_str() {
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                source => format!("{prefix}{source}"),
+            };
+            route.pattern = Pattern::parse(&pattern);
+            route.middleware = other
+                .middleware
+                .iter()
+                .chain(&route.middleware)
+                .cloned()
+                .collect();
+            self.routes.push(route);
+        }
+    }
+
+    // (methods, pattern, description) in registration order
+    pub fn table(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.routes.iter().map(|route| {
+            (
+                route.methods,
+                route.pattern.source.as_str(),
+                route.description.as_str(),
+            )
+        })
+    }
+
+    pub async fn respond(&self, request: &HttpRequest, path: &str, state: &S) -> HttpResponse
+    where
+        S: Sync,
+    {
+        let matched: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, route.pattern.matches(path)?)))
+            .collect();
+        if matched.is_empty() {
+            return HttpResponse::not_found();
+        }
+
+        let method = request.method.as_str();
+        let best = matched
+            .iter()
+            .filter(|(route, _)| {
+                route.methods == ANY_METHOD || route.methods.split(", ").any(|m| m == method)
+            })
+            .min_by_key(|(route, _)| Reverse(route.pattern.specificity));
+        if let Some((route, params)) = best {
+            *request.params.lock().unwrap() = params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let chain: Vec<_> = self
+                .middleware
+                .iter()
+                .chain(&route.middleware)
+                .cloned()
+                .collect();
+            let endpoint = Box::new(move |request| {
+                let routed = Routed {
+                    request,
+                    path,
+                    state,
+                };
+                Box::pin(async move {
+                    route
+                        .handler
+                        .handle(routed)
+                        .await
+                        .unwrap_or_else(HandlerError::into_response)
+                }) as ResponseFuture<'_>
+            });
+            return Next::new(&chain, endpoint).run(request).await;
+        }
+
+        let mut allow: Vec<&str> = Vec::new();
+        for method in matched
+            .iter()
+            .flat_map(|(route, _)| route.methods.split(", "))
+        {
+            if !allow.contains(&method) {
+                allow.push(method);
+            }
+        }
+        let allow = allow.join(", ");
+        if request.method == HttpMethod::Options {
+            let mut response = HttpResponse::new(StatusCode::NO_CONTENT, "text/plain", vec![]);
+            response.set_header(header::ALLOW, format!("{allow}, OPTIONS"));
+            return response;
+        }
+        let mut response = HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED, "text/plain", vec![]);
+        response.set_header(header::ALLOW, allow);
+        response
+    }
+}
+
+// A path with `{name}` segments, each matching a single non-empty segment, and maybe
+// a `*name` one at the end that takes the rest of the path, slashes included, and may
+// be empty.
+struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+    // Literal bytes, so "/echo/{text}" outranks "/{page}"
+    specificity: usize,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+impl Pattern {
+    fn parse(source: &str) -> Self {
+        let source_segments: Vec<&str> = source.trim_start_matches('/').split('/').collect();
+        let last = source_segments.len() - 1;
+        let segments: Vec<Segment> = source_segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                if let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*')
+                    && i == last
+                {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        let specificity = segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(literal) => literal.len() + 1,
+                Segment::Param(_) | Segment::Rest(_) => 0,
+            })
+            .sum();
+        Pattern {
+            source: source.to_string(),
+            segments,
+            specificity,
+        }
+    }
+
+    fn matches<'p>(&'p self, path: &'p str) -> Option<Vec<(&'p str, &'p str)>> {
+        let mut rest = path.strip_prefix('/')?;
+        let mut params = Vec::new();
+        let last = self.segments.len() - 1;
+        for (i, segment) in self.segments.iter().enumerate() {
+            let (head, tail) = match rest.split_once('/') {
+                Some((head, tail)) => (head, Some(tail)),
+                None => (rest, None),
+            };
+            match segment {
+                Segment::Rest(name) => {
+                    params.push((name.as_str(), rest));
+                    return Some(params);
+                }
+                Segment::Param(name) if !head.is_empty() => params.push((name.as_str(), head)),
+                Segment::Literal(literal) if literal == head => {}
+                _ => return None,
+            }
+            match tail {
+                Some(tail) if i < last => rest = tail,
+                None if i == last => return Some(params),
+                // The path is longer or shorter than the pattern
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Uri;
+
+    fn request(method: HttpMethod, path: &str) -> HttpRequest {
+        HttpRequest {
+            method,
+            uri: Uri::parse(path).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn reply(body: &'static str) -> impl for<'a> Fn(Routed<'a, ()>) -> BoxFuture<'a> {
+        move |_| {
+            Box::pin(
+                async move { Ok(HttpResponse::new(StatusCode::OK, "text/plain", body.into())) },
+            )
+        }
+    }
+
+    async fn body(router: &Router<()>, method: HttpMethod, path: &str) -> String {
+        let response = router.respond(&request(method, path), path, &()).await;
+        format!("{} {response:#}", response.status_code().as_u16())
+    }
+
+    #[test]
+    fn patterns_capture_segments_and_the_rest() {
+        let files = Pattern::parse("/files/*name");
+        assert_eq!(
+            files.matches("/files/a/b.txt"),
+            Some(vec![("name", "a/b.txt")])
+        );
+        assert_eq!(files.matches("/files/"), Some(vec![("name", "")]));
+        assert_eq!(files.matches("/files"), None);
+
+        let single = Pattern::parse("/users/{id}");
+        assert_eq!(single.matches("/users/7"), Some(vec![("id", "7")]));
+        assert_eq!(single.matches("/users/7/posts"), None);
+        assert_eq!(single.matches("/users/"), None);
+
+        let nested = Pattern::parse("/users/{id}/posts");
+        assert_eq!(nested.matches("/users/7/posts"), Some(vec![("id", "7")]));
+        assert_eq!(nested.matches("/users//posts"), None);
+        assert_eq!(nested.matches("/users/7/posts/1"), None);
+
+        let root = Pattern::parse("/");
+        assert_eq!(root.matches("/"), Some(vec![]));
+        assert_eq!(root.matches("/x"), None);
+        assert_eq!(Pattern::parse("/user-agent").matches("/user-agent/"), None);
+    }
+
+    #[tokio::test]
+    async fn the_most_specific_route_for_the_method_wins() {
+        let mut router = Router::new();
+        router.route("GET, HEAD", "/*page", "pages", reply("page"));
+        router.route("GET, HEAD", "/echo/*text", "echo", |routed| {
+            let text = routed
+                .request
+                .param("text")
+                .unwrap_or_default()
+                .into_bytes();
+            Box::pin(async move { Ok(HttpResponse::new(StatusCode::OK, "text/plain", text)) })
+        });
+        router.route("POST", "/echo/*text", "posted", reply("posted"));
+        router.route("GET, HEAD", "/*other", "shadowed", reply("shadowed"));
+
+        assert!(
+            body(&router, HttpMethod::Get, "/echo/hi/there")
+                .await
+                .ends_with("\r\nhi/there")
+        );
+        assert!(
+            body(&router, HttpMethod::Post, "/echo/hi")
+                .await
+                .ends_with("\r\nposted")
+        );
+        assert!(
+            body(&router, HttpMethod::Get, "/about")
+                .await
+                .ends_with("\r\npage")
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_errors_become_responses() {
+        let mut router = Router::new();
+        router.route("GET", "/gone", "gone", |_| {
+            Box::pin(async { Err(HandlerError::Status(StatusCode::GONE)) })
+        });
+        router.route("GET", "/missing", "missing", |_| {
+            Box::pin(async { Err(io::Error::from(io::ErrorKind::NotFound).into()) })
+        });
+        router.route("GET", "/broken", "broken", |_| {
+            Box::pin(async { Err(io::Error::other("disk on fire").into()) })
+        });
+
+        for (path, status) in [("/gone", "410 "), ("/missing", "404 "), ("/broken", "500 ")] {
+            assert!(
+                body(&router, HttpMethod::Get, path)
+                    .await
+                    .starts_with(status)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn closures_answer_get_and_head() {
+        let mut router = Router::new();
+        router.get("/ping", "pong", |_| {
+            HttpResponse::new(StatusCode::OK, "text/plain", b"pong".to_vec())
+        });
+
+        assert!(
+            body(&router, HttpMethod::Get, "/ping")
+                .await
+                .ends_with("\r\npong")
+        );
+        assert!(
+            body(&router, HttpMethod::Head, "/ping")
+                .await
+                .starts_with("200 ")
+        );
+        assert_eq!(router.table().next(), Some(("GET, HEAD", "/ping", "pong")));
+    }
+
+    #[tokio::test]
+    async fn unmatched_methods_and_paths_are_answered_by_the_router() {
+        let mut router = Router::new();
+        router.route("GET, HEAD", "/items/{id}", "read", reply("item"));
+        router.route("DELETE", "/items/{id}", "delete", reply("deleted"));
+
+        let options = router
+            .respond(&request(HttpMethod::Options, "/items/1"), "/items/1", &())
+            .await;
+        assert_eq!(options.status_code(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            options.header(header::ALLOW),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        let put = router
+            .respond(&request(HttpMethod::Put, "/items/1"), "/items/1", &())
+            .await;
+        assert_eq!(put.status_code(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(put.header(header::ALLOW), Some("GET, HEAD, DELETE"));
+
+        assert!(
+            body(&router, HttpMethod::Get, "/nothing")
+                .await
+                .starts_with("404 ")
+        );
+    }
+
+    #[tokio::test]
+    async fn captured_segments_reach_the_handler_through_the_request() {
+        let mut router = Router::new();
+        router.route("GET", "/users/{id}/posts/{post_id}", "post", |routed| {
+            let request = routed.request;
+            let body = format!(
+                "{}:{}:{:?}",
+                request.param("id").unwrap_or_default(),
+                request.param("post_id").unwrap_or_default(),
+                request.param("missing"),
+            );
+            Box::pin(
+                async move { Ok(HttpResponse::new(StatusCode::OK, "text/plain", body.into())) },
+            )
+        });
+
+        assert!(
+            body(&router, HttpMethod::Get, "/users/7/posts/42")
+                .await
+                .ends_with("\r\n7:42:None")
+        );
+    }
+
+    #[tokio::test]
+    async fn mounted_handlers_take_every_method_under_their_prefix() {
+        let mut router = Router::new();
+        router.mount("/assets/", "assets", |routed| {
+            let body = format!(
+                "{} {}",
+                routed.request.method.as_str(),
+                routed.request.param("rest").unwrap_or_default()
+            );
+            Box::pin(
+                async move { Ok(HttpResponse::new(StatusCode::OK, "text/plain", body.into())) },
+            )
+        });
+        router.route(
+            "GET",
+            "/assets/manifest.json",
+            "manifest",
+            reply("manifest"),
+        );
+
+        assert!(
+            body(&router, HttpMethod::Delete, "/assets/css/app.css")
+                .await
+                .ends_with("\r\nDELETE css/app.css")
+        );
+        assert!(
+            body(&router, HttpMethod::Get, "/assets/")
+                .await
+                .ends_with("\r\nGET ")
+        );
+        assert!(
+            body(&router, HttpMethod::Get, "/assets/manifest.json")
+                .await
+                .ends_with("\r\nmanifest")
+        );
+        assert!(
+            body(&router, HttpMethod::Get, "/assets")
+                .await
+                .starts_with("404 ")
+        );
+        assert_eq!(
+            router.table().next(),
+            Some((ANY_METHOD, "/assets/*rest", "assets"))
+        );
+    }
+
+    // Tags the response with its name, outermost tag first
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a> {
+            Box::pin(async move {
+                let mut response = next.run(request).await;
+                let tags = match response.header("x-tags") {
+                    Some(inner) => format!("{} {inner}", self.0),
+                    None => self.0.to_string(),
+                };
+                response.set_header("x-tags", tags);
+                response
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn scopes_prefix_their_routes_and_wrap_only_them() {
+        let mut router = Router::new();
+        router.add_middleware(Tag("all"));
+        router.scope("/api/", |api| {
+            api.add_middleware(Tag("api"));
+            api.route("GET", "/", "index", reply("index"));
+            api.route("GET", "/users", "users", reply("users"));
+            api.scope("/v2", |v2| {
+                v2.add_middleware(Tag("v2"));
+                v2.route("GET", "/users/{id}", "user", reply("user"));
+            });
+        });
+        router.route("GET", "/", "root", reply("root"));
+
+        let tags = |path: &'static str| {
+            let router = &router;
+            async move {
+                let response = router
+                    .respond(&request(HttpMethod::Get, path), path, &())
+                    .await;
+                let tags = response.header("x-tags").map(str::to_string);
+                (format!("{response:#}"), tags)
+            }
+        };
+        let (users, users_tags) = tags("/api/users").await;
+        assert!(users.ends_with("\r\nusers"));
+        assert_eq!(users_tags.as_deref(), Some("all api"));
+        let (user, user_tags) = tags("/api/v2/users/7").await;
+        assert!(user.ends_with("\r\nuser"));
+        assert_eq!(user_tags.as_deref(), Some("all api v2"));
+        assert!(tags("/api").await.0.ends_with("\r\nindex"));
+        assert_eq!(tags("/").await.1.as_deref(), Some("all"));
+        // Nothing matched, so no route's middleware ran
+        assert_eq!(tags("/api/nope").await.1, None);
+
+        let patterns: Vec<_> = router.table().map(|(_, pattern, _)| pattern).collect();
+        assert_eq!(patterns, ["/api", "/api/users", "/api/v2/users/{id}", "/"]);
+    }
+}
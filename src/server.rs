@@ -1,89 +1,1853 @@
+use crate::access_log::RequestLog;
+use crate::assets::Assets;
+use crate::cache::ResponseCache;
+use crate::chaos::{self, Chaos, Fault, Truncating};
+use crate::config::{Config, Mount, TrailingSlash};
+use crate::connections::{ConnectionState, Connections, Tracked};
+use crate::error_pages::{ErrorHandler, ErrorPages};
+use crate::file_cache::FileCache;
+use crate::file_manager;
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
 use crate::handlers;
-use crate::http::{HttpRequest, HttpResponse};
-use tokio::io::BufReader;
+use crate::hooks::{ConnectionStats, Hooks};
+use crate::http::incoming::{self, BodySender, Remaining};
+use crate::http::request::{HttpMethod, RequestError};
+use crate::http::upgrade::Io;
+use crate::http::{
+    ConnectionInfo, HttpRequest, HttpResponse, IncomingBody, RequestContext, StatusCode, Upgraded,
+    conditional, header,
+};
+use crate::logging::{debug, error, info, trace};
+use crate::metrics::Metrics;
+use crate::middleware::{Gzip, Middleware, Next, ResponseFuture};
+use crate::mime;
+use crate::mirror::Mirror;
+use crate::policy::Policy;
+use crate::rate_limit::RateLimiter;
+use crate::record::{Capture, Recorder};
+use crate::resources;
+use crate::router::{BoxFuture, HandlerError, Routed, Router};
+use crate::throttle::{Bandwidth, Throttled};
+use crate::utils;
+use std::any::Any;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Instant, timeout};
+
+// Every method at least one route answers, for the server-wide Allow header
+const SUPPORTED_METHODS: &[HttpMethod] = &[
+    HttpMethod::Get,
+    HttpMethod::Head,
+    HttpMethod::Post,
+    HttpMethod::Put,
+    HttpMethod::Delete,
+    HttpMethod::Options,
+    HttpMethod::Move,
+];
+
+const X_MAX_BODY_SIZE: &str = "x-max-body-size";
+
+// Capacity of each connection's request reader (tokio's BufReader default)
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+// Where the stock server listens unless told otherwise
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:4221";
 
 pub struct Server {
     addr: String,
+    directory: String,
+    config: Config,
+    hooks: Vec<Arc<dyn Hooks>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    error_handlers: Vec<Arc<ErrorHandler>>,
+    // Routes added through `scope`, ahead of the built-in ones
+    scopes: Router<Shared>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<GeoIp>>,
+    recorder: Option<Recorder>,
+}
+
+// Routes under one prefix, behind their own middleware; see `Server::scope`
+pub struct Scope<'a> {
+    router: &'a mut Router<Shared>,
+}
+
+impl Scope<'_> {
+    // Wraps every route of this scope and of the scopes nested in it, inside the
+    // server's middleware and the enclosing scopes'
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.router.add_middleware(middleware);
+        self
+    }
+
+    // A GET (and HEAD) route, its pattern relative to the scope's prefix: `{name}`
+    // captures a segment and a trailing `*name` the rest, both read with
+    // `request.param`
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.router
+            .get(pattern, "added by the embedding program", handler);
+        self
+    }
+
+    pub fn scope(&mut self, prefix: &str, f: impl FnOnce(&mut Scope)) -> &mut Self {
+        self.router.scope(prefix, |router| f(&mut Scope { router }));
+        self
+    }
+}
+
+// Everything a connection task needs, shared by all connections
+struct Shared {
+    directory: String,
+    // What the file routes serve, by prefix
+    mounts: Vec<Mount>,
+    config: Config,
+    metrics: Metrics,
+    connections: Connections,
+    hooks: Vec<Arc<dyn Hooks>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    error_pages: ErrorPages,
+    cache: Option<Arc<ResponseCache>>,
+    file_cache: Option<Arc<FileCache>>,
+    rate_limiter: RateLimiter,
+    policy: Policy,
+    mirror: Option<Mirror>,
+    recorder: Option<Recorder>,
+    chaos: Option<Chaos>,
+    assets: Option<Assets>,
+    router: Router<Shared>,
+}
+
+impl Shared {
+    // Drops everything computed from the directory's contents
+    fn reload(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        if let Some(file_cache) = &self.file_cache {
+            file_cache.clear();
+        }
+        if let Some(assets) = &self.assets
+            && let Err(e) = assets.rescan()
+        {
+            error!("could not rescan assets: {e}");
+        }
+        info!("reloaded static content from {}", self.directory);
+    }
+}
+
+pub struct ServerBuilder {
+    server: Server,
+}
+
+impl ServerBuilder {
+    // Anything TcpListener::bind takes: "0.0.0.0:8080", "[::1]:4221", "localhost:80"
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.server.addr = addr.into();
+        self
+    }
+
+    // Where /files/ reads and writes, unless the config mounts directories of its own
+    pub fn directory(mut self, directory: impl Into<String>) -> Self {
+        self.server.directory = directory.into();
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.server.config = config;
+        self
+    }
+
+    pub fn build(self) -> Server {
+        self.server
+    }
 }
 
 impl Server {
     pub fn new(addr: String) -> Self {
-        Self { addr }
+        Self::builder().bind(addr).build()
     }
 
-    pub async fn run(self, directory: String) {
-        let listener = TcpListener::bind(&self.addr).await.unwrap();
+    // Everything has a default: the builder alone gives the stock server, serving the
+    // current directory on DEFAULT_ADDRESS
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            server: Self {
+                addr: DEFAULT_ADDRESS.to_string(),
+                directory: ".".to_string(),
+                config: Config::default(),
+                hooks: Vec::new(),
+                middleware: vec![Arc::new(Gzip)],
+                error_handlers: Vec::new(),
+                scopes: Router::new(),
+                #[cfg(feature = "geoip")]
+                geoip: None,
+                recorder: None,
+            },
+        }
+    }
+
+    // Hooks run in registration order
+    pub fn add_hooks(&mut self, hooks: impl Hooks + 'static) {
+        self.hooks.push(Arc::new(hooks));
+    }
+
+    // Wraps request handling, inside what was added before; the stock `Gzip` comes
+    // first, so it compresses whatever the rest of the chain answers
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
 
+    // Adds routes under `prefix`, e.g. an API next to the static files:
+    // `server.scope("/api", |api| { api.add_middleware(Auth).get("/users", users); })`.
+    // They're matched like the built-in ones, and win a tie with them.
+    pub fn scope(&mut self, prefix: &str, f: impl FnOnce(&mut Scope)) -> &mut Self {
+        self.scopes.scope(prefix, |router| f(&mut Scope { router }));
+        self
+    }
+
+    // Gives the server's bodiless error responses a body (say, a JSON envelope for an
+    // API); see ErrorPages for what comes after. Handlers run in registration order.
+    pub fn add_error_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&HttpRequest, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.error_handlers.push(Arc::new(handler));
+    }
+
+    // Resolves each client's country as it connects, for access rules and handlers
+    #[cfg(feature = "geoip")]
+    pub fn set_geoip(&mut self, geoip: GeoIp) {
+        self.geoip = Some(Arc::new(geoip));
+    }
+
+    // Writes every exchange to disk for later replay
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    // Only returns if the listening socket can't be bound
+    pub async fn run(self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        let admin_listener = match self.config.admin_address {
+            Some(addr) => match TcpListener::bind(addr).await {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    error!("could not bind admin listener on {addr}: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        #[cfg(feature = "geoip")]
+        let geoip = self.geoip;
+        let cache_entries = self.config.response_cache_entries;
+        let file_cache = (self.config.file_cache_size > 0).then(|| {
+            Arc::new(FileCache::new(
+                self.config.file_cache_size,
+                self.config.file_cache_max_file_size,
+            ))
+        });
+        let rate_limiter = RateLimiter::new(self.config.rate_limits.clone());
+        let policy = Policy::new(self.config.access_rules.clone(), self.config.users.clone());
+        let mirror = self.config.mirror.clone().map(Mirror::new);
+        let assets = self.config.assets.clone().and_then(|config| {
+            let dir = config.directory.display().to_string();
+            Assets::new(config)
+                .inspect_err(|e| error!("could not index assets in {dir}: {e}"))
+                .ok()
+        });
+        let chaos = (!self.config.chaos.is_empty()).then(|| Chaos::new(self.config.chaos.clone()));
+        let metrics = match self.config.analytics_path {
+            Some(_) => Metrics::with_analytics(),
+            None => Metrics::default(),
+        };
+        // Before anything reads files it will serve
+        mime::configure(
+            self.config.mime_types.clone(),
+            self.config.default_charset.clone(),
+        );
+        let mounts = Server::mounts(&self.config, &self.directory);
+        let mut router = self.scopes;
+        router.nest("", Server::routes(&self.config, &self.directory));
+        let error_pages = ErrorPages::new(
+            self.error_handlers,
+            self.config.problem_json.clone(),
+            &self.config.error_pages,
+            &self.directory,
+        );
+        let shared = Arc::new(Shared {
+            directory: self.directory,
+            mounts,
+            config: self.config,
+            metrics,
+            connections: Connections::default(),
+            hooks: self.hooks,
+            middleware: self.middleware,
+            error_pages,
+            cache: (cache_entries > 0).then(|| Arc::new(ResponseCache::new(cache_entries))),
+            file_cache,
+            rate_limiter,
+            policy,
+            mirror,
+            recorder: self.recorder,
+            chaos,
+            assets,
+            router,
+        });
+
+        // One per mount, living as long as the server; dropping them stops the watch
+        #[cfg(feature = "watch")]
+        let _watchers: Vec<_> = shared
+            .mounts
+            .iter()
+            .filter(|_| shared.cache.is_some() || shared.file_cache.is_some())
+            .filter_map(|mount| {
+                let dir = std::path::Path::new(&mount.directory);
+                let (cache, file_cache) = (shared.cache.clone(), shared.file_cache.clone());
+                let (prefix, directory) = (mount.prefix.clone(), mount.directory.clone());
+                crate::watch::watch_directory(dir, move |name| {
+                    if let Some(cache) = &cache {
+                        cache.invalidate_prefix(&format!("{prefix}/{name}"));
+                    }
+                    if let Some(file_cache) = &file_cache {
+                        let path = std::path::Path::new(&directory).join(name);
+                        file_cache.invalidate_prefix(&path.to_string_lossy());
+                    }
+                })
+                .inspect_err(|e| error!("could not watch {}: {e}", mount.directory))
+                .ok()
+            })
+            .collect();
+
+        #[cfg(unix)]
+        tokio::spawn(Server::reload_on_signal(Arc::clone(&shared)));
+        #[cfg(unix)]
+        tokio::spawn(Server::dump_stats_on_signal(Arc::clone(&shared)));
+
+        if let Some(admin_listener) = admin_listener {
+            tokio::spawn(Server::accept_loop(
+                admin_listener,
+                Arc::clone(&shared),
+                #[cfg(feature = "geoip")]
+                geoip.clone(),
+            ));
+        }
+        Server::accept_loop(
+            listener,
+            shared,
+            #[cfg(feature = "geoip")]
+            geoip,
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn accept_loop(
+        listener: TcpListener,
+        shared: Arc<Shared>,
+        #[cfg(feature = "geoip")] geoip: Option<Arc<GeoIp>>,
+    ) {
         loop {
             match listener.accept().await {
-                Ok((stream, _addr)) => {
-                    println!("accepted new connection");
-                    let dir = directory.clone();
+                Ok((stream, peer_addr)) => {
+                    info!("accepted new connection");
+                    let shared = Arc::clone(&shared);
+                    #[cfg(feature = "geoip")]
+                    let geoip = geoip.clone();
 
                     tokio::spawn(async move {
-                        Server::handle_connection(stream, dir).await;
+                        let local_addr = match stream.local_addr() {
+                            Ok(addr) => addr,
+                            Err(e) => {
+                                error!("could not read local address: {e}");
+                                return;
+                            }
+                        };
+                        #[allow(unused_mut)]
+                        let mut info = ConnectionInfo::tcp(peer_addr, local_addr);
+                        #[cfg(feature = "geoip")]
+                        if let Some(geoip) = &geoip {
+                            info.country = geoip.country(peer_addr.ip());
+                        }
+                        let info = Arc::new(info);
+                        if !shared.hooks.iter().all(|h| h.on_connect(&info)) {
+                            debug!("connection from {peer_addr} rejected by hook");
+                            return;
+                        }
+                        Server::handle_connection(stream, info, shared).await;
                     });
                 }
                 Err(e) => {
-                    eprintln!("error accepting connection: {e}");
+                    error!("error accepting connection: {e}");
                 }
             }
         }
     }
 
-    async fn handle_connection(stream: TcpStream, directory: String) {
-        let mut reader = BufReader::new(stream);
+    // SIGUSR2 rebuilds state derived from the served directory, for deployments that
+    // rsync new content in place
+    #[cfg(unix)]
+    async fn reload_on_signal(shared: Arc<Shared>) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("could not install SIGUSR2 handler: {e}");
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            shared.reload();
+        }
+    }
+
+    // SIGUSR1 logs what every connection is doing and how busy the runtime is, for
+    // looking into a wedged or overloaded server without an admin port
+    #[cfg(unix)]
+    async fn dump_stats_on_signal(shared: Arc<Shared>) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                error!("could not install SIGUSR1 handler: {e}");
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            info!("{}", Server::stats_report(&shared));
+        }
+    }
+
+    fn stats_report(shared: &Shared) -> String {
+        let runtime = tokio::runtime::Handle::current().metrics();
+        let connections = shared.connections.snapshot();
+        let totals = shared.metrics.snapshot();
+        let [c1, c2, c3, c4, c5] = totals.status_classes;
+        let mut report = format!(
+            "runtime stats: {} connections open; {} tasks alive, {} queued for {} workers\n\
+             requests {}, bytes sent {}, handler time {:.3}s; \
+             1xx {c1}, 2xx {c2}, 3xx {c3}, 4xx {c4}, 5xx {c5}",
+            connections.len(),
+            runtime.num_alive_tasks(),
+            runtime.global_queue_depth(),
+            runtime.num_workers(),
+            totals.requests,
+            totals.bytes_sent,
+            totals.handler_time.as_secs_f64(),
+        );
+        if let Some(cache) = &shared.cache {
+            report.push_str(&format!("; {} cached responses", cache.len()));
+        }
+        if let Some(file_cache) = &shared.file_cache {
+            report.push_str(&format!("; {} cached files", file_cache.len()));
+        }
+        for conn in connections {
+            report.push_str(&format!(
+                "\n  {} open {:.1}s, {} requests, {} for {:.1}s",
+                conn.peer,
+                conn.age.as_secs_f64(),
+                conn.requests,
+                conn.state,
+                conn.state_age.as_secs_f64()
+            ));
+        }
+        report
+    }
+
+    // Serves one connection over any stream: TCP, TLS, a Unix socket or an in-memory
+    // pipe. Plain TCP sockets also get the sendfile and hang-up detection fast paths.
+    async fn handle_connection<S: Io>(stream: S, info: Arc<ConnectionInfo>, shared: Arc<Shared>) {
+        let started = Instant::now();
+        let tracked = shared.connections.register(info.peer_addr);
+        let served = Server::serve_requests(stream, &info, &shared, &tracked).await;
+        drop(tracked);
+
+        let stats = ConnectionStats {
+            requests: served,
+            duration: started.elapsed(),
+        };
+        for hook in &shared.hooks {
+            hook.on_disconnect(&info, &stats);
+        }
+    }
+
+    // The keep-alive loop; returns how many requests were served. Requests are handled
+    // strictly one at a time: each response is compressed, written and flushed before
+    // the next request is parsed, so pipelined responses go out in order. Pipelining
+    // costs no extra memory: requests beyond the read buffer wait in the socket, and the
+    // client stops sending once that fills.
+    async fn serve_requests<S: Io>(
+        stream: S,
+        info: &Arc<ConnectionInfo>,
+        shared: &Shared,
+        tracked: &Tracked<'_>,
+    ) -> usize {
+        let config = &shared.config;
+        let metrics = &shared.metrics;
+        let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, stream);
+        let mut served = 0;
+        // Bandwidth buckets persist across the connection's requests
+        let mut download = None;
+        let mut upload = None;
 
         loop {
-            let request = match HttpRequest::from_stream(&mut reader).await {
-                Some(req) => req,
-                None => {
-                    println!("Connection closed by client.");
+            tracked.set(ConnectionState::Idle);
+            // Wait for the first byte of the next request; idle keep-alive connections are
+            // simply closed, there is no request to answer yet
+            match timeout(config.keep_alive_timeout, reader.fill_buf()).await {
+                Err(_) => {
+                    debug!("Closing idle connection.");
+                    break;
+                }
+                Ok(Ok([])) => {
+                    debug!("Connection closed by client.");
+                    break;
+                }
+                Ok(Ok(_)) => tracked.set(ConnectionState::Reading),
+                Ok(Err(e)) => {
+                    Server::log_read_error(&e);
+                    break;
+                }
+            }
+
+            let mut request = match timeout(
+                config.request_timeout,
+                HttpRequest::from_stream(
+                    &mut reader,
+                    config.max_body_size,
+                    config.body_buffer_limit,
+                    &config.head_limits,
+                ),
+            )
+            .await
+            {
+                Ok(Ok(mut req)) => {
+                    req.connection_info = Arc::clone(info);
+                    req.decompression = config.request_decompression;
+                    req.context =
+                        RequestContext::new(Some(Instant::now() + config.handler_timeout));
+                    req
+                }
+                Ok(Err(RequestError::ConnectionClosed)) => {
+                    debug!("Connection closed by client.");
+                    break;
+                }
+                Ok(Err(RequestError::Io(e))) => {
+                    Server::log_read_error(&e);
+                    break;
+                }
+                Ok(Err(RequestError::Malformed(reason))) => {
+                    // What's left of the request can't be framed, so the connection goes
+                    debug!("Malformed request ({reason}), closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::BAD_REQUEST).await;
+                    break;
+                }
+                Ok(Err(RequestError::UriTooLong)) => {
+                    debug!("Request line too long, closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::URI_TOO_LONG).await;
+                    break;
+                }
+                Ok(Err(RequestError::HeadersTooLarge)) => {
+                    debug!("Request headers too large, closing connection.");
+                    Server::send_error(
+                        &mut reader,
+                        shared,
+                        None,
+                        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    )
+                    .await;
+                    break;
+                }
+                Ok(Err(RequestError::ExpectationFailed)) => {
+                    debug!("Unsupported Expect header, closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::EXPECTATION_FAILED)
+                        .await;
+                    break;
+                }
+                Ok(Err(RequestError::UnknownMethod)) => {
+                    // Headers and body are still on the wire, as with 413
+                    debug!("Unknown request method, closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::NOT_IMPLEMENTED)
+                        .await;
+                    break;
+                }
+                Ok(Err(RequestError::BodyTooLarge)) => {
+                    // The body is still on the wire, so the connection can't be reused
+                    debug!("Request body too large, closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::PAYLOAD_TOO_LARGE)
+                        .await;
+                    break;
+                }
+                Err(_) => {
+                    // The client started a request but stalled part way through
+                    debug!("Request timed out, closing connection.");
+                    Server::send_error(&mut reader, shared, None, StatusCode::REQUEST_TIMEOUT)
+                        .await;
                     break;
                 }
             };
+            served += 1;
+            tracked.set(ConnectionState::Handling(format!(
+                "{} {}",
+                request.method, request.uri
+            )));
 
-            println!("request received for path: {}", request.path);
+            // Bodies too large to buffer are fed to the handler while it runs
+            let body_tx = request.body_pending().then(|| {
+                let (body, tx) = IncomingBody::channel(request.unread_body);
+                *request.body_stream.get_mut().unwrap() = Some(body);
+                let remaining = match &request.chunked {
+                    // What was decoded while buffering goes ahead of the rest
+                    Some(decoder) => {
+                        let _ = tx.try_send(Ok(std::mem::take(&mut request.body).into()));
+                        Remaining::Chunked(decoder.clone())
+                    }
+                    None => Remaining::Length(request.unread_body),
+                };
+                (tx, remaining)
+            });
 
-            let response = match request.path.as_str() {
-                "/" => HttpResponse::new("200 OK", "text/plain", vec![]),
+            trace!("request:\n{:#}", request);
+            if let Some(mirror) = &shared.mirror {
+                mirror.maybe_mirror(&request);
+            }
+            // Taken before the handler runs, in case it changes the request
+            let recorded_request = shared
+                .recorder
+                .as_ref()
+                .filter(|r| r.wants(&request))
+                .map(|_| request.to_wire());
+            let mut log = RequestLog::start(&request);
 
-                p if p.starts_with("/echo/") => {
-                    let content = p.as_bytes()[6..].to_vec();
-                    HttpResponse::new("200 OK", "text/plain", content)
+            let fault = shared.chaos.as_ref().and_then(|c| c.pick(&request));
+            if let Some(fault) = fault {
+                debug!("injecting {fault:?} into {}", request.path());
+            }
+            match fault {
+                Some(Fault::Drop) => {
+                    log.finish(None, 0, metrics);
+                    break;
                 }
+                Some(Fault::Delay(delay)) => tokio::time::sleep(delay).await,
+                _ => {}
+            }
 
-                "/user-agent" => {
-                    let ua = request
-                        .headers
-                        .get("user-agent")
-                        .cloned()
-                        .unwrap_or_default();
-                    HttpResponse::new("200 OK", "text/plain", ua.into_bytes())
+            // Over-budget clients are turned away before anything else runs, then the
+            // access rules; past those a hook may answer the request itself, in which
+            // case routing is skipped
+            let limit = shared.rate_limiter.check(&request);
+            let answered = match &limit {
+                Some(decision) if !decision.allowed => Some(decision.too_many_requests()),
+                _ => shared
+                    .policy
+                    .check(&request)
+                    .or_else(|| match fault {
+                        Some(Fault::Error(Some(status))) => Some(chaos::error_response(status)),
+                        _ => None,
+                    })
+                    .or_else(|| shared.hooks.iter().find_map(|h| h.on_request(&mut request))),
+            };
+
+            // A panicking handler gets one 500 and the connection is closed, since we
+            // can't know what state it left behind
+            let endpoint = Box::new(move |request| -> ResponseFuture<'_> {
+                Box::pin(async move {
+                    match answered {
+                        Some(response) => response,
+                        None => match &shared.cache {
+                            Some(cache) => {
+                                cache
+                                    .get_or_fetch(request, async |req| {
+                                        Server::respond(req, shared).await
+                                    })
+                                    .await
+                            }
+                            None => Server::respond(request, shared).await,
+                        },
+                    }
+                })
+            });
+            let handler =
+                utils::catch_unwind(Next::new(&shared.middleware, endpoint).run(&request));
+            let (output, body_complete) = match body_tx {
+                Some((tx, remaining)) => {
+                    let (_, rate) = config.bandwidth_for(request.path());
+                    let body = Throttled::new(&mut reader, Server::bucket(&mut upload, rate));
+                    Server::feed_body(handler, body, remaining, tx).await
+                }
+                None => (Server::watch_client(handler, &reader, &request).await, true),
+            };
+            let mut response = match output {
+                Ok(response) => response,
+                Err(payload) => {
+                    // The message stays in the log; the client only gets the 500
+                    error!(
+                        "handler panicked for path {}: {}",
+                        request.path(),
+                        utils::panic_message(&*payload)
+                    );
+                    Server::send_error(
+                        &mut reader,
+                        shared,
+                        Some(&request),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .await;
+                    log.finish(Some(StatusCode::INTERNAL_SERVER_ERROR), 0, metrics);
+                    break;
                 }
+            };
+            log.handled();
+            if let Some(decision) = &limit {
+                decision.apply(&mut response);
+            }
+            shared.error_pages.render(&mut response, Some(&request));
+            let status = Some(response.status_code());
+
+            // HTTP/1.1 is persistent by default, but clients can send "Connection: close"
+            let client_close = request
+                .headers
+                .get(header::CONNECTION)
+                .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+            let remaining = config.max_requests_per_connection.saturating_sub(served);
+            // Body bytes the handler never read are still in the way of the next request
+            // A truncated response leaves the client nothing to reuse
+            let truncate = fault == Some(Fault::Truncate);
+            let closing = client_close || remaining == 0 || !body_complete || truncate;
 
-                p if p.starts_with("/files/") => {
-                    handlers::handle_file_request(p, &request, &directory).await
+            // An upgrade hands the connection to the handler once the 101 is out
+            let upgrade = response.take_upgrade();
+
+            // Tell the client how long we'll keep the connection open and how many more
+            // requests it may send, so it doesn't reuse a connection we're about to drop.
+            // Upgrades leave HTTP entirely, so neither applies.
+            if upgrade.is_none() {
+                if closing {
+                    response.set_header(header::CONNECTION, "close");
+                } else {
+                    response.set_header(
+                        header::KEEP_ALIVE,
+                        format!(
+                            "timeout={}, max={}",
+                            config.keep_alive_timeout.as_secs(),
+                            remaining
+                        ),
+                    );
                 }
+            }
 
-                _ => HttpResponse::new("404 Not Found", "text/plain", vec![]),
-            };
+            for hook in &shared.hooks {
+                hook.on_response(&request, &mut response);
+            }
+
+            // A handler-set Content-Length that disagrees with the body would desync the
+            // client, so send a clean 500 instead
+            if let Err(e) = response.check_length() {
+                error!("bad response framing for {}: {e}", request.path());
+                Server::send_error(
+                    &mut reader,
+                    shared,
+                    Some(&request),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .await;
+                log.finish(Some(StatusCode::INTERNAL_SERVER_ERROR), 0, metrics);
+                break;
+            }
 
             // This is where the magic happens: GZIP, Headers, and Writing
-            let stream = reader.get_mut();
-            if response.send(stream, &request).await.is_err() {
-                eprintln!("error sending response");
+            tracked.set(ConnectionState::Writing);
+            let (rate, _) = config.bandwidth_for(request.path());
+            let mut recorded_response = Vec::new();
+            let sent = if rate.is_none() && recorded_request.is_none() && !truncate {
+                // Straight to the socket, so file bodies can go out with sendfile
+                response
+                    .send_direct(reader.get_mut(), &request, &config.response_defaults)
+                    .await
+            } else {
+                // Recordings get what actually went out, so capture inside the truncation
+                let mut stream = Truncating::new(
+                    Capture::new(
+                        Throttled::new(reader.get_mut(), Server::bucket(&mut download, rate)),
+                        recorded_request.as_ref().map(|_| &mut recorded_response),
+                    ),
+                    truncate,
+                );
+                response
+                    .send(&mut stream, &request, &config.response_defaults)
+                    .await
+            };
+            match sent {
+                Ok(written) => {
+                    log.finish(status, written, metrics);
+                    if let (Some(recorder), Some(wire)) = (&shared.recorder, &recorded_request) {
+                        recorder.save(wire, &recorded_response).await;
+                    }
+                }
+                Err(e) => {
+                    // A client hanging up mid-response is routine; only log the rest as errors
+                    if utils::is_disconnect(&e) {
+                        debug!("client disconnected while sending response: {e}");
+                    } else {
+                        error!("error sending response: {e}");
+                    }
+                    log.finish(status, 0, metrics);
+                    break;
+                }
+            }
+
+            if let Some(on_upgrade) = upgrade {
+                debug!("switching protocols for {}", request.path());
+                tracked.set(ConnectionState::Upgraded);
+                on_upgrade
+                    .run(Upgraded::new(reader, Arc::clone(info)))
+                    .await;
                 break;
             }
 
-            // Check if we should close the connection
-            // HTTP/1.1 is persistent by default, but clients can send "Connection: close"
-            if let Some(conn_header) = request.headers.get("connection")
-                && conn_header.to_lowercase() == "close"
-            {
+            if closing {
+                if remaining == 0 {
+                    debug!("Request limit reached, closing connection.");
+                }
                 break;
             }
         }
+        served
+    }
+
+    // Drives the handler while watching the socket, so a client that hangs up cancels
+    // the request context instead of leaving the handler working for nobody
+    async fn watch_client<F: Future, S: Io>(
+        handler: F,
+        reader: &BufReader<S>,
+        request: &HttpRequest,
+    ) -> F::Output {
+        // A buffered next request means the client is pipelining; it may well have shut
+        // its side down after sending them all, which must not cancel the ones in flight.
+        // Only TCP sockets can be peeked at without consuming anything.
+        let socket = (reader.get_ref() as &dyn Any).downcast_ref::<TcpStream>();
+        let Some(stream) = socket.filter(|_| reader.buffer().is_empty()) else {
+            return handler.await;
+        };
+        tokio::pin!(handler);
+        let mut probe = [0_u8; 1];
+        tokio::select! {
+            output = &mut handler => return output,
+            res = stream.peek(&mut probe) => {
+                // Data means the client is pipelining its next request, so it's still there
+                if !matches!(res, Ok(n) if n > 0) {
+                    debug!("client went away, cancelling request for {}", request.path());
+                    request.context.cancel();
+                }
+            }
+        }
+        handler.await
+    }
+
+    // Drives the handler while pumping the rest of the body to it. The pump stops as
+    // soon as the handler is done; the flag says whether the whole body was consumed.
+    async fn feed_body<F: Future, R: AsyncRead + Unpin>(
+        handler: F,
+        mut reader: R,
+        remaining: Remaining,
+        tx: BodySender,
+    ) -> (F::Output, bool) {
+        tokio::pin!(handler);
+        let pump = incoming::pump(&mut reader, remaining, tx);
+        tokio::pin!(pump);
+        let mut complete = None;
+        loop {
+            tokio::select! {
+                output = &mut handler => return (output, complete.unwrap_or(false)),
+                done = &mut pump, if complete.is_none() => complete = Some(done),
+            }
+        }
+    }
+
+    // The connection's bucket, set to `rate`; None when unlimited
+    fn bucket(slot: &mut Option<Bandwidth>, rate: Option<u64>) -> Option<&mut Bandwidth> {
+        let rate = rate?;
+        let bucket = slot.get_or_insert_with(|| Bandwidth::new(rate));
+        if bucket.rate() != rate {
+            bucket.set_rate(rate);
+        }
+        Some(bucket)
+    }
+
+    // The routed response, or a 304 when the client's copy is still current
+    async fn respond(request: &HttpRequest, shared: &Shared) -> HttpResponse {
+        conditional::evaluate(request, Server::route(request, shared).await)
+    }
+
+    async fn route(request: &HttpRequest, shared: &Shared) -> HttpResponse {
+        if let Some(admin) = shared.config.admin_address
+            && Server::is_admin_connection(request, admin)
+        {
+            return Server::route_admin(request, shared);
+        }
+        if request.uri.is_asterisk() {
+            return match request.method {
+                HttpMethod::Options => Server::options_asterisk(&shared.config),
+                _ => HttpResponse::new(StatusCode::BAD_REQUEST, "text/plain", vec![]),
+            };
+        }
+
+        let path = request.path();
+        let trimmed = match path.strip_suffix('/') {
+            Some(p) if !p.is_empty() => p,
+            _ => path,
+        };
+        let path = match shared.config.trailing_slash {
+            TrailingSlash::Strict => path,
+            TrailingSlash::Ignore => trimmed,
+            TrailingSlash::Redirect if trimmed != path => {
+                // The path is decoded; the query is still as sent, and only holds URL
+                // characters
+                let trimmed = utils::encode_path(trimmed);
+                let location = match request.uri.query() {
+                    Some(query) => format!("{trimmed}?{query}"),
+                    None => trimmed,
+                };
+                return HttpResponse::redirect(location, true);
+            }
+            TrailingSlash::Redirect | TrailingSlash::Directories => path,
+        };
+
+        shared.router.respond(request, path, shared).await
+    }
+
+    // Everything but the admin listener's routes and `OPTIONS *`. Mounts from the
+    // config come first, so they win over a built-in route with the same pattern.
+    fn routes(config: &Config, directory: &str) -> Router<Shared> {
+        let mut router: Router<Shared> = Router::new();
+        if let Some(path) = &config.file_manager {
+            router.get(path, "file manager page", file_manager::respond);
+        }
+        if let Some(path) = &config.analytics_path {
+            router.route("GET, HEAD", path, "traffic analytics (JSON)", |routed| {
+                Box::pin(async move {
+                    let analytics = routed
+                        .state
+                        .metrics
+                        .analytics()
+                        .ok_or(HandlerError::Status(StatusCode::NOT_FOUND))?;
+                    Ok(HttpResponse::builder()
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .header(header::CACHE_CONTROL, "no-store")
+                        .body(analytics.to_json().into_bytes()))
+                })
+            });
+        }
+        if let Some(assets) = &config.assets {
+            router.route(
+                "GET, HEAD",
+                &assets.manifest_path(),
+                "asset manifest (JSON)",
+                Server::serve_asset,
+            );
+            router.mount(
+                &assets.prefix,
+                format!(
+                    "assets in {}, also under hashed names",
+                    assets.directory.display()
+                ),
+                Server::serve_asset,
+            );
+        }
+
+        router.get("/", "200 OK", |_| HttpResponse::ok());
+        router.get("/echo/*text", "echoes the rest of the path", |request| {
+            let content = request.param("text").unwrap_or_default().into_bytes();
+            HttpResponse::new(StatusCode::OK, "text/plain", content)
+        });
+        router.get("/user-agent", "the User-Agent header", |request| {
+            let ua = request.headers.get(header::USER_AGENT).unwrap_or_default();
+            HttpResponse::new(StatusCode::OK, "text/plain", ua.as_bytes().to_vec())
+        });
+        for mount in Server::mounts(config, directory) {
+            let prefix = mount.prefix;
+            router.route(
+                handlers::ALLOWED_METHODS,
+                &format!("{prefix}/*name"),
+                format!("files in {}", mount.directory),
+                move |routed| {
+                    let state = routed.state;
+                    let mount = state.mounts.iter().find(|m| m.prefix == prefix);
+                    Box::pin(async move {
+                        let mount = mount.ok_or(HandlerError::Status(StatusCode::NOT_FOUND))?;
+                        if state.config.trailing_slash == TrailingSlash::Directories
+                            && let Some(redirect) =
+                                handlers::directory_redirect(routed.request, &mount.directory)
+                        {
+                            return Ok(redirect);
+                        }
+                        handlers::handle_file_request(
+                            routed.path,
+                            routed.request,
+                            mount,
+                            &state.config,
+                            state.file_cache.as_deref(),
+                        )
+                        .await
+                    })
+                },
+            );
+        }
+        router
+    }
+
+    // The configured mounts, or else `directory` under /files/
+    fn mounts(config: &Config, directory: &str) -> Vec<Mount> {
+        match config.mounts.is_empty() {
+            true => vec![Mount {
+                prefix: "/files".to_string(),
+                directory: directory.to_string(),
+            }],
+            false => config.mounts.clone(),
+        }
+    }
+
+    fn serve_asset(routed: Routed<'_, Shared>) -> BoxFuture<'_> {
+        Box::pin(async move {
+            let response = match &routed.state.assets {
+                Some(assets) => assets.respond(routed.request, routed.path).await,
+                None => None,
+            };
+            response.ok_or(HandlerError::Status(StatusCode::NOT_FOUND))
+        })
+    }
+
+    // An unspecified admin address (0.0.0.0:port) accepts on every interface, so only
+    // the port tells its connections apart
+    fn is_admin_connection(request: &HttpRequest, admin: SocketAddr) -> bool {
+        let local = request.connection_info.local_addr;
+        if admin.ip().is_unspecified() {
+            local.port() == admin.port()
+        } else {
+            local == admin
+        }
+    }
+
+    // The admin listener serves operator endpoints only, none of the public routes
+    fn route_admin(request: &HttpRequest, shared: &Shared) -> HttpResponse {
+        if request.path() != "/resources" {
+            return HttpResponse::not_found();
+        }
+        if !matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+            let mut response =
+                HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED, "text/plain", vec![]);
+            response.set_header(header::ALLOW, "GET, HEAD");
+            return response;
+        }
+        let body = resources::to_json(
+            resources::rss_bytes(),
+            resources::open_fds(),
+            &Server::memory_usage(shared),
+        );
+        HttpResponse::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body.into_bytes())
+    }
+
+    // What the server's own in-memory stores hold
+    fn memory_usage(shared: &Shared) -> Vec<resources::Section> {
+        let connections = shared.connections.snapshot().len() as u64;
+        let mut sections = vec![(
+            "connections",
+            vec![
+                ("open", connections),
+                // Each connection's request reader holds one buffer of this size
+                ("read_buffer_bytes", connections * READ_BUFFER_SIZE as u64),
+            ],
+        )];
+        if let Some(cache) = &shared.cache {
+            sections.push((
+                "response_cache",
+                vec![
+                    ("entries", cache.len() as u64),
+                    ("capacity", cache.capacity() as u64),
+                    ("bytes", cache.memory_size() as u64),
+                ],
+            ));
+        }
+        if let Some(file_cache) = &shared.file_cache {
+            sections.push((
+                "file_cache",
+                vec![
+                    ("files", file_cache.len() as u64),
+                    ("budget", file_cache.budget()),
+                    ("bytes", file_cache.used()),
+                ],
+            ));
+        }
+        sections.push((
+            "rate_limiter",
+            vec![("windows", shared.rate_limiter.tracked() as u64)],
+        ));
+        if let Some(assets) = &shared.assets {
+            sections.push(("assets", vec![("files", assets.files() as u64)]));
+        }
+        sections
+    }
+
+    // What `routes` prints: (methods, path, what answers), in registration order
+    pub fn route_table(config: &Config, directory: &str) -> Vec<(String, String, String)> {
+        let mut table = Vec::new();
+        if let Some(admin) = config.admin_address {
+            table.push((
+                "GET, HEAD".to_string(),
+                "/resources".to_string(),
+                format!("process and memory usage (JSON), on {admin} only"),
+            ));
+        }
+        table.push((
+            "OPTIONS".to_string(),
+            "*".to_string(),
+            "server capabilities".to_string(),
+        ));
+        let router = Server::routes(config, directory);
+        for (methods, path, target) in router.table() {
+            table.push((methods.to_string(), path.to_string(), target.to_string()));
+        }
+        table
+    }
+
+    // `OPTIONS *` is a capability probe: which methods any route accepts, which
+    // encodings we can compress with, and how large a body we'll take
+    fn options_asterisk(config: &Config) -> HttpResponse {
+        let allow: Vec<&str> = SUPPORTED_METHODS.iter().map(HttpMethod::as_str).collect();
+        let mut response = HttpResponse::new(StatusCode::NO_CONTENT, "text/plain", vec![]);
+        response.set_header(header::ALLOW, allow.join(", "));
+        response.set_header(header::ACCEPT_ENCODING, "gzip");
+        if let Some(max) = config.max_body_size {
+            response.set_header(X_MAX_BODY_SIZE, max.to_string());
+        }
+        response
+    }
+
+    // Resets are the client going away; anything else is worth an error line
+    fn log_read_error(e: &std::io::Error) {
+        if utils::is_disconnect(e) {
+            debug!("Connection reset by client: {e}");
+        } else {
+            error!("error reading request: {e}");
+        }
+    }
+
+    // Best-effort error response on a connection we're about to close. Without a
+    // parsed request there's nothing to decide the error format by, so it stays plain.
+    async fn send_error<S: Io>(
+        reader: &mut BufReader<S>,
+        shared: &Shared,
+        request: Option<&HttpRequest>,
+        status: StatusCode,
+    ) {
+        let mut response = HttpResponse::new(status, "text/plain", vec![]);
+        response.set_header(header::CONNECTION, "close");
+        shared.error_pages.render(&mut response, request);
+        if let Err(e) = response
+            .send(
+                reader.get_mut(),
+                &HttpRequest::default(),
+                &shared.config.response_defaults,
+            )
+            .await
+        {
+            debug!("could not send {status}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::ConnectionStats;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    struct RecordingHooks(Arc<Recorder>);
+
+    impl Hooks for RecordingHooks {
+        fn on_request(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+            self.0
+                .events
+                .lock()
+                .unwrap()
+                .push(format!("request {}", request.path()));
+            (request.path() == "/blocked")
+                .then(|| HttpResponse::new(StatusCode::FORBIDDEN, "text/plain", vec![]))
+        }
+
+        fn on_response(&self, _request: &HttpRequest, response: &mut HttpResponse) {
+            self.0.events.lock().unwrap().push("response".to_string());
+            response.set_header("x-tagged", "yes");
+        }
+
+        fn on_disconnect(&self, _info: &ConnectionInfo, stats: &ConnectionStats) {
+            self.0
+                .events
+                .lock()
+                .unwrap()
+                .push(format!("disconnect {}", stats.requests));
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_see_each_stage_and_can_short_circuit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let shared = Arc::new(Shared {
+            hooks: vec![Arc::new(RecordingHooks(Arc::clone(&recorder)))],
+            ..shared_with(TrailingSlash::Strict)
+        });
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /blocked HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert!(raw.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(raw.contains("X-Tagged: yes\r\n"));
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["request /blocked", "response", "disconnect 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_can_be_served_over_any_stream() {
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        let shared = Arc::new(shared_with(TrailingSlash::Strict));
+        let server = tokio::spawn(async move {
+            let info = Arc::new(ConnectionInfo::default());
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        client
+            .write_all(b"GET /echo/one HTTP/1.1\r\n\r\nGET /echo/two HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(raw.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(raw.find("\r\n\r\none").unwrap() < raw.find("\r\n\r\ntwo").unwrap());
+    }
+
+    struct EchoUpgrade;
+
+    impl Hooks for EchoUpgrade {
+        fn on_request(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+            Some(HttpResponse::switching_protocols(
+                "echo",
+                |mut io| async move {
+                    let mut buf = [0_u8; 4];
+                    io.read_exact(&mut buf).await.unwrap();
+                    io.write_all(&buf).await.unwrap();
+                },
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_hands_over_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            hooks: vec![Arc::new(EchoUpgrade)],
+            ..shared_with(TrailingSlash::Strict)
+        });
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        // The first protocol bytes arrive together with the request, so they sit in the
+        // HTTP reader's buffer and must survive the handover
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\nping")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert!(raw.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(raw.contains("Upgrade: echo\r\n"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\nping"));
+    }
+
+    fn shared_with(trailing_slash: TrailingSlash) -> Shared {
+        Shared {
+            directory: String::new(),
+            mounts: Server::mounts(&Config::default(), ""),
+            config: Config {
+                trailing_slash,
+                ..Config::default()
+            },
+            router: Server::routes(&Config::default(), ""),
+            metrics: Metrics::default(),
+            connections: Connections::default(),
+            hooks: Vec::new(),
+            middleware: vec![Arc::new(Gzip)],
+            error_pages: ErrorPages::default(),
+            cache: None,
+            file_cache: None,
+            rate_limiter: RateLimiter::new(Vec::new()),
+            policy: Policy::new(Vec::new(), Vec::new()),
+            mirror: None,
+            recorder: None,
+            chaos: None,
+            assets: None,
+        }
+    }
+
+    async fn route_with(target: &str, trailing_slash: TrailingSlash) -> HttpResponse {
+        let request = HttpRequest {
+            uri: crate::http::Uri::parse(target).unwrap(),
+            ..Default::default()
+        };
+        Server::route(&request, &shared_with(trailing_slash)).await
+    }
+
+    #[tokio::test]
+    async fn analytics_endpoint_serves_json_when_configured() {
+        let request = HttpRequest {
+            uri: crate::http::Uri::parse("/admin/analytics").unwrap(),
+            ..Default::default()
+        };
+        let off = Server::route(&request, &shared_with(TrailingSlash::Strict)).await;
+        assert_eq!(off.status_code(), StatusCode::NOT_FOUND);
+
+        let config = Config {
+            analytics_path: Some("/admin/analytics".to_string()),
+            ..Config::default()
+        };
+        let shared = Shared {
+            router: Server::routes(&config, ""),
+            config,
+            metrics: Metrics::with_analytics(),
+            ..shared_with(TrailingSlash::Strict)
+        };
+        let on = Server::route(&request, &shared).await;
+        assert_eq!(on.status_code(), StatusCode::OK);
+        assert_eq!(on.header(header::CONTENT_TYPE), Some("application/json"));
+        // The preview escapes quotes
+        assert!(format!("{on:#}").contains(r#"{\"windows\":{\"1m\":"#));
+    }
+
+    #[tokio::test]
+    async fn chaos_rules_truncate_responses_and_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            chaos: Some(Chaos::new(vec![crate::chaos::ChaosRule {
+                prefix: "/echo".to_string(),
+                percent: 100,
+                fault: Fault::Truncate,
+            }])),
+            ..shared_with(TrailingSlash::Strict)
+        });
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        // Keep-alive is requested, but the server hangs up after half the body
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /echo/0123456789 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(raw.contains("Content-Length: 10\r\n"));
+        assert!(raw.ends_with("\r\n\r\n01234"));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(shared_with(TrailingSlash::Strict));
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        // All three in one write, then the client's side is shut down
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"GET /echo/one HTTP/1.1\r\n\r\n\
+                  GET /echo/two HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n\
+                  GET /user-agent HTTP/1.1\r\nUser-Agent: three\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut raw = Vec::new();
+        client.read_to_end(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        let mut bodies = Vec::new();
+        let mut rest = &raw[..];
+        while !rest.is_empty() {
+            let end = rest.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let head = std::str::from_utf8(&rest[..end]).unwrap();
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            let len: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = rest[end..end + len].to_vec();
+            if head.contains("Content-Encoding: gzip\r\n") {
+                let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+                std::io::Write::write_all(&mut decoder, &body).unwrap();
+                body = decoder.finish().unwrap();
+            }
+            bodies.push(String::from_utf8(body).unwrap());
+            rest = &rest[end + len..];
+        }
+        assert_eq!(bodies, ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn builder_defaults_to_the_stock_server() {
+        let stock = Server::builder().build();
+        assert_eq!(stock.addr, DEFAULT_ADDRESS);
+        assert_eq!(stock.directory, ".");
+
+        let custom = Server::builder()
+            .bind("0.0.0.0:8080")
+            .directory("/srv")
+            .build();
+        assert_eq!(custom.addr, "0.0.0.0:8080");
+        assert_eq!(custom.directory, "/srv");
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_policy() {
+        let strict = route_with("/user-agent/", TrailingSlash::Strict).await;
+        assert_eq!(strict.status_code(), StatusCode::NOT_FOUND);
+
+        let ignore = route_with("/user-agent/", TrailingSlash::Ignore).await;
+        assert_eq!(ignore.status_code(), StatusCode::OK);
+        let root = route_with("/", TrailingSlash::Ignore).await;
+        assert_eq!(root.status_code(), StatusCode::OK);
+
+        let redirect = route_with("/user-agent/?x=1", TrailingSlash::Redirect).await;
+        assert_eq!(redirect.status_code(), StatusCode::MOVED_PERMANENTLY);
+        assert!(
+            redirect
+                .to_string()
+                .contains("Location: /user-agent?x=1\r\n")
+        );
+        let exact = route_with("/user-agent", TrailingSlash::Redirect).await;
+        assert_eq!(exact.status_code(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn redirects_cannot_inject_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(shared_with(TrailingSlash::Redirect));
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"GET /echo/a%0d%0aSet-Cookie:%20evil=1/ HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+        let head = raw.split("\r\n\r\n").next().unwrap();
+        assert!(
+            !head.lines().any(|line| line.starts_with("Set-Cookie")),
+            "{head}"
+        );
+
+        // Whatever is redirected goes back out encoded
+        let spaced = route_with("/echo/a%20b/", TrailingSlash::Redirect).await;
+        assert_eq!(spaced.header(header::LOCATION), Some("/echo/a%20b"));
+    }
+
+    #[tokio::test]
+    async fn directories_can_be_redirected_to_their_slash() {
+        // Tests run in the crate root, which `directory: ""` serves
+        let dir = route_with("/files/src?x=1", TrailingSlash::Directories).await;
+        assert_eq!(dir.status_code(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(dir.header(header::LOCATION), Some("/files/src/?x=1"));
+
+        for (target, trailing_slash) in [
+            ("/files/src/", TrailingSlash::Directories),
+            ("/files/Cargo.toml", TrailingSlash::Directories),
+            ("/files/src", TrailingSlash::Strict),
+        ] {
+            // Listed, so the directories answer 200
+            let shared = Shared {
+                config: Config {
+                    trailing_slash,
+                    autoindex: true,
+                    ..Config::default()
+                },
+                ..shared_with(trailing_slash)
+            };
+            let request = HttpRequest {
+                uri: crate::http::Uri::parse(target).unwrap(),
+                ..Default::default()
+            };
+            let response = Server::route(&request, &shared).await;
+            assert_eq!(response.status_code(), StatusCode::OK, "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn directory_redirects_are_encoded() {
+        let dir = utils::temp_dir("redirect");
+        std::fs::create_dir_all(dir.join("my docs")).unwrap();
+        let shared = Shared {
+            mounts: Server::mounts(&Config::default(), dir.to_str().unwrap()),
+            ..shared_with(TrailingSlash::Directories)
+        };
+        let request = HttpRequest {
+            uri: crate::http::Uri::parse("/files/my%20docs?x=1").unwrap(),
+            ..Default::default()
+        };
+        let response = Server::route(&request, &shared).await;
+        assert_eq!(response.status_code(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.header(header::LOCATION),
+            Some("/files/my%20docs/?x=1")
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn resources_are_only_served_on_the_admin_listener() {
+        let request = |local: &str, target: &str| HttpRequest {
+            uri: crate::http::Uri::parse(target).unwrap(),
+            connection_info: Arc::new(ConnectionInfo::tcp(
+                "10.0.0.1:5000".parse().unwrap(),
+                local.parse().unwrap(),
+            )),
+            ..Default::default()
+        };
+        let shared = Shared {
+            config: Config {
+                admin_address: Some("0.0.0.0:9901".parse().unwrap()),
+                ..Config::default()
+            },
+            cache: Some(Arc::new(ResponseCache::new(16))),
+            file_cache: Some(Arc::new(FileCache::new(4096, 1024))),
+            ..shared_with(TrailingSlash::Strict)
+        };
+
+        let admin = Server::route(&request("127.0.0.1:9901", "/resources"), &shared).await;
+        assert_eq!(admin.status_code(), StatusCode::OK);
+        assert_eq!(admin.header(header::CACHE_CONTROL), Some("no-store"));
+        let body = format!("{admin:#}");
+        assert!(body.contains(r#"\"rss_bytes\":"#), "{body}");
+        assert!(body.contains(r#"\"response_cache\":{\"entries\":0,\"capacity\":16"#));
+        assert!(body.contains(r#"\"file_cache\":{\"files\":0,\"budget\":4096,\"bytes\":0}"#));
+
+        let public = Server::route(&request("127.0.0.1:4221", "/resources"), &shared).await;
+        assert_eq!(public.status_code(), StatusCode::NOT_FOUND);
+        let elsewhere = Server::route(&request("127.0.0.1:9901", "/"), &shared).await;
+        assert_eq!(elsewhere.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stats_report_lists_open_connections() {
+        let shared = shared_with(TrailingSlash::Strict);
+        shared
+            .metrics
+            .record(Some(StatusCode::OK), 10, std::time::Duration::ZERO);
+        let _idle = shared
+            .connections
+            .register("10.0.0.1:5000".parse().unwrap());
+        let busy = shared
+            .connections
+            .register("10.0.0.2:5000".parse().unwrap());
+        busy.set(ConnectionState::Handling("GET /slow".to_string()));
+
+        let report = Server::stats_report(&shared);
+        assert!(
+            report.starts_with("runtime stats: 2 connections open"),
+            "{report}"
+        );
+        assert!(report.contains("requests 1, bytes sent 10"), "{report}");
+        let lines: Vec<&str> = report.lines().skip(2).collect();
+        assert!(lines[0].contains("10.0.0.1:5000 open") && lines[0].contains("idle"));
+        assert!(lines[1].contains("1 requests, handling GET /slow for"));
+    }
+
+    #[test]
+    fn route_table_lists_mounts_before_builtin_routes() {
+        let config = Config {
+            analytics_path: Some("/admin/analytics".to_string()),
+            assets: Some(crate::assets::AssetConfig {
+                prefix: "/static/".to_string(),
+                directory: "public".into(),
+                manifest_path: None,
+            }),
+            ..Config::default()
+        };
+        let table = Server::route_table(&config, "/srv");
+        let paths: Vec<&str> = table.iter().map(|(_, path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "*",
+                "/admin/analytics",
+                "/static/manifest.json",
+                "/static/*rest",
+                "/",
+                "/echo/*text",
+                "/user-agent",
+                "/files/*name"
+            ]
+        );
+        assert_eq!(table.last().unwrap().2, "files in /srv");
+    }
+
+    #[tokio::test]
+    async fn options_asterisk_advertises_capabilities() {
+        let request = HttpRequest {
+            method: HttpMethod::Options,
+            uri: crate::http::Uri::parse("*").unwrap(),
+            ..Default::default()
+        };
+        let mut shared = shared_with(TrailingSlash::Strict);
+        shared.config.max_body_size = Some(1024);
+
+        let response = Server::route(&request, &shared).await;
+        assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.header(header::ALLOW),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS, MOVE")
+        );
+        assert_eq!(response.header(header::ACCEPT_ENCODING), Some("gzip"));
+        assert_eq!(response.header(X_MAX_BODY_SIZE), Some("1024"));
+
+        let get = route_with("/", TrailingSlash::Strict).await;
+        assert_eq!(get.header(X_MAX_BODY_SIZE), None);
+    }
+
+    #[tokio::test]
+    async fn routes_answer_options_and_405_with_their_methods() {
+        let shared = shared_with(TrailingSlash::Strict);
+        let request = |method, target| HttpRequest {
+            method,
+            uri: crate::http::Uri::parse(target).unwrap(),
+            ..Default::default()
+        };
+
+        let options = Server::route(&request(HttpMethod::Options, "/echo/hi"), &shared).await;
+        assert_eq!(options.status_code(), StatusCode::NO_CONTENT);
+        assert_eq!(options.header(header::ALLOW), Some("GET, HEAD, OPTIONS"));
+
+        let post = Server::route(&request(HttpMethod::Post, "/user-agent"), &shared).await;
+        assert_eq!(post.status_code(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(post.header(header::ALLOW), Some("GET, HEAD"));
+
+        let patch = Server::route(&request(HttpMethod::Patch, "/files/a.txt"), &shared).await;
+        assert_eq!(
+            patch.header(header::ALLOW),
+            Some("GET, HEAD, POST, PUT, DELETE, MOVE")
+        );
+
+        let nowhere = Server::route(&request(HttpMethod::Post, "/nowhere"), &shared).await;
+        assert_eq!(nowhere.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn mounts_serve_each_directory_under_its_prefix() {
+        let root = utils::temp_dir("mounts");
+        let (static_dir, uploads) = (root.join("static"), root.join("uploads"));
+        std::fs::create_dir_all(&static_dir).unwrap();
+        std::fs::create_dir_all(&uploads).unwrap();
+        std::fs::write(static_dir.join("site.css"), b"body{}").unwrap();
+        std::fs::write(uploads.join("a.txt"), b"upload").unwrap();
+        let config = Config {
+            mounts: vec![
+                Mount::new("/static", static_dir.to_str().unwrap()).unwrap(),
+                Mount::new("/uploads", uploads.to_str().unwrap()).unwrap(),
+            ],
+            ..Config::default()
+        };
+        let shared = Shared {
+            mounts: Server::mounts(&config, ""),
+            router: Server::routes(&config, ""),
+            config,
+            ..shared_with(TrailingSlash::Strict)
+        };
+        let request = |method, target: &str, destination: Option<&str>| {
+            let mut request = HttpRequest {
+                method,
+                uri: crate::http::Uri::parse(target).unwrap(),
+                ..Default::default()
+            };
+            if let Some(destination) = destination {
+                request.headers.insert(header::DESTINATION, destination);
+            }
+            request
+        };
+
+        let css = Server::route(&request(HttpMethod::Get, "/static/site.css", None), &shared).await;
+        assert_eq!(css.status_code(), StatusCode::OK);
+        assert_eq!(css.header(header::CONTENT_TYPE), Some("text/css"));
+        let upload = request(HttpMethod::Get, "/uploads/a.txt", None);
+        let upload = Server::route(&upload, &shared).await;
+        assert_eq!(upload.status_code(), StatusCode::OK);
+        // Mounts replace /files/, and don't see each other's files
+        for target in ["/files/site.css", "/static/a.txt"] {
+            let response = Server::route(&request(HttpMethod::Get, target, None), &shared).await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND, "{target}");
+        }
+
+        // A MOVE stays within its mount
+        let across = request(HttpMethod::Move, "/uploads/a.txt", Some("/static/a.txt"));
+        let across = Server::route(&across, &shared).await;
+        assert_eq!(across.status_code(), StatusCode::BAD_REQUEST);
+        let within = request(HttpMethod::Move, "/uploads/a.txt", Some("/uploads/b.txt"));
+        let within = Server::route(&within, &shared).await;
+        assert_eq!(within.status_code(), StatusCode::CREATED);
+        assert!(uploads.join("b.txt").exists() && !static_dir.join("a.txt").exists());
+
+        let table = Server::route_table(&shared.config, "");
+        assert!(table.iter().any(|(_, path, _)| path == "/static/*name"));
+        assert!(!table.iter().any(|(_, path, _)| path == "/files/*name"));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn large_uploads_are_streamed_to_disk() {
+        let dir = utils::temp_dir("upload");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            directory: dir.to_str().unwrap().to_string(),
+            mounts: Server::mounts(&Config::default(), dir.to_str().unwrap()),
+            config: Config {
+                body_buffer_limit: 4,
+                ..Config::default()
+            },
+            ..shared_with(TrailingSlash::Strict)
+        });
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        // A streamed body followed by a second request on the same connection
+        let payload = vec![b'x'; 100_000];
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "PUT /files/big.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            payload.len()
+        );
+        client.write_all(head.as_bytes()).await.unwrap();
+        client.write_all(&payload).await.unwrap();
+        client
+            .write_all(b"GET /echo/next HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert!(raw.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(raw.ends_with("\r\n\r\nnext"));
+        assert_eq!(std::fs::read(dir.join("big.bin")).unwrap(), payload);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn truncated_upload_is_an_error_not_a_201() {
+        let dir = utils::temp_dir("truncated");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = Arc::new(Shared {
+            directory: dir.to_str().unwrap().to_string(),
+            mounts: Server::mounts(&Config::default(), dir.to_str().unwrap()),
+            config: Config {
+                body_buffer_limit: 4,
+                ..Config::default()
+            },
+            ..shared_with(TrailingSlash::Strict)
+        });
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let info = Arc::new(ConnectionInfo::tcp(peer, addr));
+            Server::handle_connection(stream, info, shared).await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"POST /files/cut.bin HTTP/1.1\r\nContent-Length: 100\r\n\r\nonly some")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let mut raw = String::new();
+        client.read_to_string(&mut raw).await.unwrap();
+        server.await.unwrap();
+
+        assert!(raw.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{raw}");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
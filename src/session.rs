@@ -0,0 +1,359 @@
+use crate::crypto::{hex, hmac_sha256};
+use crate::http::cookie::SameSite;
+use crate::http::{Cookie, HttpRequest};
+use crate::middleware::{Middleware, Next, ResponseFuture};
+use crate::utils::constant_time_eq;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub type SessionData = HashMap<String, String>;
+
+// How often the middleware asks the store to drop expired sessions
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Where sessions live between requests. A session not saved or touched for its ttl
+// is gone: `load` doesn't return it, and `purge_expired` frees what it held.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: SessionData, ttl: Duration);
+    // Restarts the ttl of a session used without changing it
+    fn touch(&self, id: &str, ttl: Duration);
+    fn remove(&self, id: &str);
+    fn purge_expired(&self);
+}
+
+// Sessions in the server's memory, lost on restart
+#[derive(Default)]
+pub struct MemoryStore {
+    // id -> (data, expiry)
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let sessions = self.sessions.lock().unwrap();
+        let (data, expires) = sessions.get(id)?;
+        (Instant::now() < *expires).then(|| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, ttl: Duration) {
+        let expires = Instant::now() + ttl;
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), (data, expires));
+    }
+
+    fn touch(&self, id: &str, ttl: Duration) {
+        if let Some((_, expires)) = self.sessions.lock().unwrap().get_mut(id) {
+            *expires = Instant::now() + ttl;
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    fn purge_expired(&self) {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+// The current request's session, from `request.session()`. What handlers change is
+// saved once the response is on its way; concurrent requests of one session each save
+// their own changes, the last one winning.
+#[derive(Debug, Default)]
+pub struct Session {
+    data: Mutex<SessionData>,
+    changed: AtomicBool,
+    destroyed: AtomicBool,
+}
+
+impl Session {
+    fn new(data: SessionData) -> Self {
+        Session {
+            data: Mutex::new(data),
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: impl Into<String>, value: impl Into<String>) {
+        self.data.lock().unwrap().insert(key.into(), value.into());
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let old = self.data.lock().unwrap().remove(key);
+        self.changed.store(true, Ordering::Relaxed);
+        old
+    }
+
+    // Ends the session, say on logout: it's dropped from the store and the client is
+    // told to forget its cookie
+    pub fn destroy(&self) {
+        self.data.lock().unwrap().clear();
+        self.destroyed.store(true, Ordering::Relaxed);
+    }
+}
+
+// Middleware giving each client a session, identified by a signed cookie. Sessions are
+// only created (and the cookie only sent) once a handler puts something in one; a
+// cookie that fails its signature or names an expired session is ignored.
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    key: Vec<u8>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    // Session IDs are a keyed hash of a counter, so they can't be guessed
+    ids: RandomState,
+    next_id: AtomicU64,
+    last_purge: Mutex<Instant>,
+}
+
+impl Sessions {
+    // `key` signs the cookies and should be 32 or more random bytes, kept secret. By
+    // default the cookie is "sid" and sessions expire after a day without requests.
+    pub fn new(key: impl Into<Vec<u8>>, store: impl SessionStore + 'static) -> Self {
+        Sessions {
+            store: Box::new(store),
+            key: key.into(),
+            cookie_name: "sid".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            ids: RandomState::new(),
+            next_id: AtomicU64::new(0),
+            last_purge: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // Only send the cookie over HTTPS
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    fn new_id(&self) -> String {
+        let n = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let high = self.ids.hash_one((n, 0_u8));
+        let low = self.ids.hash_one((n, 1_u8));
+        format!("{high:016x}{low:016x}")
+    }
+
+    // "id.signature"
+    fn sign(&self, id: &str) -> String {
+        format!("{id}.{}", hex(&hmac_sha256(&self.key, id.as_bytes())))
+    }
+
+    fn verify<'v>(&self, value: &'v str) -> Option<&'v str> {
+        let (id, _) = value.rsplit_once('.')?;
+        constant_time_eq(&self.sign(id), value).then_some(id)
+    }
+
+    fn cookie(&self, cookie: Cookie) -> Cookie {
+        let cookie = cookie.path("/").http_only().same_site(SameSite::Lax);
+        match self.secure {
+            true => cookie.secure(),
+            false => cookie,
+        }
+    }
+
+    fn purge_if_due(&self) {
+        let mut last = self.last_purge.lock().unwrap();
+        if last.elapsed() >= PURGE_INTERVAL {
+            *last = Instant::now();
+            drop(last);
+            self.store.purge_expired();
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle<'a>(&'a self, request: &'a HttpRequest, next: Next<'a>) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            self.purge_if_due();
+            let cookies = request.cookies();
+            let loaded = cookies
+                .get(&self.cookie_name)
+                .and_then(|value| self.verify(value))
+                .and_then(|id| Some((id.to_string(), self.store.load(id)?)));
+            let (id, data) = match loaded {
+                Some((id, data)) => (Some(id), data),
+                None => (None, SessionData::default()),
+            };
+            request.extensions.insert(Session::new(data));
+
+            let mut response = next.run(request).await;
+            let Some(session) = request.extensions.remove::<Session>() else {
+                return response;
+            };
+            let data = || session.data.lock().unwrap().clone();
+            match id {
+                Some(id) if session.destroyed.load(Ordering::Relaxed) => {
+                    self.store.remove(&id);
+                    response.add_cookie(self.cookie(Cookie::removal(&self.cookie_name)));
+                }
+                Some(id) if session.changed.load(Ordering::Relaxed) => {
+                    self.store.save(&id, data(), self.ttl);
+                }
+                Some(id) => self.store.touch(&id, self.ttl),
+                None if session.changed.load(Ordering::Relaxed)
+                    && !session.destroyed.load(Ordering::Relaxed) =>
+                {
+                    let id = self.new_id();
+                    self.store.save(&id, data(), self.ttl);
+                    let cookie = Cookie::new(&self.cookie_name, self.sign(&id));
+                    response.add_cookie(self.cookie(cookie));
+                }
+                None => {}
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HttpResponse, header};
+    use std::sync::Arc;
+
+    // Runs `handler` behind the middleware, as a client sending `cookie`; returns what
+    // it returned and the Set-Cookie value, if any
+    async fn visit(
+        sessions: &Arc<Sessions>,
+        cookie: Option<&str>,
+        handler: impl FnOnce(&Session) -> String + Send + 'static,
+    ) -> (String, Option<String>) {
+        let mut request = HttpRequest::default();
+        if let Some(cookie) = cookie {
+            request.headers.insert(header::COOKIE, cookie);
+        }
+        let chain: Vec<Arc<dyn Middleware>> = vec![sessions.clone()];
+        let endpoint = Box::new(move |request: &HttpRequest| {
+            let body = handler(&request.session().unwrap());
+            let mut response = HttpResponse::ok();
+            response.set_header("x-value", body);
+            Box::pin(async move { response }) as ResponseFuture<'_>
+        });
+        let response = Next::new(&chain, endpoint).run(&request).await;
+        let value = response.header("x-value").unwrap_or_default().to_string();
+        let set_cookie = response.header(header::SET_COOKIE).map(str::to_string);
+        (value, set_cookie)
+    }
+
+    // The name=value part of a Set-Cookie, as the client sends it back
+    fn pair(set_cookie: &str) -> String {
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn sessions_start_when_written_and_come_back_with_the_cookie() {
+        let sessions = Arc::new(Sessions::new(
+            *b"0123456789abcdef0123456789abcdef",
+            MemoryStore::default(),
+        ));
+
+        let (_, none) = visit(&sessions, None, |session| {
+            session.get("user").unwrap_or_default()
+        })
+        .await;
+        assert_eq!(none, None);
+
+        let (_, set_cookie) = visit(&sessions, None, |session| {
+            session.insert("user", "alice");
+            String::new()
+        })
+        .await;
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.starts_with("sid="));
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"));
+        let cookie = pair(&set_cookie);
+
+        let (user, again) = visit(&sessions, Some(&cookie), |session| {
+            session.get("user").unwrap_or_default()
+        })
+        .await;
+        assert_eq!(user, "alice");
+        assert_eq!(again, None);
+
+        // A forged signature, or another ID under the same one, gets nothing
+        let mut forged = cookie.clone();
+        forged.pop();
+        forged.push(if cookie.ends_with('0') { '1' } else { '0' });
+        let other = if cookie[4..].starts_with('0') {
+            '1'
+        } else {
+            '0'
+        };
+        let other_id = format!("sid={other}{}", &cookie[5..]);
+        for cookie in [forged, other_id, "sid=garbage".to_string()] {
+            let (user, _) = visit(&sessions, Some(&cookie), |session| {
+                session.get("user").unwrap_or_default()
+            })
+            .await;
+            assert_eq!(user, "", "{cookie}");
+        }
+    }
+
+    #[tokio::test]
+    async fn destroyed_sessions_are_forgotten() {
+        let sessions = Arc::new(Sessions::new("key", MemoryStore::default()).secure());
+        let (_, set_cookie) = visit(&sessions, None, |session| {
+            session.insert("user", "alice");
+            String::new()
+        })
+        .await;
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.contains("; Secure"));
+        let cookie = pair(&set_cookie);
+
+        let (_, removal) = visit(&sessions, Some(&cookie), |session| {
+            session.destroy();
+            String::new()
+        })
+        .await;
+        assert!(removal.unwrap().starts_with("sid=; Path=/; Max-Age=0"));
+        let (user, _) = visit(&sessions, Some(&cookie), |session| {
+            session.get("user").unwrap_or_default()
+        })
+        .await;
+        assert_eq!(user, "");
+    }
+
+    #[test]
+    fn the_memory_store_expires_idle_sessions() {
+        let store = MemoryStore::default();
+        let data = SessionData::from([("user".to_string(), "alice".to_string())]);
+        store.save("kept", data.clone(), Duration::from_secs(60));
+        store.save("stale", data, Duration::ZERO);
+        assert!(store.load("kept").is_some());
+        assert_eq!(store.load("stale"), None);
+
+        store.purge_expired();
+        assert_eq!(store.sessions.lock().unwrap().len(), 1);
+        store.touch("kept", Duration::ZERO);
+        assert_eq!(store.load("kept"), None);
+    }
+}
@@ -0,0 +1,174 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Duration, Instant, Sleep};
+
+// A token bucket holding up to one second's worth of bytes, so short bursts go out at
+// full speed while sustained transfers settle at the configured rate.
+pub struct Bandwidth {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bandwidth {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Bandwidth {
+            rate: bytes_per_sec.max(1),
+            tokens: bytes_per_sec.max(1) as f64,
+            last: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, bytes_per_sec: u64) {
+        self.refill();
+        self.rate = bytes_per_sec.max(1);
+        self.tokens = self.tokens.min(self.rate as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+    }
+
+    // How many of `want` bytes may go now. When the bucket is empty the task is parked
+    // until a slice (1/20 s worth) has refilled, rather than trickling out single bytes.
+    fn poll_quota(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        let need = (want as f64).min((self.rate as f64 / 20.0).max(1.0));
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if self.tokens >= need {
+                return Poll::Ready(want.min(self.tokens as usize));
+            }
+            let wait = Duration::from_secs_f64((need - self.tokens) / self.rate as f64);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+// Rate-limits reads and writes on `inner` through a connection's bucket; with no bucket
+// it passes straight through
+pub struct Throttled<'a, S> {
+    inner: S,
+    bucket: Option<&'a mut Bandwidth>,
+}
+
+impl<'a, S> Throttled<'a, S> {
+    pub fn new(inner: S, bucket: Option<&'a mut Bandwidth>) -> Self {
+        Throttled { inner, bucket }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(bucket) = this.bucket.as_deref_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_quota(cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        bucket.consume(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(bucket) = this.bucket.as_deref_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let allowed = ready!(bucket.poll_quota(cx, buf.remaining()));
+        let n = if allowed == buf.remaining() {
+            let before = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - before
+        } else {
+            // Read into a smaller buffer so the inner reader can't go over quota
+            let mut quota = vec![0_u8; allowed];
+            let mut limited = ReadBuf::new(&mut quota);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+            limited.filled().len()
+        };
+        bucket.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn writes_settle_at_the_configured_rate() {
+        let mut bucket = Bandwidth::new(10_000);
+        let mut sink = Vec::new();
+        let started = Instant::now();
+
+        // The first second's worth is the burst; the next 5000 bytes take ~0.5s
+        let mut out = Throttled::new(&mut sink, Some(&mut bucket));
+        out.write_all(&[0_u8; 15_000]).await.unwrap();
+
+        assert_eq!(sink.len(), 15_000);
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn reads_pass_through_without_a_bucket() {
+        let mut source: &[u8] = b"unthrottled";
+        let mut out = String::new();
+        Throttled::new(&mut source, None)
+            .read_to_string(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, "unthrottled");
+    }
+
+    #[tokio::test]
+    async fn reads_are_limited_by_the_bucket() {
+        let mut bucket = Bandwidth::new(4);
+        let mut source: &[u8] = b"0123456789";
+        let mut buf = [0_u8; 10];
+
+        let n = Throttled::new(&mut source, Some(&mut bucket))
+            .read(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf[..n], b"0123");
+    }
+}
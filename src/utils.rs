@@ -1,8 +1,287 @@
 use flate2::{Compression, write::GzEncoder};
-use std::io::Write;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::task::Poll;
+
+// How many body bytes the alternate (`{:#}`) Display form shows
+const BODY_PREVIEW_LIMIT: usize = 256;
 
 pub fn compress_body(data: &[u8]) -> Vec<u8> {
     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
     encoder.write_all(data).unwrap();
     encoder.finish().unwrap() // Returns the compressed Vec<u8>
 }
+
+// Errors that just mean the peer went away; these are a normal end of a connection
+pub fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WriteZero
+    )
+}
+
+// Runs a future, turning a panic while polling it into an Err instead of unwinding
+// through the connection task
+pub async fn catch_unwind<F: Future>(fut: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut fut = Box::pin(fut);
+    std::future::poll_fn(move |cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+// The text a panic was raised with, for the log; panics with a non-string payload
+// (`panic_any`) have none
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload")
+}
+
+// Shared by the Display impls: a size summary, plus an escaped preview when `{:#}` is used.
+// Non-printable bytes are escaped so binary bodies can't garble the terminal.
+pub fn fmt_body(f: &mut fmt::Formatter<'_>, body: &[u8]) -> fmt::Result {
+    write!(f, "[{} bytes]", body.len())?;
+    if f.alternate() && !body.is_empty() {
+        let shown = &body[..body.len().min(BODY_PREVIEW_LIMIT)];
+        write!(f, "\r\n{}", shown.escape_ascii())?;
+        if shown.len() < body.len() {
+            write!(f, "... ({} more bytes)", body.len() - shown.len())?;
+        }
+    }
+    Ok(())
+}
+
+// Standard (RFC 4648) base64 with padding, as used by Basic auth credentials
+pub fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, quad) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0_u32;
+        for &c in &quad[..4 - padding] {
+            bits = (bits << 6) | sextet(c)?;
+        }
+        bits <<= 6 * padding as u32;
+        out.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+// Compares secrets without stopping at the first difference, so response timing
+// doesn't reveal how much of a guess was right
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0_u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// A JSON string literal, quotes included
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Text safe to put in HTML content or a quoted attribute
+pub fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// One path segment for a URL: everything but unreserved characters percent-encoded,
+// so `/`, `?`, `#` and `%` in a file name stay part of it
+pub fn percent_encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+// A decoded path back in URL form, each segment percent-encoded, so it can go in a
+// Location header: CR, LF and the like can't split the response
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Whether a `/`-separated path matches a glob: `*` stands for any run of characters
+// within a segment, `**` for any run across segments, `?` for one character. A
+// pattern without a `/` is matched against the last segment alone, so `*.html`
+// covers HTML files at any depth.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let path = match pattern.contains('/') {
+        true => path,
+        false => path.rsplit('/').next().unwrap_or_default(),
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    wildcard(&pattern, &path)
+}
+
+fn wildcard(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| wildcard(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            // Up to the next `/` at most
+            let segment = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=segment).any(|i| wildcard(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => text.first().is_some_and(|&c| c != '/') && wildcard(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && wildcard(rest, &text[1..]),
+    }
+}
+
+// A new, empty directory under the system temp dir for one test's files. The name says
+// which test it belongs to; the process id and a counter keep parallel tests apart.
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "cc_http_{name}_{}_{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn catch_unwind_turns_panics_into_errors() {
+        assert_eq!(catch_unwind(async { 7 }).await.ok(), Some(7));
+
+        let result = catch_unwind(async {
+            tokio::task::yield_now().await;
+            panic!("boom");
+        })
+        .await;
+        assert_eq!(panic_message(&*result.unwrap_err()), "boom");
+
+        let code = 7;
+        let result = catch_unwind(async move { panic!("failed with {code}") }).await;
+        assert_eq!(panic_message(&*result.unwrap_err()), "failed with 7");
+        let result = catch_unwind(async { std::panic::panic_any(7_u8) }).await;
+        assert_eq!(
+            panic_message(&*result.unwrap_err()),
+            "unknown panic payload"
+        );
+    }
+
+    #[test]
+    fn base64_decodes_padded_input() {
+        assert_eq!(base64_decode("YWxpY2U6czNjcmV0").unwrap(), b"alice:s3cret");
+        assert_eq!(base64_decode("Ym9iOmh1bnRlcjI=").unwrap(), b"bob:hunter2");
+        assert_eq!(base64_decode("YQ==").unwrap(), b"a");
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert!(base64_decode("YQ=").is_none());
+        assert!(base64_decode("YQ==YQ==").is_none());
+        assert!(base64_decode("Y!==").is_none());
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_strings() {
+        assert!(constant_time_eq("s3cret", "s3cret"));
+        assert!(!constant_time_eq("s3cret", "s3creT"));
+        assert!(!constant_time_eq("s3cret", "s3cre"));
+    }
+
+    #[test]
+    fn json_string_escapes_specials() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn html_and_url_escaping() {
+        assert_eq!(
+            html_escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(percent_encode("my file#1.txt"), "my%20file%231.txt");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+        assert_eq!(encode_path("/a b/\r\nx/"), "/a%20b/%0D%0Ax/");
+    }
+
+    #[test]
+    fn globs_match_within_and_across_segments() {
+        assert!(glob_match("/assets/**", "/assets/js/app.js"));
+        assert!(!glob_match("/assets/**", "/media/app.js"));
+        assert!(glob_match("/assets/*.css", "/assets/site.css"));
+        assert!(!glob_match("/assets/*.css", "/assets/old/site.css"));
+        assert!(glob_match("/**/*.map", "/js/vendor/app.js.map"));
+        assert!(glob_match("*.html", "/docs/guide/index.html"));
+        assert!(!glob_match("*.html", "/index.htm"));
+        assert!(glob_match("/v?/*", "/v2/data.json"));
+        assert!(!glob_match("/v?/*", "/v10/data.json"));
+    }
+}
@@ -0,0 +1,56 @@
+// Keeps what the server caches in step with the served directory: any change under it
+// is reported by its name there, so edits show up without a restart.
+use crate::logging::{debug, error};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+
+// `on_change` gets the name of what changed relative to the directory ("css/site.css");
+// for a directory, that's the prefix of everything inside it
+pub fn watch_directory(
+    directory: &Path,
+    on_change: impl Fn(&str) + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    // Events carry absolute paths, so compare against the canonical root
+    let root = directory.canonicalize()?;
+    let base = root.clone();
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+            for path in &event.paths {
+                if let Some(name) = relative_name(&base, path) {
+                    debug!("{} changed, invalidating {name}", path.display());
+                    on_change(&name);
+                }
+            }
+        }
+        Ok(_) => {}
+        Err(e) => error!("file watcher error: {e}"),
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+// A path under the root as its /files/ name
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_paths_under_the_root_to_names() {
+        let root = Path::new("/srv/www");
+        assert_eq!(
+            relative_name(root, Path::new("/srv/www/css/site.css")).as_deref(),
+            Some("css/site.css")
+        );
+        assert_eq!(relative_name(root, Path::new("/etc/passwd")), None);
+    }
+}